@@ -0,0 +1,86 @@
+//! Benchmarks of merging a synthetic bracket of three exposures.
+//!
+//! Run with `cargo bench --bench merge`
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use image_hdr::deghost::Deghosting;
+use image_hdr::input::HDRInput;
+use image_hdr::merge::{Debevec, MergeStrategy, PoissonEstimator};
+use ndarray::Array3;
+use std::time::Duration;
+
+/// Exposure times of the bracket, in seconds.
+const EXPOSURES: [f32; 3] = [1. / 500., 1. / 60., 1. / 8.];
+
+/// Edge lengths of the square images to merge.
+const SIZES: [usize; 2] = [512, 1024];
+
+/// Create a bracket of RGB exposures of a scene spanning roughly twelve stops, with highlights
+/// clipped in the longer exposures.
+#[allow(clippy::cast_precision_loss)]
+fn bracket(size: usize) -> Vec<HDRInput> {
+    EXPOSURES
+        .iter()
+        .map(|&exposure| {
+            let buffer = Array3::from_shape_fn((size, size, 3), |(y, x, channel)| {
+                let level = ((x * 7 + y * 13 + channel * 31) % 997) as f32 / 997.;
+                let radiance = 2_f32.powf(level * 12.) / 64.;
+
+                (radiance * exposure).min(1.)
+            });
+
+            HDRInput::with_buffer(buffer, Duration::from_secs_f32(exposure), 100.)
+                .expect("Synthetic exposures should be valid inputs")
+        })
+        .collect()
+}
+
+fn merge(criterion: &mut Criterion) {
+    let strategies: [(&str, Box<dyn MergeStrategy>); 3] = [
+        ("poisson", Box::new(PoissonEstimator::default())),
+        (
+            "poisson_deghosting",
+            Box::new(PoissonEstimator::default().with_deghosting(Deghosting::default())),
+        ),
+        ("debevec", Box::new(Debevec)),
+    ];
+
+    let mut group = criterion.benchmark_group("merge");
+    group.sample_size(10);
+
+    for size in SIZES {
+        let mut inputs = bracket(size);
+        group.throughput(Throughput::Elements((size * size) as u64));
+
+        for (name, strategy) in &strategies {
+            group.bench_with_input(BenchmarkId::new(*name, size), &size, |bencher, _| {
+                bencher.iter(|| strategy.merge(&mut inputs).expect("Bracket should merge"));
+            });
+        }
+    }
+
+    group.finish();
+}
+
+fn estimate(criterion: &mut Criterion) {
+    let mut group = criterion.benchmark_group("estimate");
+    group.sample_size(10);
+
+    for size in SIZES {
+        let inputs = bracket(size);
+        group.throughput(Throughput::Elements((size * size) as u64));
+
+        group.bench_with_input(BenchmarkId::new("poisson", size), &size, |bencher, _| {
+            bencher.iter(|| {
+                PoissonEstimator::default()
+                    .estimate(&inputs)
+                    .expect("Bracket should merge")
+            });
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, merge, estimate);
+criterion_main!(benches);
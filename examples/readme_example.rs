@@ -8,6 +8,7 @@ use image_hdr::{
     exif::{get_exif_data, get_exposures, get_gains},
     input::HDRInput,
     stretch::apply_histogram_stretch,
+    transfer::TransferFunction,
 };
 
 #[derive(Debug, thiserror::Error)]
@@ -67,10 +68,11 @@ fn main() -> Result<(), Error> {
         println!("Adding image: {url}");
         dbg!(gains, exposure);
 
-        images.push(HDRInput::with_image(
+        images.push(HDRInput::with_transfer_function(
             &image,
             Duration::from_secs_f32(exposure),
             gains,
+            &TransferFunction::Srgb,
         )?);
 
         buf.clear();
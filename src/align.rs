@@ -0,0 +1,384 @@
+//! Alignment of handheld brackets using Ward's Median Threshold Bitmap (MTB) pyramid as described
+//! in [Fast, Robust Image Registration for Compositing High Dynamic Range Photographs from Hand-Held Exposures](https://doi.org/10.1080/10867651.2003.10487583).
+//!
+//! Median threshold bitmaps are largely invariant to exposure, which allows comparing frames of a
+//! bracket directly, without knowing the camera response.
+
+use crate::input::{HDRInput, HDRInputList};
+use crate::Error;
+use ndarray::{s, Array2, Array3, Axis};
+use rayon::prelude::*;
+
+pub mod registration;
+
+/// Frames whose smaller dimension falls below this size are not downsampled any further.
+const MIN_PYRAMID_SIZE: usize = 16;
+
+/// Integer translation of an input relative to the reference exposure, in pixels. A pixel at
+/// `(x, y)` in the reference corresponds to the pixel at `(x + self.x, y + self.y)` in the input.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Offset {
+    /// Horizontal offset in pixels.
+    pub x: i32,
+    /// Vertical offset in pixels.
+    pub y: i32,
+}
+
+/// Translation-only alignment of a bracket using median threshold bitmaps.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MtbAlignment {
+    /// Number of times the frames are downsampled by a factor of two. Offsets are searched at
+    /// `levels + 1` resolutions, so the largest offset that can be found is `2^(levels + 1) - 1`
+    /// pixels in every direction.
+    pub levels: usize,
+    /// Pixels that are within this distance of the median (in gamma encoded `0..=1` values) are
+    /// excluded from the comparison, since they are dominated by noise.
+    pub exclusion_threshold: f32,
+}
+
+impl Default for MtbAlignment {
+    fn default() -> Self {
+        Self {
+            levels: 6,
+            exclusion_threshold: 4. / 255.,
+        }
+    }
+}
+
+/// Threshold and exclusion bitmaps of a single pyramid level.
+struct Bitmaps {
+    threshold: Array2<bool>,
+    exclusion: Array2<bool>,
+}
+
+impl Bitmaps {
+    /// Compute bitmaps of a luminance image.
+    fn new(luminance: &Array2<f32>, exclusion_threshold: f32) -> Self {
+        let mut values = luminance.iter().copied().collect::<Vec<f32>>();
+        let middle = values.len() / 2;
+        let median = *values.select_nth_unstable_by(middle, f32::total_cmp).1;
+
+        Self {
+            threshold: luminance.mapv(|value| value > median),
+            exclusion: luminance.mapv(|value| (value - median).abs() > exclusion_threshold),
+        }
+    }
+
+    /// Count the differing, non-excluded pixels between two bitmaps given the offset of `other`.
+    fn difference(&self, other: &Self, offset: Offset) -> usize {
+        let (height, width) = self.threshold.dim();
+
+        (0..height)
+            .into_par_iter()
+            .map(|y| {
+                let Some(other_y) = y.checked_add_signed(offset.y as isize) else {
+                    return 0;
+                };
+                if other_y >= height {
+                    return 0;
+                }
+
+                (0..width)
+                    .filter(|&x| {
+                        let Some(other_x) = x.checked_add_signed(offset.x as isize) else {
+                            return false;
+                        };
+
+                        other_x < width
+                            && self.exclusion[[y, x]]
+                            && other.exclusion[[other_y, other_x]]
+                            && self.threshold[[y, x]] != other.threshold[[other_y, other_x]]
+                    })
+                    .count()
+            })
+            .sum()
+    }
+}
+
+/// Gamma encoded luminance of a buffer, so that the exclusion threshold is perceptually uniform.
+fn luminance(buffer: &Array3<f32>) -> Array2<f32> {
+    let maximum = buffer.iter().copied().fold(f32::EPSILON, f32::max);
+
+    buffer
+        .mean_axis(Axis(2))
+        .unwrap_or_else(|| panic!("Expected at least 1 channel"))
+        .mapv(|value| (value.max(0.) / maximum).powf(1. / 2.2))
+}
+
+/// Validate that no input carries CFA data, whose colour filter pattern would be broken by
+/// shifting frames by odd offsets or resampling them.
+///
+/// # Errors
+/// - If any of the inputs carries CFA data
+pub(crate) fn validate_demosaiced(inputs: &[HDRInput]) -> Result<(), Error> {
+    if inputs.iter().any(|input| {
+        input
+            .get_sensor_metadata()
+            .is_some_and(|sensor| sensor.cfa.is_some())
+    }) {
+        return Err(Error::InputError {
+            parameter_name: "inputs".to_string(),
+            message: "Frames carrying CFA data cannot be aligned, demosaic them first".to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Index of the middle exposure of the bracket, which is used as the reference.
+pub(crate) fn reference_index(inputs: &[HDRInput]) -> usize {
+    let mut order = (0..inputs.len()).collect::<Vec<usize>>();
+    order.sort_by(|&a, &b| {
+        inputs[a]
+            .get_exposure_factor()
+            .total_cmp(&inputs[b].get_exposure_factor())
+    });
+
+    order[order.len() / 2]
+}
+
+/// Downsample an image by a factor of two by averaging blocks of 2x2 pixels.
+fn downsample(image: &Array2<f32>) -> Array2<f32> {
+    let (height, width) = image.dim();
+
+    Array2::from_shape_fn((height / 2, width / 2), |(y, x)| {
+        (image[[2 * y, 2 * x]]
+            + image[[2 * y + 1, 2 * x]]
+            + image[[2 * y, 2 * x + 1]]
+            + image[[2 * y + 1, 2 * x + 1]])
+            / 4.
+    })
+}
+
+impl MtbAlignment {
+    /// Build the bitmap pyramid of an input, from finest to coarsest level.
+    fn pyramid(&self, input: &HDRInput) -> Vec<Bitmaps> {
+        let mut level = luminance(input.get_buffer());
+        let mut pyramid = vec![Bitmaps::new(&level, self.exclusion_threshold)];
+
+        while pyramid.len() <= self.levels
+            && level.nrows().min(level.ncols()) / 2 >= MIN_PYRAMID_SIZE
+        {
+            level = downsample(&level);
+            pyramid.push(Bitmaps::new(&level, self.exclusion_threshold));
+        }
+
+        pyramid
+    }
+
+    /// Estimate the offset of an input relative to the reference, from coarse to fine.
+    fn estimate_offset(reference: &[Bitmaps], input: &[Bitmaps]) -> Offset {
+        reference
+            .iter()
+            .zip(input)
+            .rev()
+            .fold(Offset::default(), |offset, (reference, input)| {
+                let centre = Offset {
+                    x: offset.x * 2,
+                    y: offset.y * 2,
+                };
+
+                (-1..=1)
+                    .flat_map(|y| (-1..=1).map(move |x| (x, y)))
+                    .map(|(x, y)| Offset {
+                        x: centre.x + x,
+                        y: centre.y + y,
+                    })
+                    .min_by_key(|&candidate| reference.difference(input, candidate))
+                    .unwrap_or(centre)
+            })
+    }
+
+    /// Estimate the translation of every input relative to the middle exposure of the bracket,
+    /// without modifying the inputs.
+    ///
+    /// # Errors
+    /// - If fewer than two inputs are provided
+    /// - If inputs are of different dimensions
+    pub fn estimate(&self, inputs: &[HDRInput]) -> Result<Vec<Offset>, Error> {
+        crate::merge::validate_inputs(inputs)?;
+        let inputs = &*crate::merge::without_partial_aperture(inputs);
+
+        let reference_index = reference_index(inputs);
+
+        let pyramids = inputs
+            .par_iter()
+            .map(|input| self.pyramid(input))
+            .collect::<Vec<Vec<Bitmaps>>>();
+        let reference = &pyramids[reference_index];
+
+        Ok(pyramids
+            .par_iter()
+            .enumerate()
+            .map(|(index, pyramid)| {
+                if index == reference_index {
+                    Offset::default()
+                } else {
+                    Self::estimate_offset(reference, pyramid)
+                }
+            })
+            .collect())
+    }
+
+    /// Align the inputs to the middle exposure of the bracket. Every input is shifted by its
+    /// estimated offset and all inputs are cropped to the region covered by every frame.
+    /// Returns the estimated offsets, in the same order as the inputs.
+    ///
+    /// # Errors
+    /// - If fewer than two inputs are provided
+    /// - If inputs are of different dimensions
+    /// - If any of the inputs carries CFA data
+    /// - If the frames don't overlap after alignment
+    pub fn align(&self, inputs: &mut HDRInputList) -> Result<Vec<Offset>, Error> {
+        validate_demosaiced(inputs.as_slice())?;
+        let offsets = self.estimate(inputs.as_slice())?;
+        apply_offsets(inputs.as_slice_mut(), &offsets)?;
+
+        Ok(offsets)
+    }
+}
+
+/// Shift every input by its offset and crop all inputs to their common region.
+///
+/// # Errors
+/// - If the frames don't overlap after alignment
+#[allow(clippy::cast_possible_wrap)]
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_sign_loss)]
+fn apply_offsets(inputs: &mut [HDRInput], offsets: &[Offset]) -> Result<(), Error> {
+    let (height, width, _) = inputs[0].get_buffer().dim();
+    let (height, width) = (height as i64, width as i64);
+
+    let left = offsets
+        .iter()
+        .map(|offset| i64::from(-offset.x).max(0))
+        .max();
+    let top = offsets
+        .iter()
+        .map(|offset| i64::from(-offset.y).max(0))
+        .max();
+    let right = offsets
+        .iter()
+        .map(|offset| (width - i64::from(offset.x)).min(width))
+        .min();
+    let bottom = offsets
+        .iter()
+        .map(|offset| (height - i64::from(offset.y)).min(height))
+        .min();
+
+    let (Some(left), Some(top), Some(right), Some(bottom)) = (left, top, right, bottom) else {
+        return Ok(());
+    };
+
+    if left >= right || top >= bottom {
+        return Err(Error::InputError {
+            parameter_name: "inputs".to_string(),
+            message: "Frames don't overlap after alignment".to_string(),
+        });
+    }
+
+    inputs
+        .par_iter_mut()
+        .zip(offsets)
+        .for_each(|(input, offset)| {
+            let (x, y) = (i64::from(offset.x), i64::from(offset.y));
+            let cropped = input
+                .get_buffer()
+                .slice(s![
+                    (top + y) as usize..(bottom + y) as usize,
+                    (left + x) as usize..(right + x) as usize,
+                    ..
+                ])
+                .to_owned();
+
+            *input.get_buffer_mut() = cropped;
+        });
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::sensor::{CfaPattern, SensorMetadata};
+    use std::time::Duration;
+
+    /// Smooth, textured scene sampled at a position of the reference frame.
+    #[allow(clippy::cast_possible_truncation)]
+    pub(crate) fn scene(x: f64, y: f64) -> f32 {
+        (0.45
+            + 0.2 * (x * 0.21).sin() * (y * 0.17).cos()
+            + 0.15 * (x * 0.05 + y * 0.09).sin()
+            + 0.1 * ((x - 40.) * 0.11).cos()) as f32
+    }
+
+    /// Bracket of the scene where the frame of every exposure is translated by the given offset.
+    #[allow(clippy::cast_precision_loss)]
+    pub(crate) fn shifted_bracket(offsets: &[(f64, f64)]) -> HDRInputList {
+        offsets
+            .iter()
+            .enumerate()
+            .map(|(index, &(dx, dy))| {
+                let exposure = 2_f32.powi(i32::try_from(index).unwrap_or(0)) / 100.;
+                let buffer = Array3::from_shape_fn((96, 128, 3), |(y, x, _)| {
+                    scene(x as f64 + dx, y as f64 + dy) * exposure * 20.
+                });
+
+                HDRInput::with_buffer(buffer, Duration::from_secs_f32(exposure), 100.)
+                    .expect("Synthetic exposure should be valid")
+            })
+            .collect::<Vec<HDRInput>>()
+            .into()
+    }
+
+    #[test]
+    fn finds_integer_offsets() {
+        let mut inputs = shifted_bracket(&[(3., -2.), (0., 0.), (-5., 4.)]);
+
+        let offsets = MtbAlignment::default()
+            .align(&mut inputs)
+            .expect("Bracket should be aligned");
+
+        assert_eq!(
+            offsets,
+            vec![
+                Offset { x: -3, y: 2 },
+                Offset::default(),
+                Offset { x: 5, y: -4 }
+            ]
+        );
+        assert_eq!(inputs.as_slice()[0].get_buffer().dim(), (90, 120, 3));
+    }
+
+    /// Attach a Bayer pattern to every input, as if they were read as raw mosaics.
+    pub(crate) fn with_cfa(inputs: HDRInputList) -> HDRInputList {
+        let sensor = SensorMetadata {
+            make: String::new(),
+            model: String::new(),
+            cfa: Some(
+                CfaPattern::new(ndarray::arr2(&[[0, 1], [1, 2]])).expect("Pattern should be valid"),
+            ),
+            black_levels: [0.; 4],
+            white_levels: [1.; 4],
+            white_balance: [1.; 4],
+            xyz_to_camera: [[0.; 3]; 4],
+        };
+
+        inputs
+            .into_vec()
+            .into_iter()
+            .map(|input| input.with_sensor_metadata(sensor.clone()))
+            .collect::<Vec<HDRInput>>()
+            .into()
+    }
+
+    #[test]
+    fn rejects_cfa_data() {
+        let mut inputs = with_cfa(shifted_bracket(&[(0., 0.), (1., 0.)]));
+
+        assert!(matches!(
+            MtbAlignment::default().align(&mut inputs),
+            Err(Error::InputError { .. })
+        ));
+    }
+}
@@ -0,0 +1,759 @@
+//! Sub-pixel registration of bracketed frames supporting rotation, scale and perspective changes.
+//!
+//! Frames are normalized by their exposure and compared in the log domain, so that they can be
+//! registered directly against the reference exposure. The transform of every frame is estimated
+//! with a coarse-to-fine Gauss-Newton (Lucas-Kanade) optimisation driven by the image gradients,
+//! starting from the translation found by [`MtbAlignment`].
+
+use super::{downsample, reference_index, validate_demosaiced, MtbAlignment, Offset};
+use crate::input::{HDRInput, HDRInputList};
+use crate::linalg::NormalEquations;
+use crate::merge::{
+    sample, validate_inputs, without_partial_aperture, BLACK_THRESHOLD, SATURATION_THRESHOLD,
+};
+use crate::Error;
+use ndarray::{Array2, Array3, Axis, Zip};
+use rayon::prelude::*;
+
+/// Projective transform mapping homogeneous pixel coordinates `(x, y, 1)` of the reference frame
+/// to pixel coordinates of another frame.
+pub type Transform = [[f64; 3]; 3];
+
+/// Iteration at a pyramid level stops once no parameter changes more than this.
+const CONVERGENCE_THRESHOLD: f64 = 1e-7;
+
+/// Maximum number of pixels sampled per Gauss-Newton iteration.
+const MAX_SAMPLES: usize = 1 << 18;
+
+const IDENTITY: Transform = [[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]];
+
+/// Family of transforms frames are allowed to move by.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MotionModel {
+    /// Rotation, uniform scale and translation.
+    #[default]
+    Similarity,
+    /// Full perspective transform.
+    Homography,
+}
+
+/// Resampling filter used to warp frames.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Interpolation {
+    /// Bilinear interpolation of the 2x2 nearest pixels.
+    #[default]
+    Bilinear,
+    /// Catmull-Rom bicubic interpolation of the 4x4 nearest pixels.
+    Bicubic,
+}
+
+/// Rectangle of the reference frame that is covered by every registered frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct CropRect {
+    /// Left edge in pixels.
+    pub x: usize,
+    /// Top edge in pixels.
+    pub y: usize,
+    /// Width in pixels.
+    pub width: usize,
+    /// Height in pixels.
+    pub height: usize,
+}
+
+/// Result of registering a bracket.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Registration {
+    /// Transform of every frame relative to the reference exposure, in the same order as the
+    /// inputs. Pixel `(x, y)` of the reference corresponds to `transform * (x, y, 1)` in the frame.
+    pub transforms: Vec<Transform>,
+    /// Region of the reference frame the registered frames were cropped to.
+    pub crop: CropRect,
+}
+
+/// Registration of a bracket using exposure-normalized image gradients.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GradientAlignment {
+    /// Family of transforms to estimate.
+    pub model: MotionModel,
+    /// Resampling filter used to warp the frames.
+    pub interpolation: Interpolation,
+    /// Number of pyramid levels to refine the transforms at.
+    pub levels: usize,
+    /// Maximum number of Gauss-Newton iterations per pyramid level.
+    pub max_iterations: usize,
+}
+
+impl Default for GradientAlignment {
+    fn default() -> Self {
+        Self {
+            model: MotionModel::default(),
+            interpolation: Interpolation::default(),
+            levels: 4,
+            max_iterations: 30,
+        }
+    }
+}
+
+impl MotionModel {
+    fn parameter_count(self) -> usize {
+        match self {
+            Self::Similarity => 4,
+            Self::Homography => 8,
+        }
+    }
+
+    fn parameters(self, transform: &Transform) -> Vec<f64> {
+        match self {
+            Self::Similarity => vec![
+                transform[0][0],
+                transform[1][0],
+                transform[0][2],
+                transform[1][2],
+            ],
+            Self::Homography => vec![
+                transform[0][0],
+                transform[0][1],
+                transform[0][2],
+                transform[1][0],
+                transform[1][1],
+                transform[1][2],
+                transform[2][0],
+                transform[2][1],
+            ],
+        }
+    }
+
+    fn transform(self, parameters: &[f64]) -> Transform {
+        match (self, parameters) {
+            (Self::Similarity, &[a, b, x, y]) => [[a, -b, x], [b, a, y], [0., 0., 1.]],
+            (Self::Homography, &[h00, h01, h02, h10, h11, h12, h20, h21]) => {
+                [[h00, h01, h02], [h10, h11, h12], [h20, h21, 1.]]
+            }
+            _ => panic!("Unexpected number of transform parameters"),
+        }
+    }
+
+    /// Derivatives of the warped position `(x', y')` with respect to every parameter, evaluated
+    /// at `(x, y)`.
+    fn jacobian(self, transform: &Transform, x: f64, y: f64) -> ([f64; 8], [f64; 8]) {
+        match self {
+            Self::Similarity => (
+                [x, -y, 1., 0., 0., 0., 0., 0.],
+                [y, x, 0., 1., 0., 0., 0., 0.],
+            ),
+            Self::Homography => {
+                let (warped_x, warped_y) = apply(transform, x, y);
+                let denominator = transform[2][0] * x + transform[2][1] * y + 1.;
+                let (x, y) = (x / denominator, y / denominator);
+                let inverse = 1. / denominator;
+
+                (
+                    [x, y, inverse, 0., 0., 0., -x * warped_x, -y * warped_x],
+                    [0., 0., 0., x, y, inverse, -x * warped_y, -y * warped_y],
+                )
+            }
+        }
+    }
+}
+
+/// Apply a transform to a point.
+fn apply(transform: &Transform, x: f64, y: f64) -> (f64, f64) {
+    let w = transform[2][0] * x + transform[2][1] * y + transform[2][2];
+
+    (
+        (transform[0][0] * x + transform[0][1] * y + transform[0][2]) / w,
+        (transform[1][0] * x + transform[1][1] * y + transform[1][2]) / w,
+    )
+}
+
+/// Multiply two transforms.
+fn multiply(a: &Transform, b: &Transform) -> Transform {
+    let mut result = [[0.; 3]; 3];
+
+    for (row, result) in result.iter_mut().enumerate() {
+        for (column, value) in result.iter_mut().enumerate() {
+            *value = (0..3).map(|k| a[row][k] * b[k][column]).sum();
+        }
+    }
+
+    result
+}
+
+/// Mapping between pixel coordinates of an image and normalized coordinates, which are centred
+/// on the image and scaled so that they are independent of the pyramid level.
+#[derive(Clone, Copy)]
+struct Normalization {
+    centre_x: f64,
+    centre_y: f64,
+    scale: f64,
+}
+
+impl Normalization {
+    #[allow(clippy::cast_precision_loss)]
+    fn new(height: usize, width: usize) -> Self {
+        Self {
+            centre_x: width as f64 / 2. - 0.5,
+            centre_y: height as f64 / 2. - 0.5,
+            scale: width.max(height) as f64 / 2.,
+        }
+    }
+
+    /// Transform from pixel coordinates to normalized coordinates.
+    fn forward(self) -> Transform {
+        let inverse = 1. / self.scale;
+
+        [
+            [inverse, 0., -self.centre_x * inverse],
+            [0., inverse, -self.centre_y * inverse],
+            [0., 0., 1.],
+        ]
+    }
+
+    /// Transform from normalized coordinates to pixel coordinates.
+    fn backward(self) -> Transform {
+        [
+            [self.scale, 0., self.centre_x],
+            [0., self.scale, self.centre_y],
+            [0., 0., 1.],
+        ]
+    }
+}
+
+/// Exposure-normalized log luminance of a frame, along with the pixels that are usable.
+struct Level {
+    values: Array2<f32>,
+    gradient_x: Array2<f32>,
+    gradient_y: Array2<f32>,
+    valid: Array2<bool>,
+}
+
+impl Level {
+    fn new(values: Array2<f32>, valid: Array2<bool>) -> Self {
+        let (height, width) = values.dim();
+        let gradient = |dy: usize, dx: usize| {
+            Array2::from_shape_fn((height, width), |(y, x)| {
+                let (before_y, after_y) = (y.saturating_sub(dy), (y + dy).min(height - 1));
+                let (before_x, after_x) = (x.saturating_sub(dx), (x + dx).min(width - 1));
+
+                (values[[after_y, after_x]] - values[[before_y, before_x]]) / 2.
+            })
+        };
+
+        Self {
+            gradient_x: gradient(0, 1),
+            gradient_y: gradient(1, 0),
+            values,
+            valid,
+        }
+    }
+
+    /// Build the pyramid of a frame, from finest to coarsest level.
+    fn pyramid(input: &HDRInput, levels: usize) -> Vec<Self> {
+        let buffer = Array3::from_shape_fn(input.get_buffer().dim(), |index| sample(input, index));
+        let scale = input.get_exposure_factor();
+
+        let mut values = buffer
+            .mean_axis(Axis(2))
+            .unwrap_or_else(|| panic!("Expected at least 1 channel"))
+            .mapv(|value| (value.max(BLACK_THRESHOLD) / scale).ln());
+        let mut valid = buffer.map_axis(Axis(2), |pixel| {
+            pixel.iter().all(|&value| value < SATURATION_THRESHOLD)
+                && pixel.iter().any(|&value| value > BLACK_THRESHOLD)
+        });
+
+        let mut pyramid = Vec::with_capacity(levels);
+
+        loop {
+            let (height, width) = values.dim();
+            let next_values = downsample(&values);
+            let next_valid = Array2::from_shape_fn((height / 2, width / 2), |(y, x)| {
+                valid[[2 * y, 2 * x]]
+                    && valid[[2 * y + 1, 2 * x]]
+                    && valid[[2 * y, 2 * x + 1]]
+                    && valid[[2 * y + 1, 2 * x + 1]]
+            });
+
+            pyramid.push(Self::new(values, valid));
+
+            if pyramid.len() >= levels || height.min(width) / 2 < super::MIN_PYRAMID_SIZE {
+                return pyramid;
+            }
+
+            values = next_values;
+            valid = next_valid;
+        }
+    }
+
+    /// Sample the level at a (fractional) pixel position, returning the value and its gradient,
+    /// or `None` if the position is outside the frame or not usable.
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    #[allow(clippy::cast_precision_loss)]
+    fn sample(&self, x: f64, y: f64) -> Option<(f64, f64, f64)> {
+        let (height, width) = self.values.dim();
+
+        if x < 0. || y < 0. || x >= (width - 1) as f64 || y >= (height - 1) as f64 {
+            return None;
+        }
+
+        let (left, top) = (x.floor() as usize, y.floor() as usize);
+        let (fx, fy) = (x - left as f64, y - top as f64);
+
+        if !(self.valid[[top, left]]
+            && self.valid[[top + 1, left]]
+            && self.valid[[top, left + 1]]
+            && self.valid[[top + 1, left + 1]])
+        {
+            return None;
+        }
+
+        let bilinear = |image: &Array2<f32>| {
+            f64::from(image[[top, left]]) * (1. - fx) * (1. - fy)
+                + f64::from(image[[top, left + 1]]) * fx * (1. - fy)
+                + f64::from(image[[top + 1, left]]) * (1. - fx) * fy
+                + f64::from(image[[top + 1, left + 1]]) * fx * fy
+        };
+
+        Some((
+            bilinear(&self.values),
+            bilinear(&self.gradient_x),
+            bilinear(&self.gradient_y),
+        ))
+    }
+}
+
+impl GradientAlignment {
+    /// Refine the transform (in normalized coordinates) of a frame at a single pyramid level.
+    fn refine(&self, reference: &Level, frame: &Level, transform: Transform) -> Transform {
+        let (height, width) = reference.values.dim();
+        let normalization = Normalization::new(height, width);
+        let to_pixels = normalization.backward();
+        let parameter_count = self.model.parameter_count();
+        let stride = ((height * width) / MAX_SAMPLES).isqrt().max(1);
+
+        let mut parameters = self.model.parameters(&transform);
+        let mut bias = 0.;
+
+        for _ in 0..self.max_iterations {
+            let transform = self.model.transform(&parameters);
+            let to_frame = multiply(&to_pixels, &transform);
+
+            let system = (0..height)
+                .into_par_iter()
+                .step_by(stride)
+                .fold(
+                    || NormalEquations::new(parameter_count + 1),
+                    |mut system, y| {
+                        for x in (0..width).step_by(stride) {
+                            if !reference.valid[[y, x]] {
+                                continue;
+                            }
+
+                            #[allow(clippy::cast_precision_loss)]
+                            let (u, v) = apply(&normalization.forward(), x as f64, y as f64);
+                            let (frame_x, frame_y) = apply(&to_frame, u, v);
+
+                            let Some((value, gradient_x, gradient_y)) =
+                                frame.sample(frame_x, frame_y)
+                            else {
+                                continue;
+                            };
+
+                            let (jacobian_x, jacobian_y) = self.model.jacobian(&transform, u, v);
+                            let mut coefficients = (0..parameter_count)
+                                .map(|parameter| {
+                                    (
+                                        parameter,
+                                        normalization.scale
+                                            * (gradient_x * jacobian_x[parameter]
+                                                + gradient_y * jacobian_y[parameter]),
+                                    )
+                                })
+                                .collect::<Vec<(usize, f64)>>();
+                            coefficients.push((parameter_count, 1.));
+
+                            let residual = f64::from(reference.values[[y, x]]) - value - bias;
+                            system.add_row(&coefficients, residual);
+                        }
+
+                        system
+                    },
+                )
+                .reduce(
+                    || NormalEquations::new(parameter_count + 1),
+                    |system, other| system.combine(&other),
+                );
+
+            let Some(update) = system.solve() else {
+                break;
+            };
+
+            for (parameter, delta) in parameters.iter_mut().zip(&update) {
+                *parameter += delta;
+            }
+            bias += update[parameter_count];
+
+            if update[..parameter_count]
+                .iter()
+                .all(|delta| delta.abs() < CONVERGENCE_THRESHOLD)
+            {
+                break;
+            }
+        }
+
+        self.model.transform(&parameters)
+    }
+
+    /// Estimate the transform of every frame relative to the middle exposure of the bracket,
+    /// without modifying the inputs.
+    ///
+    /// # Errors
+    /// - If fewer than two inputs are provided
+    /// - If inputs are of different dimensions
+    pub fn estimate(&self, inputs: &[HDRInput]) -> Result<Vec<Transform>, Error> {
+        let (height, width, _) = validate_inputs(inputs)?;
+        let inputs = &*without_partial_aperture(inputs);
+        let offsets = MtbAlignment::default().estimate(inputs)?;
+        let reference_index = reference_index(inputs);
+
+        let normalization = Normalization::new(height, width);
+
+        let pyramids = inputs
+            .par_iter()
+            .map(|input| Level::pyramid(input, self.levels.max(1)))
+            .collect::<Vec<Vec<Level>>>();
+        let reference = &pyramids[reference_index];
+
+        Ok(pyramids
+            .par_iter()
+            .zip(offsets)
+            .enumerate()
+            .map(|(index, (pyramid, offset))| {
+                if index == reference_index {
+                    return IDENTITY;
+                }
+
+                let initial = translation(offset, normalization.scale);
+                let transform = reference
+                    .iter()
+                    .zip(pyramid)
+                    .rev()
+                    .fold(initial, |transform, (reference, frame)| {
+                        self.refine(reference, frame, transform)
+                    });
+
+                multiply(
+                    &normalization.backward(),
+                    &multiply(&transform, &normalization.forward()),
+                )
+            })
+            .collect())
+    }
+
+    /// Register the inputs to the middle exposure of the bracket. Every input is warped by its
+    /// estimated transform and all inputs are cropped to the largest rectangle covered by every
+    /// frame.
+    ///
+    /// # Errors
+    /// - If fewer than two inputs are provided
+    /// - If inputs are of different dimensions
+    /// - If any of the inputs carries CFA data
+    /// - If the frames don't overlap after registration
+    pub fn register(&self, inputs: &mut HDRInputList) -> Result<Registration, Error> {
+        validate_demosaiced(inputs.as_slice())?;
+        let transforms = self.estimate(inputs.as_slice())?;
+        let (height, width, _) = inputs.as_slice()[0].get_buffer().dim();
+        let crop = valid_region(&transforms, height, width).ok_or_else(|| Error::InputError {
+            parameter_name: "inputs".to_string(),
+            message: "Frames don't overlap after registration".to_string(),
+        })?;
+
+        inputs
+            .as_slice_mut()
+            .par_iter_mut()
+            .zip(&transforms)
+            .for_each(|(input, transform)| {
+                let warped = warp(input.get_buffer(), transform, crop, self.interpolation);
+                *input.get_buffer_mut() = warped;
+            });
+
+        Ok(Registration { transforms, crop })
+    }
+}
+
+/// Transform, in normalized coordinates, corresponding to an integer pixel offset.
+fn translation(offset: Offset, scale: f64) -> Transform {
+    [
+        [1., 0., f64::from(offset.x) / scale],
+        [0., 1., f64::from(offset.y) / scale],
+        [0., 0., 1.],
+    ]
+}
+
+/// Find the largest rectangle of the reference frame that every transform maps inside its frame.
+#[allow(clippy::cast_precision_loss)]
+fn valid_region(transforms: &[Transform], height: usize, width: usize) -> Option<CropRect> {
+    let mut valid = Array2::<bool>::from_elem((height, width), false);
+
+    Zip::indexed(&mut valid).par_for_each(|(y, x), valid| {
+        *valid = transforms.iter().all(|transform| {
+            let (frame_x, frame_y) = apply(transform, x as f64, y as f64);
+
+            frame_x >= 0.
+                && frame_y >= 0.
+                && frame_x <= (width - 1) as f64
+                && frame_y <= (height - 1) as f64
+        });
+    });
+
+    let mut heights = vec![0_usize; width];
+    let mut best: Option<CropRect> = None;
+
+    for (y, row) in valid.outer_iter().enumerate() {
+        for (height, &valid) in heights.iter_mut().zip(row) {
+            *height = if valid { *height + 1 } else { 0 };
+        }
+
+        let mut stack: Vec<usize> = Vec::with_capacity(width);
+        for x in 0..=width {
+            let current = heights.get(x).copied().unwrap_or(0);
+
+            while let Some(&top) = stack.last() {
+                if heights[top] < current {
+                    break;
+                }
+                stack.pop();
+
+                let left = stack.last().map_or(0, |&left| left + 1);
+                let area = heights[top] * (x - left);
+
+                if area > best.map_or(0, |best| best.width * best.height) {
+                    best = Some(CropRect {
+                        x: left,
+                        y: y + 1 - heights[top],
+                        width: x - left,
+                        height: heights[top],
+                    });
+                }
+            }
+
+            stack.push(x);
+        }
+    }
+
+    best
+}
+
+/// Catmull-Rom weights of the four samples around a fractional position.
+fn catmull_rom(t: f64) -> [f64; 4] {
+    let t2 = t * t;
+    let t3 = t2 * t;
+
+    [
+        0.5 * (-t3 + 2. * t2 - t),
+        0.5 * (3. * t3 - 5. * t2 + 2.),
+        0.5 * (-3. * t3 + 4. * t2 + t),
+        0.5 * (t3 - t2),
+    ]
+}
+
+/// Warp a buffer by a transform into the given region of the reference frame.
+#[allow(clippy::cast_precision_loss)]
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_possible_wrap)]
+#[allow(clippy::cast_sign_loss)]
+fn warp(
+    buffer: &Array3<f32>,
+    transform: &Transform,
+    crop: CropRect,
+    interpolation: Interpolation,
+) -> Array3<f32> {
+    let (height, width, channels) = buffer.dim();
+    let clamp = |value: i64, size: usize| value.clamp(0, size as i64 - 1) as usize;
+
+    let mut warped = Array3::<f32>::zeros((crop.height, crop.width, channels));
+
+    Zip::indexed(&mut warped).par_for_each(|(y, x, channel), value| {
+        let (frame_x, frame_y) = apply(transform, (x + crop.x) as f64, (y + crop.y) as f64);
+        let (left, top) = (frame_x.floor(), frame_y.floor());
+        let (fx, fy) = (frame_x - left, frame_y - top);
+        let (left, top) = (left as i64, top as i64);
+
+        let result = match interpolation {
+            Interpolation::Bilinear => {
+                let sample = |dy: i64, dx: i64| {
+                    f64::from(buffer[[clamp(top + dy, height), clamp(left + dx, width), channel]])
+                };
+
+                sample(0, 0) * (1. - fx) * (1. - fy)
+                    + sample(0, 1) * fx * (1. - fy)
+                    + sample(1, 0) * (1. - fx) * fy
+                    + sample(1, 1) * fx * fy
+            }
+            Interpolation::Bicubic => {
+                let weights_x = catmull_rom(fx);
+                let weights_y = catmull_rom(fy);
+
+                (0..4)
+                    .map(|dy: i64| {
+                        let row = clamp(top + dy - 1, height);
+
+                        weights_y[dy as usize]
+                            * (0..4)
+                                .map(|dx: i64| {
+                                    weights_x[dx as usize]
+                                        * f64::from(
+                                            buffer[[row, clamp(left + dx - 1, width), channel]],
+                                        )
+                                })
+                                .sum::<f64>()
+                    })
+                    .sum()
+            }
+        };
+
+        *value = result as f32;
+    });
+
+    warped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::align::tests::{scene, shifted_bracket, with_cfa};
+    use std::time::Duration;
+
+    /// Bracket of two exposures of the scene, the longer one being the reference. Pixel `(x, y)`
+    /// of the shorter exposure shows the scene at `frame_to_reference * (x, y, 1)`.
+    #[allow(clippy::cast_precision_loss)]
+    fn warped_bracket(frame_to_reference: &Transform) -> HDRInputList {
+        [(0.01, frame_to_reference), (0.02, &IDENTITY)]
+            .into_iter()
+            .map(|(exposure, transform)| {
+                let buffer = Array3::from_shape_fn((96, 128, 3), |(y, x, _)| {
+                    let (x, y) = apply(transform, x as f64, y as f64);
+
+                    scene(x, y) * exposure * 20.
+                });
+
+                HDRInput::with_buffer(buffer, Duration::from_secs_f32(exposure), 100.)
+                    .expect("Synthetic exposure should be valid")
+            })
+            .collect::<Vec<HDRInput>>()
+            .into()
+    }
+
+    /// Register a warped bracket and check that the estimated transform inverts the warp, that
+    /// the crop only covers pixels the frame maps inside of it, and that the warped frame matches
+    /// the reference.
+    #[allow(clippy::cast_precision_loss)]
+    fn assert_registers(model: MotionModel, frame_to_reference: &Transform) {
+        for interpolation in [Interpolation::Bilinear, Interpolation::Bicubic] {
+            let mut inputs = warped_bracket(frame_to_reference);
+            let alignment = GradientAlignment {
+                model,
+                interpolation,
+                ..GradientAlignment::default()
+            };
+
+            let Registration { transforms, crop } = alignment
+                .register(&mut inputs)
+                .expect("Bracket should be registered");
+            assert_eq!(transforms[1], IDENTITY);
+
+            // Mapping the reference into the frame and back is the identity
+            let round_trip = multiply(frame_to_reference, &transforms[0]);
+            for (row, expected) in round_trip.iter().zip(IDENTITY) {
+                for (column, (value, expected)) in row.iter().zip(expected).enumerate() {
+                    let value = value / round_trip[2][2];
+                    let tolerance = if column == 2 { 0.05 } else { 1e-3 };
+                    assert!(
+                        (value - expected).abs() < tolerance,
+                        "{model:?} {interpolation:?}: {round_trip:?}"
+                    );
+                }
+            }
+
+            assert!(crop.width >= 96 && crop.height >= 64, "{crop:?}");
+            for (x, y) in [
+                (crop.x, crop.y),
+                (crop.x + crop.width - 1, crop.y),
+                (crop.x, crop.y + crop.height - 1),
+                (crop.x + crop.width - 1, crop.y + crop.height - 1),
+            ] {
+                let (frame_x, frame_y) = apply(&transforms[0], x as f64, y as f64);
+                assert!((0. ..=127.).contains(&frame_x), "{crop:?}: x = {frame_x}");
+                assert!((0. ..=95.).contains(&frame_y), "{crop:?}: y = {frame_y}");
+            }
+
+            let [frame, reference] = [0, 1].map(|index| {
+                let input = &inputs.as_slice()[index];
+                input.get_buffer() / input.get_exposure()
+            });
+            assert_eq!(frame.dim(), (crop.height, crop.width, 3));
+            let error = (&frame - &reference)
+                .mapv(f32::abs)
+                .mean()
+                .unwrap_or(f32::NAN);
+            assert!(error < 0.02, "{interpolation:?}: mean error {error}");
+        }
+    }
+
+    /// Transform rotating by `angle` radians and scaling by `scale` around the centre of the
+    /// frame, then translating by `(dx, dy)`.
+    fn similarity(angle: f64, scale: f64, dx: f64, dy: f64) -> Transform {
+        let (sin, cos) = angle.sin_cos();
+        let (a, b) = (scale * cos, scale * sin);
+        let (centre_x, centre_y) = (63.5, 47.5);
+
+        [
+            [a, -b, centre_x - a * centre_x + b * centre_y + dx],
+            [b, a, centre_y - b * centre_x - a * centre_y + dy],
+            [0., 0., 1.],
+        ]
+    }
+
+    #[test]
+    fn recovers_sub_pixel_translation() {
+        let inputs = shifted_bracket(&[(0., 0.), (2.4, -1.7)]);
+
+        let transforms = GradientAlignment::default()
+            .estimate(inputs.as_slice())
+            .expect("Bracket should be registered");
+        // The longer exposure is the reference
+        assert_eq!(transforms[1], IDENTITY);
+        let (x, y) = apply(&transforms[0], 64., 48.);
+
+        assert!((x - 66.4).abs() < 0.05, "x = {x}");
+        assert!((y - 46.3).abs() < 0.05, "y = {y}");
+    }
+
+    #[test]
+    fn rejects_cfa_data() {
+        let mut inputs = with_cfa(shifted_bracket(&[(0., 0.), (1., 0.)]));
+
+        assert!(matches!(
+            GradientAlignment::default().register(&mut inputs),
+            Err(Error::InputError { .. })
+        ));
+    }
+
+    #[test]
+    fn recovers_rotation_and_scale() {
+        assert_registers(
+            MotionModel::Similarity,
+            &similarity(3_f64.to_radians(), 1.03, 1.2, -0.8),
+        );
+    }
+
+    #[test]
+    fn recovers_perspective_warp() {
+        let frame_to_reference = multiply(
+            &similarity(-2_f64.to_radians(), 0.98, -0.6, 1.1),
+            &[[1., 0.01, 0.], [-0.008, 1., 0.], [1.5e-4, -1e-4, 1.]],
+        );
+
+        assert_registers(MotionModel::Homography, &frame_to_reference);
+    }
+}
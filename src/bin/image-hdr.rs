@@ -0,0 +1,1135 @@
+//! Command line interface merging brackets of exposures into a single high dynamic range image,
+//! optionally aligning, tone mapping and exporting it.
+//!
+//! Build with `cargo build --release --features cli`, then run e.g.
+//! `image-hdr "brackets/*.jpg" --align mtb --tonemap reinhard -o merged.png`.
+
+use clap::{Parser, ValueEnum};
+use image::{DynamicImage, ImageFormat};
+use image_hdr::align::registration::GradientAlignment;
+use image_hdr::align::MtbAlignment;
+use image_hdr::calibration::{LuminanceCalibration, MeterCalibration, Patch};
+use image_hdr::deghost::Deghosting;
+use image_hdr::exif::{get_exif_data, get_exif_data_from_path};
+use image_hdr::export::dng::{DngMetadata, DngSampleType, DngWriter};
+use image_hdr::export::pfm::PfmWriter;
+use image_hdr::export::radiance::RadianceWriter;
+use image_hdr::export::tiff::TiffWriter;
+use image_hdr::exposure::ExposureEstimation;
+use image_hdr::extensions::NDArrayBuffer;
+use image_hdr::fusion::ExposureFusion;
+use image_hdr::input::{ExposureOverrides, HDRInput, HDRInputList};
+use image_hdr::merge::{Debevec, MergeStrategy, PoissonEstimator, Robertson};
+use image_hdr::stretch::apply_histogram_stretch;
+use image_hdr::tiled::TiledMerge;
+use image_hdr::tonemap::{Aces, Drago, Durand, Fattal, Hable, Mantiuk, Reinhard, ToneMapOperator};
+use ndarray::Array3;
+use rayon::prelude::*;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, thiserror::Error)]
+enum CliError {
+    #[error("{0}")]
+    Usage(String),
+    #[error("{path}: {source}")]
+    Input {
+        path: PathBuf,
+        source: image_hdr::Error,
+    },
+    #[error(transparent)]
+    ImageHdr(#[from] image_hdr::Error),
+    #[error(transparent)]
+    Image(#[from] image::ImageError),
+    #[error(transparent)]
+    Pattern(#[from] glob::PatternError),
+    #[error(transparent)]
+    Glob(#[from] glob::GlobError),
+}
+
+/// Algorithm used to combine the exposures.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum MergeAlgorithm {
+    /// Noise-aware Poisson Photon Noise Estimator.
+    Poisson,
+    /// Debevec and Malik's hat weighted average.
+    Debevec,
+    /// Robertson's iterative response and radiance estimation.
+    Robertson,
+    /// Mertens exposure fusion, producing a display image without exposure metadata.
+    Fusion,
+}
+
+/// Alignment of the exposures before merging.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Alignment {
+    /// Exposures are used as they are.
+    None,
+    /// Translation using median threshold bitmaps.
+    Mtb,
+    /// Similarity transform registration using image gradients.
+    Gradient,
+}
+
+/// Step turning the merged radiance into display values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum ToneMapping {
+    /// Radiances are written as they are, clipped for integer formats.
+    None,
+    /// Histogram stretch of the clipped radiances.
+    Stretch,
+    /// Reinhard's photographic operator.
+    Reinhard,
+    /// Drago's adaptive logarithmic operator.
+    Drago,
+    /// ACES filmic curve.
+    Aces,
+    /// Hable's filmic curve.
+    Hable,
+    /// Durand's bilateral filter operator.
+    Durand,
+    /// Fattal's gradient domain operator.
+    Fattal,
+    /// Mantiuk's contrast equalization operator.
+    Mantiuk,
+}
+
+/// Format of the output file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Png,
+    Jpeg,
+    Tiff,
+    Exr,
+    Hdr,
+    Pfm,
+    Dng,
+}
+
+impl OutputFormat {
+    /// Guess the format from the extension of a path.
+    fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+
+        Some(match extension.as_str() {
+            "png" => Self::Png,
+            "jpg" | "jpeg" => Self::Jpeg,
+            "tif" | "tiff" => Self::Tiff,
+            "exr" => Self::Exr,
+            "hdr" | "rgbe" => Self::Hdr,
+            "pfm" => Self::Pfm,
+            "dng" => Self::Dng,
+            _ => return None,
+        })
+    }
+
+    /// Supported bits per channel, the default first.
+    fn bit_depths(self) -> &'static [u8] {
+        match self {
+            Self::Png => &[16, 8],
+            Self::Jpeg => &[8],
+            Self::Tiff => &[16, 8, 32],
+            Self::Exr | Self::Dng => &[32, 16],
+            Self::Hdr | Self::Pfm => &[32],
+        }
+    }
+}
+
+/// Merge brackets of exposures into a high dynamic range image.
+#[derive(Debug, Parser)]
+#[command(name = "image-hdr", version, about)]
+#[allow(clippy::struct_excessive_bools)]
+struct Cli {
+    /// Input files or glob patterns, e.g. "brackets/*.CR2".
+    #[arg(required = true)]
+    inputs: Vec<String>,
+
+    /// Output file. Its extension selects the format unless `--format` is given.
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Output format.
+    #[arg(short, long, value_enum)]
+    format: Option<OutputFormat>,
+
+    /// Bits per channel of the output: 8 or 16 for PNG, 8 for JPEG, 8, 16 or 32 (floating
+    /// point) for TIFF, 16 or 32 for EXR and DNG. Defaults to the largest integer depth, or
+    /// 32 for floating point formats and merges within a memory budget.
+    #[arg(short, long)]
+    bit_depth: Option<u8>,
+
+    /// Exposure times in seconds overriding EXIF, e.g. `1/250,1/60,1/15`. Either one per input,
+    /// in the order of the inputs, or a single one for all inputs.
+    #[arg(long, value_delimiter = ',', value_parser = parse_exposure)]
+    exposure: Vec<f32>,
+
+    /// ISO sensitivities overriding EXIF. Either one per input, in the order of the inputs, or a
+    /// single one for all inputs.
+    #[arg(long, value_delimiter = ',')]
+    iso: Vec<f32>,
+
+    /// Apertures as f-numbers overriding EXIF, e.g. `8` or `f/8`. Either one per input, in the
+    /// order of the inputs, or a single one for all inputs.
+    #[arg(long, value_delimiter = ',', value_parser = parse_aperture)]
+    aperture: Vec<f32>,
+
+    /// Densities of neutral density filters in stops, e.g. `3` for an ND8 filter. Either one per
+    /// input, in the order of the inputs, or a single one for all inputs.
+    #[arg(long, value_delimiter = ',', value_parser = parse_nd)]
+    nd: Vec<f32>,
+
+    /// Estimate the relative exposures from the pixel data instead of EXIF, for images with
+    /// missing or wrong exposure metadata. Exposure times from EXIF or `--exposure` only set the
+    /// absolute scale.
+    #[arg(long)]
+    estimate_exposure: bool,
+
+    /// Merge algorithm.
+    #[arg(short, long, value_enum, default_value_t = MergeAlgorithm::Poisson)]
+    merge: MergeAlgorithm,
+
+    /// Leave out moving objects detected against the middle exposure (Poisson merge only).
+    #[arg(long)]
+    deghost: bool,
+
+    /// Merge raw files on their undemosaiced sensor data and demosaic the result once.
+    #[arg(long)]
+    sensor_domain: bool,
+
+    /// Alignment of the exposures.
+    #[arg(short, long, value_enum, default_value_t = Alignment::None)]
+    align: Alignment,
+
+    /// Tone mapping step. Defaults to a histogram stretch for integer formats and none for
+    /// floating point formats.
+    #[arg(short, long, value_enum)]
+    tonemap: Option<ToneMapping>,
+
+    /// Calibrate the radiance to absolute luminance in cd/m² and print luminance statistics. The
+    /// calibration relies on the exposure time, ISO and aperture of the inputs, which the
+    /// radiance is normalized by, unless `--reference-patch` is given.
+    #[arg(long)]
+    luminance: bool,
+
+    /// Reflected light meter calibration constant (ISO 2720 K factor) for `--luminance`.
+    #[arg(long, default_value_t = 12.5)]
+    k_factor: f32,
+
+    /// Region of the output with known mean luminance for `--luminance`, as
+    /// `x,y,width,height=luminance` in pixels and cd/m².
+    #[arg(long, value_parser = parse_patch)]
+    reference_patch: Option<(Patch, f32)>,
+
+    /// Merge in strips of rows using about this many MiB of memory for pixel data, and write the
+    /// result strip by strip, for brackets too large to fit into memory. Needs a 32-bit TIFF or
+    /// an EXR output without tone mapping, alignment or exposure estimation.
+    #[arg(long, value_name = "MIB")]
+    memory_budget: Option<usize>,
+}
+
+/// Reports the progress of the run on standard error.
+struct Progress {
+    start: Instant,
+}
+
+impl Progress {
+    fn report(&self, message: &str) {
+        eprintln!("[{:>7.2}s] {message}", self.start.elapsed().as_secs_f32());
+    }
+}
+
+/// Exposure time assumed for inputs without one when exposures are estimated.
+const DEFAULT_EXPOSURE: f32 = 1.;
+
+/// ISO sensitivity assumed for inputs without one when exposures are estimated.
+const DEFAULT_ISO: f32 = 100.;
+
+/// Confidence of an exposure estimate below which a warning is printed.
+const LOW_CONFIDENCE: f32 = 0.5;
+
+/// Parse an exposure time given in seconds, either as a decimal or a fraction.
+fn parse_exposure(value: &str) -> Result<f32, String> {
+    let parse = |value: &str| {
+        value
+            .trim()
+            .parse::<f32>()
+            .map_err(|err| format!("invalid exposure time `{value}`: {err}"))
+    };
+
+    let seconds = match value.split_once('/') {
+        Some((numerator, denominator)) => parse(numerator)? / parse(denominator)?,
+        None => parse(value)?,
+    };
+
+    if seconds.is_finite() && seconds > 0. {
+        Ok(seconds)
+    } else {
+        Err(format!("exposure time `{value}` must be positive"))
+    }
+}
+
+/// Parse an aperture given as an f-number, optionally prefixed with `f/`.
+fn parse_aperture(value: &str) -> Result<f32, String> {
+    let number = value.trim();
+    let number = number
+        .strip_prefix("f/")
+        .or_else(|| number.strip_prefix("F/"))
+        .unwrap_or(number);
+
+    match number.parse::<f32>() {
+        Ok(aperture) if aperture.is_finite() && aperture > 0. => Ok(aperture),
+        Ok(_) => Err(format!("aperture `{value}` must be positive")),
+        Err(err) => Err(format!("invalid aperture `{value}`: {err}")),
+    }
+}
+
+/// Parse the density of a neutral density filter in stops.
+fn parse_nd(value: &str) -> Result<f32, String> {
+    match value.trim().parse::<f32>() {
+        Ok(stops) if stops.is_finite() && stops >= 0. => Ok(stops),
+        Ok(_) => Err(format!("ND filter density `{value}` must not be negative")),
+        Err(err) => Err(format!("invalid ND filter density `{value}`: {err}")),
+    }
+}
+
+/// Parse a reference patch given as `x,y,width,height=luminance`.
+fn parse_patch(value: &str) -> Result<(Patch, f32), String> {
+    let invalid = || format!("reference patch `{value}` must be given as x,y,width,height=cd/m²");
+
+    let (region, luminance) = value.split_once('=').ok_or_else(invalid)?;
+    let region = region
+        .split(',')
+        .map(|number| number.trim().parse::<usize>())
+        .collect::<Result<Vec<usize>, _>>()
+        .map_err(|_| invalid())?;
+    let luminance = luminance.trim().parse::<f32>().map_err(|_| invalid())?;
+
+    match region[..] {
+        [x, y, width, height] => Ok((
+            Patch {
+                x,
+                y,
+                width,
+                height,
+            },
+            luminance,
+        )),
+        _ => Err(invalid()),
+    }
+}
+
+/// Expand glob patterns into the list of input files, keeping plain paths as they are.
+fn expand_inputs(inputs: &[String]) -> Result<Vec<PathBuf>, CliError> {
+    let mut paths = Vec::new();
+
+    for input in inputs {
+        if !input.contains(['*', '?', '[']) {
+            paths.push(PathBuf::from(input));
+            continue;
+        }
+
+        let matches = glob::glob(input)?.collect::<Result<Vec<PathBuf>, _>>()?;
+        if matches.is_empty() {
+            return Err(CliError::Usage(format!("No files match `{input}`")));
+        }
+
+        paths.extend(matches);
+    }
+
+    Ok(paths)
+}
+
+/// Value of an override for an input, if any.
+fn override_value(values: &[f32], index: usize) -> Option<f32> {
+    match values {
+        [] => None,
+        [value] => Some(*value),
+        values => values.get(index).copied(),
+    }
+}
+
+/// Resolve the exposure parameters of every input from EXIF unless overridden, see
+/// [`ExposureOverrides::resolve`], printing the values used for every file.
+fn read_exposures(cli: &Cli, paths: &[PathBuf]) -> Result<Vec<ExposureOverrides>, CliError> {
+    for (name, values) in [
+        ("--exposure", &cli.exposure),
+        ("--iso", &cli.iso),
+        ("--aperture", &cli.aperture),
+        ("--nd", &cli.nd),
+    ] {
+        if values.len() > 1 && values.len() != paths.len() {
+            return Err(CliError::Usage(format!(
+                "{name} takes either a single value or one per input ({} inputs, {} values)",
+                paths.len(),
+                values.len()
+            )));
+        }
+    }
+
+    paths
+        .iter()
+        .enumerate()
+        .map(|(index, path)| {
+            let overrides = ExposureOverrides {
+                exposure: override_value(&cli.exposure, index).map(Duration::from_secs_f32),
+                gain: override_value(&cli.iso, index),
+                aperture: override_value(&cli.aperture, index),
+                nd_stops: Some(override_value(&cli.nd, index).unwrap_or(0.)),
+                ..ExposureOverrides::default()
+            };
+            let exif_required = overrides.exposure.is_none() || overrides.gain.is_none();
+            let exif = if exif_required || overrides.aperture.is_none() {
+                match get_exif_data_from_path(path) {
+                    Ok(exif) => Some(exif),
+                    Err(_) if cli.estimate_exposure || !exif_required => None,
+                    Err(err) => {
+                        return Err(CliError::Usage(format!(
+                            "{}: {err}, use --exposure and --iso to set exposures manually, or \
+                             --estimate-exposure to estimate them",
+                            path.display()
+                        )))
+                    }
+                }
+            } else {
+                None
+            };
+
+            let mut resolved = overrides.resolve(exif.as_ref());
+
+            // Missing EXIF values are only tolerated when exposures are estimated
+            let source = |overridden: bool, found: bool, name: &str, flag: &str| {
+                if overridden {
+                    Ok("override")
+                } else if found {
+                    Ok("EXIF")
+                } else if cli.estimate_exposure {
+                    Ok("default")
+                } else {
+                    Err(CliError::Usage(format!(
+                        "{}: {name} is missing from EXIF, use {flag} to set it manually, or \
+                         --estimate-exposure to estimate exposures",
+                        path.display()
+                    )))
+                }
+            };
+            let seconds_source = source(
+                overrides.exposure.is_some(),
+                resolved.exposure.is_some(),
+                "exposure time",
+                "--exposure",
+            )?;
+            let iso_source = source(
+                overrides.gain.is_some(),
+                resolved.gain.is_some(),
+                "ISO",
+                "--iso",
+            )?;
+            let aperture_source = match (overrides.aperture, resolved.aperture) {
+                (Some(_), _) => "override",
+                (None, Some(_)) => "EXIF",
+                (None, None) => "unknown",
+            };
+
+            resolved.exposure = resolved
+                .exposure
+                .or(Some(Duration::from_secs_f32(DEFAULT_EXPOSURE)));
+            resolved.gain = resolved.gain.or(Some(DEFAULT_ISO));
+
+            let seconds = resolved.exposure.unwrap_or_default().as_secs_f32();
+            println!(
+                "{}: exposure {} s ({seconds_source}), ISO {} ({iso_source}), aperture {} \
+                 ({aperture_source}), ND {} stops",
+                path.display(),
+                format_exposure(seconds),
+                resolved.gain.unwrap_or(DEFAULT_ISO),
+                resolved
+                    .aperture
+                    .map_or_else(|| "-".to_string(), |aperture| format!("f/{aperture}")),
+                resolved.nd_stops.unwrap_or(0.),
+            );
+
+            Ok(resolved)
+        })
+        .collect()
+}
+
+/// Format an exposure time as a fraction for exposures shorter than a second.
+fn format_exposure(seconds: f32) -> String {
+    if seconds > 0. && seconds < 1. {
+        format!("1/{}", (1. / seconds).round())
+    } else {
+        seconds.to_string()
+    }
+}
+
+/// Read the inputs with the given exposures.
+fn read_inputs(
+    cli: &Cli,
+    paths: &[PathBuf],
+    exposures: &[ExposureOverrides],
+) -> Result<HDRInputList, CliError> {
+    let inputs = paths
+        .par_iter()
+        .zip(exposures)
+        .map(|(path, overrides)| {
+            let input = if cli.sensor_domain {
+                read_cfa_input(path, overrides)
+            } else {
+                HDRInput::with_overrides(path, overrides)
+            };
+
+            input.map_err(|source| CliError::Input {
+                path: path.clone(),
+                source,
+            })
+        })
+        .collect::<Result<Vec<HDRInput>, CliError>>()?;
+
+    Ok(HDRInputList::from(inputs))
+}
+
+#[cfg(feature = "read-raw-image")]
+fn read_cfa_input(
+    path: &Path,
+    overrides: &ExposureOverrides,
+) -> Result<HDRInput, image_hdr::Error> {
+    image_hdr::raw::read_cfa_input_with_overrides(path, overrides)
+}
+
+#[cfg(not(feature = "read-raw-image"))]
+fn read_cfa_input(_: &Path, _: &ExposureOverrides) -> Result<HDRInput, image_hdr::Error> {
+    Err(image_hdr::Error::InputError {
+        parameter_name: "sensor-domain".to_string(),
+        message: "Built without raw image support".to_string(),
+    })
+}
+
+/// Replace the exposures of the inputs with estimates from their pixel data, anchored at the
+/// exposure of the reference input, printing the estimated values.
+fn estimate_exposures(paths: &[PathBuf], inputs: &mut HDRInputList) -> Result<(), CliError> {
+    let estimate = ExposureEstimation::default().apply(inputs.as_slice_mut())?;
+
+    for ((path, input), confidence) in paths
+        .iter()
+        .zip(inputs.as_slice())
+        .zip(&estimate.input_confidence)
+    {
+        println!(
+            "{}: estimated exposure {} s at ISO {} (confidence {confidence:.2})",
+            path.display(),
+            format_exposure(input.get_exposure()),
+            input.get_gain(),
+        );
+    }
+
+    if estimate.confidence < LOW_CONFIDENCE {
+        eprintln!(
+            "warning: exposure estimate has low confidence ({:.2}), check the merged result",
+            estimate.confidence
+        );
+    }
+
+    Ok(())
+}
+
+/// Calibrate the radiance to absolute luminance in cd/m², printing the calibration and luminance
+/// statistics.
+fn calibrate_luminance(
+    cli: &Cli,
+    radiance: &mut Array3<f32>,
+    inputs: &[HDRInput],
+) -> Result<(), CliError> {
+    let calibration = match cli.reference_patch {
+        Some((patch, luminance)) => {
+            LuminanceCalibration::from_reference_patch(radiance, patch, luminance)?
+        }
+        None => exposure_calibration(cli, inputs)?,
+    };
+
+    let statistics = calibration.statistics(radiance)?;
+    calibration.apply(radiance);
+
+    println!("Luminance scale: {} cd/m² per unit", calibration.scale);
+    println!(
+        "Luminance (cd/m²): min {}, max {}, mean {}, log mean {}",
+        statistics.min, statistics.max, statistics.mean, statistics.log_mean
+    );
+    for (percentile, luminance) in &statistics.percentiles {
+        println!("  P{percentile:<2}: {luminance}");
+    }
+
+    Ok(())
+}
+
+/// Luminance calibration from the exposure of the inputs.
+fn exposure_calibration(cli: &Cli, inputs: &[HDRInput]) -> Result<LuminanceCalibration, CliError> {
+    LuminanceCalibration::from_exposure(
+        inputs,
+        &MeterCalibration {
+            k_factor: cli.k_factor,
+            ..MeterCalibration::default()
+        },
+    )
+    .map_err(|err| {
+        CliError::Usage(format!(
+            "{err}, use --aperture or --reference-patch to calibrate the luminance"
+        ))
+    })
+}
+
+/// Merge strategy selected on the command line.
+fn strategy(cli: &Cli) -> Box<dyn MergeStrategy> {
+    match cli.merge {
+        MergeAlgorithm::Debevec => Box::new(Debevec),
+        MergeAlgorithm::Robertson => Box::new(Robertson::default()),
+        _ if cli.deghost => {
+            Box::new(PoissonEstimator::default().with_deghosting(Deghosting::default()))
+        }
+        _ => Box::new(PoissonEstimator::default()),
+    }
+}
+
+/// Tone mapping operator selected on the command line, if it is one.
+fn operator(tone_mapping: ToneMapping) -> Option<Box<dyn ToneMapOperator>> {
+    Some(match tone_mapping {
+        ToneMapping::None | ToneMapping::Stretch => return None,
+        ToneMapping::Reinhard => Box::new(Reinhard::default()),
+        ToneMapping::Drago => Box::new(Drago::default()),
+        ToneMapping::Aces => Box::new(Aces::default()),
+        ToneMapping::Hable => Box::new(Hable::default()),
+        ToneMapping::Durand => Box::new(Durand::default()),
+        ToneMapping::Fattal => Box::new(Fattal::default()),
+        ToneMapping::Mantiuk => Box::new(Mantiuk::default()),
+    })
+}
+
+/// Options of the output file.
+struct Output<'a> {
+    path: &'a Path,
+    format: OutputFormat,
+    bit_depth: u8,
+    tone_mapping: ToneMapping,
+}
+
+impl Output<'_> {
+    fn is_floating_point(&self) -> bool {
+        self.bit_depth == 32
+    }
+
+    /// Write an integer image, converting it to the bit depth of the output.
+    fn write_image(&self, image: &DynamicImage) -> Result<(), CliError> {
+        let color = image.color().has_color();
+        let image = match (self.bit_depth, color) {
+            (8, true) => DynamicImage::ImageRgb8(image.to_rgb8()),
+            (8, false) => DynamicImage::ImageLuma8(image.to_luma8()),
+            (16, true) => DynamicImage::ImageRgb16(image.to_rgb16()),
+            (16, false) => DynamicImage::ImageLuma16(image.to_luma16()),
+            _ => DynamicImage::ImageRgb32F(image.to_rgb32f()),
+        };
+
+        let format = match self.format {
+            OutputFormat::Png => ImageFormat::Png,
+            OutputFormat::Jpeg => ImageFormat::Jpeg,
+            _ => ImageFormat::Tiff,
+        };
+
+        Ok(image.save_with_format(self.path, format)?)
+    }
+
+    /// Write a radiance map, tone mapping it first if requested.
+    fn write_radiance(
+        &self,
+        radiance: &Array3<f32>,
+        inputs: &[HDRInput],
+        dng: &DngMetadata,
+        algorithm: &str,
+    ) -> Result<(), CliError> {
+        let operator = operator(self.tone_mapping);
+
+        if !self.is_floating_point() {
+            let image = match operator {
+                Some(operator) => operator.to_image(radiance)?,
+                None if self.tone_mapping == ToneMapping::Stretch => {
+                    apply_histogram_stretch(&DynamicImage::from_nd_array_buffer(radiance.clone()))?
+                }
+                None => DynamicImage::from_nd_array_buffer(radiance.clone()),
+            };
+
+            return self.write_image(&image);
+        }
+
+        let values = match operator {
+            Some(operator) => operator.tone_map(radiance)?,
+            None => radiance.clone(),
+        };
+
+        match self.format {
+            OutputFormat::Png | OutputFormat::Jpeg | OutputFormat::Tiff => {
+                self.write_image(&DynamicImage::from_nd_array_buffer(values))
+            }
+            OutputFormat::Hdr => Ok(RadianceWriter::default().save(&values, self.path)?),
+            OutputFormat::Pfm => Ok(PfmWriter::default().save(&values, self.path)?),
+            OutputFormat::Dng => Ok(self.dng_writer().save(&values, dng, self.path)?),
+            OutputFormat::Exr => self.write_exr(&values, inputs, algorithm),
+        }
+    }
+
+    fn dng_writer(&self) -> DngWriter {
+        DngWriter {
+            sample_type: if self.bit_depth == 16 {
+                DngSampleType::Integer
+            } else {
+                DngSampleType::Float
+            },
+        }
+    }
+
+    #[cfg(feature = "openexr")]
+    fn exr_writer(&self) -> image_hdr::export::openexr::ExrWriter {
+        use image_hdr::export::openexr::{ExrSampleType, ExrWriter};
+
+        ExrWriter {
+            sample_type: if self.bit_depth == 16 {
+                ExrSampleType::Half
+            } else {
+                ExrSampleType::Float
+            },
+            ..ExrWriter::default()
+        }
+    }
+
+    #[cfg(feature = "openexr")]
+    fn write_exr(
+        &self,
+        values: &Array3<f32>,
+        inputs: &[HDRInput],
+        algorithm: &str,
+    ) -> Result<(), CliError> {
+        use image_hdr::export::openexr::ExrMetadata;
+
+        Ok(self.exr_writer().save(
+            values,
+            &[],
+            &ExrMetadata::from_inputs(inputs, algorithm),
+            self.path,
+        )?)
+    }
+
+    #[cfg(not(feature = "openexr"))]
+    fn write_exr(&self, _: &Array3<f32>, _: &[HDRInput], _: &str) -> Result<(), CliError> {
+        Err(CliError::Usage("Built without OpenEXR support".to_string()))
+    }
+
+    /// Write a radiance map produced strip by strip, see [`merge_tiled`].
+    fn write_strips<I>(
+        &self,
+        dimensions: (usize, usize, usize),
+        strips: I,
+        inputs: &[HDRInput],
+        algorithm: &str,
+    ) -> Result<(), CliError>
+    where
+        I: IntoIterator<Item = Result<Array3<f32>, image_hdr::Error>>,
+    {
+        match self.format {
+            OutputFormat::Tiff => Ok(TiffWriter.save_strips(dimensions, strips, self.path)?),
+            #[cfg(feature = "openexr")]
+            OutputFormat::Exr => Ok(self.exr_writer().save_strips(
+                dimensions,
+                strips,
+                &image_hdr::export::openexr::ExrMetadata::from_inputs(inputs, algorithm),
+                self.path,
+            )?),
+            _ => {
+                let _ = (inputs, algorithm);
+                Err(CliError::Usage(
+                    "Merging within a memory budget needs a 32-bit TIFF or an EXR output"
+                        .to_string(),
+                ))
+            }
+        }
+    }
+}
+
+/// Resolve the format, bit depth and tone mapping of the output.
+fn output(cli: &Cli) -> Result<Output<'_>, CliError> {
+    let format = cli
+        .format
+        .or_else(|| OutputFormat::from_path(&cli.output))
+        .ok_or_else(|| {
+            CliError::Usage(format!(
+                "Cannot guess the format of `{}`, use --format",
+                cli.output.display()
+            ))
+        })?;
+
+    let depths = format.bit_depths();
+    // Merges within a memory budget are written as floating point
+    let bit_depth =
+        cli.bit_depth
+            .unwrap_or(if cli.memory_budget.is_some() && depths.contains(&32) {
+                32
+            } else {
+                depths[0]
+            });
+    if !depths.contains(&bit_depth) {
+        return Err(CliError::Usage(format!(
+            "{format:?} output supports bit depths {depths:?}, not {bit_depth}"
+        )));
+    }
+
+    let mut output = Output {
+        path: &cli.output,
+        format,
+        bit_depth,
+        tone_mapping: ToneMapping::None,
+    };
+    output.tone_mapping = cli.tonemap.unwrap_or(if output.is_floating_point() {
+        ToneMapping::None
+    } else {
+        ToneMapping::Stretch
+    });
+
+    if output.tone_mapping == ToneMapping::Stretch && output.is_floating_point() {
+        return Err(CliError::Usage(
+            "The histogram stretch needs an integer output format".to_string(),
+        ));
+    }
+
+    Ok(output)
+}
+
+/// Metadata of the DNG output, taken from the inputs and the EXIF of the first file.
+fn dng_metadata(paths: &[PathBuf], inputs: &[HDRInput]) -> DngMetadata {
+    let metadata = DngMetadata::from_inputs(inputs);
+    let exif = paths
+        .first()
+        .and_then(|path| std::fs::read(path).ok())
+        .and_then(|data| get_exif_data(&data).ok());
+
+    let developed = inputs.iter().any(|input| {
+        input
+            .get_sensor_metadata()
+            .is_some_and(|sensor| sensor.cfa.is_none())
+    });
+
+    match exif {
+        // Developed raw files were already rotated upright.
+        Some(exif) if developed => DngMetadata {
+            orientation: 1,
+            ..metadata.with_exif(&exif)
+        },
+        Some(exif) => metadata.with_exif(&exif),
+        None => metadata,
+    }
+}
+
+/// Fuse the exposures without merging them into radiances.
+fn fuse(
+    cli: &Cli,
+    paths: &[PathBuf],
+    output: &Output,
+    progress: &Progress,
+) -> Result<(), CliError> {
+    if output.is_floating_point() {
+        return Err(CliError::Usage(
+            "Exposure fusion produces a display image, use PNG, JPEG or 8/16-bit TIFF output"
+                .to_string(),
+        ));
+    }
+
+    if cli.align != Alignment::None || cli.sensor_domain {
+        return Err(CliError::Usage(
+            "Exposure fusion doesn't support alignment or sensor domain merges".to_string(),
+        ));
+    }
+
+    progress.report(&format!("Fusing {} exposures", paths.len()));
+    let image = ExposureFusion::default().fuse_paths(paths)?;
+
+    progress.report(&format!("Writing {}", output.path.display()));
+    output.write_image(&image)?;
+    progress.report("Done");
+
+    Ok(())
+}
+
+/// Merge the exposures strip by strip within the memory budget, writing the result as it is
+/// merged.
+fn merge_tiled(
+    cli: &Cli,
+    paths: &[PathBuf],
+    exposures: &[ExposureOverrides],
+    output: &Output,
+    memory_budget: usize,
+    progress: &Progress,
+) -> Result<(), CliError> {
+    if !output.is_floating_point() || output.tone_mapping != ToneMapping::None {
+        return Err(CliError::Usage(
+            "Merging within a memory budget needs a 32-bit TIFF or an EXR output without tone \
+             mapping"
+                .to_string(),
+        ));
+    }
+
+    if cli.align != Alignment::None
+        || cli.sensor_domain
+        || cli.estimate_exposure
+        || cli.reference_patch.is_some()
+    {
+        return Err(CliError::Usage(
+            "Merging within a memory budget doesn't support alignment, sensor domain merges, \
+             exposure estimation or reference patches"
+                .to_string(),
+        ));
+    }
+
+    let tiled = TiledMerge {
+        memory_budget: memory_budget.saturating_mul(1 << 20),
+        ..TiledMerge::default()
+    };
+    progress.report("Opening images");
+    let mut inputs = tiled.open(paths, exposures)?;
+
+    let calibration = if cli.luminance {
+        let calibration = exposure_calibration(cli, inputs.as_slice())?;
+        println!("Luminance scale: {} cd/m² per unit", calibration.scale);
+
+        Some(calibration)
+    } else {
+        None
+    };
+
+    let algorithm = format!("{:?}", cli.merge).to_lowercase();
+    let strategy = strategy(cli);
+    let metadata = inputs.as_slice().to_vec();
+    let strips = tiled.merge(&mut inputs, strategy.as_ref())?;
+    let dimensions = strips.dim();
+
+    progress.report(&format!(
+        "Merging {} exposures with {algorithm} in strips of {} rows into {}",
+        metadata.len(),
+        strips.strip_height(),
+        output.path.display()
+    ));
+
+    let strips = strips.map(|strip| {
+        let mut strip = strip?;
+        if let Some(calibration) = calibration {
+            calibration.apply(&mut strip);
+        }
+
+        Ok(strip)
+    });
+
+    output.write_strips(dimensions, strips, &metadata, &algorithm)?;
+    progress.report("Done");
+
+    Ok(())
+}
+
+fn run(cli: &Cli) -> Result<(), CliError> {
+    let progress = Progress {
+        start: Instant::now(),
+    };
+    let output = output(cli)?;
+    let paths = expand_inputs(&cli.inputs)?;
+
+    if cli.merge == MergeAlgorithm::Fusion {
+        return fuse(cli, &paths, &output, &progress);
+    }
+
+    if cli.deghost && cli.merge != MergeAlgorithm::Poisson {
+        return Err(CliError::Usage(
+            "Deghosting is only supported by the Poisson merge".to_string(),
+        ));
+    }
+
+    if cli.luminance && output.format == OutputFormat::Dng {
+        return Err(CliError::Usage(
+            "DNG outputs can't be calibrated to absolute luminance".to_string(),
+        ));
+    }
+
+    if cli.sensor_domain && cli.align != Alignment::None {
+        return Err(CliError::Usage(
+            "Sensor domain merges don't support alignment".to_string(),
+        ));
+    }
+
+    progress.report(&format!("Reading exposures of {} files", paths.len()));
+    let exposures = read_exposures(cli, &paths)?;
+
+    if let Some(memory_budget) = cli.memory_budget {
+        return merge_tiled(cli, &paths, &exposures, &output, memory_budget, &progress);
+    }
+
+    progress.report("Reading images");
+    let mut inputs = read_inputs(cli, &paths, &exposures)?;
+
+    if cli.estimate_exposure {
+        progress.report("Estimating exposures from pixel data");
+        estimate_exposures(&paths, &mut inputs)?;
+    }
+
+    match cli.align {
+        Alignment::None => {}
+        Alignment::Mtb => {
+            progress.report("Aligning exposures with median threshold bitmaps");
+            MtbAlignment::default().align(&mut inputs)?;
+        }
+        Alignment::Gradient => {
+            progress.report("Registering exposures");
+            GradientAlignment::default().register(&mut inputs)?;
+        }
+    }
+
+    let algorithm = format!("{:?}", cli.merge).to_lowercase();
+    progress.report(&format!(
+        "Merging {} exposures with {algorithm}",
+        inputs.len()
+    ));
+
+    let dng = dng_metadata(&paths, inputs.as_slice());
+    let mut radiance = if cli.sensor_domain {
+        let merged = merge_sensor_domain(
+            &mut inputs,
+            strategy(cli).as_ref(),
+            &output,
+            &dng,
+            &progress,
+        )?;
+
+        let Some(radiance) = merged else {
+            progress.report("Done");
+            return Ok(());
+        };
+
+        radiance
+    } else {
+        strategy(cli).merge(inputs.as_slice_mut())?
+    };
+
+    if cli.luminance {
+        progress.report("Calibrating to absolute luminance");
+        calibrate_luminance(cli, &mut radiance, inputs.as_slice())?;
+    }
+
+    progress.report(&format!("Writing {}", output.path.display()));
+    output.write_radiance(&radiance, inputs.as_slice(), &dng, &algorithm)?;
+    progress.report("Done");
+
+    Ok(())
+}
+
+/// Merge inputs in the sensor domain. DNG outputs are written as a mosaic right away, otherwise
+/// the demosaiced radiance is returned.
+#[cfg(feature = "read-raw-image")]
+fn merge_sensor_domain(
+    inputs: &mut HDRInputList,
+    strategy: &dyn MergeStrategy,
+    output: &Output,
+    dng: &DngMetadata,
+    progress: &Progress,
+) -> Result<Option<Array3<f32>>, CliError> {
+    let merge = image_hdr::raw::merge_cfa(inputs, strategy)?;
+
+    if output.format == OutputFormat::Dng {
+        progress.report(&format!("Writing {}", output.path.display()));
+        output
+            .dng_writer()
+            .save_mosaic(&merge.mosaic, dng, output.path)?;
+
+        return Ok(None);
+    }
+
+    progress.report("Demosaicing");
+    Ok(Some(merge.demosaic()?))
+}
+
+#[cfg(not(feature = "read-raw-image"))]
+fn merge_sensor_domain(
+    _: &mut HDRInputList,
+    _: &dyn MergeStrategy,
+    _: &Output,
+    _: &DngMetadata,
+    _: &Progress,
+) -> Result<Option<Array3<f32>>, CliError> {
+    Err(CliError::Usage(
+        "Built without raw image support".to_string(),
+    ))
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    match run(&cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_exposure_times() {
+        assert_eq!(
+            parse_exposure("1/250").map(f32::to_bits),
+            Ok(0.004_f32.to_bits())
+        );
+        assert_eq!(
+            parse_exposure(" 2.5 ").map(f32::to_bits),
+            Ok(2.5_f32.to_bits())
+        );
+        assert!(parse_exposure("1/0").is_err());
+        assert!(parse_exposure("-1").is_err());
+        assert!(parse_exposure("fast").is_err());
+    }
+
+    #[test]
+    fn parses_apertures_and_nd_filters() {
+        for value in ["f/2.8", "F/2.8", "2.8"] {
+            assert_eq!(
+                parse_aperture(value).map(f32::to_bits),
+                Ok(2.8_f32.to_bits())
+            );
+        }
+        assert!(parse_aperture("f/0").is_err());
+        assert!(parse_aperture("wide").is_err());
+
+        assert_eq!(parse_nd("0").map(f32::to_bits), Ok(0_f32.to_bits()));
+        assert!(parse_nd("-3").is_err());
+    }
+
+    #[test]
+    fn parses_reference_patches() {
+        assert_eq!(
+            parse_patch("10, 20, 30, 40 = 1200.5"),
+            Ok((
+                Patch {
+                    x: 10,
+                    y: 20,
+                    width: 30,
+                    height: 40,
+                },
+                1200.5
+            ))
+        );
+        assert!(parse_patch("10,20,30=100").is_err());
+        assert!(parse_patch("10,20,30,40").is_err());
+    }
+
+    #[test]
+    fn applies_overrides_to_all_or_one_input() {
+        assert_eq!(override_value(&[], 1), None);
+        assert_eq!(override_value(&[2.], 1), Some(2.));
+        assert_eq!(override_value(&[1., 2., 3.], 1), Some(2.));
+        assert_eq!(override_value(&[1., 2., 3.], 3), None);
+    }
+}
@@ -0,0 +1,420 @@
+//! Grouping of a mixed set of shots into exposure brackets, using only the EXIF metadata of the
+//! files so that no pixel data needs to be decoded.
+
+use crate::exif::{get_exif_data_from_path, get_exposure_bias, get_exposures};
+use crate::Error;
+use exif::{Exif, In, Tag, Value};
+use rayon::prelude::*;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Metadata of a single file relevant to bracket grouping.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Shot {
+    /// Path of the file
+    pub path: PathBuf,
+    /// Capture time in seconds, from `DateTimeOriginal` and `SubSecTimeOriginal`. Only
+    /// differences between shots are meaningful, as the time zone is ignored.
+    pub capture_time: Option<f64>,
+    /// Sequence number taken from the trailing digits of the file name, e.g. `1234` for
+    /// `IMG_1234.CR2`
+    pub sequence_number: Option<u64>,
+    /// Exposure time in seconds
+    pub exposure: Option<f32>,
+    /// Exposure bias (exposure compensation) in EV
+    pub exposure_bias: Option<f32>,
+    /// Width and height of the image in pixels
+    pub dimensions: Option<(u32, u32)>,
+}
+
+impl Shot {
+    /// Read the metadata of a shot from the EXIF data of the file at `path`, without decoding
+    /// its pixel data.
+    ///
+    /// # Errors
+    /// - failed to open the file
+    /// - file doesn't contain EXIF data
+    pub fn read(path: &Path) -> Result<Self, Error> {
+        let exif = get_exif_data_from_path(path)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            capture_time: capture_time(&exif),
+            sequence_number: sequence_number(path),
+            exposure: get_exposures(&exif).ok().filter(|exposure| *exposure > 0.),
+            exposure_bias: get_exposure_bias(&exif).ok(),
+            dimensions: dimensions(&exif),
+        })
+    }
+
+    fn compare(&self, other: &Self) -> Ordering {
+        // Shots without a capture time are ordered after all others by sequence number
+        let time = |shot: &Self| (shot.capture_time.is_none(), shot.capture_time.unwrap_or(0.));
+        let ((this_missing, this_time), (other_missing, other_time)) = (time(self), time(other));
+
+        this_missing
+            .cmp(&other_missing)
+            .then_with(|| this_time.total_cmp(&other_time))
+            .then_with(|| self.sequence_number.cmp(&other.sequence_number))
+            .then_with(|| self.path.cmp(&other.path))
+    }
+}
+
+/// Options for grouping shots into exposure brackets.
+///
+/// Files are grouped separately per file extension, so that shots recorded in several formats
+/// at once (e.g. RAW+JPEG pairs sharing a file name) result in one bracket per format. Within a
+/// format, shots are ordered by capture time and sequence number, and consecutive shots belong
+/// to the same bracket unless one of the following starts a new one:
+/// - the time between the end of a shot and the start of the next exceeds `max_interval`
+/// - the sequence numbers of the files are not consecutive, where a counter rolling over from
+///   e.g. `9999` to `0000` or `0001` counts as consecutive
+/// - the image dimensions differ
+/// - the bracket already contains a shot with the same exposure time and exposure bias, i.e.
+///   the exposure pattern repeats
+/// - the bracket already contains `max_size` shots
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BracketGrouping {
+    /// Maximum time between the end of a shot and the start of the next one in a bracket
+    pub max_interval: Duration,
+    /// Minimum number of shots in a bracket. Smaller groups, e.g. single shots taken between
+    /// brackets, are dropped.
+    pub min_size: usize,
+    /// Maximum number of shots in a bracket, for cameras that always shoot brackets of a fixed
+    /// size
+    pub max_size: Option<usize>,
+}
+
+impl Default for BracketGrouping {
+    fn default() -> Self {
+        Self {
+            max_interval: Duration::from_secs(2),
+            min_size: 2,
+            max_size: None,
+        }
+    }
+}
+
+impl BracketGrouping {
+    /// Group the files in `directory` into brackets. Subdirectories are not scanned, and files
+    /// without EXIF data (e.g. sidecar files) are ignored.
+    ///
+    /// returns: One list of paths per bracket, ordered by capture
+    ///
+    /// # Errors
+    /// - failed to read the directory
+    /// - `min_size` or `max_size` is invalid
+    pub fn group_directory(&self, directory: &Path) -> Result<Vec<Vec<PathBuf>>, Error> {
+        let mut paths = Vec::new();
+
+        for entry in std::fs::read_dir(directory)? {
+            let path = entry?.path();
+
+            if path.is_file() {
+                paths.push(path);
+            }
+        }
+
+        self.group(&paths)
+    }
+
+    /// Group the files at `paths` into brackets. Files without EXIF data are ignored.
+    ///
+    /// returns: One list of paths per bracket, ordered by capture
+    ///
+    /// # Errors
+    /// - `min_size` or `max_size` is invalid
+    pub fn group<P: AsRef<Path> + Sync>(&self, paths: &[P]) -> Result<Vec<Vec<PathBuf>>, Error> {
+        let shots = paths
+            .par_iter()
+            .filter_map(|path| Shot::read(path.as_ref()).ok())
+            .collect();
+
+        Ok(self
+            .group_shots(shots)?
+            .into_iter()
+            .map(|group| group.into_iter().map(|shot| shot.path).collect())
+            .collect())
+    }
+
+    /// Group already read shots into brackets.
+    ///
+    /// returns: One list of shots per bracket, ordered by capture
+    ///
+    /// # Errors
+    /// - `min_size` or `max_size` is invalid
+    pub fn group_shots(&self, shots: Vec<Shot>) -> Result<Vec<Vec<Shot>>, Error> {
+        self.validate()?;
+
+        let mut formats: BTreeMap<Option<String>, Vec<Shot>> = BTreeMap::new();
+
+        for shot in shots {
+            let extension = shot
+                .path
+                .extension()
+                .map(|extension| extension.to_string_lossy().to_lowercase());
+
+            formats.entry(extension).or_default().push(shot);
+        }
+
+        let mut groups: Vec<Vec<Shot>> = Vec::new();
+
+        for mut shots in formats.into_values() {
+            shots.sort_by(Shot::compare);
+
+            let mut format_groups: Vec<Vec<Shot>> = Vec::new();
+
+            for shot in shots {
+                match format_groups.last_mut() {
+                    Some(group) if self.belongs_to(group, &shot) => group.push(shot),
+                    _ => format_groups.push(vec![shot]),
+                }
+            }
+
+            groups.extend(format_groups);
+        }
+
+        groups.retain(|group| group.len() >= self.min_size);
+        groups.sort_by(|group, other| group[0].compare(&other[0]));
+
+        Ok(groups)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.min_size == 0 {
+            return Err(Error::InputError {
+                parameter_name: "min_size".to_string(),
+                message: "Minimum bracket size must be at least 1".to_string(),
+            });
+        }
+
+        if self
+            .max_size
+            .is_some_and(|max_size| max_size < self.min_size)
+        {
+            return Err(Error::InputError {
+                parameter_name: "max_size".to_string(),
+                message: "Maximum bracket size must not be smaller than the minimum size"
+                    .to_string(),
+            });
+        }
+
+        Ok(())
+    }
+
+    fn belongs_to(&self, group: &[Shot], shot: &Shot) -> bool {
+        let Some(previous) = group.last() else {
+            return true;
+        };
+
+        if self
+            .max_size
+            .is_some_and(|max_size| group.len() >= max_size)
+        {
+            return false;
+        }
+
+        if let (Some(previous_time), Some(time)) = (previous.capture_time, shot.capture_time) {
+            let interval = time - previous_time - f64::from(previous.exposure.unwrap_or(0.));
+
+            if interval > self.max_interval.as_secs_f64() {
+                return false;
+            }
+        }
+
+        if let (Some(previous_number), Some(number)) =
+            (previous.sequence_number, shot.sequence_number)
+        {
+            if !is_consecutive(previous_number, number) {
+                return false;
+            }
+        }
+
+        if previous.dimensions != shot.dimensions {
+            return false;
+        }
+
+        !group.iter().any(|other| {
+            other.exposure == shot.exposure && other.exposure_bias == shot.exposure_bias
+        })
+    }
+}
+
+fn ascii_field(exif: &Exif, tag: Tag) -> Option<&[u8]> {
+    match exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(ref values) => values.first().map(Vec::as_slice),
+        _ => None,
+    }
+}
+
+fn uint_field(exif: &Exif, tag: Tag) -> Option<u32> {
+    exif.get_field(tag, In::PRIMARY)?.value.get_uint(0)
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn capture_time(exif: &Exif) -> Option<f64> {
+    let (mut date_time, sub_sec) = [
+        (Tag::DateTimeOriginal, Tag::SubSecTimeOriginal),
+        (Tag::DateTimeDigitized, Tag::SubSecTimeDigitized),
+        (Tag::DateTime, Tag::SubSecTime),
+    ]
+    .into_iter()
+    .find_map(|(date_time, sub_sec)| {
+        let date_time = exif::DateTime::from_ascii(ascii_field(exif, date_time)?).ok()?;
+
+        Some((date_time, sub_sec))
+    })?;
+
+    if let Some(sub_sec) = ascii_field(exif, sub_sec) {
+        // Invalid sub-second values are ignored, whole seconds are still usable
+        let _ = date_time.parse_subsec(sub_sec);
+    }
+
+    let days = days_from_civil(
+        i64::from(date_time.year),
+        i64::from(date_time.month),
+        i64::from(date_time.day),
+    );
+    let seconds = days * 86_400
+        + i64::from(date_time.hour) * 3_600
+        + i64::from(date_time.minute) * 60
+        + i64::from(date_time.second);
+
+    Some(seconds as f64 + f64::from(date_time.nanosecond.unwrap_or(0)) / 1e9)
+}
+
+/// Number of days since 1970-01-01 of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn sequence_number(path: &Path) -> Option<u64> {
+    let stem = path.file_stem()?.to_str()?;
+    let digits = stem.len() - stem.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+
+    stem[stem.len() - digits..].parse().ok()
+}
+
+/// Whether `number` follows `previous`, allowing a counter that is all nines (e.g. `9999`) to
+/// roll over to `0` or `1`.
+fn is_consecutive(previous: u64, number: u64) -> bool {
+    let rolls_over = number <= 1 && previous.to_string().bytes().all(|digit| digit == b'9');
+
+    previous.checked_add(1) == Some(number) || rolls_over
+}
+
+fn dimensions(exif: &Exif) -> Option<(u32, u32)> {
+    uint_field(exif, Tag::PixelXDimension)
+        .zip(uint_field(exif, Tag::PixelYDimension))
+        .or_else(|| uint_field(exif, Tag::ImageWidth).zip(uint_field(exif, Tag::ImageLength)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shot(name: &str, capture_time: f64, exposure: f32) -> Shot {
+        let path = PathBuf::from(name);
+
+        Shot {
+            sequence_number: sequence_number(&path),
+            path,
+            capture_time: Some(capture_time),
+            exposure: Some(exposure),
+            exposure_bias: Some(0.),
+            dimensions: Some((6000, 4000)),
+        }
+    }
+
+    fn names(groups: &[Vec<Shot>]) -> Vec<Vec<&str>> {
+        groups
+            .iter()
+            .map(|group| {
+                group
+                    .iter()
+                    .map(|shot| shot.path.to_str().expect("Paths should be valid UTF-8"))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn splits_on_gaps_and_repeated_exposures() {
+        let shots = vec![
+            shot("IMG_0001.CR2", 0., 0.01),
+            shot("IMG_0002.CR2", 0.5, 0.04),
+            shot("IMG_0003.CR2", 1., 0.01),
+            shot("IMG_0004.CR2", 1.5, 0.04),
+            shot("IMG_0006.CR2", 2., 0.16),
+            shot("IMG_0007.CR2", 30., 0.01),
+        ];
+
+        let groups = BracketGrouping::default()
+            .group_shots(shots)
+            .expect("Grouping should be valid");
+
+        assert_eq!(
+            names(&groups),
+            [
+                vec!["IMG_0001.CR2", "IMG_0002.CR2"],
+                vec!["IMG_0003.CR2", "IMG_0004.CR2"],
+            ]
+        );
+    }
+
+    #[test]
+    fn groups_raw_and_jpeg_pairs_per_format() {
+        let shots = [0.01, 0.04, 0.16]
+            .into_iter()
+            .enumerate()
+            .flat_map(|(index, exposure)| {
+                let time = f64::from(u8::try_from(index).expect("Index should be small"));
+
+                [
+                    shot(&format!("IMG_{:04}.JPG", index + 1), time, exposure),
+                    shot(&format!("IMG_{:04}.CR2", index + 1), time, exposure),
+                ]
+            })
+            .collect();
+
+        let groups = BracketGrouping::default()
+            .group_shots(shots)
+            .expect("Grouping should be valid");
+
+        assert_eq!(
+            names(&groups),
+            [
+                vec!["IMG_0001.CR2", "IMG_0002.CR2", "IMG_0003.CR2"],
+                vec!["IMG_0001.JPG", "IMG_0002.JPG", "IMG_0003.JPG"],
+            ]
+        );
+    }
+
+    #[test]
+    fn continues_across_counter_rollover() {
+        let shots = vec![
+            shot("IMG_9998.CR2", 0., 0.01),
+            shot("IMG_9999.CR2", 0.5, 0.04),
+            shot("IMG_0001.CR2", 1., 0.16),
+        ];
+
+        let groups = BracketGrouping::default()
+            .group_shots(shots)
+            .expect("Grouping should be valid");
+
+        assert_eq!(
+            names(&groups),
+            [vec!["IMG_9998.CR2", "IMG_9999.CR2", "IMG_0001.CR2"]]
+        );
+        assert!(!is_consecutive(9998, 1));
+        assert!(is_consecutive(99, 0));
+    }
+}
@@ -0,0 +1,375 @@
+//! Calibration of merged radiance to absolute scene luminance in cd/m².
+//!
+//! Merged radiance is the normalized pixel value divided by the exposure factor of the inputs
+//! (see [`HDRInput::get_exposure_factor`]), i.e. proportional to `v·N²/(t·S)` for pixel value
+//! `v`, aperture `N`, exposure time `t` and ISO sensitivity `S`. A reflected light meter with
+//! calibration constant `K` exposes a surface of luminance `L` at `N²/t = L·S/K`, which places it
+//! at the mid grey pixel value (`key`) of the camera. Hence `L = radiance·K/key`.
+//!
+//! The result is only as accurate as the exposure metadata and the assumed key of the camera, so
+//! [`LuminanceCalibration::from_reference_patch`] fits the scale to a patch of known luminance,
+//! e.g. measured with a luminance meter, instead.
+
+use crate::input::HDRInput;
+use crate::tonemap::luminance;
+use crate::Error;
+use ndarray::{s, Array2, Array3};
+use rayon::prelude::*;
+
+/// Percentiles reported by [`LuminanceCalibration::statistics`].
+const PERCENTILES: [f32; 9] = [1., 5., 10., 25., 50., 75., 90., 95., 99.];
+
+/// Calibration of the camera's exposure metering, see the [module documentation](self).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MeterCalibration {
+    /// Reflected light meter calibration constant `K` in cd·s/m² (ISO 2720), typically between
+    /// 10.6 and 13.4.
+    pub k_factor: f32,
+    /// Linear pixel value a correctly metered surface is rendered at. 0.18 corresponds to the
+    /// standard output sensitivity of ISO 12232.
+    pub key: f32,
+}
+
+impl Default for MeterCalibration {
+    fn default() -> Self {
+        Self {
+            k_factor: 12.5,
+            key: 0.18,
+        }
+    }
+}
+
+/// Rectangular region of a radiance buffer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Patch {
+    /// Left edge in pixels.
+    pub x: usize,
+    /// Top edge in pixels.
+    pub y: usize,
+    /// Width in pixels.
+    pub width: usize,
+    /// Height in pixels.
+    pub height: usize,
+}
+
+/// Statistics of the absolute luminance of a radiance buffer in cd/m². Non-finite values are
+/// ignored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LuminanceStatistics {
+    /// Smallest luminance.
+    pub min: f32,
+    /// Largest luminance.
+    pub max: f32,
+    /// Arithmetic mean of the luminance.
+    pub mean: f32,
+    /// Geometric mean of the luminance of the pixels that aren't black, an estimate of the
+    /// adaptation luminance of the scene.
+    pub log_mean: f32,
+    /// Pairs of percentile (in the `0..=100` range) and luminance at that percentile.
+    pub percentiles: Vec<(f32, f32)>,
+}
+
+impl LuminanceStatistics {
+    /// Compute statistics of a luminance buffer, with the luminance at every given percentile.
+    ///
+    /// # Errors
+    /// - If the buffer doesn't contain any finite luminance
+    /// - If any percentile is outside of the `0..=100` range
+    #[allow(clippy::cast_precision_loss)]
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    pub fn new(luminance: &Array2<f32>, percentiles: &[f32]) -> Result<Self, Error> {
+        if percentiles
+            .iter()
+            .any(|percentile| !(0. ..=100.).contains(percentile))
+        {
+            return Err(Error::InputError {
+                parameter_name: "percentiles".to_string(),
+                message: "Percentiles must be within 0 and 100".to_string(),
+            });
+        }
+
+        let mut values = luminance
+            .iter()
+            .copied()
+            .filter(|value| value.is_finite())
+            .collect::<Vec<f32>>();
+
+        if values.is_empty() {
+            return Err(Error::InputError {
+                parameter_name: "radiance".to_string(),
+                message: "Radiance doesn't contain any finite values".to_string(),
+            });
+        }
+
+        values.par_sort_unstable_by(f32::total_cmp);
+
+        let count = values.len() as f64;
+        let mean = values
+            .par_iter()
+            .map(|&value| f64::from(value))
+            .sum::<f64>()
+            / count;
+        let (log_sum, log_count) = values
+            .par_iter()
+            .filter(|&&value| value > 0.)
+            .map(|&value| (f64::from(value).ln(), 1usize))
+            .reduce(|| (0., 0), |a, b| (a.0 + b.0, a.1 + b.1));
+        let log_mean = if log_count > 0 {
+            (log_sum / log_count as f64).exp()
+        } else {
+            0.
+        };
+
+        let last = values.len() - 1;
+
+        Ok(Self {
+            min: values[0],
+            max: values[last],
+            mean: mean as f32,
+            log_mean: log_mean as f32,
+            percentiles: percentiles
+                .iter()
+                .map(|&percentile| {
+                    let position = percentile / 100. * last as f32;
+                    let lower = (position.floor() as usize).min(last);
+                    let upper = (lower + 1).min(last);
+                    let fraction = position - lower as f32;
+
+                    (
+                        percentile,
+                        values[lower] * (1. - fraction) + values[upper] * fraction,
+                    )
+                })
+                .collect(),
+        })
+    }
+
+    /// Luminance at a percentile that was computed, if any.
+    #[must_use]
+    pub fn percentile(&self, percentile: f32) -> Option<f32> {
+        self.percentiles
+            .iter()
+            .find(|(computed, _)| (computed - percentile).abs() < f32::EPSILON)
+            .map(|(_, luminance)| *luminance)
+    }
+}
+
+/// Scale converting the relative luminance of merged radiance into absolute luminance in cd/m².
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LuminanceCalibration {
+    /// Absolute luminance in cd/m² per unit of relative luminance.
+    pub scale: f32,
+}
+
+impl LuminanceCalibration {
+    /// Create a calibration with a known scale.
+    ///
+    /// # Errors
+    /// - If the scale is not a positive finite number
+    pub fn new(scale: f32) -> Result<Self, Error> {
+        if !scale.is_finite() || scale <= 0. {
+            return Err(Error::InputError {
+                parameter_name: "scale".to_string(),
+                message: "Scale must be a positive finite number".to_string(),
+            });
+        }
+
+        Ok(Self { scale })
+    }
+
+    /// Calibration of radiance merged from the given inputs, from the meter calibration alone.
+    ///
+    /// The exposure time, ISO sensitivity, aperture and ND filter of the inputs are already
+    /// divided out of the merged radiance (see the [module documentation](self)), so the scale
+    /// is always `K/key`. The inputs are only checked for whether that radiance is absolute,
+    /// which requires the aperture of every input to be known, as it is otherwise left out of
+    /// the exposure factors.
+    ///
+    /// # Errors
+    /// - If no inputs are provided
+    /// - If the aperture of any input is unknown
+    /// - If the meter calibration is invalid
+    pub fn from_exposure(inputs: &[HDRInput], meter: &MeterCalibration) -> Result<Self, Error> {
+        if inputs.is_empty() {
+            return Err(Error::InputError {
+                parameter_name: "inputs".to_string(),
+                message: "At least one input must be provided".to_string(),
+            });
+        }
+
+        if inputs.iter().any(|input| input.get_aperture().is_none()) {
+            return Err(Error::InputError {
+                parameter_name: "aperture".to_string(),
+                message: "Aperture of every input must be known for absolute calibration"
+                    .to_string(),
+            });
+        }
+
+        if !meter.key.is_finite() || meter.key <= 0. {
+            return Err(Error::InputError {
+                parameter_name: "key".to_string(),
+                message: "Key must be a positive finite pixel value".to_string(),
+            });
+        }
+
+        Self::new(meter.k_factor / meter.key).map_err(|_| Error::InputError {
+            parameter_name: "k_factor".to_string(),
+            message: "K factor must be a positive finite number".to_string(),
+        })
+    }
+
+    /// Calibration fitted to a patch of the radiance with known mean luminance.
+    ///
+    /// # Arguments
+    ///
+    /// * `radiance`: Merged radiance of shape `(height, width, channels)`
+    /// * `patch`: Region of the radiance covering the reference surface
+    /// * `luminance`: Mean luminance of the reference surface in cd/m²
+    ///
+    /// # Errors
+    /// - If the patch is empty or not within the radiance
+    /// - If the luminance is not a positive finite number
+    /// - If the patch is black in the radiance
+    pub fn from_reference_patch(
+        radiance: &Array3<f32>,
+        patch: Patch,
+        luminance: f32,
+    ) -> Result<Self, Error> {
+        let (height, width, _) = radiance.dim();
+
+        if patch.width == 0
+            || patch.height == 0
+            || patch.x + patch.width > width
+            || patch.y + patch.height > height
+        {
+            return Err(Error::InputError {
+                parameter_name: "patch".to_string(),
+                message: "Patch must be a non-empty region within the radiance".to_string(),
+            });
+        }
+
+        if !luminance.is_finite() || luminance <= 0. {
+            return Err(Error::InputError {
+                parameter_name: "luminance".to_string(),
+                message: "Luminance must be a positive finite number of cd/m²".to_string(),
+            });
+        }
+
+        let region = radiance
+            .slice(s![
+                patch.y..patch.y + patch.height,
+                patch.x..patch.x + patch.width,
+                ..
+            ])
+            .to_owned();
+        let relative = luminance_of(&region)?.mean().unwrap_or(0.);
+
+        Self::new(luminance / relative).map_err(|_| Error::InputError {
+            parameter_name: "patch".to_string(),
+            message: "Patch must not be black".to_string(),
+        })
+    }
+
+    /// Absolute luminance of every pixel of a radiance buffer in cd/m².
+    ///
+    /// # Errors
+    /// - If the buffer is neither RGB nor grayscale
+    pub fn luminance(&self, radiance: &Array3<f32>) -> Result<Array2<f32>, Error> {
+        let mut luminance = luminance_of(radiance)?;
+        luminance.par_mapv_inplace(|value| value * self.scale);
+
+        Ok(luminance)
+    }
+
+    /// Scale a radiance buffer in place, so that its luminance is in cd/m².
+    pub fn apply(&self, radiance: &mut Array3<f32>) {
+        radiance.par_mapv_inplace(|value| value * self.scale);
+    }
+
+    /// Statistics of the absolute luminance of a radiance buffer, with the 1st, 5th, 10th, 25th,
+    /// 50th, 75th, 90th, 95th and 99th percentile.
+    ///
+    /// # Errors
+    /// - If the buffer is neither RGB nor grayscale
+    /// - If the buffer doesn't contain any finite values
+    pub fn statistics(&self, radiance: &Array3<f32>) -> Result<LuminanceStatistics, Error> {
+        LuminanceStatistics::new(&self.luminance(radiance)?, &PERCENTILES)
+    }
+}
+
+/// Relative luminance of a radiance buffer.
+///
+/// # Errors
+/// - If the buffer is neither RGB nor grayscale
+fn luminance_of(radiance: &Array3<f32>) -> Result<Array2<f32>, Error> {
+    if !matches!(radiance.dim().2, 1 | 3) {
+        return Err(Error::InputError {
+            parameter_name: "radiance".to_string(),
+            message: "Radiance must either be RGB or grayscale".to_string(),
+        });
+    }
+
+    Ok(luminance(radiance))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merge::{MergeStrategy, PoissonEstimator};
+    use std::time::Duration;
+
+    /// Exposures of a grey surface metered by a camera with the default meter calibration.
+    fn metered_bracket(luminance: f32, aperture: f32, iso: f32) -> Vec<HDRInput> {
+        let meter = MeterCalibration::default();
+        let metered_time = aperture * aperture * meter.k_factor / (luminance * iso);
+
+        [1., 4.]
+            .into_iter()
+            .map(|stops: f32| {
+                let buffer = Array3::from_elem((4, 4, 1), meter.key * stops);
+                let mut input = HDRInput::with_buffer(
+                    buffer,
+                    Duration::from_secs_f32(metered_time * stops),
+                    iso,
+                )
+                .expect("Synthetic exposure should be a valid input");
+                input
+                    .set_aperture(Some(aperture))
+                    .expect("Aperture should be valid");
+                input
+            })
+            .collect()
+    }
+
+    #[test]
+    fn recovers_metered_luminance_regardless_of_exposure() {
+        for (aperture, iso) in [(4., 100.), (11., 1600.)] {
+            let mut inputs = metered_bracket(100., aperture, iso);
+            let mut radiance = PoissonEstimator::default()
+                .merge(&mut inputs)
+                .expect("Bracket should merge");
+
+            let calibration =
+                LuminanceCalibration::from_exposure(&inputs, &MeterCalibration::default())
+                    .expect("Exposure should be known");
+            assert!((calibration.scale - 12.5 / 0.18).abs() < 1e-3);
+
+            calibration.apply(&mut radiance);
+            assert!(radiance
+                .iter()
+                .all(|luminance| (luminance - 100.).abs() < 0.1));
+        }
+    }
+
+    #[test]
+    fn requires_known_aperture() {
+        let mut inputs = metered_bracket(100., 4., 100.);
+        inputs[1]
+            .set_aperture(None)
+            .expect("Unknown aperture should be valid");
+
+        let result = LuminanceCalibration::from_exposure(&inputs, &MeterCalibration::default());
+        assert!(matches!(result, Err(Error::InputError { .. })));
+    }
+}
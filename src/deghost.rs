@@ -0,0 +1,240 @@
+//! Detection of moving objects in a bracket, so that they can be left out of the merge instead of
+//! being averaged into semi-transparent ghosts.
+//!
+//! A reference exposure is chosen and the radiance of every other exposure is compared against
+//! it. Samples that disagree with the reference by more than the noise predicted by the
+//! [`NoiseModel`] are considered to be motion.
+
+use crate::align::reference_index;
+use crate::input::HDRInput;
+use crate::merge::poisson::NoiseModel;
+use crate::merge::{sample, validate_inputs, without_partial_aperture};
+use crate::Error;
+use ndarray::{Array2, Zip};
+use rayon::prelude::*;
+
+/// Parameters of the ghost detection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Deghosting {
+    /// Index of the input used as reference. Defaults to the middle exposure of the bracket.
+    pub reference: Option<usize>,
+    /// Number of standard deviations of the predicted noise a sample may deviate from the
+    /// reference before it is considered motion.
+    pub threshold: f32,
+    /// Relative difference in radiance that is always tolerated, to account for errors in the
+    /// noise model and camera response.
+    pub relative_tolerance: f32,
+    /// Radius in pixels by which detected motion is grown, to also cover the soft edges of moving
+    /// objects.
+    pub dilation: usize,
+}
+
+impl Default for Deghosting {
+    fn default() -> Self {
+        Self {
+            reference: None,
+            threshold: 3.,
+            relative_tolerance: 0.1,
+            dilation: 1,
+        }
+    }
+}
+
+/// Samples of a bracket that were detected as motion.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MotionMask {
+    /// Index of the input used as reference.
+    pub reference: usize,
+    /// Per input mask of pixels that are excluded from the merge, in the same order as the inputs.
+    /// The mask of the reference is always empty.
+    pub rejected: Vec<Array2<bool>>,
+    /// Pixels where motion was detected in any of the inputs.
+    pub motion: Array2<bool>,
+}
+
+impl MotionMask {
+    /// Returns whether the pixel at given position of given input is excluded from the merge.
+    #[must_use]
+    pub fn is_rejected(&self, input: usize, y: usize, x: usize) -> bool {
+        self.rejected[input][[y, x]]
+    }
+}
+
+/// Grow the `true` regions of a mask by given radius.
+fn dilate(mask: &Array2<bool>, radius: usize) -> Array2<bool> {
+    if radius == 0 {
+        return mask.clone();
+    }
+
+    let (height, width) = mask.dim();
+    let mut dilated = Array2::<bool>::from_elem((height, width), false);
+
+    Zip::indexed(&mut dilated).par_for_each(|(y, x), value| {
+        let rows = y.saturating_sub(radius)..(y + radius + 1).min(height);
+
+        *value = rows.into_iter().any(|row| {
+            (x.saturating_sub(radius)..(x + radius + 1).min(width))
+                .any(|column| mask[[row, column]])
+        });
+    });
+
+    dilated
+}
+
+impl Deghosting {
+    /// Detect motion in a bracket relative to the reference exposure.
+    ///
+    /// # Errors
+    /// - If fewer than two inputs are provided
+    /// - If inputs are of different dimensions or are neither RGB nor grayscale
+    /// - If the reference index is out of range
+    /// - If the thresholds are not finite non-negative numbers
+    pub fn detect(
+        &self,
+        inputs: &[HDRInput],
+        noise_model: &NoiseModel,
+    ) -> Result<MotionMask, Error> {
+        let (height, width, channels) = validate_inputs(inputs)?;
+        let inputs = &*without_partial_aperture(inputs);
+
+        for (parameter_name, value) in [
+            ("threshold", self.threshold),
+            ("relative_tolerance", self.relative_tolerance),
+        ] {
+            if !value.is_finite() || value < 0. {
+                return Err(Error::InputError {
+                    parameter_name: parameter_name.to_string(),
+                    message: "Must be a finite non-negative floating point number".to_string(),
+                });
+            }
+        }
+
+        let reference = match self.reference {
+            Some(reference) if reference >= inputs.len() => {
+                return Err(Error::InputError {
+                    parameter_name: "reference".to_string(),
+                    message: format!("Reference must be less than {}", inputs.len()),
+                })
+            }
+            Some(reference) => reference,
+            None => reference_index(inputs),
+        };
+
+        let reference_input = &inputs[reference];
+
+        let rejected = inputs
+            .par_iter()
+            .enumerate()
+            .map(|(index, input)| {
+                let mut mask = Array2::<bool>::from_elem((height, width), false);
+
+                if index == reference {
+                    return mask;
+                }
+
+                Zip::indexed(&mut mask).par_for_each(|(y, x), rejected| {
+                    *rejected = (0..channels).any(|channel| {
+                        let sample = |input: &HDRInput| {
+                            let gain = input.get_gain();
+                            let scaling_factor = input.get_exposure_factor();
+
+                            noise_model
+                                .signal(sample(input, (y, x, channel)))
+                                .map(|signal| {
+                                    (
+                                        signal / scaling_factor,
+                                        noise_model.variance(signal, gain) / scaling_factor.powi(2),
+                                    )
+                                })
+                        };
+
+                        let (Some((radiance, variance)), Some((expected, expected_variance))) =
+                            (sample(input), sample(reference_input))
+                        else {
+                            return false;
+                        };
+
+                        let difference = (radiance - expected).abs();
+
+                        difference > self.relative_tolerance * expected
+                            && difference > self.threshold * (variance + expected_variance).sqrt()
+                    });
+                });
+
+                dilate(&mask, self.dilation)
+            })
+            .collect::<Vec<Array2<bool>>>();
+
+        let mut motion = Array2::<bool>::from_elem((height, width), false);
+        for mask in &rejected {
+            Zip::from(&mut motion)
+                .and(mask)
+                .for_each(|motion, &rejected| *motion |= rejected);
+        }
+
+        Ok(MotionMask {
+            reference,
+            rejected,
+            motion,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merge::{MergeStrategy, PoissonEstimator};
+    use ndarray::Array3;
+    use std::time::Duration;
+
+    /// Bracket of a flat scene of radiance 10, with an object of radiance 40 passing through
+    /// pixel `(2, 2)` of the shortest exposure.
+    fn bracket() -> Vec<HDRInput> {
+        [0.01, 0.02, 0.04]
+            .into_iter()
+            .enumerate()
+            .map(|(index, exposure)| {
+                let buffer = Array3::from_shape_fn((8, 8, 1), |(y, x, _)| {
+                    let radiance = if index == 0 && (y, x) == (2, 2) {
+                        40.
+                    } else {
+                        10.
+                    };
+
+                    radiance * exposure
+                });
+
+                HDRInput::with_buffer(buffer, Duration::from_secs_f32(exposure), 1.)
+                    .expect("Synthetic exposure should be a valid input")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rejects_samples_deviating_from_reference() {
+        let mask = Deghosting::default()
+            .detect(&bracket(), &NoiseModel::default())
+            .expect("Motion should be detected");
+
+        assert_eq!(mask.reference, 1);
+        assert!(mask.is_rejected(0, 2, 2));
+        assert!(mask.is_rejected(0, 3, 3));
+        assert!(!mask.is_rejected(0, 5, 5));
+        assert!(!mask.rejected[2].iter().any(|&rejected| rejected));
+        assert_eq!(mask.motion, mask.rejected[0]);
+    }
+
+    #[test]
+    fn removes_ghosts_from_merge() {
+        let ghosted = PoissonEstimator::default()
+            .merge(&mut bracket())
+            .expect("Bracket should merge");
+        let deghosted = PoissonEstimator::default()
+            .with_deghosting(Deghosting::default())
+            .merge(&mut bracket())
+            .expect("Bracket should merge");
+
+        assert!(ghosted[[2, 2, 0]] > 11.);
+        assert!((deghosted[[2, 2, 0]] - 10.).abs() < 1e-4);
+    }
+}
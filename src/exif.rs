@@ -2,6 +2,9 @@
 
 use crate::Error;
 use exif::{Exif, In, Tag, Value};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
 
 /// Extract the exif information from the bytes of an image file
 ///
@@ -19,6 +22,7 @@ pub fn get_exif_data(data: &[u8]) -> Result<Exif, Error> {
 ///
 /// # Errors
 /// - failed to exposure from exif data
+#[allow(clippy::cast_possible_truncation)]
 pub fn get_exposures(exif: &Exif) -> Result<f32, Error> {
     match exif
         .get_field(Tag::ExposureTime, In::PRIMARY)
@@ -28,7 +32,12 @@ pub fn get_exposures(exif: &Exif) -> Result<f32, Error> {
         .value
     {
         Value::Rational(ref v) if !v.is_empty() => Ok(v[0].to_f32()),
-        _ => Ok(0.),
+        Value::SRational(ref v) if !v.is_empty() => Ok(v[0].to_f32()),
+        Value::Float(ref v) if !v.is_empty() => Ok(v[0]),
+        Value::Double(ref v) if !v.is_empty() => Ok(v[0] as f32),
+        _ => Err(Error::ExifError(exif::Error::UnexpectedValue(
+            "ExposureTime is not a number",
+        ))),
     }
 }
 
@@ -51,6 +60,81 @@ pub fn get_gains(exif: &Exif) -> Result<f32, Error> {
     {
         Value::Long(ref v) if !v.is_empty() => Ok(v[0] as f32),
         Value::Short(ref v) if !v.is_empty() => Ok(f32::from(v[0])),
-        _ => Ok(0.),
+        _ => Err(Error::ExifError(exif::Error::UnexpectedValue(
+            "ISO is not an integer",
+        ))),
+    }
+}
+
+/// Extract the exif information of an image file without reading its pixel data, as far as the
+/// container format allows.
+///
+/// # Errors
+/// - failed to open the file
+/// - failed to extract exif data
+pub fn get_exif_data_from_path(path: &Path) -> Result<Exif, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let exif = exif::Reader::new().read_from_container(&mut reader)?;
+
+    Ok(exif)
+}
+
+/// Extract the exposure bias (exposure compensation) in EV from exif information
+///
+/// # Errors
+/// - failed to get exposure bias from exif data
+pub fn get_exposure_bias(exif: &Exif) -> Result<f32, Error> {
+    match exif
+        .get_field(Tag::ExposureBiasValue, In::PRIMARY)
+        .ok_or(Error::ExifError(exif::Error::NotFound(
+            "ExposureBiasValue not found",
+        )))?
+        .value
+    {
+        Value::SRational(ref v) if !v.is_empty() => Ok(v[0].to_f32()),
+        Value::Rational(ref v) if !v.is_empty() => Ok(v[0].to_f32()),
+        _ => Err(Error::ExifError(exif::Error::UnexpectedValue(
+            "ExposureBiasValue is not a number",
+        ))),
+    }
+}
+
+/// Extract the aperture as an f-number from exif information, using the APEX `ApertureValue` if
+/// `FNumber` is missing
+///
+/// # Errors
+/// - failed to get aperture from exif data
+/// - aperture is not positive, e.g. for manual lenses that don't report it
+pub fn get_aperture(exif: &Exif) -> Result<f32, Error> {
+    let aperture = if let Some(field) = exif.get_field(Tag::FNumber, In::PRIMARY) {
+        match field.value {
+            Value::Rational(ref v) if !v.is_empty() => v[0].to_f32(),
+            _ => {
+                return Err(Error::ExifError(exif::Error::UnexpectedValue(
+                    "FNumber is not a number",
+                )))
+            }
+        }
+    } else {
+        match exif
+            .get_field(Tag::ApertureValue, In::PRIMARY)
+            .ok_or(Error::ExifError(exif::Error::NotFound("FNumber not found")))?
+            .value
+        {
+            Value::Rational(ref v) if !v.is_empty() => (v[0].to_f32() / 2.).exp2(),
+            _ => {
+                return Err(Error::ExifError(exif::Error::UnexpectedValue(
+                    "ApertureValue is not a number",
+                )))
+            }
+        }
+    };
+
+    if aperture.is_finite() && aperture > 0. {
+        Ok(aperture)
+    } else {
+        Err(Error::ExifError(exif::Error::UnexpectedValue(
+            "Aperture is not positive",
+        )))
     }
 }
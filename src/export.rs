@@ -0,0 +1,74 @@
+//! Reading and writing of radiance maps in floating point file formats, so that merged results can
+//! be opened in standard HDR viewers and re-processed later.
+//!
+//! Radiance maps are exchanged as `Array3<f32>` buffers of shape `(height, width, channels)`, the
+//! same layout used by [`crate::input::HDRInput`]. A merged [`image::DynamicImage`] can be
+//! converted with [`crate::extensions::NDArrayBuffer::to_nd_array_buffer`].
+
+use crate::Error;
+use ndarray::Array3;
+
+pub mod dng;
+mod ifd;
+#[cfg(feature = "openexr")]
+pub mod openexr;
+pub mod pfm;
+pub mod radiance;
+pub mod tiff;
+
+/// Largest width or height of an image read from a file. Headers with larger dimensions are
+/// treated as corrupt rather than trusted with an allocation.
+pub(crate) const MAX_DIMENSION: usize = 1 << 20;
+
+/// Error for a file that doesn't follow the expected format.
+pub(crate) fn invalid(format: &str, message: &str) -> Error {
+    Error::IoError(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Invalid {format} file: {message}"),
+    ))
+}
+
+/// Size in bytes of the samples of an image read from a file with the given dimensions from its
+/// header and size of a sample.
+///
+/// # Errors
+/// - If the image is empty, either dimension exceeds [`MAX_DIMENSION`] or the size overflows
+pub(crate) fn checked_size(
+    format: &str,
+    (height, width, channels): (usize, usize, usize),
+    sample_size: usize,
+) -> Result<usize, Error> {
+    if height == 0 || width == 0 {
+        return Err(invalid(format, "empty image"));
+    }
+
+    if height > MAX_DIMENSION || width > MAX_DIMENSION {
+        return Err(invalid(format, "dimensions too large"));
+    }
+
+    height
+        .checked_mul(width)
+        .and_then(|size| size.checked_mul(channels))
+        .and_then(|size| size.checked_mul(sample_size))
+        .filter(|&size| isize::try_from(size).is_ok())
+        .ok_or_else(|| invalid(format, "dimensions too large"))
+}
+
+/// Validate that a buffer is non-empty and either RGB or grayscale and return its dimensions.
+pub(crate) fn validate_buffer(buffer: &Array3<f32>) -> Result<(usize, usize, usize), Error> {
+    validate_dimensions(buffer.dim())
+}
+
+/// Validate that the dimensions of a buffer are non-empty and either RGB or grayscale.
+pub(crate) fn validate_dimensions(
+    (height, width, channels): (usize, usize, usize),
+) -> Result<(usize, usize, usize), Error> {
+    if height == 0 || width == 0 || !matches!(channels, 1 | 3) {
+        return Err(Error::InputError {
+            parameter_name: "buffer".to_string(),
+            message: "Buffer must be non-empty and have either 1 or 3 channels".to_string(),
+        });
+    }
+
+    Ok((height, width, channels))
+}
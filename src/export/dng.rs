@@ -0,0 +1,621 @@
+//! Linear Digital Negative (`.dng`) files, so that merged brackets can be developed further in raw
+//! editors like any other raw file.
+//!
+//! Two kinds of DNG are written, both holding scene linear, black level subtracted data:
+//! - `LinearRaw` files with one value per colour and pixel, from merged RGB or grayscale buffers.
+//!   Linear sRGB buffers are converted back to the colours of the camera when the sensor is known,
+//!   so that raw editors can apply their own camera profiles and white balance.
+//! - `CFA` files with a single value per photosite, from merges in the sensor domain, see
+//!   [`crate::raw`].
+//!
+//! Files carry the colour matrix and as shot white balance of the camera along with EXIF
+//! metadata of a source bracket. As raw editors expect pixel values at most at the white level,
+//! radiances are scaled so that the brightest one is white, and the `BaselineExposure` tag tells
+//! the editor how much to brighten the image so that its geometric mean becomes a mid-tone.
+//! Floating point files need a DNG 1.4 compatible reader.
+
+use crate::export::ifd::{Entry, Layout};
+use crate::export::validate_buffer;
+use crate::input::HDRInput;
+use crate::sensor::{SensorMetadata, XYZ_TO_SRGB};
+use crate::tonemap::{log_average, luminance};
+use crate::Error;
+use exif::{Context, Exif, Field, In, Tag, Value};
+use ndarray::{Array2, Array3, ArrayView3, Axis, Zip};
+use std::collections::BTreeMap;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Value of the `PhotometricInterpretation` tag for mosaic data.
+const PHOTOMETRIC_CFA: u16 = 32803;
+
+/// Value of the `PhotometricInterpretation` tag for demosaiced data.
+const PHOTOMETRIC_LINEAR_RAW: u16 = 34892;
+
+/// Value of the `CalibrationIlluminant1` tag for colour matrices calibrated under D65.
+const ILLUMINANT_D65: u16 = 21;
+
+/// Mid-tone the geometric mean of the image is exposed to by the `BaselineExposure` tag.
+const MIDDLE_GREY: f32 = 0.18;
+
+/// Tags of the 0th IFD of a source file that are carried over.
+const CARRIED_TIFF_TAGS: [Tag; 6] = [
+    Tag::Make,
+    Tag::Model,
+    Tag::DateTime,
+    Tag::ImageDescription,
+    Tag::Artist,
+    Tag::Copyright,
+];
+
+/// Type of the written samples.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DngSampleType {
+    /// 32-bit floating point, preserving the full range of the radiances.
+    #[default]
+    Float,
+    /// 16-bit unsigned integers, for compatibility with older readers. Radiances more than 16
+    /// stops below the brightest one are lost.
+    Integer,
+}
+
+/// Metadata recorded along with the pixel data.
+#[derive(Clone, Debug)]
+pub struct DngMetadata {
+    /// Sensor the brackets were captured with, providing make, model, colour matrix and white
+    /// balance. Without it, RGB buffers are recorded as linear sRGB and mosaics can't be written.
+    pub sensor: Option<SensorMetadata>,
+    /// EXIF fields of a source bracket. Fields of the EXIF IFD are carried over, except for the
+    /// maker note, along with the date, description, artist and copyright of the 0th IFD.
+    pub exif: Vec<(Tag, Value)>,
+    /// Orientation of the pixel data as in the EXIF `Orientation` tag, 1 being upright.
+    pub orientation: u16,
+}
+
+impl Default for DngMetadata {
+    fn default() -> Self {
+        Self {
+            sensor: None,
+            exif: Vec::new(),
+            orientation: 1,
+        }
+    }
+}
+
+impl DngMetadata {
+    /// Collect the sensor metadata of the source images of a merge, taken from the first image
+    /// that carries any.
+    #[must_use]
+    pub fn from_inputs(inputs: &[HDRInput]) -> Self {
+        Self {
+            sensor: inputs
+                .iter()
+                .find_map(HDRInput::get_sensor_metadata)
+                .cloned(),
+            ..Self::default()
+        }
+    }
+
+    /// Carry over the EXIF metadata of a source bracket, see [`crate::exif::get_exif_data`]. The
+    /// orientation is taken from the source as well, which matches the orientation of sensor
+    /// domain data, but not of images that were rotated upright when developed.
+    #[must_use]
+    pub fn with_exif(mut self, exif: &Exif) -> Self {
+        let fields = exif.fields().filter(|field| field.ifd_num == In::PRIMARY);
+
+        for Field { tag, value, .. } in fields {
+            if *tag == Tag::Orientation {
+                if let Some(orientation) = value.get_uint(0).and_then(|v| u16::try_from(v).ok()) {
+                    self.orientation = orientation;
+                }
+            }
+
+            let carried = match tag.context() {
+                Context::Tiff => CARRIED_TIFF_TAGS.contains(tag),
+                Context::Exif => !matches!(*tag, Tag::MakerNote | Tag::InteropIFDPointer),
+                _ => false,
+            };
+
+            if carried {
+                self.exif.push((*tag, value.clone()));
+            }
+        }
+
+        self
+    }
+}
+
+/// Writer of linear DNG files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DngWriter {
+    /// Type of the written samples.
+    pub sample_type: DngSampleType,
+}
+
+impl DngWriter {
+    /// Write a radiance map of shape `(height, width, channels)` as a `LinearRaw` DNG. RGB
+    /// buffers are expected in linear sRGB, as produced by the merge, and are converted to the
+    /// colours of the camera if the sensor of the metadata is known.
+    ///
+    /// # Errors
+    /// - If the buffer is empty or is neither RGB nor grayscale
+    /// - If the image is too large for a DNG file
+    /// - If writing fails
+    pub fn write_to<W: Write>(
+        &self,
+        buffer: &Array3<f32>,
+        metadata: &DngMetadata,
+        writer: W,
+    ) -> Result<(), Error> {
+        let channels = validate_buffer(buffer)?.2;
+        let key = log_average(&luminance(buffer));
+
+        let mut entries = BTreeMap::new();
+        entries.insert(262, Entry::shorts(&[PHOTOMETRIC_LINEAR_RAW]));
+
+        if channels == 1 {
+            return self.write_image(buffer.view(), key, entries, metadata, writer);
+        }
+
+        let srgb = SensorMetadata {
+            make: String::new(),
+            model: String::new(),
+            cfa: None,
+            black_levels: [0.; 4],
+            white_levels: [1.; 4],
+            white_balance: [1.; 4],
+            xyz_to_camera: [XYZ_TO_SRGB[0], XYZ_TO_SRGB[1], XYZ_TO_SRGB[2], [0.; 3]],
+        };
+        let sensor = metadata.sensor.as_ref().unwrap_or(&srgb);
+        insert_color_tags(&mut entries, sensor, 3);
+
+        let srgb_to_camera = sensor.srgb_to_camera();
+        let mut camera = buffer.clone();
+        Zip::from(camera.lanes_mut(Axis(2)))
+            .and(buffer.lanes(Axis(2)))
+            .par_for_each(|mut camera, rgb| {
+                for (color, value) in camera.iter_mut().enumerate() {
+                    let balanced = srgb_to_camera[color]
+                        .iter()
+                        .zip(rgb)
+                        .map(|(coefficient, value)| coefficient * value)
+                        .sum::<f32>();
+
+                    *value = balanced / white_balance(sensor, color);
+                }
+            });
+
+        self.write_image(camera.view(), key, entries, metadata, writer)
+    }
+
+    /// Save a radiance map as a `LinearRaw` DNG, see [`DngWriter::write_to`].
+    ///
+    /// # Errors
+    /// - If the buffer is empty or is neither RGB nor grayscale
+    /// - If the image is too large for a DNG file
+    /// - If the file cannot be written
+    pub fn save(
+        &self,
+        buffer: &Array3<f32>,
+        metadata: &DngMetadata,
+        path: &Path,
+    ) -> Result<(), Error> {
+        self.write_to(
+            buffer,
+            metadata,
+            BufWriter::new(std::fs::File::create(path)?),
+        )
+    }
+
+    /// Write the mosaic of a merge in the sensor domain as a `CFA` DNG, e.g. the mosaic of a
+    /// [`crate::raw::RawMerge`]. The metadata must contain the sensor along with its CFA pattern.
+    ///
+    /// # Errors
+    /// - If the mosaic is empty
+    /// - If the metadata doesn't contain a sensor with a CFA pattern
+    /// - If the image is too large for a DNG file
+    /// - If writing fails
+    pub fn write_mosaic_to<W: Write>(
+        &self,
+        mosaic: &Array2<f32>,
+        metadata: &DngMetadata,
+        writer: W,
+    ) -> Result<(), Error> {
+        if mosaic.is_empty() {
+            return Err(Error::InputError {
+                parameter_name: "mosaic".to_string(),
+                message: "Mosaic must be non-empty".to_string(),
+            });
+        }
+
+        let Some((sensor, cfa)) = metadata
+            .sensor
+            .as_ref()
+            .and_then(|sensor| Some((sensor, sensor.cfa.as_ref()?)))
+        else {
+            return Err(Error::InputError {
+                parameter_name: "metadata".to_string(),
+                message: "Metadata must contain a sensor with a CFA pattern".to_string(),
+            });
+        };
+
+        let pattern = cfa.pattern();
+        let (rows, columns) = pattern.dim();
+        let colors = cfa.colors();
+
+        let mut entries = BTreeMap::new();
+        entries.insert(262, Entry::shorts(&[PHOTOMETRIC_CFA]));
+        entries.insert(
+            33421,
+            Entry::shorts(&[
+                u16::try_from(rows).unwrap_or(u16::MAX),
+                u16::try_from(columns).unwrap_or(u16::MAX),
+            ]),
+        );
+        entries.insert(
+            33422,
+            Entry::bytes(
+                &pattern
+                    .iter()
+                    .map(|&color| u8::try_from(color).unwrap_or(u8::MAX))
+                    .collect::<Vec<u8>>(),
+            ),
+        );
+        entries.insert(50710, Entry::bytes(&[0, 1, 2, 3][..colors]));
+        entries.insert(50711, Entry::shorts(&[1]));
+        insert_color_tags(&mut entries, sensor, colors);
+
+        let key = log_average(mosaic);
+
+        self.write_image(
+            mosaic.view().insert_axis(Axis(2)),
+            key,
+            entries,
+            metadata,
+            writer,
+        )
+    }
+
+    /// Save the mosaic of a merge in the sensor domain as a `CFA` DNG, see
+    /// [`DngWriter::write_mosaic_to`].
+    ///
+    /// # Errors
+    /// - If the mosaic is empty
+    /// - If the metadata doesn't contain a sensor with a CFA pattern
+    /// - If the image is too large for a DNG file
+    /// - If the file cannot be written
+    pub fn save_mosaic(
+        &self,
+        mosaic: &Array2<f32>,
+        metadata: &DngMetadata,
+        path: &Path,
+    ) -> Result<(), Error> {
+        self.write_mosaic_to(
+            mosaic,
+            metadata,
+            BufWriter::new(std::fs::File::create(path)?),
+        )
+    }
+
+    /// Write the samples of an image of shape `(height, width, samples)` along with given
+    /// photometric specific entries. `key` is the geometric mean of the image, which is exposed
+    /// to a mid-tone by the `BaselineExposure` tag.
+    fn write_image<W: Write>(
+        self,
+        samples: ArrayView3<f32>,
+        key: f32,
+        mut entries: BTreeMap<u16, Entry>,
+        metadata: &DngMetadata,
+        mut writer: W,
+    ) -> Result<(), Error> {
+        let (height, width, channels) = samples.dim();
+        let brightest = samples
+            .iter()
+            .copied()
+            .filter(|value| value.is_finite())
+            .fold(0., f32::max);
+        let scale = if brightest > 0. {
+            brightest.recip()
+        } else {
+            1.
+        };
+
+        let (bits, sample_format, white_level) = match self.sample_type {
+            DngSampleType::Float => (32, 3, 1),
+            DngSampleType::Integer => (16, 1, u32::from(u16::MAX)),
+        };
+        let sample_size = usize::from(bits / 8);
+        let data_size = height * width * channels * sample_size;
+
+        let mut exif = BTreeMap::new();
+        for (tag, value) in &metadata.exif {
+            if let Some(entry) = Entry::from_exif(value) {
+                match tag.context() {
+                    Context::Exif => exif.insert(tag.number(), entry),
+                    _ => entries.insert(tag.number(), entry),
+                };
+            }
+        }
+
+        let unique_camera_model = match &metadata.sensor {
+            Some(sensor) if !sensor.make.is_empty() || !sensor.model.is_empty() => {
+                entries.insert(271, Entry::ascii(&sensor.make));
+                entries.insert(272, Entry::ascii(&sensor.model));
+
+                format!("{} {}", sensor.make, sensor.model)
+            }
+            _ => "Linear sRGB".to_string(),
+        };
+
+        let dimension = |value: usize| u32::try_from(value).unwrap_or(u32::MAX);
+        let channel_count = u16::try_from(channels).unwrap_or(u16::MAX);
+
+        entries.insert(254, Entry::longs(&[0]));
+        entries.insert(256, Entry::longs(&[dimension(width)]));
+        entries.insert(257, Entry::longs(&[dimension(height)]));
+        entries.insert(258, Entry::shorts(&vec![bits; channels]));
+        entries.insert(259, Entry::shorts(&[1]));
+        entries.insert(273, Entry::longs(&[0]));
+        entries.insert(274, Entry::shorts(&[metadata.orientation]));
+        entries.insert(277, Entry::shorts(&[channel_count]));
+        entries.insert(278, Entry::longs(&[dimension(height)]));
+        entries.insert(279, Entry::longs(&[dimension(data_size)]));
+        entries.insert(284, Entry::shorts(&[1]));
+        entries.insert(305, Entry::ascii("image-hdr"));
+        entries.insert(339, Entry::shorts(&vec![sample_format; channels]));
+        entries.insert(50706, Entry::bytes(&[1, 4, 0, 0]));
+        entries.insert(50707, Entry::bytes(&[1, 4, 0, 0]));
+        entries.insert(50708, Entry::ascii(&unique_camera_model));
+        entries.insert(50717, Entry::longs(&[white_level]));
+        entries.insert(
+            50730,
+            Entry::srationals(&[(MIDDLE_GREY / (key * scale)).log2().clamp(-16., 16.)]),
+        );
+        if !exif.is_empty() {
+            entries.insert(34665, Entry::longs(&[0]));
+        }
+
+        let layout = Layout::CLASSIC;
+        let exif_offset = layout.header_size() + layout.ifd_size(&entries);
+        let data_offset = exif_offset
+            + if exif.is_empty() {
+                0
+            } else {
+                layout.ifd_size(&exif)
+            };
+        let too_large = || Error::InputError {
+            parameter_name: "buffer".to_string(),
+            message: "Image is too large to be written as a DNG".to_string(),
+        };
+
+        u32::try_from(data_offset + data_size).map_err(|_| too_large())?;
+        entries.insert(273, Entry::longs(&[dimension(data_offset)]));
+        if !exif.is_empty() {
+            entries.insert(34665, Entry::longs(&[dimension(exif_offset)]));
+        }
+
+        writer.write_all(&layout.encode_header())?;
+        writer.write_all(
+            &layout
+                .encode_ifd(&entries, layout.header_size())
+                .ok_or_else(too_large)?,
+        )?;
+        if !exif.is_empty() {
+            writer.write_all(
+                &layout
+                    .encode_ifd(&exif, exif_offset)
+                    .ok_or_else(too_large)?,
+            )?;
+        }
+
+        self.write_samples(samples, scale, &mut writer)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Write the scaled samples of an image row by row.
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    fn write_samples<W: Write>(
+        self,
+        samples: ArrayView3<f32>,
+        scale: f32,
+        writer: &mut W,
+    ) -> Result<(), Error> {
+        let mut row_bytes = Vec::new();
+
+        for row in samples.axis_iter(Axis(0)) {
+            row_bytes.clear();
+
+            for value in row {
+                let value = if value.is_finite() {
+                    (value * scale).clamp(0., 1.)
+                } else {
+                    0.
+                };
+
+                match self.sample_type {
+                    DngSampleType::Float => row_bytes.extend(value.to_le_bytes()),
+                    DngSampleType::Integer => row_bytes
+                        .extend(((value * f32::from(u16::MAX)).round() as u16).to_le_bytes()),
+                }
+            }
+
+            writer.write_all(&row_bytes)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// White balance multiplier of a colour of a sensor, 1 if unknown.
+fn white_balance(sensor: &SensorMetadata, color: usize) -> f32 {
+    let multiplier = sensor.white_balance[color];
+
+    if multiplier.is_normal() && multiplier > 0. {
+        multiplier
+    } else {
+        1.
+    }
+}
+
+/// Insert the colour matrix and white balance of a sensor with given number of colours.
+fn insert_color_tags(entries: &mut BTreeMap<u16, Entry>, sensor: &SensorMetadata, colors: usize) {
+    let matrix = sensor.xyz_to_camera[..colors]
+        .iter()
+        .flatten()
+        .copied()
+        .collect::<Vec<f32>>();
+    let neutral = (0..colors)
+        .map(|color| white_balance(sensor, color).recip())
+        .collect::<Vec<f32>>();
+
+    entries.insert(50721, Entry::srationals(&matrix));
+    entries.insert(50728, Entry::rationals(&neutral));
+    entries.insert(50778, Entry::shorts(&[ILLUMINANT_D65]));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exif::get_exif_data;
+    use crate::sensor::CfaPattern;
+    use exif::Rational;
+
+    /// Sensor whose camera colours are linear sRGB, with an RGGB pattern if `cfa` is set.
+    fn sensor(cfa: bool) -> SensorMetadata {
+        SensorMetadata {
+            make: "Make".to_string(),
+            model: "Model".to_string(),
+            cfa: cfa.then(|| {
+                CfaPattern::new(ndarray::arr2(&[[0, 1], [1, 2]])).expect("Pattern is valid")
+            }),
+            black_levels: [0.; 4],
+            white_levels: [1.; 4],
+            white_balance: [2., 1., 1.5, 0.],
+            xyz_to_camera: [XYZ_TO_SRGB[0], XYZ_TO_SRGB[1], XYZ_TO_SRGB[2], [0.; 3]],
+        }
+    }
+
+    /// Value of a field of the 0th IFD as unsigned integers.
+    fn uints(exif: &Exif, tag: Tag) -> Vec<u32> {
+        let value = &exif
+            .get_field(tag, In::PRIMARY)
+            .unwrap_or_else(|| panic!("{tag} should be written"))
+            .value;
+
+        value
+            .iter_uint()
+            .unwrap_or_else(|| panic!("{tag} should be unsigned"))
+            .collect()
+    }
+
+    /// Samples of a single strip DNG.
+    fn samples(file: &[u8], exif: &Exif, sample_size: usize) -> Vec<f32> {
+        let offset = uints(exif, Tag::StripOffsets)[0] as usize;
+        let size = uints(exif, Tag::StripByteCounts)[0] as usize;
+
+        file[offset..offset + size]
+            .chunks_exact(sample_size)
+            .map(|bytes| match bytes {
+                [a, b, c, d] => f32::from_le_bytes([*a, *b, *c, *d]),
+                [a, b] => f32::from(u16::from_le_bytes([*a, *b])) / f32::from(u16::MAX),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn writes_linear_raw_in_camera_colours() {
+        let buffer = Array3::from_shape_fn((2, 3, 3), |(y, x, channel)| {
+            [0.5, 1., 1.5][channel] * (1 + y * 3 + x) as f32
+        });
+        let metadata = DngMetadata {
+            sensor: Some(sensor(false)),
+            exif: vec![(
+                Tag::ExposureTime,
+                Value::Rational(vec![Rational::from((1, 50))]),
+            )],
+            ..DngMetadata::default()
+        };
+
+        let mut file = Vec::new();
+        DngWriter::default()
+            .write_to(&buffer, &metadata, &mut file)
+            .expect("DNG should be written");
+        let exif = get_exif_data(&file).expect("DNG should be read");
+
+        assert_eq!(uints(&exif, Tag::ImageWidth), [3]);
+        assert_eq!(uints(&exif, Tag::ImageLength), [2]);
+        assert_eq!(
+            uints(&exif, Tag::PhotometricInterpretation),
+            [u32::from(PHOTOMETRIC_LINEAR_RAW)]
+        );
+        assert_eq!(uints(&exif, Tag(Context::Tiff, 339)), [3, 3, 3]);
+        assert_eq!(
+            exif.get_field(Tag::Make, In::PRIMARY)
+                .map(|field| field.display_value().to_string()),
+            Some("\"Make\"".to_string())
+        );
+        assert!(matches!(
+            exif.get_field(Tag::ExposureTime, In::PRIMARY).map(|field| &field.value),
+            Some(Value::Rational(values)) if (values[0].num, values[0].denom) == (1, 50)
+        ));
+
+        // Camera colours are sRGB divided by the white balance, the brightest being white
+        let white_balance = [2., 1., 1.5];
+        let brightest = 6.;
+        for ((y, x, channel), value) in samples(&file, &exif, 4)
+            .into_iter()
+            .enumerate()
+            .map(|(index, value)| ((index / 9, index / 3 % 3, index % 3), value))
+        {
+            let expected = buffer[[y, x, channel]] / white_balance[channel] / brightest;
+            assert!((value - expected).abs() < 1e-5, "{value} != {expected}");
+        }
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn writes_cfa_mosaics() {
+        let mosaic = Array2::from_shape_fn((4, 4), |(y, x)| (y * 4 + x) as f32 / 15.);
+        let metadata = DngMetadata {
+            sensor: Some(sensor(true)),
+            ..DngMetadata::default()
+        };
+        let writer = DngWriter {
+            sample_type: DngSampleType::Integer,
+        };
+
+        let mut file = Vec::new();
+        writer
+            .write_mosaic_to(&mosaic, &metadata, &mut file)
+            .expect("DNG should be written");
+        let exif = get_exif_data(&file).expect("DNG should be read");
+
+        assert_eq!(
+            uints(&exif, Tag::PhotometricInterpretation),
+            [u32::from(PHOTOMETRIC_CFA)]
+        );
+        assert_eq!(uints(&exif, Tag(Context::Tiff, 33421)), [2, 2]);
+        assert_eq!(uints(&exif, Tag(Context::Tiff, 33422)), [0, 1, 1, 2]);
+        assert_eq!(uints(&exif, Tag::BitsPerSample), [16]);
+
+        for (value, expected) in samples(&file, &exif, 2).into_iter().zip(&mosaic) {
+            assert!((value - expected).abs() < 1e-4, "{value} != {expected}");
+        }
+
+        let demosaiced = DngMetadata {
+            sensor: Some(sensor(false)),
+            ..DngMetadata::default()
+        };
+        assert!(writer
+            .write_mosaic_to(&mosaic, &demosaiced, &mut Vec::new())
+            .is_err());
+        assert!(writer
+            .write_mosaic_to(&mosaic, &DngMetadata::default(), &mut Vec::new())
+            .is_err());
+    }
+}
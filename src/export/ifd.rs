@@ -0,0 +1,342 @@
+//! Little endian TIFF image file directories (IFDs), shared by the TIFF based formats. Files are
+//! written as classic TIFF, or as `BigTIFF` when offsets don't fit into 32 bits.
+
+use exif::Value;
+use std::collections::BTreeMap;
+
+/// TIFF field types.
+const BYTE: u16 = 1;
+const ASCII: u16 = 2;
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+const RATIONAL: u16 = 5;
+const SBYTE: u16 = 6;
+const UNDEFINED: u16 = 7;
+const SSHORT: u16 = 8;
+const SLONG: u16 = 9;
+const SRATIONAL: u16 = 10;
+const FLOAT: u16 = 11;
+const DOUBLE: u16 = 12;
+const LONG8: u16 = 16;
+
+/// Denominator of rationals converted from floating point values.
+const DENOMINATOR: u32 = 1_000_000;
+
+/// A TIFF field, with its value encoded in little endian.
+pub(crate) struct Entry {
+    field_type: u16,
+    count: usize,
+    data: Vec<u8>,
+}
+
+impl Entry {
+    pub(crate) fn bytes(values: &[u8]) -> Self {
+        Self::encode(BYTE, values, |value| vec![*value])
+    }
+
+    pub(crate) fn shorts(values: &[u16]) -> Self {
+        Self::encode(SHORT, values, |value| value.to_le_bytes().to_vec())
+    }
+
+    pub(crate) fn longs(values: &[u32]) -> Self {
+        Self::encode(LONG, values, |value| value.to_le_bytes().to_vec())
+    }
+
+    /// Offsets or sizes within the file, which are 64-bit in `BigTIFF` files.
+    #[allow(clippy::cast_possible_truncation)]
+    pub(crate) fn offsets(values: &[u64], layout: Layout) -> Self {
+        if layout.big {
+            Self::encode(LONG8, values, |value| value.to_le_bytes().to_vec())
+        } else {
+            Self::encode(LONG, values, |value| (*value as u32).to_le_bytes().to_vec())
+        }
+    }
+
+    pub(crate) fn ascii(value: &str) -> Self {
+        let mut data = value.as_bytes().to_vec();
+        data.push(0);
+
+        Self::encode(ASCII, &data, |byte| vec![*byte])
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    pub(crate) fn rationals(values: &[f32]) -> Self {
+        Self::encode(RATIONAL, values, |value| {
+            let numerator = (f64::from(*value) * f64::from(DENOMINATOR)).round() as u32;
+
+            [numerator.to_le_bytes(), DENOMINATOR.to_le_bytes()].concat()
+        })
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_possible_wrap)]
+    pub(crate) fn srationals(values: &[f32]) -> Self {
+        Self::encode(SRATIONAL, values, |value| {
+            let numerator = (f64::from(*value) * f64::from(DENOMINATOR)).round() as i32;
+
+            [numerator.to_le_bytes(), (DENOMINATOR as i32).to_le_bytes()].concat()
+        })
+    }
+
+    /// Encode a list of values of given field type.
+    fn encode<T>(field_type: u16, values: &[T], encode: impl Fn(&T) -> Vec<u8>) -> Self {
+        Self {
+            field_type,
+            count: values.len(),
+            data: values.iter().flat_map(encode).collect(),
+        }
+    }
+
+    /// Convert an EXIF value, returning `None` for values of unknown type.
+    pub(crate) fn from_exif(value: &Value) -> Option<Self> {
+        let entry = |field_type: u16, count: usize, data: Vec<u8>| {
+            Some(Self {
+                field_type,
+                count,
+                data,
+            })
+        };
+
+        match value {
+            Value::Byte(values) => entry(BYTE, values.len(), values.clone()),
+            Value::Ascii(strings) => {
+                let data = strings
+                    .iter()
+                    .flat_map(|string| string.iter().copied().chain([0]))
+                    .collect::<Vec<u8>>();
+
+                entry(ASCII, data.len(), data)
+            }
+            Value::Short(values) => entry(
+                SHORT,
+                values.len(),
+                values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+            ),
+            Value::Long(values) => entry(
+                LONG,
+                values.len(),
+                values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+            ),
+            Value::Rational(values) => entry(
+                RATIONAL,
+                values.len(),
+                values
+                    .iter()
+                    .flat_map(|v| [v.num.to_le_bytes(), v.denom.to_le_bytes()].concat())
+                    .collect(),
+            ),
+            Value::SByte(values) => entry(
+                SBYTE,
+                values.len(),
+                values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+            ),
+            Value::Undefined(values, _) => entry(UNDEFINED, values.len(), values.clone()),
+            Value::SShort(values) => entry(
+                SSHORT,
+                values.len(),
+                values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+            ),
+            Value::SLong(values) => entry(
+                SLONG,
+                values.len(),
+                values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+            ),
+            Value::SRational(values) => entry(
+                SRATIONAL,
+                values.len(),
+                values
+                    .iter()
+                    .flat_map(|v| [v.num.to_le_bytes(), v.denom.to_le_bytes()].concat())
+                    .collect(),
+            ),
+            Value::Float(values) => entry(
+                FLOAT,
+                values.len(),
+                values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+            ),
+            Value::Double(values) => entry(
+                DOUBLE,
+                values.len(),
+                values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+            ),
+            Value::Unknown(..) => None,
+        }
+    }
+}
+
+/// Layout of the header and directories of a file, which differs between classic TIFF and
+/// `BigTIFF`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Layout {
+    big: bool,
+}
+
+impl Layout {
+    pub(crate) const CLASSIC: Self = Self { big: false };
+    pub(crate) const BIG: Self = Self { big: true };
+
+    /// Size of the file header, after which the first directory is written.
+    pub(crate) fn header_size(self) -> usize {
+        if self.big {
+            16
+        } else {
+            8
+        }
+    }
+
+    /// Size of a value or offset within a directory entry.
+    fn word_size(self) -> usize {
+        if self.big {
+            8
+        } else {
+            4
+        }
+    }
+
+    fn entry_size(self) -> usize {
+        if self.big {
+            20
+        } else {
+            12
+        }
+    }
+
+    /// Size of the entries of a directory, without the values stored outside of it.
+    fn directory_size(self, entries: &BTreeMap<u16, Entry>) -> usize {
+        let count_size = if self.big { 8 } else { 2 };
+
+        count_size + self.entry_size() * entries.len() + self.word_size()
+    }
+
+    /// Size of a value stored outside of the directory, padded to a word boundary.
+    fn external_size(self, entry: &Entry) -> usize {
+        if entry.data.len() > self.word_size() {
+            entry.data.len().next_multiple_of(2)
+        } else {
+            0
+        }
+    }
+
+    /// Size of an encoded directory, including the values stored outside of it.
+    pub(crate) fn ifd_size(self, entries: &BTreeMap<u16, Entry>) -> usize {
+        self.directory_size(entries)
+            + entries
+                .values()
+                .map(|entry| self.external_size(entry))
+                .sum::<usize>()
+    }
+
+    /// Encode the file header pointing at a directory right after it.
+    pub(crate) fn encode_header(self) -> Vec<u8> {
+        if self.big {
+            [
+                b"II+\0".as_slice(),
+                &8_u16.to_le_bytes(),
+                &[0, 0],
+                &16_u64.to_le_bytes(),
+            ]
+            .concat()
+        } else {
+            [b"II*\0".as_slice(), &8_u32.to_le_bytes()].concat()
+        }
+    }
+
+    /// Encode a directory located at given offset of the file, without a next directory.
+    /// Returns `None` if a count or an offset doesn't fit into the layout.
+    pub(crate) fn encode_ifd(
+        self,
+        entries: &BTreeMap<u16, Entry>,
+        offset: usize,
+    ) -> Option<Vec<u8>> {
+        let word = |value: usize| -> Option<Vec<u8>> {
+            if self.big {
+                Some(u64::try_from(value).ok()?.to_le_bytes().to_vec())
+            } else {
+                Some(u32::try_from(value).ok()?.to_le_bytes().to_vec())
+            }
+        };
+
+        let mut ifd = if self.big {
+            word(entries.len())?
+        } else {
+            u16::try_from(entries.len()).ok()?.to_le_bytes().to_vec()
+        };
+        let mut external = Vec::new();
+        let mut external_offset = offset + self.directory_size(entries);
+
+        for (tag, entry) in entries {
+            ifd.extend(tag.to_le_bytes());
+            ifd.extend(entry.field_type.to_le_bytes());
+            ifd.extend(word(entry.count)?);
+
+            if entry.data.len() > self.word_size() {
+                ifd.extend(word(external_offset)?);
+
+                external.extend(&entry.data);
+                external.resize(external.len().next_multiple_of(2), 0);
+                external_offset += self.external_size(entry);
+            } else {
+                let mut value = entry.data.clone();
+                value.resize(self.word_size(), 0);
+                ifd.extend(value);
+            }
+        }
+
+        ifd.extend(word(0)?);
+        ifd.extend(external);
+
+        Some(ifd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode a 3×2 8-bit grayscale image with its samples after the directory.
+    fn encode(layout: Layout) -> Vec<u8> {
+        let samples = [0_u8, 50, 100, 150, 200, 250];
+        let mut entries = BTreeMap::new();
+        entries.insert(256, Entry::longs(&[3]));
+        entries.insert(257, Entry::longs(&[2]));
+        entries.insert(258, Entry::shorts(&[8]));
+        entries.insert(259, Entry::shorts(&[1]));
+        entries.insert(262, Entry::shorts(&[1]));
+        entries.insert(273, Entry::offsets(&[0], layout));
+        entries.insert(277, Entry::shorts(&[1]));
+        entries.insert(278, Entry::longs(&[2]));
+        entries.insert(279, Entry::offsets(&[samples.len() as u64], layout));
+        entries.insert(305, Entry::ascii("image-hdr"));
+
+        let offset = layout.header_size() + layout.ifd_size(&entries);
+        entries.insert(273, Entry::offsets(&[offset as u64], layout));
+
+        let ifd = layout
+            .encode_ifd(&entries, layout.header_size())
+            .expect("Directory should fit");
+        assert_eq!(ifd.len(), layout.ifd_size(&entries));
+
+        [layout.encode_header(), ifd, samples.to_vec()].concat()
+    }
+
+    #[test]
+    fn encodes_classic_and_big_tiff() {
+        for layout in [Layout::CLASSIC, Layout::BIG] {
+            let file = encode(layout);
+            let image = image::load_from_memory_with_format(&file, image::ImageFormat::Tiff)
+                .expect("File should be decoded");
+
+            assert_eq!(image.into_luma8().into_raw(), [0, 50, 100, 150, 200, 250]);
+        }
+    }
+
+    #[test]
+    fn rejects_offsets_beyond_classic_tiff() {
+        let mut entries = BTreeMap::new();
+        entries.insert(305, Entry::ascii("image-hdr"));
+
+        assert!(Layout::CLASSIC.encode_ifd(&entries, 1 << 32).is_none());
+        assert!(Layout::BIG.encode_ifd(&entries, 1 << 32).is_some());
+    }
+}
@@ -0,0 +1,592 @@
+//! `OpenEXR` files, the interchange format of most compositing pipelines.
+//!
+//! Radiance is written as half or full float `R`, `G` and `B` channels, or a single `Y` channel
+//! for grayscale buffers. Additional named channels, e.g. the per-pixel variance of a
+//! [`PoissonEstimate`], can be written alongside, and the exposures of the source images are
+//! recorded as custom header attributes.
+
+use crate::export::{validate_buffer, validate_dimensions};
+use crate::input::HDRInput;
+use crate::merge::poisson::PoissonEstimate;
+use crate::Error;
+use exr::block::writer::ChunksWriter;
+use exr::block::{BlockIndex, UncompressedBlock};
+use exr::meta::header::Header;
+use exr::meta::{BlockDescription, Headers};
+use exr::prelude::{
+    f16, AnyChannel, AnyChannels, AttributeValue, Blocks, ChannelDescription, Compression,
+    Encoding, FlatSamples, Image, Layer, LayerAttributes, LineOrder, SampleType, SmallVec, Text,
+    Vec2, WritableImage,
+};
+use ndarray::{Array3, ArrayView2, Axis};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::io::{BufWriter, Seek, Write};
+use std::path::Path;
+
+/// Names of the radiance channels of RGB buffers.
+const RGB_CHANNELS: [&str; 3] = ["R", "G", "B"];
+
+/// Name of the radiance channel of grayscale buffers.
+const LUMINANCE_CHANNEL: &str = "Y";
+
+/// Floating point precision of the radiance channels.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExrSampleType {
+    /// 16-bit half precision floats. Half the size of full floats, with about three significant
+    /// digits and a largest value of 65504.
+    Half,
+    /// 32-bit full precision floats.
+    #[default]
+    Float,
+}
+
+/// Lossless compression of the pixel data.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExrCompression {
+    /// No compression.
+    None,
+    /// Deflate compression of blocks of 16 scanlines.
+    #[default]
+    Zip,
+    /// Wavelet compression, which usually compresses noisy images better than ZIP.
+    Piz,
+}
+
+impl ExrCompression {
+    fn encoding(self) -> Encoding {
+        Encoding {
+            compression: match self {
+                Self::None => Compression::Uncompressed,
+                Self::Zip => Compression::ZIP16,
+                Self::Piz => Compression::PIZ,
+            },
+            blocks: Blocks::ScanLines,
+            line_order: LineOrder::Increasing,
+        }
+    }
+}
+
+/// Description of the merge that is recorded in the header of the file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExrMetadata {
+    /// Exposure times of the source images in seconds, written as the `sourceExposures` attribute.
+    pub exposures: Vec<f32>,
+    /// Gains of the source images, written as the `sourceGains` attribute.
+    pub gains: Vec<f32>,
+    /// Name of the merge algorithm, written as the `mergeAlgorithm` attribute.
+    pub algorithm: Option<String>,
+}
+
+impl ExrMetadata {
+    /// Collect the exposures and gains of the source images of a merge.
+    #[must_use]
+    pub fn from_inputs(inputs: &[HDRInput], algorithm: &str) -> Self {
+        Self {
+            exposures: inputs.iter().map(HDRInput::get_exposure).collect(),
+            gains: inputs.iter().map(HDRInput::get_gain).collect(),
+            algorithm: Some(algorithm.to_string()),
+        }
+    }
+
+    /// Convert the metadata into custom header attributes.
+    fn attributes(&self) -> Result<HashMap<Text, AttributeValue>, Error> {
+        let list = |values: &[f32]| {
+            AttributeValue::TextVector(
+                values
+                    .iter()
+                    .map(|value| Text::from(value.to_string().as_str()))
+                    .collect(),
+            )
+        };
+
+        let mut attributes = HashMap::new();
+
+        if !self.exposures.is_empty() {
+            attributes.insert(Text::from("sourceExposures"), list(&self.exposures));
+        }
+
+        if !self.gains.is_empty() {
+            attributes.insert(Text::from("sourceGains"), list(&self.gains));
+        }
+
+        if let Some(algorithm) = &self.algorithm {
+            let algorithm = Text::new_or_none(algorithm).ok_or_else(|| Error::InputError {
+                parameter_name: "algorithm".to_string(),
+                message: "Algorithm name must only contain Latin-1 characters".to_string(),
+            })?;
+
+            attributes.insert(
+                Text::from("mergeAlgorithm"),
+                AttributeValue::Text(algorithm),
+            );
+        }
+
+        Ok(attributes)
+    }
+}
+
+/// Writer of `OpenEXR` files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExrWriter {
+    /// Precision of the radiance channels. Extra channels are always written as full floats, so
+    /// that small variances are not flushed to zero.
+    pub sample_type: ExrSampleType,
+    /// Compression of the pixel data.
+    pub compression: ExrCompression,
+}
+
+/// Attributes of the layer of a file, describing the merge.
+fn layer_attributes(metadata: &ExrMetadata) -> Result<LayerAttributes, Error> {
+    Ok(LayerAttributes {
+        software_name: Some(Text::from("image-hdr")),
+        other: metadata.attributes()?,
+        ..LayerAttributes::default()
+    })
+}
+
+/// Convert an error of the `OpenEXR` encoder.
+fn exr_error(error: exr::error::Error) -> Error {
+    match error {
+        exr::error::Error::Io(error) => Error::IoError(error),
+        error => Error::IoError(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            error.to_string(),
+        )),
+    }
+}
+
+impl ExrWriter {
+    /// Convert the samples of a channel to the configured precision.
+    fn samples(self, channel: ArrayView2<'_, f32>) -> FlatSamples {
+        match self.sample_type {
+            ExrSampleType::Half => {
+                FlatSamples::F16(channel.iter().copied().map(f16::from_f32).collect())
+            }
+            ExrSampleType::Float => FlatSamples::F32(channel.iter().copied().collect()),
+        }
+    }
+
+    /// Write a radiance map of shape `(height, width, channels)` as an `OpenEXR` file, along with
+    /// extra named channels of shape `(height, width)` and header attributes describing the
+    /// merge.
+    ///
+    /// # Errors
+    /// - If the buffer is empty or is neither RGB nor grayscale
+    /// - If an extra channel has different dimensions than the buffer, or its name is empty, not
+    ///   Latin-1 or used more than once
+    /// - If writing fails
+    pub fn write_to<W: Write + Seek>(
+        &self,
+        buffer: &Array3<f32>,
+        extra_channels: &[(&str, ArrayView2<'_, f32>)],
+        metadata: &ExrMetadata,
+        writer: W,
+    ) -> Result<(), Error> {
+        let (height, width, channels) = validate_buffer(buffer)?;
+
+        let names: &[&str] = if channels == 1 {
+            &[LUMINANCE_CHANNEL]
+        } else {
+            &RGB_CHANNELS
+        };
+
+        let mut list = names
+            .iter()
+            .zip(buffer.axis_iter(Axis(2)))
+            .map(|(name, channel)| AnyChannel::new(*name, self.samples(channel)))
+            .collect::<SmallVec<[AnyChannel<FlatSamples>; 4]>>();
+
+        for (name, channel) in extra_channels {
+            let text = Text::new_or_none(name)
+                .filter(|_| !name.is_empty() && !list.iter().any(|existing| existing.name.eq(name)))
+                .ok_or_else(|| Error::InputError {
+                    parameter_name: "extra_channels".to_string(),
+                    message: format!("Invalid or duplicate channel name {name:?}"),
+                })?;
+
+            if channel.dim() != (height, width) {
+                return Err(Error::InputError {
+                    parameter_name: "extra_channels".to_string(),
+                    message: format!("Channel {name:?} must be of the same size as the buffer"),
+                });
+            }
+
+            list.push(AnyChannel::new(
+                text,
+                FlatSamples::F32(channel.iter().copied().collect()),
+            ));
+        }
+
+        let layer = Layer::new(
+            (width, height),
+            layer_attributes(metadata)?,
+            self.compression.encoding(),
+            AnyChannels::sort(list),
+        );
+
+        Image::from_layer(layer)
+            .write()
+            .to_buffered(writer)
+            .map_err(exr_error)
+    }
+
+    /// Save a radiance map to an `OpenEXR` file, see [`ExrWriter::write_to`].
+    ///
+    /// # Errors
+    /// - If the buffer is empty or is neither RGB nor grayscale
+    /// - If an extra channel has different dimensions than the buffer, or its name is empty, not
+    ///   Latin-1 or used more than once
+    /// - If the file cannot be written
+    pub fn save(
+        &self,
+        buffer: &Array3<f32>,
+        extra_channels: &[(&str, ArrayView2<'_, f32>)],
+        metadata: &ExrMetadata,
+        path: &Path,
+    ) -> Result<(), Error> {
+        self.write_to(
+            buffer,
+            extra_channels,
+            metadata,
+            BufWriter::new(std::fs::File::create(path)?),
+        )
+    }
+
+    /// Write a [`PoissonEstimate`] as an `OpenEXR` file. The variance of every channel is written
+    /// to the `variance` layer, e.g. `variance.R`, and the number of contributing exposures to the
+    /// `count` channel.
+    ///
+    /// # Errors
+    /// - If the estimate is empty or is neither RGB nor grayscale
+    /// - If writing fails
+    pub fn write_estimate<W: Write + Seek>(
+        &self,
+        estimate: &PoissonEstimate,
+        metadata: &ExrMetadata,
+        writer: W,
+    ) -> Result<(), Error> {
+        let (_, _, channels) = validate_buffer(&estimate.radiance)?;
+
+        let names: &[&str] = if channels == 1 {
+            &["variance.Y"]
+        } else {
+            &["variance.R", "variance.G", "variance.B"]
+        };
+        let count = estimate.count.mapv(f32::from);
+
+        let extra_channels = names
+            .iter()
+            .copied()
+            .zip(estimate.variance.axis_iter(Axis(2)))
+            .chain([("count", count.view())])
+            .collect::<Vec<(&str, ArrayView2<'_, f32>)>>();
+
+        self.write_to(&estimate.radiance, &extra_channels, metadata, writer)
+    }
+
+    /// Save a [`PoissonEstimate`] to an `OpenEXR` file, see [`ExrWriter::write_estimate`].
+    ///
+    /// # Errors
+    /// - If the estimate is empty or is neither RGB nor grayscale
+    /// - If the file cannot be written
+    pub fn save_estimate(
+        &self,
+        estimate: &PoissonEstimate,
+        metadata: &ExrMetadata,
+        path: &Path,
+    ) -> Result<(), Error> {
+        self.write_estimate(
+            estimate,
+            metadata,
+            BufWriter::new(std::fs::File::create(path)?),
+        )
+    }
+
+    /// Append the samples of a row to an uncompressed block, one channel after another in given
+    /// order, in native endianness.
+    fn encode_row(self, row: ArrayView2<'_, f32>, order: &[usize], block: &mut Vec<u8>) {
+        for &channel in order {
+            for &value in row.column(channel) {
+                match self.sample_type {
+                    ExrSampleType::Half => block.extend(f16::from_f32(value).to_ne_bytes()),
+                    ExrSampleType::Float => block.extend(value.to_ne_bytes()),
+                }
+            }
+        }
+    }
+
+    /// Write a radiance map that is produced strip by strip as an `OpenEXR` file, without holding
+    /// all of it in memory, e.g. the output of [`crate::tiled::TiledMerge`]. Rows are compressed
+    /// and written as soon as a block of scanlines is complete.
+    ///
+    /// # Arguments
+    ///
+    /// * `dimensions`: Shape `(height, width, channels)` of the whole radiance map
+    /// * `strips`: Consecutive rows of the radiance map from top to bottom, each of shape
+    ///   `(rows, width, channels)`
+    /// * `metadata`: Description of the merge recorded in the header
+    /// * `writer`: Destination of the file
+    ///
+    /// # Errors
+    /// - If the dimensions are empty or neither RGB nor grayscale
+    /// - If a strip doesn't match the dimensions or the strips don't add up to the height
+    /// - If a strip couldn't be produced
+    /// - If writing fails
+    pub fn write_strips_to<W, I>(
+        &self,
+        dimensions: (usize, usize, usize),
+        strips: I,
+        metadata: &ExrMetadata,
+        writer: W,
+    ) -> Result<(), Error>
+    where
+        W: Write + Seek,
+        I: IntoIterator<Item = Result<Array3<f32>, Error>>,
+    {
+        let (height, width, channels) = validate_dimensions(dimensions)?;
+
+        let names: &[&str] = if channels == 1 {
+            &[LUMINANCE_CHANNEL]
+        } else {
+            &RGB_CHANNELS
+        };
+        // Samples of a block are stored in alphabetical order of the channel names
+        let mut order = (0..channels).collect::<Vec<usize>>();
+        order.sort_by_key(|&channel| names[channel]);
+
+        let sample_type = match self.sample_type {
+            ExrSampleType::Half => SampleType::F16,
+            ExrSampleType::Float => SampleType::F32,
+        };
+        let compression = self.compression.encoding().compression;
+        let lines_per_block = compression.scan_lines_per_block();
+
+        let header = Header::new(
+            Text::from(""),
+            (width, height),
+            order
+                .iter()
+                .map(|&channel| ChannelDescription::named(names[channel], sample_type))
+                .collect(),
+        )
+        .with_encoding(
+            compression,
+            BlockDescription::ScanLines,
+            LineOrder::Increasing,
+        )
+        .with_attributes(layer_attributes(metadata)?);
+
+        // Errors of the strips are kept here, as the encoder only reports that writing was aborted
+        let mut failure = None;
+        let mut abort = |error: Error| {
+            failure = Some(error);
+            exr::error::Error::Aborted
+        };
+
+        let result = exr::block::write(
+            writer,
+            Headers::from_vec(vec![header]),
+            true,
+            |meta, chunk_writer| {
+                let mut block = Vec::new();
+                let mut block_start = 0;
+                let mut rows = 0;
+
+                for strip in strips {
+                    let strip = strip.map_err(&mut abort)?;
+                    let (strip_height, strip_width, strip_channels) = strip.dim();
+
+                    if strip_width != width
+                        || strip_channels != channels
+                        || rows + strip_height > height
+                    {
+                        return Err(abort(Error::InputError {
+                            parameter_name: "strips".to_string(),
+                            message: "Strips must match the dimensions of the image".to_string(),
+                        }));
+                    }
+
+                    let mut blocks = Vec::new();
+
+                    for row in strip.axis_iter(Axis(0)) {
+                        self.encode_row(row, &order, &mut block);
+                        rows += 1;
+
+                        if rows % lines_per_block == 0 || rows == height {
+                            blocks.push(UncompressedBlock {
+                                index: BlockIndex {
+                                    layer: 0,
+                                    pixel_position: Vec2(0, block_start),
+                                    pixel_size: Vec2(width, rows - block_start),
+                                    level: Vec2(0, 0),
+                                },
+                                data: std::mem::take(&mut block),
+                            });
+                            block_start = rows;
+                        }
+                    }
+
+                    let chunks = blocks
+                        .into_par_iter()
+                        .map(|block| {
+                            let index = block.index.pixel_position.1 / lines_per_block;
+                            Ok((index, block.compress_to_chunk(&meta.headers)?))
+                        })
+                        .collect::<exr::error::Result<Vec<_>>>()?;
+
+                    for (index, chunk) in chunks {
+                        chunk_writer.write_chunk(index, chunk)?;
+                    }
+                }
+
+                if rows != height {
+                    return Err(abort(Error::InputError {
+                        parameter_name: "strips".to_string(),
+                        message: format!("Strips cover {rows} of {height} rows of the image"),
+                    }));
+                }
+
+                Ok(())
+            },
+        );
+
+        match failure {
+            Some(error) => Err(error),
+            None => result.map_err(exr_error),
+        }
+    }
+
+    /// Save a radiance map that is produced strip by strip to an `OpenEXR` file, see
+    /// [`ExrWriter::write_strips_to`].
+    ///
+    /// # Errors
+    /// - If the dimensions are empty or neither RGB nor grayscale
+    /// - If a strip doesn't match the dimensions or the strips don't add up to the height
+    /// - If a strip couldn't be produced
+    /// - If the file cannot be written
+    pub fn save_strips<I>(
+        &self,
+        dimensions: (usize, usize, usize),
+        strips: I,
+        metadata: &ExrMetadata,
+        path: &Path,
+    ) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = Result<Array3<f32>, Error>>,
+    {
+        self.write_strips_to(
+            dimensions,
+            strips,
+            metadata,
+            BufWriter::new(std::fs::File::create(path)?),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exr::prelude::{ReadChannels, ReadLayers};
+    use ndarray::s;
+    use std::io::Cursor;
+
+    #[allow(clippy::cast_precision_loss)]
+    fn ramp() -> Array3<f32> {
+        Array3::from_shape_fn((20, 7, 3), |(y, x, channel)| {
+            (y * 100 + x * 10 + channel) as f32 * 0.37 + 0.01
+        })
+    }
+
+    /// Read the channels of a file by name, along with its custom attributes.
+    fn read(file: Vec<u8>) -> (HashMap<String, Vec<f32>>, HashMap<Text, AttributeValue>) {
+        let image = exr::prelude::read()
+            .no_deep_data()
+            .largest_resolution_level()
+            .all_channels()
+            .first_valid_layer()
+            .all_attributes()
+            .from_buffered(Cursor::new(file))
+            .expect("File should be decoded");
+
+        let channels = image
+            .layer_data
+            .channel_data
+            .list
+            .iter()
+            .map(|channel| {
+                (
+                    channel.name.to_string(),
+                    channel.sample_data.values_as_f32().collect(),
+                )
+            })
+            .collect();
+
+        (channels, image.layer_data.attributes.other)
+    }
+
+    /// Samples of a channel of a buffer in row-major order.
+    fn channel(buffer: &Array3<f32>, channel: usize) -> Vec<f32> {
+        buffer
+            .index_axis(Axis(2), channel)
+            .iter()
+            .copied()
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_channels_and_metadata() {
+        let buffer = ramp();
+        let variance = buffer.index_axis(Axis(2), 0).mapv(|value| value * 1e-6);
+        let metadata = ExrMetadata {
+            exposures: vec![0.01, 0.04],
+            gains: vec![100., 100.],
+            algorithm: Some("poisson".to_string()),
+        };
+
+        let mut file = Cursor::new(Vec::new());
+        ExrWriter::default()
+            .write_to(
+                &buffer,
+                &[("variance", variance.view())],
+                &metadata,
+                &mut file,
+            )
+            .expect("Buffer should be written");
+
+        let (channels, attributes) = read(file.into_inner());
+        for (index, name) in RGB_CHANNELS.iter().enumerate() {
+            assert_eq!(channels[*name], channel(&buffer, index));
+        }
+        assert_eq!(
+            channels["variance"],
+            variance.iter().copied().collect::<Vec<f32>>()
+        );
+        assert_eq!(
+            attributes.get(&Text::from("mergeAlgorithm")),
+            Some(&AttributeValue::Text(Text::from("poisson")))
+        );
+    }
+
+    #[test]
+    fn writes_strips_and_half_floats() {
+        let buffer = ramp();
+        let writer = ExrWriter {
+            sample_type: ExrSampleType::Half,
+            compression: ExrCompression::Piz,
+        };
+
+        let strips = [0..9, 9..20].map(|rows| Ok(buffer.slice(s![rows, .., ..]).to_owned()));
+        let mut file = Cursor::new(Vec::new());
+        writer
+            .write_strips_to(buffer.dim(), strips, &ExrMetadata::default(), &mut file)
+            .expect("Strips should be written");
+
+        let (channels, _) = read(file.into_inner());
+        for (index, name) in RGB_CHANNELS.iter().enumerate() {
+            for (read, written) in channels[*name].iter().zip(channel(&buffer, index)) {
+                assert!((read / written - 1.).abs() < 1e-3);
+            }
+        }
+    }
+}
@@ -0,0 +1,217 @@
+//! Portable Float Map (`.pfm`) files, the simplest lossless floating point image format.
+//!
+//! A short text header is followed by the raw 32-bit floats of every pixel, from the bottom row
+//! to the top. The sign of the scale in the header encodes the byte order of the floats: negative
+//! for little endian and positive for big endian.
+
+use crate::export::{checked_size, invalid, validate_buffer};
+use crate::Error;
+use ndarray::{Array3, Axis};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Name of the format used in error messages.
+const FORMAT_NAME: &str = "PFM";
+
+/// Byte order of the floats of a file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ByteOrder {
+    /// Least significant byte first, as used by most tools.
+    #[default]
+    LittleEndian,
+    /// Most significant byte first.
+    BigEndian,
+}
+
+/// Writer of Portable Float Map files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PfmWriter {
+    /// Byte order of the written floats.
+    pub byte_order: ByteOrder,
+}
+
+impl PfmWriter {
+    /// Write a radiance map of shape `(height, width, channels)` as a Portable Float Map. RGB
+    /// buffers are written as colour `PF` files and grayscale buffers as `Pf` files.
+    ///
+    /// # Errors
+    /// - If the buffer is empty or is neither RGB nor grayscale
+    /// - If writing fails
+    pub fn write_to<W: Write>(&self, buffer: &Array3<f32>, mut writer: W) -> Result<(), Error> {
+        let (height, width, channels) = validate_buffer(buffer)?;
+
+        let magic = if channels == 1 { "Pf" } else { "PF" };
+        let scale = match self.byte_order {
+            ByteOrder::LittleEndian => "-1.0",
+            ByteOrder::BigEndian => "1.0",
+        };
+
+        write!(writer, "{magic}\n{width} {height}\n{scale}\n")?;
+
+        let mut row_bytes = Vec::with_capacity(width * channels * 4);
+        for row in buffer.axis_iter(Axis(0)).rev() {
+            row_bytes.clear();
+            row_bytes.extend(row.iter().flat_map(|value| match self.byte_order {
+                ByteOrder::LittleEndian => value.to_le_bytes(),
+                ByteOrder::BigEndian => value.to_be_bytes(),
+            }));
+
+            writer.write_all(&row_bytes)?;
+        }
+
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Save a radiance map to a Portable Float Map, see [`PfmWriter::write_to`].
+    ///
+    /// # Errors
+    /// - If the buffer is empty or is neither RGB nor grayscale
+    /// - If the file cannot be written
+    pub fn save(&self, buffer: &Array3<f32>, path: &Path) -> Result<(), Error> {
+        self.write_to(buffer, BufWriter::new(std::fs::File::create(path)?))
+    }
+}
+
+/// Read the next whitespace separated token of the header. Exactly one whitespace character
+/// following the token is consumed, so that the pixel data starts right after the last token.
+fn read_token<R: BufRead>(reader: &mut R) -> Result<String, Error> {
+    let mut token = String::new();
+
+    for byte in reader.bytes() {
+        let byte = byte?;
+
+        if byte.is_ascii_whitespace() {
+            if token.is_empty() {
+                continue;
+            }
+
+            return Ok(token);
+        }
+
+        if !byte.is_ascii_graphic() || token.len() >= 32 {
+            return Err(invalid(FORMAT_NAME, "malformed header"));
+        }
+
+        token.push(char::from(byte));
+    }
+
+    Err(invalid(FORMAT_NAME, "unexpected end of header"))
+}
+
+/// Read a Portable Float Map into a radiance map of shape `(height, width, channels)`, with 3
+/// channels for colour `PF` files and 1 channel for grayscale `Pf` files. The magnitude of the
+/// scale in the header is ignored.
+///
+/// # Errors
+/// - If reading fails
+/// - If the data is not a valid Portable Float Map
+pub fn read_from<R: Read>(reader: R) -> Result<Array3<f32>, Error> {
+    let mut reader = BufReader::new(reader);
+
+    let channels = match read_token(&mut reader)?.as_str() {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err(invalid(FORMAT_NAME, "missing magic number")),
+    };
+
+    let mut dimension = || -> Result<usize, Error> {
+        read_token(&mut reader)?
+            .parse::<usize>()
+            .map_err(|_| invalid(FORMAT_NAME, "malformed dimensions"))
+    };
+    let width = dimension()?;
+    let height = dimension()?;
+
+    let scale = read_token(&mut reader)?
+        .parse::<f32>()
+        .ok()
+        .filter(|scale| scale.is_finite() && *scale != 0.)
+        .ok_or_else(|| invalid(FORMAT_NAME, "malformed scale"))?;
+    let byte_order = if scale < 0. {
+        ByteOrder::LittleEndian
+    } else {
+        ByteOrder::BigEndian
+    };
+
+    // The pixel data is read before allocating the buffer, so that a corrupt header can't
+    // cause an allocation larger than the file.
+    let size = checked_size(FORMAT_NAME, (height, width, channels), 4)?;
+    let mut data = Vec::new();
+    reader.take(size as u64).read_to_end(&mut data)?;
+
+    if data.len() < size {
+        return Err(invalid(FORMAT_NAME, "unexpected end of pixel data"));
+    }
+
+    let mut buffer = Array3::<f32>::zeros((height, width, channels));
+
+    for (mut row, row_bytes) in buffer
+        .axis_iter_mut(Axis(0))
+        .rev()
+        .zip(data.chunks_exact(width * channels * 4))
+    {
+        for (value, bytes) in row.iter_mut().zip(row_bytes.chunks_exact(4)) {
+            let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+
+            *value = match byte_order {
+                ByteOrder::LittleEndian => f32::from_le_bytes(bytes),
+                ByteOrder::BigEndian => f32::from_be_bytes(bytes),
+            };
+        }
+    }
+
+    Ok(buffer)
+}
+
+/// Load a radiance map from a Portable Float Map, see [`read_from`].
+///
+/// # Errors
+/// - If the file cannot be read
+/// - If the file is not a valid Portable Float Map
+pub fn load(path: &Path) -> Result<Array3<f32>, Error> {
+    read_from(std::fs::File::open(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[allow(clippy::cast_precision_loss)]
+    fn round_trip(channels: usize, byte_order: ByteOrder) {
+        let buffer = Array3::from_shape_fn((3, 5, channels), |(y, x, channel)| {
+            (y * 100 + x * 10 + channel) as f32 * 0.37 - 4.
+        });
+
+        let mut file = Vec::new();
+        PfmWriter { byte_order }
+            .write_to(&buffer, &mut file)
+            .expect("Buffer should be written");
+
+        let magic = if channels == 1 { b"Pf" } else { b"PF" };
+        assert_eq!(&file[..2], magic);
+        assert_eq!(
+            read_from(file.as_slice()).expect("File should be read"),
+            buffer
+        );
+    }
+
+    #[test]
+    fn round_trips_grayscale_and_colour_in_both_byte_orders() {
+        for channels in [1, 3] {
+            for byte_order in [ByteOrder::LittleEndian, ByteOrder::BigEndian] {
+                round_trip(channels, byte_order);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_dimensions_larger_than_the_data() {
+        let huge = b"PF\n99999999999 99999999999\n-1\n".as_slice();
+        assert!(matches!(read_from(huge), Err(Error::IoError(_))));
+
+        let truncated = b"Pf\n1000 1000\n-1\n\0\0\0\0".as_slice();
+        assert!(matches!(read_from(truncated), Err(Error::IoError(_))));
+    }
+}
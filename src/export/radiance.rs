@@ -0,0 +1,485 @@
+//! Radiance RGBE (`.hdr`) files as described in [Real Pixels](https://doi.org/10.1016/B978-0-08-050754-5.50025-3)
+//! by Greg Ward.
+//!
+//! Every pixel is stored as three 8-bit mantissas sharing an 8-bit exponent, which covers a large
+//! dynamic range with about 1% relative precision. Scanlines are optionally run length encoded.
+
+use crate::export::{checked_size, invalid, validate_buffer};
+use crate::Error;
+use ndarray::{Array3, Axis};
+use rayon::prelude::*;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Name of the format used in error messages.
+const FORMAT_NAME: &str = "Radiance";
+
+/// Value of the `FORMAT` header field for RGBE pixels.
+const PIXEL_FORMAT: &str = "32-bit_rle_rgbe";
+
+/// Scanlines outside of this range of widths cannot be run length encoded.
+const RLE_WIDTHS: std::ops::RangeInclusive<usize> = 8..=0x7fff;
+
+/// Shortest run of equal bytes that is worth encoding as a run.
+const MIN_RUN_LENGTH: usize = 4;
+
+/// Longest run of equal bytes that can be encoded at once.
+const MAX_RUN_LENGTH: usize = 127;
+
+/// Longest sequence of literal bytes that can be encoded at once.
+const MAX_LITERAL_LENGTH: usize = 128;
+
+/// Writer of Radiance RGBE files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RadianceWriter {
+    /// Whether scanlines are run length encoded. Scanlines narrower than 8 or wider than 32767
+    /// pixels are always written uncompressed.
+    pub run_length_encoding: bool,
+}
+
+impl Default for RadianceWriter {
+    fn default() -> Self {
+        Self {
+            run_length_encoding: true,
+        }
+    }
+}
+
+/// Encode a linear colour as RGBE. Negative and NaN values are written as zero.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_sign_loss)]
+fn encode(colour: [f32; 3]) -> [u8; 4] {
+    let colour = colour.map(|value| if value.is_nan() { 0. } else { value.max(0.) });
+    let maximum = colour[0].max(colour[1]).max(colour[2]);
+
+    if maximum < 1e-32 {
+        return [0; 4];
+    }
+
+    // Exponent such that the maximum is in `[2^(exponent - 1), 2^exponent)`, correcting for
+    // rounding of the logarithm.
+    let mut exponent = (maximum.log2().floor() as i32 + 1).min(128);
+    if maximum >= 2_f32.powi(exponent) {
+        exponent += 1;
+    }
+    if maximum < 2_f32.powi(exponent - 1) {
+        exponent -= 1;
+    }
+
+    if exponent > 127 {
+        return [255, 255, 255, 255];
+    }
+
+    let scale = 2_f32.powi(8 - exponent);
+    let [red, green, blue] = colour.map(|value| (value * scale).min(255.) as u8);
+
+    [red, green, blue, (exponent + 128) as u8]
+}
+
+/// Decode an RGBE pixel into a linear colour.
+fn decode(pixel: [u8; 4]) -> [f32; 3] {
+    let [red, green, blue, exponent] = pixel;
+
+    if exponent == 0 {
+        return [0.; 3];
+    }
+
+    let scale = 2_f32.powi(i32::from(exponent) - 136);
+
+    [red, green, blue].map(|value| (f32::from(value) + 0.5) * scale)
+}
+
+/// Run length encode one component of a scanline.
+#[allow(clippy::cast_possible_truncation)]
+fn encode_component(data: &[u8], output: &mut Vec<u8>) {
+    let run_length = |start: usize| {
+        data[start..]
+            .iter()
+            .take(MAX_RUN_LENGTH)
+            .take_while(|&&value| value == data[start])
+            .count()
+    };
+
+    let mut position = 0;
+    while position < data.len() {
+        let run = run_length(position);
+        if run >= MIN_RUN_LENGTH {
+            output.extend([128 + run as u8, data[position]]);
+            position += run;
+            continue;
+        }
+
+        let start = position;
+        while position < data.len()
+            && position - start < MAX_LITERAL_LENGTH
+            && run_length(position) < MIN_RUN_LENGTH
+        {
+            position += 1;
+        }
+
+        output.push((position - start) as u8);
+        output.extend_from_slice(&data[start..position]);
+    }
+}
+
+/// Encode a single scanline.
+#[allow(clippy::cast_possible_truncation)]
+fn encode_scanline(pixels: &[[u8; 4]], run_length_encoding: bool) -> Vec<u8> {
+    let width = pixels.len();
+
+    if !run_length_encoding || !RLE_WIDTHS.contains(&width) {
+        return pixels.iter().flatten().copied().collect();
+    }
+
+    let mut output = Vec::with_capacity(width * 4);
+    output.extend([2, 2, (width >> 8) as u8, (width & 0xff) as u8]);
+
+    for component in 0..4 {
+        let data = pixels
+            .iter()
+            .map(|pixel| pixel[component])
+            .collect::<Vec<u8>>();
+        encode_component(&data, &mut output);
+    }
+
+    output
+}
+
+impl RadianceWriter {
+    /// Write a radiance map of shape `(height, width, channels)` as a Radiance RGBE file.
+    /// Grayscale buffers are written with equal red, green and blue values.
+    ///
+    /// # Errors
+    /// - If the buffer is empty or is neither RGB nor grayscale
+    /// - If writing fails
+    pub fn write_to<W: Write>(&self, buffer: &Array3<f32>, mut writer: W) -> Result<(), Error> {
+        let (height, width, channels) = validate_buffer(buffer)?;
+
+        write!(
+            writer,
+            "#?RADIANCE\n# Written by image-hdr\nFORMAT={PIXEL_FORMAT}\n\n-Y {height} +X {width}\n"
+        )?;
+
+        let scanlines = buffer
+            .axis_iter(Axis(0))
+            .into_par_iter()
+            .map(|row| {
+                let pixels = row
+                    .outer_iter()
+                    .map(|pixel| {
+                        if channels == 1 {
+                            encode([pixel[0]; 3])
+                        } else {
+                            encode([pixel[0], pixel[1], pixel[2]])
+                        }
+                    })
+                    .collect::<Vec<[u8; 4]>>();
+
+                encode_scanline(&pixels, self.run_length_encoding)
+            })
+            .collect::<Vec<Vec<u8>>>();
+
+        for scanline in scanlines {
+            writer.write_all(&scanline)?;
+        }
+
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Save a radiance map to a Radiance RGBE file, see [`RadianceWriter::write_to`].
+    ///
+    /// # Errors
+    /// - If the buffer is empty or is neither RGB nor grayscale
+    /// - If the file cannot be written
+    pub fn save(&self, buffer: &Array3<f32>, path: &Path) -> Result<(), Error> {
+        self.write_to(buffer, BufWriter::new(std::fs::File::create(path)?))
+    }
+}
+
+/// Cursor over the pixel data of a file.
+struct Cursor<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl Cursor<'_> {
+    fn take(&mut self, length: usize) -> Result<&[u8], Error> {
+        let bytes = self
+            .data
+            .get(self.position..self.position + length)
+            .ok_or_else(|| invalid(FORMAT_NAME, "unexpected end of pixel data"))?;
+        self.position += length;
+
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn pixel(&mut self) -> Result<[u8; 4], Error> {
+        let bytes = self.take(4)?;
+
+        Ok([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    /// Decode a run length encoded scanline whose 4 byte header has already been consumed.
+    fn rle_scanline(&mut self, pixels: &mut [[u8; 4]]) -> Result<(), Error> {
+        for component in 0..4 {
+            let mut x = 0;
+
+            while x < pixels.len() {
+                let count = usize::from(self.byte()?);
+                let (length, run) = if count > 128 {
+                    (count - 128, true)
+                } else {
+                    (count, false)
+                };
+
+                if length == 0 || x + length > pixels.len() {
+                    return Err(invalid(FORMAT_NAME, "malformed run length encoding"));
+                }
+
+                if run {
+                    let value = self.byte()?;
+                    for pixel in &mut pixels[x..x + length] {
+                        pixel[component] = value;
+                    }
+                } else {
+                    for (pixel, &value) in pixels[x..x + length].iter_mut().zip(self.take(length)?)
+                    {
+                        pixel[component] = value;
+                    }
+                }
+
+                x += length;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decode an uncompressed scanline, which may use the original Radiance run length encoding
+    /// of repeated pixels.
+    fn flat_scanline(&mut self, first: [u8; 4], pixels: &mut [[u8; 4]]) -> Result<(), Error> {
+        let mut pixel = first;
+        let mut x = 0_usize;
+        let mut shift = 0;
+
+        loop {
+            if pixel[..3] == [1, 1, 1] {
+                let previous = x
+                    .checked_sub(1)
+                    .map(|previous| pixels[previous])
+                    .ok_or_else(|| invalid(FORMAT_NAME, "repeat without previous pixel"))?;
+                let count = usize::from(pixel[3])
+                    .checked_shl(shift)
+                    .filter(|&count| x + count <= pixels.len())
+                    .ok_or_else(|| invalid(FORMAT_NAME, "malformed run length encoding"))?;
+
+                pixels[x..x + count].fill(previous);
+                x += count;
+                shift += 8;
+            } else {
+                pixels[x] = pixel;
+                x += 1;
+                shift = 0;
+            }
+
+            if x == pixels.len() {
+                return Ok(());
+            }
+
+            pixel = self.pixel()?;
+        }
+    }
+
+    /// Decode a scanline of given width.
+    fn scanline(&mut self, pixels: &mut [[u8; 4]]) -> Result<(), Error> {
+        let first = self.pixel()?;
+        let width = pixels.len();
+
+        if RLE_WIDTHS.contains(&width) && first[..2] == [2, 2] && first[2] & 0x80 == 0 {
+            if (usize::from(first[2]) << 8 | usize::from(first[3])) != width {
+                return Err(invalid(FORMAT_NAME, "scanline width mismatch"));
+            }
+
+            self.rle_scanline(pixels)
+        } else {
+            self.flat_scanline(first, pixels)
+        }
+    }
+}
+
+/// Read the header of a file and return the height and width of the image and the product of
+/// all `EXPOSURE` fields.
+fn read_header<R: BufRead>(reader: &mut R) -> Result<(usize, usize, f32), Error> {
+    let mut read_line = || -> Result<String, Error> {
+        let mut line = Vec::new();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Err(invalid(FORMAT_NAME, "unexpected end of header"));
+        }
+
+        Ok(String::from_utf8_lossy(&line).trim_end().to_string())
+    };
+
+    if !read_line()?.starts_with("#?") {
+        return Err(invalid(FORMAT_NAME, "missing magic number"));
+    }
+
+    let mut exposure = 1.;
+    loop {
+        let line = read_line()?;
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some(format) = line.strip_prefix("FORMAT=") {
+            if format.trim() != PIXEL_FORMAT {
+                return Err(invalid(FORMAT_NAME, "unsupported pixel format"));
+            }
+        } else if let Some(value) = line.strip_prefix("EXPOSURE=") {
+            exposure *= value
+                .trim()
+                .parse::<f32>()
+                .ok()
+                .filter(|value| value.is_finite() && *value > 0.)
+                .ok_or_else(|| invalid(FORMAT_NAME, "malformed exposure"))?;
+        }
+    }
+
+    let resolution = read_line()?;
+    let [y_axis, height, x_axis, width] = resolution.split_whitespace().collect::<Vec<&str>>()[..]
+    else {
+        return Err(invalid(FORMAT_NAME, "malformed resolution"));
+    };
+
+    if y_axis != "-Y" || x_axis != "+X" {
+        return Err(invalid(FORMAT_NAME, "unsupported image orientation"));
+    }
+
+    let height = height
+        .parse::<usize>()
+        .map_err(|_| invalid(FORMAT_NAME, "malformed resolution"))?;
+    let width = width
+        .parse::<usize>()
+        .map_err(|_| invalid(FORMAT_NAME, "malformed resolution"))?;
+
+    checked_size(FORMAT_NAME, (height, width, 3), 4)?;
+
+    Ok((height, width, exposure))
+}
+
+/// Read a Radiance RGBE file into a radiance map of shape `(height, width, 3)`. Pixel values are
+/// divided by the `EXPOSURE` recorded in the header, if any, so that they are in the same units
+/// as when they were written.
+///
+/// # Errors
+/// - If reading fails
+/// - If the data is not a valid Radiance RGBE file in the standard orientation
+pub fn read_from<R: Read>(reader: R) -> Result<Array3<f32>, Error> {
+    let mut reader = BufReader::new(reader);
+    let (height, width, exposure) = read_header(&mut reader)?;
+
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+
+    // Every scanline takes at least one pixel of data, which bounds the height of the buffer by
+    // the size of the file rather than by the header.
+    if height > data.len() / 4 {
+        return Err(invalid(FORMAT_NAME, "unexpected end of pixel data"));
+    }
+
+    let mut cursor = Cursor {
+        data: &data,
+        position: 0,
+    };
+    let mut buffer = Array3::<f32>::zeros((height, width, 3));
+    let mut pixels = vec![[0_u8; 4]; width];
+
+    for mut row in buffer.outer_iter_mut() {
+        cursor.scanline(&mut pixels)?;
+
+        for (mut target, &pixel) in row.outer_iter_mut().zip(&pixels) {
+            for (target, value) in target.iter_mut().zip(decode(pixel)) {
+                *target = value / exposure;
+            }
+        }
+    }
+
+    Ok(buffer)
+}
+
+/// Load a radiance map from a Radiance RGBE file, see [`read_from`].
+///
+/// # Errors
+/// - If the file cannot be read
+/// - If the file is not a valid Radiance RGBE file in the standard orientation
+pub fn load(path: &Path) -> Result<Array3<f32>, Error> {
+    read_from(std::fs::File::open(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Radiance map with runs of equal pixels and a range of exponents.
+    #[allow(clippy::cast_precision_loss)]
+    fn radiance_map() -> Array3<f32> {
+        Array3::from_shape_fn((4, 40, 3), |(y, x, channel)| {
+            if x < 16 {
+                0.5
+            } else {
+                2_f32.powf(x as f32 - 24.) * (1. + (y * 3 + channel) as f32 * 0.1)
+            }
+        })
+    }
+
+    fn round_trip(run_length_encoding: bool) -> usize {
+        let buffer = radiance_map();
+
+        let mut file = Vec::new();
+        RadianceWriter {
+            run_length_encoding,
+        }
+        .write_to(&buffer, &mut file)
+        .expect("Buffer should be written");
+
+        let read = read_from(file.as_slice()).expect("File should be read");
+        assert_eq!(read.dim(), buffer.dim());
+
+        ndarray::Zip::from(read.lanes(Axis(2)))
+            .and(buffer.lanes(Axis(2)))
+            .for_each(|pixel, expected| {
+                let maximum = expected.fold(0_f32, |maximum, &value| maximum.max(value));
+
+                for (value, expected) in pixel.iter().zip(expected) {
+                    assert!(
+                        (value - expected).abs() <= maximum / 128.,
+                        "{value} != {expected}"
+                    );
+                }
+            });
+
+        file.len()
+    }
+
+    #[test]
+    fn round_trips_with_and_without_run_length_encoding() {
+        let encoded = round_trip(true);
+        let flat = round_trip(false);
+
+        assert!(encoded < flat);
+    }
+
+    #[test]
+    fn rejects_dimensions_larger_than_the_data() {
+        let huge = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 99999999999 +X 99999999999\n";
+        assert!(matches!(read_from(huge.as_slice()), Err(Error::IoError(_))));
+
+        let tall = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1000000 +X 1000\n\x80\x80\x80\x80";
+        assert!(matches!(read_from(tall.as_slice()), Err(Error::IoError(_))));
+    }
+}
@@ -0,0 +1,278 @@
+//! Floating point TIFF files, readable by most image editors.
+//!
+//! Radiance is written uncompressed as 32-bit IEEE floats (`SampleFormat` 3) in little endian,
+//! with RGB or grayscale photometric interpretation. As the size of the pixel data is known up
+//! front, the image file directory precedes the samples, so that files can be written strip by
+//! strip without seeking. Files whose samples don't fit into the 4 GiB addressable by classic
+//! TIFF are written as `BigTIFF`.
+
+use crate::export::ifd::{Entry, Layout};
+use crate::export::{validate_buffer, validate_dimensions};
+use crate::Error;
+use ndarray::{Array3, ArrayView3, Axis};
+use std::collections::BTreeMap;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Size of the strips of the file the rows are grouped into, in bytes.
+const STRIP_SIZE: usize = 256 * 1024;
+
+/// Writer of 32-bit floating point TIFF files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TiffWriter;
+
+impl TiffWriter {
+    /// Write a radiance map of shape `(height, width, channels)` as a floating point TIFF.
+    ///
+    /// # Errors
+    /// - If the buffer is empty or is neither RGB nor grayscale
+    /// - If writing fails
+    pub fn write_to<W: Write>(&self, buffer: &Array3<f32>, mut writer: W) -> Result<(), Error> {
+        let dimensions = validate_buffer(buffer)?;
+
+        Self::write_header(dimensions, &mut writer)?;
+        Self::write_samples(buffer.view(), &mut writer)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Save a radiance map to a floating point TIFF file, see [`TiffWriter::write_to`].
+    ///
+    /// # Errors
+    /// - If the buffer is empty or is neither RGB nor grayscale
+    /// - If the file cannot be written
+    pub fn save(&self, buffer: &Array3<f32>, path: &Path) -> Result<(), Error> {
+        self.write_to(buffer, BufWriter::new(std::fs::File::create(path)?))
+    }
+
+    /// Write a radiance map that is produced strip by strip as a floating point TIFF, without
+    /// holding all of it in memory, e.g. the output of [`crate::tiled::TiledMerge`].
+    ///
+    /// # Arguments
+    ///
+    /// * `dimensions`: Shape `(height, width, channels)` of the whole radiance map
+    /// * `strips`: Consecutive rows of the radiance map from top to bottom, each of shape
+    ///   `(rows, width, channels)`
+    /// * `writer`: Destination of the file
+    ///
+    /// # Errors
+    /// - If the dimensions are empty or neither RGB nor grayscale
+    /// - If a strip doesn't match the dimensions or the strips don't add up to the height
+    /// - If a strip couldn't be produced
+    /// - If writing fails
+    pub fn write_strips_to<W, I>(
+        &self,
+        dimensions: (usize, usize, usize),
+        strips: I,
+        mut writer: W,
+    ) -> Result<(), Error>
+    where
+        W: Write,
+        I: IntoIterator<Item = Result<Array3<f32>, Error>>,
+    {
+        let (height, width, channels) = validate_dimensions(dimensions)?;
+
+        Self::write_header(dimensions, &mut writer)?;
+
+        let mut rows = 0;
+        for strip in strips {
+            let strip = strip?;
+            let (strip_height, strip_width, strip_channels) = strip.dim();
+
+            if strip_width != width || strip_channels != channels || rows + strip_height > height {
+                return Err(Error::InputError {
+                    parameter_name: "strips".to_string(),
+                    message: "Strips must match the dimensions of the image".to_string(),
+                });
+            }
+
+            Self::write_samples(strip.view(), &mut writer)?;
+            rows += strip_height;
+        }
+
+        if rows != height {
+            return Err(Error::InputError {
+                parameter_name: "strips".to_string(),
+                message: format!("Strips cover {rows} of {height} rows of the image"),
+            });
+        }
+
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Save a radiance map that is produced strip by strip to a floating point TIFF file, see
+    /// [`TiffWriter::write_strips_to`].
+    ///
+    /// # Errors
+    /// - If the dimensions are empty or neither RGB nor grayscale
+    /// - If a strip doesn't match the dimensions or the strips don't add up to the height
+    /// - If a strip couldn't be produced
+    /// - If the file cannot be written
+    pub fn save_strips<I>(
+        &self,
+        dimensions: (usize, usize, usize),
+        strips: I,
+        path: &Path,
+    ) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = Result<Array3<f32>, Error>>,
+    {
+        self.write_strips_to(
+            dimensions,
+            strips,
+            BufWriter::new(std::fs::File::create(path)?),
+        )
+    }
+
+    /// Write the header and the image file directory of an image of given dimensions, after which
+    /// the samples follow.
+    fn write_header<W: Write>(
+        (height, width, channels): (usize, usize, usize),
+        writer: &mut W,
+    ) -> Result<(), Error> {
+        let too_large = || Error::InputError {
+            parameter_name: "buffer".to_string(),
+            message: "Image is too large to be written as a TIFF".to_string(),
+        };
+
+        let row_size = width * channels * 4;
+        let rows_per_strip = (STRIP_SIZE / row_size).clamp(1, height);
+        let strip_count = height.div_ceil(rows_per_strip);
+        let data_size = height * row_size;
+
+        let dimension = |value: usize| u32::try_from(value).map_err(|_| too_large());
+        let channel_count = u16::try_from(channels).map_err(|_| too_large())?;
+
+        let entries = |layout: Layout, data_offset: usize| -> Result<BTreeMap<u16, Entry>, Error> {
+            let offsets = (0..strip_count)
+                .map(|strip| (data_offset + strip * rows_per_strip * row_size) as u64)
+                .collect::<Vec<u64>>();
+            let sizes = (0..strip_count)
+                .map(|strip| {
+                    let rows = rows_per_strip.min(height - strip * rows_per_strip);
+                    (rows * row_size) as u64
+                })
+                .collect::<Vec<u64>>();
+
+            let mut entries = BTreeMap::new();
+            entries.insert(254, Entry::longs(&[0]));
+            entries.insert(256, Entry::longs(&[dimension(width)?]));
+            entries.insert(257, Entry::longs(&[dimension(height)?]));
+            entries.insert(258, Entry::shorts(&vec![32; channels]));
+            entries.insert(259, Entry::shorts(&[1]));
+            entries.insert(262, Entry::shorts(&[if channels == 1 { 1 } else { 2 }]));
+            entries.insert(273, Entry::offsets(&offsets, layout));
+            entries.insert(277, Entry::shorts(&[channel_count]));
+            entries.insert(278, Entry::longs(&[dimension(rows_per_strip)?]));
+            entries.insert(279, Entry::offsets(&sizes, layout));
+            entries.insert(284, Entry::shorts(&[1]));
+            entries.insert(305, Entry::ascii("image-hdr"));
+            entries.insert(339, Entry::shorts(&vec![3; channels]));
+
+            Ok(entries)
+        };
+
+        let classic = Layout::CLASSIC;
+        let classic_offset = classic.header_size() + classic.ifd_size(&entries(classic, 0)?);
+        let layout = if u32::try_from(classic_offset + data_size).is_ok() {
+            classic
+        } else {
+            Layout::BIG
+        };
+
+        let data_offset = layout.header_size() + layout.ifd_size(&entries(layout, 0)?);
+        let entries = entries(layout, data_offset)?;
+
+        writer.write_all(&layout.encode_header())?;
+        writer.write_all(
+            &layout
+                .encode_ifd(&entries, layout.header_size())
+                .ok_or_else(too_large)?,
+        )?;
+
+        Ok(())
+    }
+
+    /// Write the samples of an image row by row.
+    fn write_samples<W: Write>(samples: ArrayView3<f32>, writer: &mut W) -> Result<(), Error> {
+        let mut row_bytes = Vec::new();
+
+        for row in samples.axis_iter(Axis(0)) {
+            row_bytes.clear();
+            row_bytes.extend(row.iter().flat_map(|value| value.to_le_bytes()));
+
+            writer.write_all(&row_bytes)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::DynamicImage;
+
+    #[allow(clippy::cast_precision_loss)]
+    fn ramp(height: usize) -> Array3<f32> {
+        Array3::from_shape_fn((height, 7, 3), |(y, x, channel)| {
+            (y * 100 + x * 10 + channel) as f32 * 0.37
+        })
+    }
+
+    fn decode(file: &[u8]) -> Array3<f32> {
+        let image = image::load_from_memory(file).expect("File should be decoded");
+        let DynamicImage::ImageRgb32F(image) = image else {
+            panic!("File should be decoded as floating point RGB");
+        };
+        let (width, height) = image.dimensions();
+
+        Array3::from_shape_vec((height as usize, width as usize, 3), image.into_raw())
+            .expect("Pixels should match the dimensions")
+    }
+
+    #[test]
+    fn round_trips_through_image_decoder() {
+        let buffer = ramp(5);
+
+        let mut file = Vec::new();
+        TiffWriter
+            .write_to(&buffer, &mut file)
+            .expect("Buffer should be written");
+
+        assert_eq!(decode(&file), buffer);
+    }
+
+    #[test]
+    fn writes_strips_like_whole_images() {
+        let buffer = ramp(5);
+
+        let mut whole = Vec::new();
+        TiffWriter
+            .write_to(&buffer, &mut whole)
+            .expect("Buffer should be written");
+
+        let strips =
+            [0..2, 2..3, 3..5].map(|rows| Ok(buffer.slice(ndarray::s![rows, .., ..]).to_owned()));
+        let mut stripped = Vec::new();
+        TiffWriter
+            .write_strips_to(buffer.dim(), strips, &mut stripped)
+            .expect("Strips should be written");
+
+        assert_eq!(stripped, whole);
+    }
+
+    #[test]
+    fn rejects_mismatched_strips() {
+        let short = [Ok(ramp(2)), Ok(ramp(2))];
+        let result = TiffWriter.write_strips_to((5, 7, 3), short, Vec::new());
+        assert!(matches!(result, Err(Error::InputError { .. })));
+
+        let long = [Ok(ramp(4)), Ok(ramp(4))];
+        let result = TiffWriter.write_strips_to((5, 7, 3), long, Vec::new());
+        assert!(matches!(result, Err(Error::InputError { .. })));
+    }
+}
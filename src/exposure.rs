@@ -0,0 +1,442 @@
+//! Estimation of the relative exposures of a bracket from its pixel data, for images whose EXIF
+//! metadata is missing or wrong, e.g. scanned film, tethering screenshots or stripped exports.
+//!
+//! Every pair of inputs is compared on the pixels that are well exposed in both, giving a robust
+//! (mode seeking) estimate of their log exposure ratio. The exposures are then fitted to all pairwise
+//! ratios with iteratively reweighted least squares, so that a single inconsistent pair (e.g. due
+//! to motion) doesn't skew the result.
+
+use crate::input::{HDRInput, HDRInputList};
+use crate::linalg::NormalEquations;
+use crate::merge::{has_partial_aperture, sample, validate_inputs};
+use crate::Error;
+use rayon::prelude::*;
+use std::path::Path;
+use std::time::Duration;
+
+/// Number of reweighting iterations of the least squares fit.
+const ITERATIONS: usize = 5;
+
+/// Relative exposures of a bracket estimated by [`ExposureEstimation`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExposureEstimate {
+    /// Exposure factor (see [`HDRInput::get_exposure_factor`]) of every input relative to the
+    /// reference input, in the same order as the inputs.
+    pub relative_exposures: Vec<f32>,
+    /// Index of the reference input, the input of median brightness.
+    pub reference: usize,
+    /// Fraction of the compared pixels of every input that agree with the estimated exposures
+    /// within the tolerance, in the same order as the inputs.
+    pub input_confidence: Vec<f32>,
+    /// Confidence of the estimate in the `0..=1` range, i.e. the lowest confidence of any input.
+    /// Low values indicate motion, a non-linear response or too little overlap between inputs.
+    pub confidence: f32,
+}
+
+/// Parameters for estimating the relative exposures of a bracket from overlapping well exposed
+/// pixels. Pixel values must be linear, see [`crate::transfer`] and [`crate::response`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExposureEstimation {
+    /// Maximum number of pixel values sampled from every input.
+    pub samples: usize,
+    /// Smallest normalized pixel value considered well exposed. Darker pixels are dominated by
+    /// noise and quantization.
+    pub min_value: f32,
+    /// Largest normalized pixel value considered well exposed. Brighter pixels may be clipped or
+    /// compressed by the shoulder of the camera's tone curve.
+    pub max_value: f32,
+    /// Minimum number of pixel values well exposed in both inputs of a pair for the pair to be
+    /// compared.
+    pub min_overlap: usize,
+    /// Largest deviation, in stops, of a pixel's exposure ratio from the estimated ratio for the
+    /// pixel to count as agreeing with the estimate.
+    pub tolerance: f32,
+}
+
+impl Default for ExposureEstimation {
+    fn default() -> Self {
+        Self {
+            samples: 100_000,
+            min_value: 0.05,
+            max_value: 0.9,
+            min_overlap: 64,
+            tolerance: 0.25,
+        }
+    }
+}
+
+/// Log exposure ratios of the pixels well exposed in both inputs of a pair, along with their
+/// robust estimate.
+struct Pair {
+    first: usize,
+    second: usize,
+    log_ratios: Vec<f32>,
+    log_ratio: f64,
+    /// Number of log ratios within the tolerance of the estimate
+    support: usize,
+}
+
+impl ExposureEstimation {
+    /// Estimate the exposures of the inputs relative to each other. The current exposure
+    /// parameters of the inputs are ignored.
+    ///
+    /// # Errors
+    /// - If fewer than two inputs are provided
+    /// - If inputs are of different dimensions or are neither RGB nor grayscale
+    /// - If the parameters are invalid
+    /// - If an input doesn't have enough well exposed pixels in common with the others
+    pub fn estimate(&self, inputs: &[HDRInput]) -> Result<ExposureEstimate, Error> {
+        let shape = validate_inputs(inputs)?;
+        self.validate()?;
+
+        let values = inputs
+            .par_iter()
+            .map(|input| self.sample_values(input, shape))
+            .collect::<Vec<Vec<f32>>>();
+        let reference = reference_index(&values);
+
+        let pairs = (0..inputs.len())
+            .flat_map(|first| (first + 1..inputs.len()).map(move |second| (first, second)))
+            .collect::<Vec<(usize, usize)>>()
+            .into_par_iter()
+            .filter_map(|(first, second)| self.pair(&values, first, second))
+            .collect::<Vec<Pair>>();
+
+        let log_exposures = self.fit(&pairs, inputs.len(), reference)?;
+        let input_confidence = self.input_confidence(&pairs, &log_exposures);
+
+        #[allow(clippy::cast_possible_truncation)]
+        Ok(ExposureEstimate {
+            relative_exposures: log_exposures
+                .iter()
+                .map(|log_exposure| log_exposure.exp() as f32)
+                .collect(),
+            reference,
+            confidence: input_confidence.iter().copied().fold(1., f32::min),
+            input_confidence,
+        })
+    }
+
+    /// Estimate the relative exposures of the inputs and replace their exposure times with the
+    /// estimate. The gain, aperture and ND filter of every input are kept, and the exposure
+    /// factor of the reference input anchors the absolute scale. If the aperture is known for
+    /// only some of the inputs, it is cleared for all of them, as the merge would leave it out.
+    ///
+    /// # Errors
+    /// - If fewer than two inputs are provided
+    /// - If inputs are of different dimensions or are neither RGB nor grayscale
+    /// - If the parameters are invalid
+    /// - If an input doesn't have enough well exposed pixels in common with the others
+    pub fn apply(&self, inputs: &mut [HDRInput]) -> Result<ExposureEstimate, Error> {
+        let estimate = self.estimate(inputs)?;
+
+        if has_partial_aperture(inputs) {
+            inputs.iter_mut().for_each(HDRInput::clear_aperture);
+        }
+
+        let reference_factor = inputs[estimate.reference].get_exposure_factor();
+
+        for (input, relative_exposure) in inputs.iter_mut().zip(&estimate.relative_exposures) {
+            // Exposure factor per second of exposure time
+            let rate = input.get_exposure_factor() / input.get_exposure();
+            let exposure = Duration::try_from_secs_f32(reference_factor * relative_exposure / rate)
+                .map_err(|_| Error::InputError {
+                    parameter_name: "inputs".to_string(),
+                    message: "Estimated exposure is out of range".to_string(),
+                })?;
+
+            input.set_exposure_and_gain(exposure, input.get_gain())?;
+        }
+
+        Ok(estimate)
+    }
+
+    /// Read images without using their exposure metadata and estimate their exposures. The
+    /// reference input is assigned an exposure of one second at unit gain, so the resulting
+    /// radiances are relative.
+    ///
+    /// # Errors
+    /// - If any image cannot be opened
+    /// - If fewer than two paths are provided
+    /// - If images are of different dimensions or are neither RGB nor grayscale
+    /// - If the parameters are invalid
+    /// - If an image doesn't have enough well exposed pixels in common with the others
+    pub fn estimate_paths<P: AsRef<Path> + Sync>(
+        &self,
+        paths: &[P],
+    ) -> Result<(HDRInputList, ExposureEstimate), Error> {
+        let mut inputs = paths
+            .par_iter()
+            .map(|path| HDRInput::with_exposure_and_gain(path.as_ref(), Duration::from_secs(1), 1.))
+            .collect::<Result<Vec<HDRInput>, Error>>()?;
+        let estimate = self.apply(&mut inputs)?;
+
+        Ok((HDRInputList::from(inputs), estimate))
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.samples == 0 || self.min_overlap == 0 {
+            return Err(Error::InputError {
+                parameter_name: "samples".to_string(),
+                message: "Number of samples and minimum overlap must be positive".to_string(),
+            });
+        }
+
+        if !(self.min_value > 0. && self.min_value < self.max_value && self.max_value <= 1.) {
+            return Err(Error::InputError {
+                parameter_name: "min_value".to_string(),
+                message: "Well exposed range must satisfy 0 < min_value < max_value <= 1"
+                    .to_string(),
+            });
+        }
+
+        if !self.tolerance.is_finite() || self.tolerance <= 0. {
+            return Err(Error::InputError {
+                parameter_name: "tolerance".to_string(),
+                message: "Tolerance must be a positive finite number of stops".to_string(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Sample normalized pixel values of every channel on a regular grid of pixels.
+    fn sample_values(&self, input: &HDRInput, shape: (usize, usize, usize)) -> Vec<f32> {
+        let (height, width, channels) = shape;
+        let step = (height * width / (self.samples / channels).max(1)).max(1);
+
+        (0..height * width)
+            .step_by(step)
+            .flat_map(|pixel| {
+                (0..channels).map(move |channel| (pixel / width, pixel % width, channel))
+            })
+            .map(|index| sample(input, index))
+            .collect()
+    }
+
+    /// Compare two inputs on the values well exposed in both, if there are enough of them.
+    #[allow(clippy::cast_precision_loss)]
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    fn pair(&self, values: &[Vec<f32>], first: usize, second: usize) -> Option<Pair> {
+        let well_exposed = |value: f32| value >= self.min_value && value <= self.max_value;
+
+        let log_ratios = values[first]
+            .iter()
+            .zip(&values[second])
+            .filter(|&(&a, &b)| well_exposed(a) && well_exposed(b))
+            .map(|(a, b)| b.ln() - a.ln())
+            .collect::<Vec<f32>>();
+
+        if log_ratios.len() < self.min_overlap {
+            return None;
+        }
+
+        // Moving objects and noise add outliers of arbitrary ratio, which can outnumber the
+        // agreeing values of pairs that barely overlap. Start from the densest bin of a histogram
+        // of the ratios and move to the mode by averaging the values within the tolerance of the
+        // current estimate.
+        let threshold = f64::from(self.tolerance) * std::f64::consts::LN_2;
+        let (min, max) = log_ratios
+            .iter()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &value| {
+                (min.min(value), max.max(value))
+            });
+        let (min, max) = (f64::from(min), f64::from(max));
+        let bin = |value: f64| ((value - min) / threshold) as usize;
+        let mut histogram = vec![0usize; bin(max) + 1];
+
+        for &value in &log_ratios {
+            histogram[bin(f64::from(value))] += 1;
+        }
+
+        let densest = (0..histogram.len())
+            .max_by_key(|&index| histogram[index])
+            .unwrap_or(0);
+        let mut log_ratio = min + (densest as f64 + 0.5) * threshold;
+
+        for _ in 0..ITERATIONS {
+            let (sum, count) = log_ratios
+                .iter()
+                .map(|&value| f64::from(value))
+                .filter(|value| (value - log_ratio).abs() <= threshold)
+                .fold((0., 0.), |(sum, count), value| (sum + value, count + 1.));
+
+            if count > 0. {
+                log_ratio = sum / count;
+            }
+        }
+
+        let support = log_ratios
+            .iter()
+            .filter(|&&value| (f64::from(value) - log_ratio).abs() <= threshold)
+            .count();
+
+        Some(Pair {
+            first,
+            second,
+            log_ratios,
+            log_ratio,
+            support,
+        })
+    }
+
+    /// Fit log exposures to the pairwise log ratios, with the log exposure of the reference
+    /// fixed at zero. Pairs are weighted by their support, and pairs that disagree with the fit
+    /// are down-weighted and eventually ignored (Tukey's biweight).
+    #[allow(clippy::cast_precision_loss)]
+    fn fit(&self, pairs: &[Pair], inputs: usize, reference: usize) -> Result<Vec<f64>, Error> {
+        let cutoff = 3. * f64::from(self.tolerance) * std::f64::consts::LN_2;
+        let mut robust_weights = vec![1.; pairs.len()];
+        let mut log_exposures: Option<Vec<f64>> = None;
+
+        for _ in 0..ITERATIONS {
+            let mut system = NormalEquations::new(inputs);
+            system.add_row(&[(reference, 1.)], 0.);
+
+            for (pair, robust_weight) in pairs.iter().zip(&robust_weights) {
+                let weight = (pair.support as f64 * robust_weight).sqrt();
+                system.add_row(
+                    &[(pair.second, weight), (pair.first, -weight)],
+                    weight * pair.log_ratio,
+                );
+            }
+
+            // Rejecting pairs may disconnect an input, the previous fit is kept in that case
+            let Some(solution) = system.solve() else {
+                break;
+            };
+
+            for (pair, robust_weight) in pairs.iter().zip(&mut robust_weights) {
+                let residual =
+                    (pair.log_ratio - (solution[pair.second] - solution[pair.first])) / cutoff;
+                *robust_weight = (1. - residual * residual).max(0.).powi(2);
+            }
+
+            log_exposures = Some(solution);
+        }
+
+        log_exposures.ok_or_else(|| Error::InputError {
+            parameter_name: "inputs".to_string(),
+            message: "Inputs don't have enough well exposed pixels in common to estimate their \
+                      exposures"
+                .to_string(),
+        })
+    }
+
+    /// Fraction of the compared values of every input whose log ratio agrees with the fitted
+    /// log exposures within the tolerance.
+    #[allow(clippy::cast_precision_loss)]
+    #[allow(clippy::cast_possible_truncation)]
+    fn input_confidence(&self, pairs: &[Pair], log_exposures: &[f64]) -> Vec<f32> {
+        let threshold = self.tolerance * std::f32::consts::LN_2;
+        let mut agreeing = vec![0usize; log_exposures.len()];
+        let mut compared = vec![0usize; log_exposures.len()];
+
+        for pair in pairs {
+            let expected = (log_exposures[pair.second] - log_exposures[pair.first]) as f32;
+            let count = pair
+                .log_ratios
+                .iter()
+                .filter(|&&log_ratio| (log_ratio - expected).abs() <= threshold)
+                .count();
+
+            for input in [pair.first, pair.second] {
+                agreeing[input] += count;
+                compared[input] += pair.log_ratios.len();
+            }
+        }
+
+        agreeing
+            .iter()
+            .zip(&compared)
+            .map(|(&agreeing, &compared)| agreeing as f32 / compared.max(1) as f32)
+            .collect()
+    }
+}
+
+/// Index of the input of median mean brightness.
+#[allow(clippy::cast_precision_loss)]
+fn reference_index(values: &[Vec<f32>]) -> usize {
+    let mut brightness = values
+        .iter()
+        .map(|values| values.iter().sum::<f32>() / values.len().max(1) as f32)
+        .enumerate()
+        .collect::<Vec<(usize, f32)>>();
+    brightness.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+    brightness[brightness.len() / 2].0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::Array3;
+
+    /// Exposures of a grayscale scene spanning eight stops, stored with unit exposure and gain
+    /// so that the estimate can't rely on the metadata.
+    #[allow(clippy::cast_precision_loss)]
+    fn bracket(exposures: &[f32]) -> Vec<HDRInput> {
+        exposures
+            .iter()
+            .map(|&exposure| {
+                let buffer = Array3::from_shape_fn((64, 64, 1), |(y, x, _)| {
+                    let level = ((x * 7 + y * 13) % 101) as f32 / 101.;
+
+                    (2_f32.powf(level * 8.) / 64. * exposure).min(1.)
+                });
+
+                HDRInput::with_buffer(buffer, Duration::from_secs(1), 1.)
+                    .expect("Synthetic exposure should be valid")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn estimates_exposures_relative_to_median_input() {
+        let estimate = ExposureEstimation::default()
+            .estimate(&bracket(&[1., 0.25, 4.]))
+            .expect("Exposures should be estimated");
+
+        assert_eq!(estimate.reference, 0);
+        for (relative, expected) in estimate.relative_exposures.iter().zip([1., 0.25, 4.]) {
+            assert!(
+                (relative / expected - 1.).abs() < 1e-3,
+                "{relative} != {expected}"
+            );
+        }
+        assert!(estimate.confidence > 0.99, "{}", estimate.confidence);
+    }
+
+    #[test]
+    fn applies_estimate_keeping_gain_and_reference_factor() {
+        let mut inputs = bracket(&[0.25, 1., 4.]);
+        inputs[2]
+            .set_exposure_and_gain(Duration::from_secs(1), 2.)
+            .expect("Gain should be valid");
+
+        let estimate = ExposureEstimation::default()
+            .apply(&mut inputs)
+            .expect("Exposures should be estimated");
+
+        assert_eq!(estimate.reference, 1);
+        assert!((inputs[1].get_exposure() - 1.).abs() < 1e-6);
+        assert!((inputs[0].get_exposure() - 0.25).abs() < 1e-3);
+        // The gain of the last input accounts for half of its exposure factor
+        assert!((inputs[2].get_exposure() - 2.).abs() < 1e-2);
+        assert!((inputs[2].get_gain() - 2.).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn rejects_inputs_without_overlap() {
+        let inputs = bracket(&[1., 1e4]);
+
+        assert!(ExposureEstimation::default().estimate(&inputs).is_err());
+        assert!(ExposureEstimation {
+            tolerance: 0.,
+            ..ExposureEstimation::default()
+        }
+        .estimate(&bracket(&[1., 2.]))
+        .is_err());
+    }
+}
@@ -0,0 +1,442 @@
+//! Exposure fusion as described in [Exposure Fusion](https://doi.org/10.1109/PG.2007.17) by
+//! Mertens, Kautz and Van Reeth.
+//!
+//! Instead of estimating radiance and tone mapping it, the frames of a bracket are blended
+//! directly into a display-referred image. Every pixel of every frame is weighted by its local
+//! contrast, colour saturation and how well exposed it is, and the frames are blended with
+//! Laplacian pyramids so that the seams between differently weighted regions are invisible.
+//!
+//! Fusion doesn't need to know the exposure or gain of the frames, so it also works for brackets
+//! without EXIF metadata, see [`ExposureFusion::fuse_paths`].
+
+use crate::export::validate_buffer;
+use crate::extensions::NDArrayBuffer;
+use crate::input::HDRInputList;
+use crate::io::read_image;
+use crate::tonemap::{quantize, srgb_encode};
+use crate::Error;
+use image::DynamicImage;
+use ndarray::{Array2, Array3, ArrayView1, ArrayView2, Axis, Zip};
+use rayon::prelude::*;
+use std::path::Path;
+
+/// Levels of the pyramid whose smaller dimension falls below this size are not computed.
+const MIN_PYRAMID_SIZE: usize = 4;
+
+/// Binomial approximation of a Gaussian used to build the pyramids, as proposed by Burt and
+/// Adelson.
+const PYRAMID_KERNEL: [f32; 5] = [1. / 16., 4. / 16., 6. / 16., 4. / 16., 1. / 16.];
+
+/// Weight added to every pixel of every frame, so that pixels whose weight is zero in all frames
+/// are blended evenly.
+const WEIGHT_EPSILON: f32 = 1e-12;
+
+/// Mertens–Kautz–Van Reeth exposure fusion.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExposureFusion {
+    /// Exponent of the contrast measure, the absolute response of a Laplacian filter on the
+    /// grayscale frame. Zero disables the measure.
+    pub contrast_weight: f32,
+    /// Exponent of the saturation measure, the standard deviation of the colour channels. Zero
+    /// disables the measure. It is ignored for grayscale frames.
+    pub saturation_weight: f32,
+    /// Exponent of the well-exposedness measure, how close the values of a pixel are to the
+    /// middle of the range. Zero disables the measure.
+    pub exposedness_weight: f32,
+    /// Standard deviation of the Gaussian around `0.5` that measures well-exposedness.
+    pub exposedness_sigma: f32,
+}
+
+impl Default for ExposureFusion {
+    fn default() -> Self {
+        Self {
+            contrast_weight: 1.,
+            saturation_weight: 1.,
+            exposedness_weight: 1.,
+            exposedness_sigma: 0.2,
+        }
+    }
+}
+
+/// Resample every lane of an image along an axis with given function.
+fn resample_axis(
+    image: ArrayView2<f32>,
+    axis: Axis,
+    length: usize,
+    resample: fn(&[f32], &mut [f32]),
+) -> Array2<f32> {
+    let mut shape = image.raw_dim();
+    shape[axis.index()] = length;
+
+    let mut result = Array2::<f32>::zeros(shape);
+    Zip::from(result.lanes_mut(axis))
+        .and(image.lanes(axis))
+        .par_for_each(|mut target, source| {
+            let source = source.to_vec();
+            let mut lane = vec![0.; length];
+            resample(&source, &mut lane);
+            target.assign(&ArrayView1::from(&lane));
+        });
+
+    result
+}
+
+/// Blur a lane with [`PYRAMID_KERNEL`] and keep every second sample. Samples beyond the ends of
+/// the lane repeat the edge.
+fn reduce_lane(source: &[f32], target: &mut [f32]) {
+    let last = source.len() - 1;
+
+    for (index, value) in target.iter_mut().enumerate() {
+        *value = PYRAMID_KERNEL
+            .iter()
+            .enumerate()
+            .map(|(offset, weight)| {
+                let position = (2 * index + offset).saturating_sub(2).min(last);
+                weight * source[position]
+            })
+            .sum();
+    }
+}
+
+/// Upsample a lane by a factor of two, interpolating with [`PYRAMID_KERNEL`]. Weights are
+/// normalized by the samples that exist, so that constant lanes stay constant at the ends.
+fn expand_lane(source: &[f32], target: &mut [f32]) {
+    for (index, value) in target.iter_mut().enumerate() {
+        let mut sum = 0.;
+        let mut weight_sum = 0.;
+
+        for (offset, weight) in PYRAMID_KERNEL.iter().enumerate() {
+            let position = index + offset;
+            if position < 2 || position % 2 != 0 {
+                continue;
+            }
+
+            if let Some(sample) = source.get((position - 2) / 2) {
+                sum += weight * sample;
+                weight_sum += weight;
+            }
+        }
+
+        *value = sum / weight_sum;
+    }
+}
+
+/// Next coarser level of a Gaussian pyramid.
+fn reduce(image: &Array2<f32>) -> Array2<f32> {
+    let (height, width) = image.dim();
+    let rows = resample_axis(image.view(), Axis(0), height.div_ceil(2), reduce_lane);
+
+    resample_axis(rows.view(), Axis(1), width.div_ceil(2), reduce_lane)
+}
+
+/// Upsample a pyramid level to the shape of the next finer level.
+fn expand(image: &Array2<f32>, (height, width): (usize, usize)) -> Array2<f32> {
+    let rows = resample_axis(image.view(), Axis(0), height, expand_lane);
+
+    resample_axis(rows.view(), Axis(1), width, expand_lane)
+}
+
+/// Gaussian pyramid of an image with given number of levels, from finest to coarsest.
+fn gaussian_pyramid(image: Array2<f32>, levels: usize) -> Vec<Array2<f32>> {
+    let mut pyramid = Vec::with_capacity(levels);
+    pyramid.push(image);
+
+    while pyramid.len() < levels {
+        let next = reduce(&pyramid[pyramid.len() - 1]);
+        pyramid.push(next);
+    }
+
+    pyramid
+}
+
+/// Laplacian pyramid of an image with given number of levels, from finest to coarsest. The
+/// coarsest level is the coarsest level of the Gaussian pyramid.
+fn laplacian_pyramid(image: Array2<f32>, levels: usize) -> Vec<Array2<f32>> {
+    let mut pyramid = gaussian_pyramid(image, levels);
+
+    for level in 0..levels - 1 {
+        let expanded = expand(&pyramid[level + 1], pyramid[level].dim());
+        pyramid[level] -= &expanded;
+    }
+
+    pyramid
+}
+
+/// Reconstruct an image from its Laplacian pyramid.
+fn collapse(pyramid: Vec<Array2<f32>>) -> Array2<f32> {
+    pyramid
+        .into_iter()
+        .rev()
+        .reduce(|coarser, level| expand(&coarser, level.dim()) + level)
+        .unwrap_or_else(|| panic!("Expected at least one pyramid level"))
+}
+
+/// Number of levels of the pyramids of an image with given shape.
+fn level_count((mut height, mut width): (usize, usize)) -> usize {
+    let mut levels = 1;
+
+    while height.min(width) / 2 >= MIN_PYRAMID_SIZE {
+        height = height.div_ceil(2);
+        width = width.div_ceil(2);
+        levels += 1;
+    }
+
+    levels
+}
+
+impl ExposureFusion {
+    /// Validate the configuration of the fusion.
+    fn validate(&self) -> Result<(), Error> {
+        for (parameter_name, value) in [
+            ("contrast_weight", self.contrast_weight),
+            ("saturation_weight", self.saturation_weight),
+            ("exposedness_weight", self.exposedness_weight),
+        ] {
+            if !value.is_finite() || value < 0. {
+                return Err(Error::InputError {
+                    parameter_name: parameter_name.to_string(),
+                    message: "Must be a finite non-negative floating point number".to_string(),
+                });
+            }
+        }
+
+        if !self.exposedness_sigma.is_finite() || self.exposedness_sigma <= 0. {
+            return Err(Error::InputError {
+                parameter_name: "exposedness_sigma".to_string(),
+                message: "Must be a finite positive floating point number".to_string(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Unnormalized weight of every pixel of a frame.
+    fn weights(&self, image: &Array3<f32>) -> Array2<f32> {
+        let (height, width, channels) = image.dim();
+        let grayscale = image.mean_axis(Axis(2)).unwrap_or_else(|| {
+            panic!("Expected at least one channel");
+        });
+        let exposedness_scale = -0.5 / self.exposedness_sigma.powi(2);
+
+        let mut weights = Array2::<f32>::zeros((height, width));
+        Zip::indexed(&mut weights)
+            .and(image.lanes(Axis(2)))
+            .par_for_each(|(y, x), weight, pixel| {
+                let neighbours = grayscale[[y.saturating_sub(1), x]]
+                    + grayscale[[(y + 1).min(height - 1), x]]
+                    + grayscale[[y, x.saturating_sub(1)]]
+                    + grayscale[[y, (x + 1).min(width - 1)]];
+                let contrast = (neighbours - 4. * grayscale[[y, x]]).abs();
+
+                let exposedness = pixel
+                    .iter()
+                    .map(|value| ((value - 0.5).powi(2) * exposedness_scale).exp())
+                    .product::<f32>();
+
+                *weight =
+                    contrast.powf(self.contrast_weight) * exposedness.powf(self.exposedness_weight);
+
+                if channels > 1 {
+                    let mean = grayscale[[y, x]];
+                    #[allow(clippy::cast_precision_loss)]
+                    let variance = pixel
+                        .iter()
+                        .map(|value| (value - mean).powi(2))
+                        .sum::<f32>()
+                        / channels as f32;
+
+                    *weight *= variance.sqrt().powf(self.saturation_weight);
+                }
+
+                *weight += WEIGHT_EPSILON;
+            });
+
+        weights
+    }
+
+    /// Fuse a bracket of display-referred frames, i.e. gamma encoded values in the `0..=1` range,
+    /// of shape `(height, width, channels)`. Returns the fused frame in the same encoding and
+    /// layout.
+    ///
+    /// # Errors
+    /// - If no frames are provided
+    /// - If a frame is empty or is neither RGB nor grayscale
+    /// - If frames are of different dimensions
+    /// - If the fusion is configured with invalid parameters
+    pub fn fuse(&self, images: &[Array3<f32>]) -> Result<Array3<f32>, Error> {
+        self.validate()?;
+
+        let Some(first) = images.first() else {
+            return Err(Error::InputError {
+                parameter_name: "images".to_string(),
+                message: "At least one image is required for fusion".to_string(),
+            });
+        };
+
+        for image in images {
+            validate_buffer(image)?;
+            if image.dim() != first.dim() {
+                return Err(Error::InputError {
+                    parameter_name: "images".to_string(),
+                    message: "All images must have the same dimensions".to_string(),
+                });
+            }
+        }
+
+        let (height, width, channels) = first.dim();
+        let levels = level_count((height, width));
+
+        let mut weights = images
+            .iter()
+            .map(|image| self.weights(image))
+            .collect::<Vec<Array2<f32>>>();
+        let total = weights
+            .iter()
+            .fold(Array2::<f32>::zeros((height, width)), |total, weight| {
+                total + weight
+            });
+        for weight in &mut weights {
+            *weight /= &total;
+        }
+
+        let mut fused = Array3::<f32>::zeros((height, width, channels));
+        for channel in 0..channels {
+            let mut blended: Option<Vec<Array2<f32>>> = None;
+
+            for (image, weight) in images.iter().zip(&weights) {
+                let laplacian =
+                    laplacian_pyramid(image.index_axis(Axis(2), channel).to_owned(), levels);
+                let gaussian = gaussian_pyramid(weight.clone(), levels);
+
+                let contribution = laplacian
+                    .into_iter()
+                    .zip(gaussian)
+                    .map(|(laplacian, gaussian)| laplacian * gaussian);
+
+                blended = Some(match blended {
+                    Some(blended) => blended
+                        .into_iter()
+                        .zip(contribution)
+                        .map(|(blended, contribution)| blended + contribution)
+                        .collect(),
+                    None => contribution.collect(),
+                });
+            }
+
+            if let Some(blended) = blended {
+                fused
+                    .index_axis_mut(Axis(2), channel)
+                    .assign(&collapse(blended).mapv(|value| value.clamp(0., 1.)));
+            }
+        }
+
+        Ok(fused)
+    }
+
+    /// Fuse a bracket of decoded images into a 16-bit image, see [`ExposureFusion::fuse`]. Pixel
+    /// values of the images are used as they are, i.e. as gamma encoded values.
+    ///
+    /// # Errors
+    /// - If no images are provided
+    /// - If images are of different dimensions
+    /// - If the fusion is configured with invalid parameters
+    pub fn fuse_images(&self, images: &[DynamicImage]) -> Result<DynamicImage, Error> {
+        let buffers = images
+            .par_iter()
+            .map(NDArrayBuffer::to_nd_array_buffer)
+            .collect::<Vec<Array3<f32>>>();
+
+        Ok(quantize(&self.fuse(&buffers)?))
+    }
+
+    /// Fuse a list of inputs into a 16-bit sRGB image, see [`ExposureFusion::fuse`]. Exposure and
+    /// gain of the inputs are ignored, their linear pixel values are encoded with the sRGB
+    /// transfer function before fusion.
+    ///
+    /// # Errors
+    /// - If the list is empty
+    /// - If inputs are of different dimensions
+    /// - If the fusion is configured with invalid parameters
+    pub fn fuse_inputs(&self, inputs: &HDRInputList) -> Result<DynamicImage, Error> {
+        let buffers = inputs
+            .as_slice()
+            .par_iter()
+            .map(|input| input.get_buffer().mapv(srgb_encode))
+            .collect::<Vec<Array3<f32>>>();
+
+        Ok(quantize(&self.fuse(&buffers)?))
+    }
+
+    /// Read a bracket from given file paths and fuse it into a 16-bit sRGB image, see
+    /// [`ExposureFusion::fuse`]. Unlike [`HDRInputList`], the files don't need EXIF metadata.
+    /// Pixel values are converted to linear light using the transfer function of the embedded
+    /// ICC profile, or sRGB for integer images without one, and encoded with sRGB for fusion.
+    ///
+    /// # Errors
+    /// - If an image cannot be opened
+    /// - If no paths are provided
+    /// - If images are of different dimensions
+    /// - If the fusion is configured with invalid parameters
+    pub fn fuse_paths<P: AsRef<Path> + Sync>(&self, paths: &[P]) -> Result<DynamicImage, Error> {
+        let buffers = paths
+            .par_iter()
+            .map(|path| -> Result<Array3<f32>, Error> {
+                let path = path.as_ref();
+                let data = std::fs::read(path)?;
+                let format = image::ImageFormat::from_path(path).ok();
+                let (image, transfer_function, _) = read_image(&data, format)?;
+
+                let mut buffer = image.to_nd_array_buffer();
+                transfer_function.linearize_buffer(&mut buffer)?;
+
+                Ok(buffer.mapv(srgb_encode))
+            })
+            .collect::<Result<Vec<Array3<f32>>, Error>>()?;
+
+        Ok(quantize(&self.fuse(&buffers)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn fusing_a_single_frame_reproduces_it() {
+        let frame = Array3::from_shape_fn((24, 40, 3), |(y, x, channel)| {
+            ((x * 7 + y * 13 + channel * 31) % 97) as f32 / 97.
+        });
+
+        let fused = ExposureFusion::default()
+            .fuse(std::slice::from_ref(&frame))
+            .expect("Frame should be fused");
+
+        assert!(fused
+            .iter()
+            .zip(&frame)
+            .all(|(fused, frame)| (fused - frame).abs() < 1e-4));
+    }
+
+    #[test]
+    fn prefers_well_exposed_frames() {
+        // The short exposure is well exposed on the left and crushed on the right, the long one
+        // clipped on the left and well exposed on the right
+        let short = Array3::from_shape_fn((32, 64, 3), |(_, x, _)| if x < 32 { 0.5 } else { 0. });
+        let long = Array3::from_shape_fn((32, 64, 3), |(_, x, _)| if x < 32 { 1. } else { 0.5 });
+
+        // Flat grey frames have neither contrast nor saturation
+        let fusion = ExposureFusion {
+            contrast_weight: 0.,
+            saturation_weight: 0.,
+            ..ExposureFusion::default()
+        };
+
+        let fused = fusion
+            .fuse(&[short, long])
+            .expect("Bracket should be fused");
+
+        assert!((fused[[16, 8, 0]] - 0.5).abs() < 0.05);
+        assert!((fused[[16, 56, 0]] - 0.5).abs() < 0.05);
+    }
+}
@@ -1,9 +1,12 @@
 //! Input type for processing HDR merge
 
-use crate::exif::{get_exif_data, get_exposures, get_gains};
+use crate::exif::{get_aperture, get_exif_data, get_exposure_bias, get_exposures, get_gains};
 use crate::extensions::NDArrayBuffer;
 use crate::io::read_image;
+use crate::sensor::SensorMetadata;
+use crate::transfer::TransferFunction;
 use crate::Error;
+use exif::Exif;
 use image::DynamicImage;
 use ndarray::Array3;
 use rayon::prelude::*;
@@ -16,11 +19,130 @@ pub struct HDRInput {
     buffer: Array3<f32>,
     exposure: f32,
     gain: f32,
+    aperture: Option<f32>,
+    nd_stops: f32,
+    exposure_bias: Option<f32>,
+    sensor: Option<SensorMetadata>,
+}
+
+/// Per input overrides of the exposure parameters of an [`HDRInput`] read from a file. Components
+/// left as `None` are read from the EXIF data of the file where available.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExposureOverrides {
+    /// Exposure time, overriding `ExposureTime`
+    pub exposure: Option<Duration>,
+    /// Gain (ISO sensitivity), overriding `ISOSpeed` and related tags
+    pub gain: Option<f32>,
+    /// Aperture as an f-number, overriding `FNumber`
+    pub aperture: Option<f32>,
+    /// Density of a neutral density filter in front of the lens in stops. There is no EXIF
+    /// equivalent, so no filter is assumed unless given.
+    pub nd_stops: Option<f32>,
+    /// Exposure bias in EV, overriding `ExposureBiasValue`
+    pub exposure_bias: Option<f32>,
+    /// Transfer function the pixel values of the file are encoded with, overriding the one read
+    /// from its ICC profile or guessed from its pixel type. [`TransferFunction::Linear`] keeps
+    /// the decoded values as they are, e.g. for linear images without an ICC profile, or to
+    /// recover the camera response with [`crate::response::ResponseRecovery`] from the encoded
+    /// values.
+    pub transfer_function: Option<TransferFunction>,
+}
+
+impl ExposureOverrides {
+    /// Fill in the components that aren't overridden from the EXIF data of a file, if any.
+    /// Components that are neither overridden nor found in valid EXIF tags are left as `None`.
+    #[must_use]
+    pub fn resolve(&self, exif: Option<&Exif>) -> Self {
+        Self {
+            exposure: self
+                .exposure
+                .or_else(|| exif.and_then(|exif| exposure_from_exif(exif).ok())),
+            gain: self
+                .gain
+                .or_else(|| exif.and_then(|exif| get_gains(exif).ok())),
+            aperture: self
+                .aperture
+                .or_else(|| exif.and_then(|exif| get_aperture(exif).ok())),
+            nd_stops: self.nd_stops,
+            exposure_bias: self
+                .exposure_bias
+                .or_else(|| exif.and_then(|exif| get_exposure_bias(exif).ok())),
+            transfer_function: self.transfer_function.clone(),
+        }
+    }
+
+    /// Resolve the overrides for reading a file, see [`ExposureOverrides::resolve`]. Exposure
+    /// time and gain are always set on the result.
+    ///
+    /// # Errors
+    /// - If exposure time or gain is not overridden and the file doesn't contain EXIF metadata
+    ///   for it
+    pub(crate) fn resolve_required(&self, exif: Result<Exif, Error>) -> Result<Self, Error> {
+        let exif = match exif {
+            Ok(exif) => Some(exif),
+            Err(error) if self.exposure.is_none() || self.gain.is_none() => return Err(error),
+            Err(_) => None,
+        };
+
+        if let Some(exif) = &exif {
+            if self.exposure.is_none() {
+                exposure_from_exif(exif)?;
+            }
+
+            if self.gain.is_none() {
+                get_gains(exif)?;
+            }
+        }
+
+        Ok(self.resolve(exif.as_ref()))
+    }
+
+    /// Apply the overridden components to an input. The transfer function is only used when
+    /// reading a file and is ignored.
+    ///
+    /// # Errors
+    /// - If any of the overridden components is invalid
+    pub fn apply(&self, input: &mut HDRInput) -> Result<(), Error> {
+        if self.exposure.is_some() || self.gain.is_some() {
+            input.set_exposure_and_gain(
+                self.exposure
+                    .unwrap_or_else(|| Duration::from_secs_f32(input.get_exposure())),
+                self.gain.unwrap_or_else(|| input.get_gain()),
+            )?;
+        }
+
+        if let Some(aperture) = self.aperture {
+            input.set_aperture(Some(aperture))?;
+        }
+
+        if let Some(nd_stops) = self.nd_stops {
+            input.set_nd_stops(nd_stops)?;
+        }
+
+        if let Some(exposure_bias) = self.exposure_bias {
+            input.set_exposure_bias(Some(exposure_bias))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Read the exposure time from EXIF data.
+///
+/// # Errors
+/// - If the EXIF data doesn't contain a valid exposure time
+fn exposure_from_exif(exif: &Exif) -> Result<Duration, Error> {
+    Duration::try_from_secs_f32(get_exposures(exif)?).map_err(|_| Error::InputError {
+        parameter_name: "exposure".to_string(),
+        message: "Exposure must be a positive non-zero duration".to_string(),
+    })
 }
 
 impl HDRInput {
     /// Create new [`HDRInput`] from a given file path. The file must have EXIF data for exposure
-    /// and gain.
+    /// and gain. Pixel values are converted to linear light using the transfer function of the
+    /// embedded ICC profile, or sRGB for integer images without one. Use
+    /// [`HDRInput::with_overrides`] to declare the transfer function instead.
     ///
     /// # Arguments
     ///
@@ -38,7 +160,10 @@ impl HDRInput {
         Ok(new_input)
     }
 
-    ///
+    /// Create new [`HDRInput`] from a given file path with known exposure and gain. Pixel values
+    /// are converted to linear light using the transfer function of the embedded ICC profile, or
+    /// sRGB for integer images without one. Use [`HDRInput::with_overrides`] to declare the
+    /// transfer function instead.
     ///
     /// # Arguments
     ///
@@ -60,11 +185,56 @@ impl HDRInput {
     ) -> Result<Self, Error> {
         let data = std::fs::read(path)?;
         let format = image::ImageFormat::from_path(path).ok();
-        let image = read_image(&data, format)?;
+        let (image, transfer_function, sensor) = read_image(&data, format)?;
+        let input = Self::with_transfer_function(&image, exposure, gain, &transfer_function)?;
 
-        Self::with_image(&image, exposure, gain)
+        Ok(match sensor {
+            Some(sensor) => input.with_sensor_metadata(sensor),
+            None => input,
+        })
     }
 
+    /// Create new [`HDRInput`] from a given file path, reading exposure parameters that aren't
+    /// overridden from its EXIF data. Pixel values are converted to linear light using the
+    /// declared transfer function if any, else the transfer function of the embedded ICC
+    /// profile, or sRGB for integer images without one.
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: Path to file
+    /// * `overrides`: Exposure parameters taking precedence over EXIF
+    ///
+    /// returns: `Result<HDRInput, Error>`
+    ///
+    /// # Errors
+    ///
+    /// - If image cannot be opened
+    /// - If exposure or gain is neither overridden nor contained in the EXIF metadata
+    /// - If any of the exposure parameters is invalid
+    pub fn with_overrides(path: &Path, overrides: &ExposureOverrides) -> Result<Self, Error> {
+        let data = std::fs::read(path)?;
+        let overrides = overrides.resolve_required(get_exif_data(&data))?;
+        let format = image::ImageFormat::from_path(path).ok();
+        let (image, detected, sensor) = read_image(&data, format)?;
+        let transfer_function = overrides.transfer_function.clone().unwrap_or(detected);
+
+        let mut input = Self::with_transfer_function(
+            &image,
+            overrides.exposure.unwrap_or_default(),
+            overrides.gain.unwrap_or_default(),
+            &transfer_function,
+        )?;
+        overrides.apply(&mut input)?;
+
+        Ok(match sensor {
+            Some(sensor) => input.with_sensor_metadata(sensor),
+            None => input,
+        })
+    }
+
+    /// Create new [`HDRInput`] from an image with known exposure and gain. Pixel values of the
+    /// image are assumed to be linear, see [`HDRInput::with_transfer_function`] for encoded
+    /// images.
     ///
     /// # Arguments
     ///
@@ -79,30 +249,83 @@ impl HDRInput {
     /// - invalid gain
     /// - invalid exposure duration
     pub fn with_image(image: &DynamicImage, exposure: Duration, gain: f32) -> Result<Self, Error> {
-        if gain.is_infinite() || gain.is_nan() || gain <= 0. {
-            return Err(Error::InputError {
-                parameter_name: "gain".to_string(),
-                message: "Gain must be a valid positive and non-zero floating point number"
-                    .to_string(),
-            });
-        }
-
-        if exposure.is_zero() {
-            return Err(Error::InputError {
-                parameter_name: "exposure".to_string(),
-                message: "Exposure must be a positive non-zero duration".to_string(),
-            });
-        }
+        Self::with_buffer(image.to_nd_array_buffer(), exposure, gain)
+    }
 
-        let buffer = image.to_nd_array_buffer();
+    /// Create new [`HDRInput`] from a buffer of linear pixel values with known exposure and
+    /// gain, e.g. a radiance map read back with [`crate::export`].
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer`: Pixel values of shape `(height, width, channels)`
+    /// * `exposure`:
+    /// * `gain`:
+    ///
+    /// returns: `Result<HDRInput, Error>`
+    ///
+    /// # Errors
+    ///
+    /// - invalid gain
+    /// - invalid exposure duration
+    pub fn with_buffer(buffer: Array3<f32>, exposure: Duration, gain: f32) -> Result<Self, Error> {
+        validate_exposure_and_gain(exposure, gain)?;
 
         Ok(Self {
             buffer,
             exposure: exposure.as_secs_f32(),
             gain,
+            aperture: None,
+            nd_stops: 0.,
+            exposure_bias: None,
+            sensor: None,
         })
     }
 
+    /// Create new [`HDRInput`] from an image with known exposure and gain, whose pixel values are
+    /// encoded with the given transfer function. Pixel values are converted to linear light.
+    ///
+    /// # Arguments
+    ///
+    /// * `image`:
+    /// * `exposure`:
+    /// * `gain`:
+    /// * `transfer_function`: Transfer function the pixel values of `image` are encoded with
+    ///
+    /// returns: `Result<HDRInput, Error>`
+    ///
+    /// # Errors
+    ///
+    /// - invalid gain
+    /// - invalid exposure duration
+    /// - invalid transfer function
+    pub fn with_transfer_function(
+        image: &DynamicImage,
+        exposure: Duration,
+        gain: f32,
+        transfer_function: &TransferFunction,
+    ) -> Result<Self, Error> {
+        let mut input = Self::with_image(image, exposure, gain)?;
+        transfer_function.linearize(&mut input)?;
+
+        Ok(input)
+    }
+
+    /// Replace the exposure and gain of the input item, e.g. with values estimated from pixel
+    /// data using [`crate::exposure::ExposureEstimation`].
+    ///
+    /// # Errors
+    ///
+    /// - invalid gain
+    /// - invalid exposure duration
+    pub fn set_exposure_and_gain(&mut self, exposure: Duration, gain: f32) -> Result<(), Error> {
+        validate_exposure_and_gain(exposure, gain)?;
+
+        self.exposure = exposure.as_secs_f32();
+        self.gain = gain;
+
+        Ok(())
+    }
+
     /// Get exposure of the input item
     #[must_use]
     pub fn get_exposure(&self) -> f32 {
@@ -115,6 +338,108 @@ impl HDRInput {
         self.gain
     }
 
+    /// Get aperture of the input item as an f-number, if known
+    #[must_use]
+    pub fn get_aperture(&self) -> Option<f32> {
+        self.aperture
+    }
+
+    /// Set aperture of the input item as an f-number, or `None` if unknown. If the aperture is
+    /// known for only some of the inputs of a merge, the known apertures must be equal and the
+    /// aperture is left out of the exposure factors of all inputs.
+    ///
+    /// # Errors
+    ///
+    /// - invalid aperture
+    pub fn set_aperture(&mut self, aperture: Option<f32>) -> Result<(), Error> {
+        if aperture.is_some_and(|aperture| !aperture.is_finite() || aperture <= 0.) {
+            return Err(Error::InputError {
+                parameter_name: "aperture".to_string(),
+                message: "Aperture must be a positive f-number".to_string(),
+            });
+        }
+
+        self.aperture = aperture;
+        Ok(())
+    }
+
+    /// Forget the aperture of the input item, leaving it out of the exposure factor.
+    pub(crate) fn clear_aperture(&mut self) {
+        self.aperture = None;
+    }
+
+    /// Get density of the neutral density filter the input item was captured with in stops
+    #[must_use]
+    pub fn get_nd_stops(&self) -> f32 {
+        self.nd_stops
+    }
+
+    /// Set density of the neutral density filter the input item was captured with in stops
+    ///
+    /// # Errors
+    ///
+    /// - invalid density
+    pub fn set_nd_stops(&mut self, nd_stops: f32) -> Result<(), Error> {
+        if !nd_stops.is_finite() || nd_stops < 0. {
+            return Err(Error::InputError {
+                parameter_name: "nd_stops".to_string(),
+                message: "ND filter density must be a non-negative number of stops".to_string(),
+            });
+        }
+
+        self.nd_stops = nd_stops;
+        Ok(())
+    }
+
+    /// Get exposure bias (exposure compensation) of the input item in EV, if known
+    #[must_use]
+    pub fn get_exposure_bias(&self) -> Option<f32> {
+        self.exposure_bias
+    }
+
+    /// Set exposure bias (exposure compensation) of the input item in EV, or `None` if unknown.
+    /// The bias is informational, since the camera realizes it through exposure time, aperture
+    /// and gain, which already make up [`HDRInput::get_exposure_factor`].
+    ///
+    /// # Errors
+    ///
+    /// - invalid exposure bias
+    pub fn set_exposure_bias(&mut self, exposure_bias: Option<f32>) -> Result<(), Error> {
+        if exposure_bias.is_some_and(|exposure_bias| !exposure_bias.is_finite()) {
+            return Err(Error::InputError {
+                parameter_name: "exposure_bias".to_string(),
+                message: "Exposure bias must be a finite number of EV".to_string(),
+            });
+        }
+
+        self.exposure_bias = exposure_bias;
+        Ok(())
+    }
+
+    /// Get the photometric exposure factor of the input item, `t·ISO/N²·2^-ND` for exposure time
+    /// `t`, gain `ISO`, aperture `N` and ND filter density `ND` in stops. Pixel values are
+    /// proportional to scene radiance times this factor. The aperture term is left out if the
+    /// aperture is unknown.
+    #[must_use]
+    pub fn get_exposure_factor(&self) -> f32 {
+        let aperture = self.aperture.unwrap_or(1.);
+
+        self.exposure * self.gain / (aperture * aperture) * (-self.nd_stops).exp2()
+    }
+
+    /// Get metadata of the sensor the input was captured with, if known
+    #[must_use]
+    pub fn get_sensor_metadata(&self) -> Option<&SensorMetadata> {
+        self.sensor.as_ref()
+    }
+
+    /// Attach metadata of the sensor the input was captured with
+    #[must_use]
+    pub fn with_sensor_metadata(mut self, sensor: SensorMetadata) -> Self {
+        self.sensor = Some(sensor);
+        self
+    }
+
     /// Get underlying image data for the input item
     #[must_use]
     pub fn get_buffer(&self) -> &Array3<f32> {
@@ -128,18 +453,35 @@ impl HDRInput {
     }
 }
 
+/// Validate exposure and gain of an input item.
+///
+/// # Errors
+///
+/// - invalid gain
+/// - invalid exposure duration
+fn validate_exposure_and_gain(exposure: Duration, gain: f32) -> Result<(), Error> {
+    if gain.is_infinite() || gain.is_nan() || gain <= 0. {
+        return Err(Error::InputError {
+            parameter_name: "gain".to_string(),
+            message: "Gain must be a valid positive and non-zero floating point number".to_string(),
+        });
+    }
+
+    if exposure.is_zero() {
+        return Err(Error::InputError {
+            parameter_name: "exposure".to_string(),
+            message: "Exposure must be a positive non-zero duration".to_string(),
+        });
+    }
+
+    Ok(())
+}
+
 impl TryFrom<&Path> for HDRInput {
     type Error = Error;
 
     fn try_from(value: &Path) -> Result<Self, Self::Error> {
-        let data = std::fs::read(value)?;
-        let format = image::ImageFormat::from_path(value).ok();
-        let image = read_image(&data, format)?;
-        let exif = get_exif_data(&data)?;
-        let exposure = get_exposures(&exif)?;
-        let gain = get_gains(&exif)?;
-
-        Self::with_image(&image, Duration::from_secs_f32(exposure), gain)
+        Self::with_overrides(value, &ExposureOverrides::default())
     }
 }
 
@@ -147,6 +489,32 @@ impl TryFrom<&Path> for HDRInput {
 pub struct HDRInputList(Vec<HDRInput>);
 
 impl HDRInputList {
+    /// Read a list of inputs with per input overrides of their exposure parameters, see
+    /// [`HDRInput::with_overrides`].
+    ///
+    /// # Errors
+    /// - If the number of overrides doesn't match the number of paths
+    /// - If any of the inputs cannot be read
+    pub fn with_overrides<P: AsRef<Path> + Sync>(
+        paths: &[P],
+        overrides: &[ExposureOverrides],
+    ) -> Result<Self, Error> {
+        if overrides.len() != paths.len() {
+            return Err(Error::InputError {
+                parameter_name: "overrides".to_string(),
+                message: "Exactly one set of overrides must be given per path".to_string(),
+            });
+        }
+
+        Ok(HDRInputList(
+            paths
+                .par_iter()
+                .zip(overrides)
+                .map(|(path, overrides)| HDRInput::with_overrides(path.as_ref(), overrides))
+                .collect::<Result<Vec<HDRInput>, Error>>()?,
+        ))
+    }
+
     /// Get list of [`HDRInput`] as a vec.
     #[must_use]
     pub fn into_vec(self) -> Vec<HDRInput> {
@@ -198,3 +566,99 @@ impl<P: AsRef<Path> + Sync> TryFrom<&[P]> for HDRInputList {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::dng::{DngMetadata, DngWriter};
+    use exif::{Rational, Tag, Value};
+    use image::{ImageBuffer, Luma};
+
+    /// EXIF data of a DNG with exposure time 1/50 s, ISO 400 and aperture f/2.8.
+    fn exif() -> Exif {
+        let metadata = DngMetadata {
+            exif: vec![
+                (
+                    Tag::ExposureTime,
+                    Value::Rational(vec![Rational::from((1, 50))]),
+                ),
+                (Tag::PhotographicSensitivity, Value::Short(vec![400])),
+                (
+                    Tag::FNumber,
+                    Value::Rational(vec![Rational::from((28, 10))]),
+                ),
+            ],
+            ..DngMetadata::default()
+        };
+
+        let mut file = Vec::new();
+        DngWriter::default()
+            .write_to(&Array3::from_elem((2, 2, 1), 0.5), &metadata, &mut file)
+            .expect("DNG should be written");
+
+        get_exif_data(&file).expect("DNG should contain EXIF data")
+    }
+
+    #[test]
+    fn resolves_overrides_before_exif() {
+        let overrides = ExposureOverrides {
+            gain: Some(100.),
+            nd_stops: Some(3.),
+            ..ExposureOverrides::default()
+        };
+
+        let resolved = overrides.resolve(Some(&exif()));
+        assert_eq!(resolved.exposure, Some(Duration::from_millis(20)));
+        assert_eq!(resolved.gain, Some(100.));
+        assert!(resolved
+            .aperture
+            .is_some_and(|aperture| (aperture - 2.8).abs() < 1e-6));
+        assert_eq!(resolved.nd_stops, Some(3.));
+        assert_eq!(resolved.exposure_bias, None);
+
+        assert_eq!(overrides.resolve(None), overrides);
+        assert_eq!(
+            ExposureOverrides::default()
+                .resolve_required(Ok(exif()))
+                .expect("EXIF should be complete")
+                .gain,
+            Some(400.)
+        );
+        assert!(ExposureOverrides::default()
+            .resolve_required(get_exif_data(&[]))
+            .is_err());
+    }
+
+    #[test]
+    fn declared_transfer_function_takes_precedence() {
+        let path = std::env::temp_dir().join(format!(
+            "image-hdr-{}-declared-transfer-function.tif",
+            std::process::id()
+        ));
+        ImageBuffer::<Luma<u16>, Vec<u16>>::from_pixel(4, 4, Luma([u16::MAX / 4]))
+            .save(&path)
+            .expect("Image should be saved");
+
+        let overrides = ExposureOverrides {
+            exposure: Some(Duration::from_millis(10)),
+            gain: Some(100.),
+            ..ExposureOverrides::default()
+        };
+        let guessed = HDRInput::with_overrides(&path, &overrides);
+        let declared = HDRInput::with_overrides(
+            &path,
+            &ExposureOverrides {
+                transfer_function: Some(TransferFunction::Linear),
+                ..overrides
+            },
+        );
+        std::fs::remove_file(&path).expect("Image should be removed");
+
+        let encoded = f32::from(u16::MAX / 4) / f32::from(u16::MAX);
+        let guessed = guessed.expect("Image should be read").get_buffer()[[0, 0, 0]];
+        let declared = declared.expect("Image should be read").get_buffer()[[0, 0, 0]];
+
+        assert!((declared - encoded).abs() < 1e-6);
+        assert!(guessed < encoded / 2.);
+    }
+}
@@ -1,45 +1,85 @@
 //! Helper functions to read and decode images
 
+use crate::sensor::SensorMetadata;
+use crate::transfer::TransferFunction;
 use crate::Error;
-use image::DynamicImage;
+use image::{DynamicImage, ImageDecoder, ImageReader, ImageResult};
+use std::io::Cursor;
 
 /// Given a path to a file, attempt to read the image.
 /// The function supports reading raw images. All
 /// formats and cameras supported by rawloader crate
 /// [rawloader](https://github.com/pedrocr/rawloader) are supported.
 ///
+/// Along with the image, the transfer function its pixel values are encoded with is returned.
+/// It is read from the embedded ICC profile if there is one, and guessed from the pixel type
+/// otherwise. Raw images also return the metadata of the sensor they were captured with.
+///
 /// # Errors
 /// If image cannot be read
 pub(crate) fn read_image(
     data: &[u8],
     format: Option<image::ImageFormat>,
-) -> Result<DynamicImage, Error> {
-    let load_result = match format {
-        Some(format) => image::load_from_memory_with_format(data, format),
-        None => image::load_from_memory(data),
-    };
+) -> Result<(DynamicImage, TransferFunction, Option<SensorMetadata>), Error> {
+    let load_result = decode_image(data, format);
 
     match load_result {
-        Ok(image) => Ok(image),
+        Ok((image, icc_profile)) => {
+            let transfer_function = icc_profile
+                .and_then(|profile| TransferFunction::from_icc_profile(&profile).ok())
+                .unwrap_or_else(|| TransferFunction::guess(&image));
+
+            Ok((image, transfer_function, None))
+        }
         Err(_err) => {
             #[cfg(not(feature = "read-raw-image"))]
             return Err(_err.into());
             #[cfg(feature = "read-raw-image")]
-            Ok(read_raw_image(data)?)
+            {
+                let (image, sensor) = read_raw_image(data)?;
+                Ok((image, TransferFunction::Srgb, Some(sensor)))
+            }
         }
     }
 }
 
+/// Decode an image along with its embedded ICC profile, if any.
+fn decode_image(
+    data: &[u8],
+    format: Option<image::ImageFormat>,
+) -> ImageResult<(DynamicImage, Option<Vec<u8>>)> {
+    let reader = match format {
+        Some(format) => ImageReader::with_format(Cursor::new(data), format),
+        None => ImageReader::new(Cursor::new(data)).with_guessed_format()?,
+    };
+
+    let mut decoder = reader.into_decoder()?;
+    let icc_profile = decoder.icc_profile()?;
+    let image = DynamicImage::from_decoder(decoder)?;
+
+    Ok((image, icc_profile))
+}
+
 /// Given a path to a file, attempt to read the RAW image.
 /// All formats and cameras supported by rawloader crate
 /// [rawloader](https://github.com/pedrocr/rawloader) are supported.
+///
+/// The resulting image is encoded with the sRGB transfer function. It is returned along with the
+/// metadata of the sensor, without CFA pattern as the image is demosaiced. Black and white levels
+/// and white balance are already applied by the raw pipeline, so the metadata is informational
+/// and isn't applied again when merging; use [`crate::raw::read_cfa_input`] to merge with the
+/// sensor levels instead.
 #[cfg(feature = "read-raw-image")]
-pub(crate) fn read_raw_image(data: &[u8]) -> Result<DynamicImage, Error> {
+pub(crate) fn read_raw_image(data: &[u8]) -> Result<(DynamicImage, SensorMetadata), Error> {
     use crate::error::{RawPipelineError, UnknownError};
     use image::{ImageBuffer, Rgb};
     use imagepipe::{ImageSource, Pipeline};
 
     let raw = rawloader::decode(&mut std::io::Cursor::new(data))?;
+    let sensor = SensorMetadata {
+        cfa: None,
+        ..crate::raw::sensor_metadata(&raw)?
+    };
 
     let source = ImageSource::Raw(raw);
     let mut pipeline = Pipeline::new_from_source(source).map_err(RawPipelineError::from)?;
@@ -56,9 +96,51 @@ pub(crate) fn read_raw_image(data: &[u8]) -> Result<DynamicImage, Error> {
     );
 
     match image {
-        Some(image) => Ok(DynamicImage::ImageRgb16(image)),
+        Some(image) => Ok((DynamicImage::ImageRgb16(image), sensor)),
         None => Err(Error::RawPipeline(RawPipelineError::from(
             "Failed to load pipeline output".to_string(),
         ))),
     }
 }
+
+#[cfg(all(test, feature = "read-raw-image"))]
+mod tests {
+    use super::*;
+    use crate::export::dng::{DngMetadata, DngSampleType, DngWriter};
+    use crate::sensor::XYZ_TO_SRGB;
+    use ndarray::Array3;
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn reads_linear_raw_dng_without_cfa() {
+        let buffer = Array3::from_shape_fn((16, 16, 3), |(y, x, channel)| {
+            (y * 16 + x + channel) as f32 / 300. + 0.05
+        });
+        let metadata = DngMetadata {
+            sensor: Some(SensorMetadata {
+                make: "Make".to_string(),
+                model: "Model".to_string(),
+                cfa: None,
+                black_levels: [0.; 4],
+                white_levels: [1.; 4],
+                white_balance: [2., 1., 1.5, 0.],
+                xyz_to_camera: [XYZ_TO_SRGB[0], XYZ_TO_SRGB[1], XYZ_TO_SRGB[2], [0.; 3]],
+            }),
+            ..DngMetadata::default()
+        };
+        let writer = DngWriter {
+            sample_type: DngSampleType::Integer,
+        };
+
+        let mut file = Vec::new();
+        writer
+            .write_to(&buffer, &metadata, &mut file)
+            .expect("DNG should be written");
+
+        let (image, transfer_function, sensor) =
+            read_image(&file, None).expect("LinearRaw DNG should be read");
+        assert_eq!((image.width(), image.height()), (16, 16));
+        assert_eq!(transfer_function, TransferFunction::Srgb);
+        assert!(sensor.is_some_and(|sensor| sensor.cfa.is_none()));
+    }
+}
@@ -1,16 +1,31 @@
 //! An implementation of HDR Radiance Estimation using Poisson Photon Noise Estimator for creating HDR image from a set of images
-#![allow(clippy::multiple_crate_versions)]
 
 use image::DynamicImage;
-use poisson::calculate_poisson_estimate;
+use merge::poisson::{NoiseModel, PoissonEstimate};
+use merge::{MergeStrategy, PoissonEstimator};
 
+pub mod align;
+pub mod bracket;
+pub mod calibration;
+pub mod deghost;
 pub mod error;
 pub mod exif;
+pub mod export;
+pub mod exposure;
 pub mod extensions;
+pub mod fusion;
 pub mod input;
 mod io;
-mod poisson;
+mod linalg;
+pub mod merge;
+#[cfg(feature = "read-raw-image")]
+pub mod raw;
+pub mod response;
+pub mod sensor;
 pub mod stretch;
+pub mod tiled;
+pub mod tonemap;
+pub mod transfer;
 
 use crate::extensions::NDArrayBuffer;
 use crate::input::HDRInputList;
@@ -24,14 +39,53 @@ pub use error::Error;
 /// - If supplied image is not an RGB image. Non RGB images include images with alpha channel, grayscale images, and images with other color encodings (like CMYK).
 /// - If images are of different dimensions.
 pub fn hdr_merge_images(inputs: &mut HDRInputList) -> Result<DynamicImage, Error> {
-    if inputs.len() < 2 {
-        return Err(Error::InputError {
-            parameter_name: "paths".to_string(),
-            message: "At least two images must be provided".to_string(),
-        });
-    }
+    hdr_merge_images_with_strategy(inputs, &PoissonEstimator::default())
+}
+
+/// Given a set of file paths, attempt to HDR merge the images using the supplied
+/// [`MergeStrategy`] and produce a single [`DynamicImage`] (from image-rs crate).
+///
+/// # Errors
+/// - If image list is empty
+/// - If the strategy is configured with invalid parameters
+/// - If supplied image is not an RGB image. Non RGB images include images with alpha channel, grayscale images, and images with other color encodings (like CMYK).
+/// - If images are of different dimensions.
+pub fn hdr_merge_images_with_strategy(
+    inputs: &mut HDRInputList,
+    strategy: &dyn MergeStrategy,
+) -> Result<DynamicImage, Error> {
+    let radiance = strategy.merge(inputs.as_slice_mut())?;
 
-    let phi = calculate_poisson_estimate(inputs.as_slice_mut());
+    Ok(DynamicImage::from_nd_array_buffer(radiance))
+}
+
+/// Given a set of file paths, attempt to HDR merge the images using the supplied camera
+/// [`NoiseModel`] and produce a single [`DynamicImage`] (from image-rs crate).
+///
+/// # Errors
+/// - If image list is empty
+/// - If noise model parameters are invalid
+/// - If supplied image is not an RGB image. Non RGB images include images with alpha channel, grayscale images, and images with other color encodings (like CMYK).
+/// - If images are of different dimensions.
+pub fn hdr_merge_images_with_noise_model(
+    inputs: &mut HDRInputList,
+    noise_model: &NoiseModel,
+) -> Result<DynamicImage, Error> {
+    hdr_merge_images_with_strategy(inputs, &PoissonEstimator::new(*noise_model))
+}
 
-    Ok(DynamicImage::from_nd_array_buffer(phi))
+/// Given a set of file paths, attempt to HDR merge the images using the supplied camera
+/// [`NoiseModel`] and produce the merged radiance along with the per-pixel variance of the
+/// estimate and the number of exposures that contributed to each pixel.
+///
+/// # Errors
+/// - If image list is empty
+/// - If noise model parameters are invalid
+/// - If supplied image is not an RGB image. Non RGB images include images with alpha channel, grayscale images, and images with other color encodings (like CMYK).
+/// - If images are of different dimensions.
+pub fn hdr_merge_images_with_uncertainty(
+    inputs: &mut HDRInputList,
+    noise_model: &NoiseModel,
+) -> Result<PoissonEstimate, Error> {
+    PoissonEstimator::new(*noise_model).estimate(inputs.as_slice())
 }
@@ -0,0 +1,88 @@
+//! Small dense linear algebra helpers used by the estimation routines of the library.
+
+/// Normal equations `AᵀA x = Aᵀb` of a sparse linear least squares problem, accumulated row by
+/// row.
+pub(crate) struct NormalEquations {
+    size: usize,
+    lhs: Vec<f64>,
+    rhs: Vec<f64>,
+}
+
+impl NormalEquations {
+    pub(crate) fn new(size: usize) -> Self {
+        Self {
+            size,
+            lhs: vec![0.; size * size],
+            rhs: vec![0.; size],
+        }
+    }
+
+    /// Add a row of the system with given non-zero coefficients and right hand side value.
+    pub(crate) fn add_row(&mut self, coefficients: &[(usize, f64)], value: f64) {
+        for &(row, a) in coefficients {
+            for &(column, b) in coefficients {
+                self.lhs[row * self.size + column] += a * b;
+            }
+
+            self.rhs[row] += a * value;
+        }
+    }
+
+    /// Add the equations accumulated in another system of the same size.
+    pub(crate) fn combine(mut self, other: &Self) -> Self {
+        for (value, other) in self.lhs.iter_mut().zip(&other.lhs) {
+            *value += other;
+        }
+        for (value, other) in self.rhs.iter_mut().zip(&other.rhs) {
+            *value += other;
+        }
+
+        self
+    }
+
+    /// Solve the system using Gaussian elimination with partial pivoting.
+    pub(crate) fn solve(mut self) -> Option<Vec<f64>> {
+        let size = self.size;
+
+        for pivot in 0..size {
+            let best = (pivot..size).max_by(|&a, &b| {
+                self.lhs[a * size + pivot]
+                    .abs()
+                    .total_cmp(&self.lhs[b * size + pivot].abs())
+            })?;
+
+            if self.lhs[best * size + pivot].abs() < 1e-12 {
+                return None;
+            }
+
+            if best != pivot {
+                for column in 0..size {
+                    self.lhs.swap(pivot * size + column, best * size + column);
+                }
+                self.rhs.swap(pivot, best);
+            }
+
+            for row in pivot + 1..size {
+                let factor = self.lhs[row * size + pivot] / self.lhs[pivot * size + pivot];
+
+                if factor != 0. {
+                    for column in pivot..size {
+                        self.lhs[row * size + column] -= factor * self.lhs[pivot * size + column];
+                    }
+                    self.rhs[row] -= factor * self.rhs[pivot];
+                }
+            }
+        }
+
+        let mut solution = vec![0.; size];
+        for row in (0..size).rev() {
+            let sum = (row + 1..size)
+                .map(|column| self.lhs[row * size + column] * solution[column])
+                .sum::<f64>();
+
+            solution[row] = (self.rhs[row] - sum) / self.lhs[row * size + row];
+        }
+
+        Some(solution)
+    }
+}
@@ -0,0 +1,208 @@
+//! Strategies for merging a set of exposures into a single linear radiance buffer.
+
+use crate::input::HDRInput;
+use crate::sensor::SensorMetadata;
+use crate::Error;
+use ndarray::Array3;
+use std::borrow::Cow;
+
+pub mod debevec;
+pub mod poisson;
+pub mod robertson;
+
+pub use debevec::Debevec;
+pub use poisson::PoissonEstimator;
+pub use robertson::Robertson;
+
+/// Pixel values at or above this level are considered clipped and are excluded from the merge.
+pub(crate) const SATURATION_THRESHOLD: f32 = 0.98;
+
+/// Pixel values at or below this level (above the black level) are considered to be buried in the
+/// noise floor and are excluded from the merge.
+pub(crate) const BLACK_THRESHOLD: f32 = 0.002;
+
+/// An algorithm that merges a set of exposures of the same scene into a single radiance buffer.
+pub trait MergeStrategy {
+    /// Merge the supplied exposures and return the estimated radiance of every pixel and channel,
+    /// in the same layout as the buffers of the inputs.
+    ///
+    /// # Errors
+    /// - If fewer than two inputs are provided
+    /// - If inputs are of different dimensions or are neither RGB nor grayscale
+    /// - If the strategy is configured with invalid parameters
+    fn merge(&self, inputs: &mut [HDRInput]) -> Result<Array3<f32>, Error>;
+}
+
+/// Get a sample of an input in the normalized `0..=1` pixel value range, i.e. with the black
+/// level of its colour subtracted and scaled so that it clips at 1, using the sensor metadata of
+/// inputs that carry CFA data. Samples of other inputs were normalized when read and are returned
+/// as is.
+pub(crate) fn sample(input: &HDRInput, index: (usize, usize, usize)) -> f32 {
+    let value = input.get_buffer()[index];
+
+    match input.get_sensor_metadata() {
+        Some(sensor @ SensorMetadata { cfa: Some(cfa), .. }) => {
+            sensor.normalize(cfa.color_at(index.0, index.1), value)
+        }
+        _ => value,
+    }
+}
+
+/// Validate that a set of inputs can be merged, returning the common shape of their buffers.
+///
+/// # Errors
+/// - If fewer than two inputs are provided
+/// - If inputs are of different dimensions or are neither RGB nor grayscale
+/// - If the aperture is unknown for some inputs and differs between the others
+pub(crate) fn validate_inputs(inputs: &[HDRInput]) -> Result<(usize, usize, usize), Error> {
+    if inputs.len() < 2 {
+        return Err(Error::InputError {
+            parameter_name: "paths".to_string(),
+            message: "At least two images must be provided".to_string(),
+        });
+    }
+
+    let shape = inputs[0].get_buffer().dim();
+
+    if !matches!(shape.2, 1 | 3) {
+        return Err(Error::InputError {
+            parameter_name: "inputs".to_string(),
+            message: "Images must either be RGB or grayscale".to_string(),
+        });
+    }
+
+    if inputs.iter().any(|input| input.get_buffer().dim() != shape) {
+        return Err(Error::InputError {
+            parameter_name: "inputs".to_string(),
+            message: "All images must have the same dimensions".to_string(),
+        });
+    }
+
+    let mut apertures = inputs.iter().filter_map(HDRInput::get_aperture);
+
+    if has_partial_aperture(inputs)
+        && apertures
+            .next()
+            .is_some_and(|first| apertures.any(|aperture| aperture.total_cmp(&first).is_ne()))
+    {
+        return Err(Error::InputError {
+            parameter_name: "aperture".to_string(),
+            message: "Aperture differs between the images but is unknown for some of them, \
+                      it must be set for all images"
+                .to_string(),
+        });
+    }
+
+    Ok(shape)
+}
+
+/// Whether the aperture is known for some but not all of the inputs.
+pub(crate) fn has_partial_aperture(inputs: &[HDRInput]) -> bool {
+    let known = inputs
+        .iter()
+        .filter(|input| input.get_aperture().is_some())
+        .count();
+
+    known > 0 && known < inputs.len()
+}
+
+/// Get the inputs with the aperture term dropped from the exposure factors of the whole bracket
+/// if the aperture is known for only some of them. The known apertures are equal then, see
+/// [`validate_inputs`], so the term doesn't change the relative exposures. Inputs are only
+/// copied in that case.
+pub(crate) fn without_partial_aperture(inputs: &[HDRInput]) -> Cow<'_, [HDRInput]> {
+    if !has_partial_aperture(inputs) {
+        return Cow::Borrowed(inputs);
+    }
+
+    Cow::Owned(
+        inputs
+            .iter()
+            .map(|input| {
+                let mut input = input.clone();
+                input.clear_aperture();
+                input
+            })
+            .collect(),
+    )
+}
+
+/// Get the inputs with the shortest and the longest exposure.
+pub(crate) fn exposure_extremes(inputs: &[HDRInput]) -> (&HDRInput, &HDRInput) {
+    let shortest = inputs
+        .iter()
+        .min_by(|a, b| a.get_exposure_factor().total_cmp(&b.get_exposure_factor()))
+        .unwrap_or_else(|| panic!("Expected at least 1 input image"));
+    let longest = inputs
+        .iter()
+        .max_by(|a, b| a.get_exposure_factor().total_cmp(&b.get_exposure_factor()))
+        .unwrap_or_else(|| panic!("Expected at least 1 input image"));
+
+    (shortest, longest)
+}
+
+/// Radiance of a pixel for which no exposure was usable. The shortest exposure is used if it is
+/// clipped and the longest exposure otherwise. Returns the chosen input along with its
+/// normalized (unscaled) pixel value, see [`sample`].
+pub(crate) fn fallback_sample<'a>(
+    shortest: &'a HDRInput,
+    longest: &'a HDRInput,
+    index: (usize, usize, usize),
+) -> (&'a HDRInput, f32) {
+    let short = sample(shortest, index);
+
+    if short >= SATURATION_THRESHOLD {
+        (shortest, short)
+    } else {
+        (longest, sample(longest, index).max(0.))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[allow(clippy::cast_precision_loss)]
+    fn bracket(apertures: &[Option<f32>]) -> Vec<HDRInput> {
+        apertures
+            .iter()
+            .enumerate()
+            .map(|(index, &aperture)| {
+                let exposure = 0.01 * 4_f32.powf(index as f32);
+                let buffer = Array3::from_elem((4, 4, 3), (5. * exposure).min(1.));
+                let mut input =
+                    HDRInput::with_buffer(buffer, Duration::from_secs_f32(exposure), 1.)
+                        .expect("Synthetic exposure should be a valid input");
+                input
+                    .set_aperture(aperture)
+                    .expect("Aperture should be valid");
+                input
+            })
+            .collect()
+    }
+
+    #[test]
+    fn drops_aperture_known_for_some_inputs() {
+        let mut partial = bracket(&[Some(8.), None, Some(8.)]);
+        let mut unknown = bracket(&[None, None, None]);
+
+        let merged = PoissonEstimator::default()
+            .merge(&mut partial)
+            .expect("Equal known apertures should be accepted");
+        let expected = PoissonEstimator::default()
+            .merge(&mut unknown)
+            .expect("Unknown apertures should be accepted");
+
+        assert_eq!(merged, expected);
+        assert_eq!(partial[0].get_aperture(), Some(8.));
+    }
+
+    #[test]
+    fn rejects_differing_apertures_unknown_for_some_inputs() {
+        let result = validate_inputs(&bracket(&[Some(4.), None, Some(8.)]));
+        assert!(matches!(result, Err(Error::InputError { .. })));
+
+        assert!(validate_inputs(&bracket(&[Some(4.), Some(5.6), Some(8.)])).is_ok());
+    }
+}
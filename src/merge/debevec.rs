@@ -0,0 +1,102 @@
+//! An implementation of HDR merging as described by Debevec and Malik in
+//! [Recovering High Dynamic Range Radiance Maps from Photographs](https://www.pauldebevec.com/Research/HDR/debevec-siggraph97.pdf),
+//! assuming linear camera response.
+
+use crate::input::HDRInput;
+use crate::merge::{
+    exposure_extremes, fallback_sample, sample, validate_inputs, without_partial_aperture,
+    MergeStrategy, BLACK_THRESHOLD, SATURATION_THRESHOLD,
+};
+use crate::Error;
+use ndarray::{Array3, Zip};
+
+/// Triangular ("hat") weighting function favouring mid-tones, returning zero for clipped and
+/// crushed pixel values.
+fn hat_weight(value: f32) -> f32 {
+    if value > BLACK_THRESHOLD && value < SATURATION_THRESHOLD {
+        value.min(1. - value)
+    } else {
+        0.
+    }
+}
+
+/// [`MergeStrategy`] that averages the log radiance of every exposure, weighted by a hat function
+/// of the pixel value.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Debevec;
+
+impl MergeStrategy for Debevec {
+    fn merge(&self, inputs: &mut [HDRInput]) -> Result<Array3<f32>, Error> {
+        let shape = validate_inputs(inputs)?;
+        let inputs = &*without_partial_aperture(inputs);
+        let (shortest, longest) = exposure_extremes(inputs);
+
+        let mut radiances = Array3::<f32>::zeros(shape);
+
+        Zip::indexed(&mut radiances).par_for_each(|index, radiance| {
+            let (log_radiance, weight) =
+                inputs
+                    .iter()
+                    .fold((0., 0.), |(log_radiance, weight_sum), input| {
+                        let value = sample(input, index);
+                        let weight = hat_weight(value);
+
+                        if weight > 0. {
+                            let scaling_factor = input.get_exposure_factor();
+
+                            (
+                                log_radiance + weight * (value.ln() - scaling_factor.ln()),
+                                weight_sum + weight,
+                            )
+                        } else {
+                            (log_radiance, weight_sum)
+                        }
+                    });
+
+            *radiance = if weight > 0. {
+                (log_radiance / weight).exp()
+            } else {
+                let (fallback, value) = fallback_sample(shortest, longest, index);
+
+                value / (fallback.get_exposure_factor())
+            };
+        });
+
+        Ok(radiances)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn input(value: f32, exposure: f32) -> HDRInput {
+        HDRInput::with_buffer(
+            Array3::from_elem((1, 1, 1), value),
+            Duration::from_secs_f32(exposure),
+            1.,
+        )
+        .expect("Synthetic exposure should be a valid input")
+    }
+
+    #[test]
+    fn averages_log_radiance_weighted_by_hat_function() {
+        // Radiance 10 from the short exposure and 20 from the long one
+        let mut inputs = vec![input(0.1, 0.01), input(0.8, 0.04)];
+
+        let radiance = Debevec.merge(&mut inputs).expect("Bracket should merge")[[0, 0, 0]];
+
+        let expected = ((0.1 * 10_f32.ln() + 0.2 * 20_f32.ln()) / 0.3).exp();
+        assert!((radiance - expected).abs() < 1e-4);
+    }
+
+    #[test]
+    fn falls_back_to_clipped_shortest_exposure() {
+        let mut inputs = vec![input(1., 0.01), input(1., 0.04)];
+
+        let radiance = Debevec.merge(&mut inputs).expect("Bracket should merge")[[0, 0, 0]];
+
+        assert!((radiance - 100.).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,532 @@
+//! An implementation of HDR merging via "Poisson Photon Noise Estimator" as introduced in
+//! [Noise-Aware Merging of High Dynamic Range Image Stacks without Camera Calibration](https://www.cl.cam.ac.uk/research/rainbow/projects/noise-aware-merging/2020-ppne-mle.pdf)
+
+use crate::deghost::{Deghosting, MotionMask};
+use crate::input::HDRInput;
+use crate::merge::{
+    exposure_extremes, fallback_sample, sample, validate_inputs, without_partial_aperture,
+    MergeStrategy, BLACK_THRESHOLD, SATURATION_THRESHOLD,
+};
+use crate::Error;
+use ndarray::prelude::*;
+use ndarray::Zip;
+
+/// Camera noise model used to predict the variance of every pixel of every exposure.
+///
+/// The variance of a black level subtracted pixel value `y`, captured with gain `g`, is modelled
+/// as `adc_gain * g * y + (g * read_noise)^2 + adc_noise^2`, i.e. photon shot noise plus read
+/// noise before and after the gain amplifier. All values are expressed in the normalized `0..=1`
+/// pixel value range.
+///
+/// The default model only contains shot noise, in which case the estimator reduces to the
+/// exposure-weighted Poisson Photon Noise Estimator.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NoiseModel {
+    /// Standard deviation of the read noise before the gain amplifier.
+    pub read_noise: f32,
+    /// Standard deviation of the read noise introduced after the gain amplifier (by the ADC).
+    pub adc_noise: f32,
+    /// Conversion factor between collected photo-electrons and pixel values at unit gain.
+    pub adc_gain: f32,
+    /// Pixel value corresponding to no light, subtracted from every pixel before merging. For
+    /// inputs that carry CFA data, this is in addition to the black level of the sensor.
+    pub black_level: f32,
+}
+
+impl Default for NoiseModel {
+    fn default() -> Self {
+        Self {
+            read_noise: 0.,
+            adc_noise: 0.,
+            adc_gain: 1. / 4096.,
+            black_level: 0.,
+        }
+    }
+}
+
+impl NoiseModel {
+    /// Validate that the parameters of the noise model are usable.
+    ///
+    /// # Errors
+    ///
+    /// - If any of the parameters is negative, infinite or NaN
+    /// - If `adc_gain` is zero
+    pub fn validate(&self) -> Result<(), Error> {
+        let parameters = [
+            ("read_noise", self.read_noise),
+            ("adc_noise", self.adc_noise),
+            ("adc_gain", self.adc_gain),
+            ("black_level", self.black_level),
+        ];
+
+        for (parameter_name, value) in parameters {
+            if !value.is_finite() || value < 0. {
+                return Err(Error::InputError {
+                    parameter_name: parameter_name.to_string(),
+                    message: "Must be a finite non-negative floating point number".to_string(),
+                });
+            }
+        }
+
+        if self.adc_gain == 0. {
+            return Err(Error::InputError {
+                parameter_name: "adc_gain".to_string(),
+                message: "ADC gain must be non-zero".to_string(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Subtract black level from a pixel value, returning `None` if the value is clipped or is
+    /// too close to the black level to carry any information.
+    pub(crate) fn signal(&self, value: f32) -> Option<f32> {
+        let signal = value - self.black_level;
+
+        (value < SATURATION_THRESHOLD && signal > BLACK_THRESHOLD).then_some(signal)
+    }
+
+    /// Predicted variance of a black level subtracted pixel value captured with given gain.
+    pub(crate) fn variance(&self, signal: f32, gain: f32) -> f32 {
+        self.adc_gain * gain * signal + (gain * self.read_noise).powi(2) + self.adc_noise.powi(2)
+    }
+}
+
+/// Returns whether a sample of an input is usable, i.e. the input has a signal at that pixel and
+/// the sample was not rejected as motion.
+fn is_usable(
+    noise_model: &NoiseModel,
+    motion: Option<&MotionMask>,
+    input_index: usize,
+    input: &HDRInput,
+    index: (usize, usize, usize),
+) -> Option<f32> {
+    if motion.is_some_and(|motion| motion.is_rejected(input_index, index.0, index.1)) {
+        return None;
+    }
+
+    noise_model.signal(sample(input, index))
+}
+
+/// Single pass merge of a set of exposures, computing every pixel from the samples of all inputs
+/// at once. Inputs are only read, and weighted sums are accumulated per pixel rather than into
+/// intermediate buffers, so that the only allocations are the outputs.
+struct FusedMerge<'a> {
+    inputs: &'a [HDRInput],
+    noise_model: &'a NoiseModel,
+    motion: Option<&'a MotionMask>,
+    shortest: &'a HDRInput,
+    longest: &'a HDRInput,
+}
+
+impl<'a> FusedMerge<'a> {
+    fn new(
+        inputs: &'a [HDRInput],
+        noise_model: &'a NoiseModel,
+        motion: Option<&'a MotionMask>,
+    ) -> Self {
+        let (shortest, longest) = exposure_extremes(inputs);
+
+        Self {
+            inputs,
+            noise_model,
+            motion,
+            shortest,
+            longest,
+        }
+    }
+
+    /// Weighted sum of the radiance estimates of all inputs at a pixel along with the sum of
+    /// weights. `weight` is called with every input that has a usable sample at the pixel.
+    /// Samples rejected as motion are skipped.
+    fn accumulate<F>(&self, index: (usize, usize, usize), weight: F) -> (f32, f32)
+    where
+        F: Fn(&HDRInput) -> f32,
+    {
+        self.inputs.iter().enumerate().fold(
+            (0., 0.),
+            |(radiance_sum, weight_sum), (input_index, input)| match is_usable(
+                self.noise_model,
+                self.motion,
+                input_index,
+                input,
+                index,
+            ) {
+                Some(signal) => {
+                    let weight = weight(input);
+
+                    (
+                        radiance_sum + weight * signal / input.get_exposure_factor(),
+                        weight_sum + weight,
+                    )
+                }
+                None => (radiance_sum, weight_sum),
+            },
+        )
+    }
+
+    /// Radiance and variance of a pixel where no exposure was usable, taken from the shortest
+    /// exposure if it is clipped, or from the longest one otherwise.
+    fn fallback(&self, index: (usize, usize, usize)) -> (f32, f32) {
+        let (fallback, value) = fallback_sample(self.shortest, self.longest, index);
+
+        let gain = fallback.get_gain();
+        let scaling_factor = fallback.get_exposure_factor();
+        let signal = (value - self.noise_model.black_level).max(0.);
+
+        (
+            signal / scaling_factor,
+            self.noise_model.variance(signal, gain) / scaling_factor.powi(2),
+        )
+    }
+
+    /// Estimate the radiance of a pixel along with its variance, assuming the weights of the
+    /// second pass are inverse variances.
+    fn estimate(&self, index: (usize, usize, usize)) -> (f32, f32) {
+        let (radiance_sum, weight_sum) = self.accumulate(index, |input| {
+            input.get_exposure_factor() / input.get_gain()
+        });
+        if weight_sum <= 0. {
+            return self.fallback(index);
+        }
+        let initial_estimate = radiance_sum / weight_sum;
+
+        let (radiance_sum, weight_sum) = self.accumulate(index, |input| {
+            let scaling_factor = input.get_exposure_factor();
+            let predicted_signal = initial_estimate * scaling_factor;
+
+            scaling_factor.powi(2)
+                / self
+                    .noise_model
+                    .variance(predicted_signal, input.get_gain())
+        });
+
+        if weight_sum > 0. {
+            (radiance_sum / weight_sum, 1. / weight_sum)
+        } else {
+            self.fallback(index)
+        }
+    }
+
+    /// Count the number of exposures that have at least one usable channel at a pixel.
+    fn count(&self, (y, x): (usize, usize), channels: usize) -> u8 {
+        let contributions = self
+            .inputs
+            .iter()
+            .enumerate()
+            .filter(|(input_index, input)| {
+                (0..channels).any(|channel| {
+                    is_usable(
+                        self.noise_model,
+                        self.motion,
+                        *input_index,
+                        input,
+                        (y, x, channel),
+                    )
+                    .is_some()
+                })
+            })
+            .count();
+
+        u8::try_from(contributions).unwrap_or(u8::MAX)
+    }
+
+    /// Merge into preallocated buffers of the shape of the inputs. The variance of every pixel
+    /// and channel and the number of exposures contributing to every pixel are only computed if
+    /// buffers for them are given.
+    fn merge_into(
+        &self,
+        radiance: &mut Array3<f32>,
+        uncertainty: Option<(&mut Array3<f32>, &mut Array2<u8>)>,
+    ) {
+        let channels = radiance.dim().2;
+
+        match uncertainty {
+            Some((variance, count)) => Zip::indexed(radiance.lanes_mut(Axis(2)))
+                .and(variance.lanes_mut(Axis(2)))
+                .and(count)
+                .par_for_each(|(y, x), radiance, variance, count| {
+                    for (channel, (radiance, variance)) in
+                        radiance.into_iter().zip(variance).enumerate()
+                    {
+                        (*radiance, *variance) = self.estimate((y, x, channel));
+                    }
+
+                    *count = self.count((y, x), channels);
+                }),
+            None => Zip::indexed(radiance).par_for_each(|index, radiance| {
+                *radiance = self.estimate(index).0;
+            }),
+        }
+    }
+}
+
+/// Result of the Poisson Photon Noise Estimator along with the uncertainty of the estimate.
+#[derive(Clone, Debug)]
+pub struct PoissonEstimate {
+    /// Estimated radiance of every pixel and channel.
+    pub radiance: Array3<f32>,
+    /// Estimated variance of the radiance of every pixel and channel. For pixels where no exposure
+    /// was usable, this is the variance of the single exposure the radiance was taken from, which
+    /// underestimates the error of clipped pixels.
+    pub variance: Array3<f32>,
+    /// Number of exposures that contributed to the estimate of every pixel, i.e. exposures that
+    /// are neither clipped nor crushed in at least one channel, and were not rejected as motion.
+    pub count: Array2<u8>,
+    /// Samples that were rejected as motion, if deghosting was enabled.
+    pub motion: Option<MotionMask>,
+}
+
+impl PoissonEstimate {
+    /// Get the standard deviation of the radiance of every pixel and channel.
+    #[must_use]
+    pub fn standard_deviation(&self) -> Array3<f32> {
+        self.variance.mapv(f32::sqrt)
+    }
+}
+
+/// Calculate the poisson estimate for an image.
+/// Given a set of image paths, this returns a
+/// pixel buffer of the resultant HDR merge of
+/// supplied images.
+///
+/// The estimate of every pixel is computed in two steps. The first step computes the
+/// exposure-weighted Poisson Photon Noise Estimator, which is then used to predict the noise-free
+/// value of the pixel in every exposure. The second step weights every exposure by the inverse of
+/// its variance as predicted by the [`NoiseModel`], so that noisier (e.g. high gain) exposures
+/// contribute less. Clipped highlights and crushed shadows are left out of both steps. The
+/// variance of the resulting estimate is the inverse of the sum of the weights of the second
+/// step. Samples marked as motion are left out of both steps. Both steps run within a single
+/// pass over the image, without copying the inputs.
+///
+/// For more details on the algorithm used, please
+/// refer to [Noise-Aware Merging of High Dynamic Range Image Stacks without Camera Calibration](https://www.cl.cam.ac.uk/research/rainbow/projects/noise-aware-merging/2020-ppne-mle.pdf)
+///
+/// specifically the section about "Poisson Photon Noise Estimator"
+///
+/// # Panics
+/// If supplied image is neither an RGB nor a grayscale image, or if no images are supplied.
+pub(crate) fn calculate_poisson_estimate(
+    inputs: &[HDRInput],
+    noise_model: &NoiseModel,
+    motion: Option<MotionMask>,
+) -> PoissonEstimate {
+    let shape = inputs
+        .first()
+        .unwrap_or_else(|| panic!("Expected at least 1 input image"))
+        .get_buffer()
+        .dim();
+
+    let mut radiance = Array3::<f32>::zeros(shape);
+    let mut variance = Array3::<f32>::zeros(shape);
+    let mut count = Array2::<u8>::zeros((shape.0, shape.1));
+
+    FusedMerge::new(inputs, noise_model, motion.as_ref())
+        .merge_into(&mut radiance, Some((&mut variance, &mut count)));
+
+    PoissonEstimate {
+        radiance,
+        variance,
+        count,
+        motion,
+    }
+}
+
+/// [`MergeStrategy`] implementing the noise-aware Poisson Photon Noise Estimator.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PoissonEstimator {
+    /// Camera noise model used to weight the exposures.
+    pub noise_model: NoiseModel,
+    /// Ghost detection to run before merging. Samples detected as motion are left out of the
+    /// merge.
+    pub deghosting: Option<Deghosting>,
+}
+
+impl PoissonEstimator {
+    /// Create a new [`PoissonEstimator`] with the given camera noise model.
+    #[must_use]
+    pub fn new(noise_model: NoiseModel) -> Self {
+        Self {
+            noise_model,
+            deghosting: None,
+        }
+    }
+
+    /// Enable ghost detection with the given parameters.
+    #[must_use]
+    pub fn with_deghosting(mut self, deghosting: Deghosting) -> Self {
+        self.deghosting = Some(deghosting);
+        self
+    }
+
+    /// Merge the supplied exposures and return the estimated radiance along with its per-pixel
+    /// variance, the number of exposures that contributed to each pixel and the detected motion.
+    ///
+    /// # Errors
+    /// - If fewer than two inputs are provided
+    /// - If inputs are of different dimensions or are neither RGB nor grayscale
+    /// - If noise model or deghosting parameters are invalid
+    pub fn estimate(&self, inputs: &[HDRInput]) -> Result<PoissonEstimate, Error> {
+        let inputs = &*without_partial_aperture(inputs);
+        let motion = self.detect_motion(inputs)?;
+
+        Ok(calculate_poisson_estimate(
+            inputs,
+            &self.noise_model,
+            motion,
+        ))
+    }
+
+    /// Validate the parameters and inputs, and detect motion if deghosting is enabled.
+    fn detect_motion(&self, inputs: &[HDRInput]) -> Result<Option<MotionMask>, Error> {
+        self.noise_model.validate()?;
+        validate_inputs(inputs)?;
+
+        self.deghosting
+            .map(|deghosting| deghosting.detect(inputs, &self.noise_model))
+            .transpose()
+    }
+}
+
+impl MergeStrategy for PoissonEstimator {
+    /// Merge the supplied exposures, skipping the variance and contribution counts computed by
+    /// [`PoissonEstimator::estimate`].
+    fn merge(&self, inputs: &mut [HDRInput]) -> Result<Array3<f32>, Error> {
+        let shape = validate_inputs(inputs)?;
+        let inputs = &*without_partial_aperture(inputs);
+        let motion = self.detect_motion(inputs)?;
+
+        let mut radiance = Array3::<f32>::zeros(shape);
+        FusedMerge::new(inputs, &self.noise_model, motion.as_ref()).merge_into(&mut radiance, None);
+
+        Ok(radiance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Grayscale input of a single row with the given pixel values.
+    fn input(values: &[f32], exposure: f32, gain: f32) -> HDRInput {
+        let buffer = Array3::from_shape_vec((1, values.len(), 1), values.to_vec())
+            .expect("Values should form a row");
+
+        HDRInput::with_buffer(buffer, Duration::from_secs_f32(exposure), gain)
+            .expect("Synthetic exposure should be a valid input")
+    }
+
+    /// Inputs exposing radiances for the given exposure times at unit gain, clipped at 1.
+    fn bracket(radiances: &[f32], exposures: &[f32]) -> Vec<HDRInput> {
+        exposures
+            .iter()
+            .map(|&exposure| {
+                let values = radiances
+                    .iter()
+                    .map(|radiance| (radiance * exposure).min(1.))
+                    .collect::<Vec<f32>>();
+
+                input(&values, exposure, 1.)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn excludes_clipped_and_crushed_samples() {
+        let radiances = [0.1, 20., 30., 200.];
+        let mut inputs = bracket(&radiances, &[0.01, 0.04]);
+
+        let radiance = PoissonEstimator::default()
+            .merge(&mut inputs)
+            .expect("Bracket should merge");
+
+        // Crushed in the short exposure, clipped in the long one, both clipped
+        assert!((radiance[[0, 0, 0]] - 0.1).abs() < 1e-6);
+        assert!((radiance[[0, 1, 0]] - 20.).abs() < 1e-4);
+        assert!((radiance[[0, 2, 0]] - 30.).abs() < 1e-4);
+        assert!((radiance[[0, 3, 0]] - 100.).abs() < 1e-4);
+    }
+
+    #[test]
+    fn weights_exposures_by_inverse_variance() {
+        let noise_model = NoiseModel {
+            read_noise: 0.01,
+            adc_noise: 0.002,
+            ..NoiseModel::default()
+        };
+        // Equal exposure factors, the second exposure amplified four times as much
+        let mut inputs = vec![input(&[0.4], 0.04, 1.), input(&[0.44], 0.01, 4.)];
+
+        let radiance = PoissonEstimator::new(noise_model)
+            .merge(&mut inputs)
+            .expect("Bracket should merge")[[0, 0, 0]];
+
+        // The first pass weights by exposure factor over gain, the second by inverse variance
+        let initial_estimate = (0.04 * 10. + 0.01 * 11.) / 0.05;
+        let predicted_signal = initial_estimate * 0.04;
+        let weights =
+            [1., 4.].map(|gain| 0.04_f32.powi(2) / noise_model.variance(predicted_signal, gain));
+        let expected = (weights[0] * 10. + weights[1] * 11.) / (weights[0] + weights[1]);
+
+        assert!((radiance - expected).abs() < 1e-4);
+        assert!(radiance < 10.5);
+    }
+
+    #[test]
+    fn estimates_variance_and_contributions() {
+        let noise_model = NoiseModel::default();
+        let inputs = bracket(&[20., 30., 200.], &[0.01, 0.04]);
+
+        let estimate = PoissonEstimator::new(noise_model)
+            .estimate(&inputs)
+            .expect("Bracket should merge");
+
+        assert_eq!(estimate.count, array![[2, 1, 0]]);
+
+        // A single usable exposure, and the clipped shortest exposure as fallback
+        let single = noise_model.variance(0.3, 1.) / 0.01_f32.powi(2);
+        let fallback = noise_model.variance(1., 1.) / 0.01_f32.powi(2);
+        assert!((estimate.variance[[0, 1, 0]] / single - 1.).abs() < 1e-4);
+        assert!((estimate.variance[[0, 2, 0]] / fallback - 1.).abs() < 1e-4);
+
+        // Both exposures together are more certain than the short one alone
+        let short = noise_model.variance(0.2, 1.) / 0.01_f32.powi(2);
+        assert!(estimate.variance[[0, 0, 0]] < short);
+        assert_eq!(
+            estimate.standard_deviation(),
+            estimate.variance.mapv(f32::sqrt)
+        );
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn merges_without_modifying_inputs() {
+        let mut inputs = [0.01_f32, 0.04, 0.16]
+            .into_iter()
+            .map(|exposure| {
+                let buffer = Array3::from_shape_fn((64, 48, 3), |(y, x, channel)| {
+                    let level = ((x * 7 + y * 13 + channel * 31) % 97) as f32 / 97.;
+
+                    (2_f32.powf(level * 10.) * exposure).min(1.)
+                });
+
+                HDRInput::with_buffer(buffer, Duration::from_secs_f32(exposure), 1.)
+                    .expect("Synthetic exposure should be a valid input")
+            })
+            .collect::<Vec<HDRInput>>();
+        let buffers = inputs
+            .iter()
+            .map(|input| input.get_buffer().clone())
+            .collect::<Vec<Array3<f32>>>();
+
+        let strategy = PoissonEstimator::default();
+        let estimate = strategy.estimate(&inputs).expect("Bracket should merge");
+        let radiance = strategy.merge(&mut inputs).expect("Bracket should merge");
+
+        assert_eq!(radiance, estimate.radiance);
+        for (input, buffer) in inputs.iter().zip(&buffers) {
+            assert_eq!(input.get_buffer(), buffer);
+        }
+    }
+}
@@ -0,0 +1,270 @@
+//! An implementation of HDR merging as described by Robertson, Borman and Stevenson in
+//! [Estimation-theoretic approach to dynamic range enhancement using multiple exposures](https://doi.org/10.1117/1.1557695).
+//!
+//! The radiance and the camera response are estimated alternately until the response converges,
+//! starting from a linear response.
+
+use crate::input::HDRInput;
+use crate::merge::{
+    exposure_extremes, fallback_sample, sample, validate_inputs, without_partial_aperture,
+    MergeStrategy, BLACK_THRESHOLD, SATURATION_THRESHOLD,
+};
+use crate::Error;
+use ndarray::{Array3, Zip};
+use rayon::prelude::*;
+
+/// Number of discrete levels the camera response is estimated at.
+const RESPONSE_BINS: usize = 1024;
+
+/// Get the response bin a pixel value falls into.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_sign_loss)]
+#[allow(clippy::cast_precision_loss)]
+fn bin(value: f32) -> usize {
+    ((value.clamp(0., 1.) * RESPONSE_BINS as f32) as usize).min(RESPONSE_BINS - 1)
+}
+
+/// Pixel value at the centre of a response bin.
+#[allow(clippy::cast_precision_loss)]
+fn bin_centre(bin: usize) -> f32 {
+    (bin as f32 + 0.5) / RESPONSE_BINS as f32
+}
+
+/// Gaussian-like weighting function favouring mid-tones, returning zero for clipped and crushed
+/// pixel values.
+fn gaussian_weight(value: f32) -> f32 {
+    if value > BLACK_THRESHOLD && value < SATURATION_THRESHOLD {
+        (-16. * (value - 0.5).powi(2)).exp()
+    } else {
+        0.
+    }
+}
+
+/// [`MergeStrategy`] that iteratively estimates the radiance and the camera response using
+/// Robertson's maximum likelihood formulation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Robertson {
+    /// Maximum number of iterations to run.
+    pub max_iterations: usize,
+    /// Iteration stops once the largest change in the estimated response is below this value.
+    pub tolerance: f32,
+}
+
+impl Default for Robertson {
+    fn default() -> Self {
+        Self {
+            max_iterations: 30,
+            tolerance: 1e-4,
+        }
+    }
+}
+
+impl Robertson {
+    /// Estimate the radiance of every pixel given the current response of every channel.
+    fn estimate_radiance(
+        inputs: &[HDRInput],
+        shape: (usize, usize, usize),
+        responses: &[Vec<f32>],
+    ) -> Array3<f32> {
+        let (shortest, longest) = exposure_extremes(inputs);
+
+        let mut radiances = Array3::<f32>::zeros(shape);
+
+        Zip::indexed(&mut radiances).par_for_each(|index, radiance| {
+            let response = &responses[index.2];
+
+            let (numerator, denominator) =
+                inputs
+                    .iter()
+                    .fold((0., 0.), |(numerator, denominator), input| {
+                        let value = sample(input, index);
+                        let weight = gaussian_weight(value);
+                        let scaling_factor = input.get_exposure_factor();
+
+                        (
+                            numerator + weight * scaling_factor * response[bin(value)],
+                            denominator + weight * scaling_factor.powi(2),
+                        )
+                    });
+
+            *radiance = if denominator > 0. {
+                numerator / denominator
+            } else {
+                let (fallback, value) = fallback_sample(shortest, longest, index);
+
+                value / (fallback.get_exposure_factor())
+            };
+        });
+
+        radiances
+    }
+
+    /// Estimate the response of every channel given the current radiance estimate. Only usable
+    /// pixel values contribute, the response of other levels is carried over. The response
+    /// is normalized to match a linear response at the middle bin, to keep radiances on the same
+    /// scale as the other strategies.
+    #[allow(clippy::cast_precision_loss)]
+    #[allow(clippy::cast_possible_truncation)]
+    fn estimate_responses(
+        inputs: &[HDRInput],
+        radiances: &Array3<f32>,
+        previous: &[Vec<f32>],
+    ) -> Vec<Vec<f32>> {
+        let channels = radiances.dim().2;
+
+        let (sums, counts) = inputs
+            .par_iter()
+            .map(|input| {
+                let scaling_factor = input.get_exposure_factor();
+                let mut sums = vec![0_f64; channels * RESPONSE_BINS];
+                let mut counts = vec![0_u64; channels * RESPONSE_BINS];
+
+                for index in ndarray::indices(radiances.dim()) {
+                    let value = sample(input, index);
+                    if gaussian_weight(value) <= 0. {
+                        continue;
+                    }
+
+                    let slot = index.2 * RESPONSE_BINS + bin(value);
+
+                    sums[slot] += f64::from(scaling_factor * radiances[index]);
+                    counts[slot] += 1;
+                }
+
+                (sums, counts)
+            })
+            .reduce(
+                || {
+                    (
+                        vec![0_f64; channels * RESPONSE_BINS],
+                        vec![0_u64; channels * RESPONSE_BINS],
+                    )
+                },
+                |(mut sums_acc, mut counts_acc), (sums, counts)| {
+                    sums_acc
+                        .iter_mut()
+                        .zip(sums)
+                        .for_each(|(acc, sum)| *acc += sum);
+                    counts_acc
+                        .iter_mut()
+                        .zip(counts)
+                        .for_each(|(acc, count)| *acc += count);
+
+                    (sums_acc, counts_acc)
+                },
+            );
+
+        (0..channels)
+            .map(|channel| {
+                let offset = channel * RESPONSE_BINS;
+
+                let mut response = (0..RESPONSE_BINS)
+                    .map(|bin| match counts[offset + bin] {
+                        0 => previous[channel][bin],
+                        count => (sums[offset + bin] / count as f64) as f32,
+                    })
+                    .collect::<Vec<f32>>();
+
+                let middle = RESPONSE_BINS / 2;
+                if response[middle] > 0. {
+                    let scale = bin_centre(middle) / response[middle];
+                    for value in &mut response {
+                        *value *= scale;
+                    }
+                }
+
+                response
+            })
+            .collect()
+    }
+}
+
+impl MergeStrategy for Robertson {
+    fn merge(&self, inputs: &mut [HDRInput]) -> Result<Array3<f32>, Error> {
+        if !self.tolerance.is_finite() || self.tolerance < 0. {
+            return Err(Error::InputError {
+                parameter_name: "tolerance".to_string(),
+                message: "Tolerance must be a finite non-negative floating point number"
+                    .to_string(),
+            });
+        }
+
+        let shape = validate_inputs(inputs)?;
+        let inputs = &*without_partial_aperture(inputs);
+
+        let mut responses = vec![(0..RESPONSE_BINS).map(bin_centre).collect::<Vec<f32>>(); shape.2];
+        let mut radiances = Self::estimate_radiance(inputs, shape, &responses);
+
+        for _ in 0..self.max_iterations {
+            let next_responses = Self::estimate_responses(inputs, &radiances, &responses);
+
+            let change = responses
+                .iter()
+                .flatten()
+                .zip(next_responses.iter().flatten())
+                .map(|(previous, next)| (previous - next).abs())
+                .fold(0., f32::max);
+
+            responses = next_responses;
+            radiances = Self::estimate_radiance(inputs, shape, &responses);
+
+            if change < self.tolerance {
+                break;
+            }
+        }
+
+        Ok(radiances)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn recovers_radiance_of_linear_bracket() {
+        let radiances = Array3::from_shape_fn((8, 16, 1), |(y, x, _)| {
+            2_f32.powf((y * 16 + x) as f32 / 16.)
+        });
+        let mut inputs = [1. / 256., 1. / 64., 1. / 16.]
+            .into_iter()
+            .map(|exposure: f32| {
+                HDRInput::with_buffer(
+                    radiances.mapv(|radiance| (radiance * exposure).min(1.)),
+                    Duration::from_secs_f32(exposure),
+                    1.,
+                )
+                .expect("Synthetic exposure should be a valid input")
+            })
+            .collect::<Vec<HDRInput>>();
+
+        let merged = Robertson::default()
+            .merge(&mut inputs)
+            .expect("Bracket should merge");
+
+        // Only pixels that are well exposed in at least one exposure are recoverable
+        for (merged, radiance) in merged.iter().zip(&radiances) {
+            if *radiance > 1. && *radiance < 200. {
+                assert!(
+                    (merged / radiance - 1.).abs() < 0.02,
+                    "{merged} != {radiance}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_tolerance() {
+        let robertson = Robertson {
+            tolerance: f32::NAN,
+            ..Robertson::default()
+        };
+
+        assert!(matches!(
+            robertson.merge(&mut []),
+            Err(Error::InputError { .. })
+        ));
+    }
+}
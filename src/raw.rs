@@ -0,0 +1,386 @@
+//! Merging of raw brackets in the sensor domain, before demosaicing.
+//!
+//! Loading raw files through [`HDRInput::new`] runs the full raw development pipeline (demosaic,
+//! white balance, curves) on every frame, which adds non-linearities and interpolation noise
+//! before the merge. Frames loaded with [`read_cfa_input`] instead keep the undemosaiced colour
+//! filter array (CFA) data of the sensor as a single channel buffer, normalized between the black
+//! and white level of every colour. Such a bracket is merged like any other, and the merged mosaic
+//! is demosaiced only once with [`RawMerge::demosaic`].
+
+use crate::error::UnknownError;
+use crate::exif::get_exif_data;
+use crate::input::{ExposureOverrides, HDRInput, HDRInputList};
+use crate::merge::MergeStrategy;
+use crate::sensor::{CfaPattern, SensorMetadata};
+use crate::Error;
+use ndarray::{Array2, Array3, Axis, Zip};
+use rawloader::{RawImage, RawImageData};
+use rayon::prelude::*;
+use std::path::Path;
+use std::time::Duration;
+
+/// Size of the largest repeating CFA pattern rawloader describes.
+const MAX_PATTERN_SIZE: usize = 48;
+
+/// Read the undemosaiced data of a raw file. The file must have EXIF data for exposure and gain.
+/// Pixel values are cropped to the usable area of the sensor and divided by its full scale, and
+/// the sensor metadata is attached to the input. Black and white levels are applied per colour
+/// when merging.
+///
+/// # Errors
+/// - If the file cannot be opened or decoded
+/// - If the file doesn't contain EXIF metadata for exposure and/or gain
+/// - If the raw data is not a colour filter array mosaic, e.g. a monochrome or linear raw
+pub fn read_cfa_input(path: &Path) -> Result<HDRInput, Error> {
+    read_cfa_input_with_overrides(path, &ExposureOverrides::default())
+}
+
+/// Read the undemosaiced data of a raw file, reading exposure parameters that aren't overridden
+/// from its EXIF data, see [`read_cfa_input`] and [`HDRInput::with_overrides`]. Raw data is
+/// linear, so a declared transfer function is ignored.
+///
+/// # Errors
+/// - If the file cannot be opened or decoded
+/// - If exposure or gain is neither overridden nor contained in the EXIF metadata
+/// - If any of the exposure parameters is invalid
+/// - If the raw data is not a colour filter array mosaic, e.g. a monochrome or linear raw
+pub fn read_cfa_input_with_overrides(
+    path: &Path,
+    overrides: &ExposureOverrides,
+) -> Result<HDRInput, Error> {
+    let data = std::fs::read(path)?;
+    let overrides = overrides.resolve_required(get_exif_data(&data))?;
+
+    let mut input = decode_cfa(
+        &data,
+        overrides.exposure.unwrap_or_default(),
+        overrides.gain.unwrap_or_default(),
+    )?;
+    overrides.apply(&mut input)?;
+
+    Ok(input)
+}
+
+/// Read the undemosaiced data of a raw file with known exposure and gain, see
+/// [`read_cfa_input`].
+///
+/// # Errors
+/// - If the file cannot be opened or decoded
+/// - If the raw data is not a colour filter array mosaic, e.g. a monochrome or linear raw
+/// - invalid gain
+/// - invalid exposure duration
+pub fn read_cfa_input_with_exposure_and_gain(
+    path: &Path,
+    exposure: Duration,
+    gain: f32,
+) -> Result<HDRInput, Error> {
+    decode_cfa(&std::fs::read(path)?, exposure, gain)
+}
+
+/// Read the undemosaiced data of a list of raw files, see [`read_cfa_input`].
+///
+/// # Errors
+/// - If any of the files cannot be read, see [`read_cfa_input`]
+pub fn read_cfa_inputs<P: AsRef<Path> + Sync>(paths: &[P]) -> Result<HDRInputList, Error> {
+    Ok(HDRInputList::from(
+        paths
+            .par_iter()
+            .map(|path| read_cfa_input(path.as_ref()))
+            .collect::<Result<Vec<HDRInput>, Error>>()?,
+    ))
+}
+
+/// Decode the CFA data of a raw file into an input.
+fn decode_cfa(data: &[u8], exposure: Duration, gain: f32) -> Result<HDRInput, Error> {
+    let raw = rawloader::decode(&mut std::io::Cursor::new(data))?;
+
+    if raw.cpp != 1 || !raw.cfa.is_valid() {
+        return Err(Error::InputError {
+            parameter_name: "path".to_string(),
+            message: "Raw image is not a colour filter array mosaic".to_string(),
+        });
+    }
+
+    let [top, right, bottom, left] = raw.crops;
+    let height = raw.height.saturating_sub(top + bottom);
+    let width = raw.width.saturating_sub(left + right);
+    if height == 0 || width == 0 {
+        return Err(Error::UnknownError(UnknownError::from(
+            "Raw image has no usable area after cropping".to_string(),
+        )));
+    }
+
+    let sensor = sensor_metadata(&raw)?;
+    let full_scale = sensor.full_scale();
+
+    let mut buffer = Array3::<f32>::zeros((height, width, 1));
+    Zip::indexed(buffer.index_axis_mut(Axis(2), 0)).par_for_each(|(y, x), value| {
+        let index = (y + top) * raw.width + x + left;
+        let sample = match &raw.data {
+            RawImageData::Integer(data) => f32::from(data[index]),
+            RawImageData::Float(data) => data[index],
+        };
+
+        *value = sample / full_scale;
+    });
+
+    Ok(HDRInput::with_buffer(buffer, exposure, gain)?.with_sensor_metadata(sensor))
+}
+
+/// Extract the sensor metadata of a decoded raw image. The CFA pattern is shifted to match the
+/// cropped image, and is `None` for raw images without one, e.g. `LinearRaw` DNGs or monochrome
+/// sensors.
+pub(crate) fn sensor_metadata(raw: &RawImage) -> Result<SensorMetadata, Error> {
+    let cfa = if raw.cfa.is_valid() {
+        let cfa = raw.cropped_cfa();
+        let pattern = Array2::from_shape_fn(
+            (
+                cfa.height.min(MAX_PATTERN_SIZE),
+                cfa.width.min(MAX_PATTERN_SIZE),
+            ),
+            |(row, column)| cfa.color_at(row, column),
+        );
+
+        Some(CfaPattern::new(pattern)?)
+    } else {
+        None
+    };
+
+    let white_balance = if raw.wb_coeffs[..3].iter().all(|value| value.is_normal()) {
+        raw.wb_coeffs
+    } else {
+        raw.neutralwb()
+    };
+    let white_balance = white_balance.map(|value| {
+        let value = value / white_balance[1];
+        if value.is_finite() {
+            value
+        } else {
+            0.
+        }
+    });
+
+    Ok(SensorMetadata {
+        make: raw.clean_make.clone(),
+        model: raw.clean_model.clone(),
+        cfa,
+        black_levels: raw.blacklevels.map(f32::from),
+        white_levels: raw.whitelevels.map(f32::from),
+        white_balance,
+        xyz_to_camera: raw.xyz_to_cam,
+    })
+}
+
+/// A bracket merged in the sensor domain.
+#[derive(Clone, Debug)]
+pub struct RawMerge {
+    /// Merged radiance of every photosite of the sensor, in the normalized pixel values of an
+    /// exposure of one second at unit gain.
+    pub mosaic: Array2<f32>,
+    /// Metadata of the sensor the bracket was captured with.
+    pub sensor: SensorMetadata,
+}
+
+/// Merge a bracket loaded with [`read_cfa_input`] in the sensor domain using the supplied
+/// [`MergeStrategy`].
+///
+/// # Errors
+/// - If fewer than two inputs are provided
+/// - If inputs are of different dimensions
+/// - If an input doesn't carry CFA data, or inputs were captured with different CFA patterns
+/// - If the strategy is configured with invalid parameters
+pub fn merge_cfa(
+    inputs: &mut HDRInputList,
+    strategy: &dyn MergeStrategy,
+) -> Result<RawMerge, Error> {
+    let mut sensors = inputs.as_slice().iter().map(|input| {
+        input
+            .get_sensor_metadata()
+            .filter(|sensor| sensor.cfa.is_some())
+    });
+
+    let Some(Some(sensor)) = sensors.next() else {
+        return Err(Error::InputError {
+            parameter_name: "inputs".to_string(),
+            message: "Inputs must be read with their CFA data".to_string(),
+        });
+    };
+    let sensor = sensor.clone();
+
+    if sensors.any(|other| other.map(|other| &other.cfa) != Some(&sensor.cfa)) {
+        return Err(Error::InputError {
+            parameter_name: "inputs".to_string(),
+            message: "All inputs must carry CFA data of the same pattern".to_string(),
+        });
+    }
+
+    let radiance = strategy.merge(inputs.as_slice_mut())?;
+
+    Ok(RawMerge {
+        mosaic: radiance.index_axis_move(Axis(2), 0),
+        sensor,
+    })
+}
+
+impl RawMerge {
+    /// Demosaic the merged mosaic into linear sRGB of shape `(height, width, 3)`. The mosaic is
+    /// white balanced as shot, every missing colour of a photosite is interpolated bilinearly from
+    /// the closest photosites of that colour, and camera colours are converted to sRGB with the
+    /// colour matrix of the camera. The result is in the orientation of the sensor.
+    ///
+    /// # Errors
+    /// - If the sensor metadata doesn't contain a CFA pattern
+    pub fn demosaic(&self) -> Result<Array3<f32>, Error> {
+        let Some(cfa) = &self.sensor.cfa else {
+            return Err(Error::InputError {
+                parameter_name: "sensor".to_string(),
+                message: "Sensor metadata must contain a CFA pattern".to_string(),
+            });
+        };
+
+        let (height, width) = self.mosaic.dim();
+        let colors = cfa.colors();
+        let camera_to_srgb = self.sensor.camera_to_srgb();
+
+        let mut balanced = self.mosaic.clone();
+        Zip::indexed(&mut balanced).par_for_each(|(y, x), value| {
+            *value = value.max(0.) * self.sensor.white_balance[cfa.color_at(y, x)];
+        });
+
+        let mut result = Array3::<f32>::zeros((height, width, 3));
+        Zip::indexed(result.lanes_mut(Axis(2))).par_for_each(|(y, x), mut pixel| {
+            let mut camera = [0_f32; 4];
+            for (color, value) in camera.iter_mut().enumerate().take(colors) {
+                *value = interpolate(&balanced, cfa, (y, x), color);
+            }
+
+            for (channel, value) in pixel.iter_mut().enumerate() {
+                *value = camera
+                    .iter()
+                    .zip(camera_to_srgb[channel])
+                    .map(|(camera, coefficient)| camera * coefficient)
+                    .sum::<f32>()
+                    .max(0.);
+            }
+        });
+
+        Ok(result)
+    }
+}
+
+/// Value of given colour at a photosite, averaged over the photosites of that colour in the
+/// smallest neighbourhood that contains any.
+fn interpolate(
+    mosaic: &Array2<f32>,
+    cfa: &CfaPattern,
+    (y, x): (usize, usize),
+    color: usize,
+) -> f32 {
+    if cfa.color_at(y, x) == color {
+        return mosaic[[y, x]];
+    }
+
+    let (height, width) = mosaic.dim();
+    for radius in 1..=2 {
+        let mut sum = 0.;
+        let mut count = 0_u8;
+
+        for row in y.saturating_sub(radius)..=(y + radius).min(height - 1) {
+            for column in x.saturating_sub(radius)..=(x + radius).min(width - 1) {
+                if cfa.color_at(row, column) == color {
+                    sum += mosaic[[row, column]];
+                    count += 1;
+                }
+            }
+        }
+
+        if count > 0 {
+            return sum / f32::from(count);
+        }
+    }
+
+    0.
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merge::PoissonEstimator;
+    use crate::sensor::XYZ_TO_SRGB;
+
+    /// Sensor with an RGGB pattern whose camera colours are linear sRGB.
+    fn sensor() -> SensorMetadata {
+        SensorMetadata {
+            make: String::new(),
+            model: String::new(),
+            cfa: Some(CfaPattern::new(ndarray::arr2(&[[0, 1], [1, 2]])).expect("Pattern is valid")),
+            black_levels: [0.; 4],
+            white_levels: [1., 1., 1., 0.],
+            white_balance: [2., 1., 1.5, 0.],
+            xyz_to_camera: [XYZ_TO_SRGB[0], XYZ_TO_SRGB[1], XYZ_TO_SRGB[2], [0.; 3]],
+        }
+    }
+
+    /// Exposure of a mosaic with given camera radiance per colour.
+    fn input(sensor: &SensorMetadata, radiance: [f32; 3], exposure: f32) -> HDRInput {
+        let cfa = sensor.cfa.clone().expect("Sensor has a CFA pattern");
+        let buffer = Array3::from_shape_fn((6, 6, 1), |(y, x, _)| {
+            (radiance[cfa.color_at(y, x)] * exposure).min(1.)
+        });
+
+        HDRInput::with_buffer(buffer, Duration::from_secs_f32(exposure), 1.)
+            .expect("Synthetic exposure should be valid")
+            .with_sensor_metadata(sensor.clone())
+    }
+
+    #[test]
+    fn merges_and_demosaics_uniform_colour() {
+        let sensor = sensor();
+        // Camera radiance of a colour that is (0.3, 0.6, 0.9) after white balance
+        let radiance = [0.15, 0.6, 0.6];
+
+        let mut inputs = HDRInputList::from(vec![
+            input(&sensor, radiance, 0.5),
+            input(&sensor, radiance, 1.),
+        ]);
+        let merged =
+            merge_cfa(&mut inputs, &PoissonEstimator::default()).expect("Bracket should merge");
+
+        let cfa = sensor.cfa.as_ref().expect("Sensor has a CFA pattern");
+        for ((y, x), &value) in merged.mosaic.indexed_iter() {
+            let expected = radiance[cfa.color_at(y, x)];
+            assert!((value - expected).abs() < 1e-4, "{value} != {expected}");
+        }
+
+        let srgb = merged.demosaic().expect("Mosaic should demosaic");
+        assert_eq!(srgb.dim(), (6, 6, 3));
+        for ((_, _, channel), &value) in srgb.indexed_iter() {
+            let expected = [0.3, 0.6, 0.9][channel];
+            assert!((value - expected).abs() < 1e-3, "{value} != {expected}");
+        }
+    }
+
+    #[test]
+    fn rejects_inputs_without_cfa_data() {
+        let sensor = sensor();
+        let demosaiced = SensorMetadata {
+            cfa: None,
+            ..sensor.clone()
+        };
+
+        let mut inputs = HDRInputList::from(vec![
+            input(&sensor, [0.1; 3], 0.5),
+            input(&sensor, [0.1; 3], 1.).with_sensor_metadata(demosaiced.clone()),
+        ]);
+        assert!(merge_cfa(&mut inputs, &PoissonEstimator::default()).is_err());
+
+        let mut inputs = HDRInputList::from(vec![
+            input(&sensor, [0.1; 3], 0.5),
+            input(&sensor, [0.1; 3], 1.),
+        ]);
+        let mut merged =
+            merge_cfa(&mut inputs, &PoissonEstimator::default()).expect("Bracket should merge");
+        merged.sensor = demosaiced;
+        assert!(merged.demosaic().is_err());
+    }
+}
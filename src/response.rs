@@ -0,0 +1,500 @@
+//! Recovery of the camera response function for brackets with non-linear pixel values (e.g. JPEG
+//! images that went through the camera's tone curve), as described by Debevec and Malik in
+//! [Recovering High Dynamic Range Radiance Maps from Photographs](https://www.pauldebevec.com/Research/HDR/debevec-siggraph97.pdf).
+
+use crate::input::HDRInput;
+use crate::linalg::NormalEquations;
+use crate::merge::{validate_inputs, without_partial_aperture};
+use crate::Error;
+use ndarray::{Array3, Zip};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+
+/// Header line of the serialized representation of [`ResponseCurve`].
+const FILE_HEADER: &str = "image-hdr response curve v1";
+
+/// Largest number of levels of a curve read from a file, enough for 16-bit encodings.
+const MAX_FILE_LEVELS: usize = 1 << 16;
+
+/// Largest number of channels of a curve read from a file.
+const MAX_FILE_CHANNELS: usize = 4;
+
+/// Largest number of levels and samples a response can be recovered with. The least squares
+/// system has one unknown per level and per sample, and is solved densely.
+const MAX_RECOVERY_UNKNOWNS: usize = 2048;
+
+/// Inverse camera response, mapping every encoded pixel level of every channel to a relative
+/// linear exposure in the `0..=1` range.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResponseCurve {
+    channels: Vec<Vec<f32>>,
+}
+
+impl ResponseCurve {
+    /// Create a new [`ResponseCurve`] from inverse responses of every channel. Each response maps
+    /// evenly spaced encoded levels in the `0..=1` range to linear values.
+    ///
+    /// # Errors
+    /// - If no channels are supplied, or channels have different number of levels
+    /// - If any channel has fewer than two levels
+    /// - If any value is not finite
+    pub fn new(channels: Vec<Vec<f32>>) -> Result<Self, Error> {
+        let levels = channels.first().map_or(0, Vec::len);
+
+        if levels < 2 || channels.iter().any(|channel| channel.len() != levels) {
+            return Err(Error::InputError {
+                parameter_name: "channels".to_string(),
+                message: "All channels must have the same number of levels, at least two"
+                    .to_string(),
+            });
+        }
+
+        if channels.iter().flatten().any(|value| !value.is_finite()) {
+            return Err(Error::InputError {
+                parameter_name: "channels".to_string(),
+                message: "Response values must be finite floating point numbers".to_string(),
+            });
+        }
+
+        Ok(Self { channels })
+    }
+
+    /// Get the inverse response of every channel.
+    #[must_use]
+    pub fn get_channels(&self) -> &[Vec<f32>] {
+        &self.channels
+    }
+
+    /// Get the number of encoded levels of the curve.
+    #[must_use]
+    pub fn levels(&self) -> usize {
+        self.channels[0].len()
+    }
+
+    /// Map an encoded pixel value in the `0..=1` range to linear light, interpolating between
+    /// levels.
+    #[allow(clippy::cast_precision_loss)]
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    fn map(response: &[f32], value: f32) -> f32 {
+        let position = value.clamp(0., 1.) * (response.len() - 1) as f32;
+        let lower = (position.floor() as usize).min(response.len() - 2);
+        let fraction = position - lower as f32;
+
+        response[lower] * (1. - fraction) + response[lower + 1] * fraction
+    }
+
+    /// Convert a buffer of encoded pixel values into linear light in place.
+    ///
+    /// # Errors
+    /// - If the curve has more than one channel and it doesn't match the channels of the buffer
+    pub fn linearize_buffer(&self, buffer: &mut Array3<f32>) -> Result<(), Error> {
+        let channels = buffer.dim().2;
+
+        if self.channels.len() != 1 && self.channels.len() != channels {
+            return Err(Error::InputError {
+                parameter_name: "buffer".to_string(),
+                message: format!(
+                    "Response curve has {} channels but image has {channels}",
+                    self.channels.len()
+                ),
+            });
+        }
+
+        Zip::indexed(buffer).par_for_each(|(_, _, channel), value| {
+            let response = &self.channels[channel.min(self.channels.len() - 1)];
+            *value = Self::map(response, *value);
+        });
+
+        Ok(())
+    }
+
+    /// Convert the buffer of an input into linear light in place.
+    ///
+    /// # Errors
+    /// - If the curve has more than one channel and it doesn't match the channels of the input
+    pub fn linearize(&self, input: &mut HDRInput) -> Result<(), Error> {
+        self.linearize_buffer(input.get_buffer_mut())
+    }
+
+    /// Write the curve in a plain text format that can be read back with
+    /// [`ResponseCurve::read_from`].
+    ///
+    /// # Errors
+    /// - If writing fails
+    pub fn write_to<W: Write>(&self, mut writer: W) -> Result<(), Error> {
+        writeln!(writer, "{FILE_HEADER}")?;
+        writeln!(writer, "{} {}", self.levels(), self.channels.len())?;
+
+        for level in 0..self.levels() {
+            let values = self
+                .channels
+                .iter()
+                .map(|channel| channel[level].to_string())
+                .collect::<Vec<String>>();
+
+            writeln!(writer, "{}", values.join(" "))?;
+        }
+
+        Ok(())
+    }
+
+    /// Read a curve previously written with [`ResponseCurve::write_to`].
+    ///
+    /// # Errors
+    /// - If reading fails
+    /// - If the data is not a valid response curve
+    pub fn read_from<R: Read>(reader: R) -> Result<Self, Error> {
+        let invalid = |message: &str| {
+            Error::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid response curve: {message}"),
+            ))
+        };
+
+        let mut lines = BufReader::new(reader).lines();
+
+        if lines.next().transpose()?.as_deref() != Some(FILE_HEADER) {
+            return Err(invalid("missing header"));
+        }
+
+        let dimensions = lines
+            .next()
+            .transpose()?
+            .ok_or_else(|| invalid("missing dimensions"))?
+            .split_whitespace()
+            .map(str::parse::<usize>)
+            .collect::<Result<Vec<usize>, _>>()
+            .map_err(|_| invalid("malformed dimensions"))?;
+
+        let [levels, channel_count] = dimensions[..] else {
+            return Err(invalid("malformed dimensions"));
+        };
+
+        if levels > MAX_FILE_LEVELS || !(1..=MAX_FILE_CHANNELS).contains(&channel_count) {
+            return Err(invalid("unsupported dimensions"));
+        }
+
+        let mut channels = vec![Vec::new(); channel_count];
+
+        for _ in 0..levels {
+            let values = lines
+                .next()
+                .transpose()?
+                .ok_or_else(|| invalid("missing levels"))?
+                .split_whitespace()
+                .map(str::parse::<f32>)
+                .collect::<Result<Vec<f32>, _>>()
+                .map_err(|_| invalid("malformed level"))?;
+
+            if values.len() != channel_count {
+                return Err(invalid("malformed level"));
+            }
+
+            for (channel, value) in channels.iter_mut().zip(values) {
+                channel.push(value);
+            }
+        }
+
+        Self::new(channels)
+    }
+
+    /// Save the curve to a file.
+    ///
+    /// # Errors
+    /// - If the file cannot be written
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        self.write_to(std::io::BufWriter::new(std::fs::File::create(path)?))
+    }
+
+    /// Load a curve from a file previously written with [`ResponseCurve::save`].
+    ///
+    /// # Errors
+    /// - If the file cannot be read
+    /// - If the file doesn't contain a valid response curve
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        Self::read_from(std::fs::File::open(path)?)
+    }
+}
+
+/// Parameters for recovering a [`ResponseCurve`] from a bracket using the Debevec-Malik least
+/// squares formulation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ResponseRecovery {
+    /// Number of encoded levels the response is recovered at, e.g. 256 for 8-bit images. Pixel
+    /// values are binned to the nearest level, so 16-bit images can be recovered at e.g. 1024
+    /// levels. Together with `samples`, at most 2048.
+    pub levels: usize,
+    /// Number of pixel locations sampled from the bracket.
+    pub samples: usize,
+    /// Weight of the smoothness term relative to the data term.
+    pub smoothness: f32,
+}
+
+impl Default for ResponseRecovery {
+    fn default() -> Self {
+        Self {
+            levels: 256,
+            samples: 100,
+            smoothness: 50.,
+        }
+    }
+}
+
+impl ResponseRecovery {
+    /// Recover the inverse camera response from a bracket of non-linear exposures. The exposure
+    /// and gain of every input must be known. The pixel values of the inputs must be the encoded
+    /// ones, so files are read with [`crate::input::HDRInput::with_overrides`] declaring
+    /// [`crate::transfer::TransferFunction::Linear`], as they are linearized with their ICC
+    /// profile or as sRGB otherwise.
+    ///
+    /// # Errors
+    /// - If fewer than two inputs are provided
+    /// - If inputs are of different dimensions or are neither RGB nor grayscale
+    /// - If the parameters are invalid, or `levels` and `samples` add up to more than 2048
+    /// - If the bracket doesn't contain enough information to recover the response
+    pub fn recover(&self, inputs: &[HDRInput]) -> Result<ResponseCurve, Error> {
+        let shape = validate_inputs(inputs)?;
+        let inputs = &*without_partial_aperture(inputs);
+
+        if self.levels < 3 || self.samples < 2 {
+            return Err(Error::InputError {
+                parameter_name: "levels".to_string(),
+                message: "At least three levels and two samples are required".to_string(),
+            });
+        }
+
+        if self.levels.saturating_add(self.samples) > MAX_RECOVERY_UNKNOWNS {
+            return Err(Error::InputError {
+                parameter_name: "levels".to_string(),
+                message: format!(
+                    "At most {MAX_RECOVERY_UNKNOWNS} levels and samples are supported, bin \
+                     higher bit depths down to fewer levels"
+                ),
+            });
+        }
+
+        if !self.smoothness.is_finite() || self.smoothness < 0. {
+            return Err(Error::InputError {
+                parameter_name: "smoothness".to_string(),
+                message: "Smoothness must be a finite non-negative floating point number"
+                    .to_string(),
+            });
+        }
+
+        let channels = (0..shape.2)
+            .map(|channel| self.recover_channel(inputs, shape, channel))
+            .collect::<Result<Vec<Vec<f32>>, Error>>()?;
+
+        ResponseCurve::new(channels)
+    }
+
+    /// Quantize a `0..=1` pixel value to an encoded level.
+    #[allow(clippy::cast_precision_loss)]
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    fn level(&self, value: f32) -> usize {
+        ((value.clamp(0., 1.) * (self.levels - 1) as f32).round() as usize).min(self.levels - 1)
+    }
+
+    /// Hat weighting function over the encoded levels.
+    #[allow(clippy::cast_precision_loss)]
+    fn weight(&self, level: usize) -> f64 {
+        let middle = (self.levels - 1) / 2;
+
+        if level <= middle {
+            level as f64
+        } else {
+            (self.levels - 1 - level) as f64
+        }
+    }
+
+    /// Pick pixel locations whose values span the range of encoded levels in the middle exposure.
+    fn sample_locations(
+        &self,
+        inputs: &[HDRInput],
+        shape: (usize, usize, usize),
+        channel: usize,
+    ) -> Vec<(usize, usize)> {
+        let mut exposures = inputs.iter().collect::<Vec<&HDRInput>>();
+        exposures.sort_by(|a, b| a.get_exposure_factor().total_cmp(&b.get_exposure_factor()));
+        let reference = exposures[exposures.len() / 2].get_buffer();
+
+        let grid = (self.samples * 16).isqrt().max(1);
+        let step_y = (shape.0 / grid).max(1);
+        let step_x = (shape.1 / grid).max(1);
+
+        let mut candidates = (0..shape.0)
+            .step_by(step_y)
+            .flat_map(|y| (0..shape.1).step_by(step_x).map(move |x| (y, x)))
+            .collect::<Vec<(usize, usize)>>();
+        candidates.sort_by(|a, b| {
+            reference[[a.0, a.1, channel]].total_cmp(&reference[[b.0, b.1, channel]])
+        });
+
+        let count = self.samples.min(candidates.len());
+
+        (0..count)
+            .map(|sample| candidates[sample * (candidates.len() - 1) / (count - 1).max(1)])
+            .collect()
+    }
+
+    /// Recover the inverse response of a single channel.
+    fn recover_channel(
+        &self,
+        inputs: &[HDRInput],
+        shape: (usize, usize, usize),
+        channel: usize,
+    ) -> Result<Vec<f32>, Error> {
+        let locations = self
+            .sample_locations(inputs, shape, channel)
+            .into_iter()
+            .filter(|&(y, x)| {
+                inputs
+                    .iter()
+                    .any(|input| self.weight(self.level(input.get_buffer()[[y, x, channel]])) > 0.)
+            })
+            .collect::<Vec<(usize, usize)>>();
+        let unknowns = self.levels + locations.len();
+        let mut system = NormalEquations::new(unknowns);
+
+        for (sample, &(y, x)) in locations.iter().enumerate() {
+            for input in inputs {
+                let level = self.level(input.get_buffer()[[y, x, channel]]);
+                let weight = self.weight(level);
+                let log_exposure = f64::from(input.get_exposure_factor()).ln();
+
+                system.add_row(
+                    &[(level, weight), (self.levels + sample, -weight)],
+                    weight * log_exposure,
+                );
+            }
+        }
+
+        system.add_row(&[((self.levels - 1) / 2, 1.)], 0.);
+
+        let smoothness = f64::from(self.smoothness);
+        for level in 1..self.levels - 1 {
+            let weight = smoothness * self.weight(level);
+            system.add_row(
+                &[
+                    (level - 1, weight),
+                    (level, -2. * weight),
+                    (level + 1, weight),
+                ],
+                0.,
+            );
+        }
+
+        let solution = system.solve().ok_or_else(|| Error::InputError {
+            parameter_name: "inputs".to_string(),
+            message: "Bracket doesn't contain enough information to recover the camera response"
+                .to_string(),
+        })?;
+
+        let mut response = Vec::with_capacity(self.levels);
+        let mut previous = 0_f64;
+        for log_exposure in &solution[..self.levels] {
+            previous = previous.max(log_exposure.exp());
+            response.push(previous);
+        }
+
+        let white = response[self.levels - 1];
+
+        #[allow(clippy::cast_possible_truncation)]
+        Ok(response
+            .into_iter()
+            .enumerate()
+            .map(|(level, value)| {
+                if level == 0 {
+                    0.
+                } else {
+                    (value / white) as f32
+                }
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Bracket of a horizontal radiance ramp spanning eight stops, encoded with gamma 2.2 and
+    /// quantized to 8 bits.
+    #[allow(clippy::cast_precision_loss)]
+    fn gamma_bracket() -> Vec<HDRInput> {
+        [1. / 64., 1. / 16., 1. / 4., 1.]
+            .into_iter()
+            .map(|exposure: f32| {
+                let buffer = Array3::from_shape_fn((16, 256, 1), |(_, x, _)| {
+                    let radiance = 2_f32.powf(x as f32 / 32. - 2.);
+                    let encoded = (radiance * exposure).clamp(0., 1.).powf(1. / 2.2);
+
+                    (encoded * 255.).round() / 255.
+                });
+
+                HDRInput::with_buffer(buffer, Duration::from_secs_f32(exposure), 100.)
+                    .expect("Synthetic exposure should be valid")
+            })
+            .collect()
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn recovers_gamma_response() {
+        let curve = ResponseRecovery::default()
+            .recover(&gamma_bracket())
+            .expect("Response should be recovered");
+        let response = &curve.get_channels()[0];
+
+        for level in (32..=224).step_by(32) {
+            let expected = (level as f32 / 255.).powf(2.2);
+            let relative_error = (response[level] - expected).abs() / expected;
+
+            assert!(
+                relative_error < 0.1,
+                "level {level}: {} != {expected}",
+                response[level]
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_too_many_levels() {
+        let recovery = ResponseRecovery {
+            levels: 1 << 16,
+            ..ResponseRecovery::default()
+        };
+
+        assert!(matches!(
+            recovery.recover(&gamma_bracket()),
+            Err(Error::InputError { .. })
+        ));
+    }
+
+    #[test]
+    fn round_trips_through_text_format() {
+        let curve = ResponseCurve::new(vec![vec![0., 0.25, 1.], vec![0., 0.5, 1.]])
+            .expect("Curve should be valid");
+
+        let mut file = Vec::new();
+        curve.write_to(&mut file).expect("Curve should be written");
+
+        assert_eq!(
+            ResponseCurve::read_from(file.as_slice()).expect("Curve should be read"),
+            curve
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_header() {
+        let file = format!("{FILE_HEADER}\n999999999999999 0\n");
+
+        assert!(matches!(
+            ResponseCurve::read_from(file.as_bytes()),
+            Err(Error::IoError(_))
+        ));
+    }
+}
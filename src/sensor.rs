@@ -0,0 +1,251 @@
+//! Sensor metadata of raw frames, needed to interpret their pixel values radiometrically and to
+//! turn undemosaiced colour filter array (CFA) data into colour.
+
+use crate::Error;
+use ndarray::Array2;
+
+/// Linear sRGB (D65) to CIE XYZ.
+const SRGB_TO_XYZ: [[f32; 3]; 3] = [
+    [0.412_453, 0.357_580, 0.180_423],
+    [0.212_671, 0.715_160, 0.072_169],
+    [0.019_334, 0.119_193, 0.950_227],
+];
+
+/// CIE XYZ to linear sRGB (D65), the inverse of [`SRGB_TO_XYZ`].
+pub(crate) const XYZ_TO_SRGB: [[f32; 3]; 3] = [
+    [3.240_479, -1.537_15, -0.498_535],
+    [-0.969_256, 1.875_992, 0.041_556],
+    [0.055_648, -0.204_043, 1.057_311],
+];
+
+/// Repeating pattern of the colour filter array of a sensor.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CfaPattern {
+    pattern: Array2<usize>,
+}
+
+impl CfaPattern {
+    /// Create a new pattern from the colour indices of one repetition, e.g. `[[0, 1], [1, 2]]` for
+    /// RGGB. Colour indices refer to the channels of [`SensorMetadata`].
+    ///
+    /// # Errors
+    /// - If the pattern is empty
+    /// - If a colour index is greater than 3
+    pub fn new(pattern: Array2<usize>) -> Result<Self, Error> {
+        if pattern.is_empty() {
+            return Err(Error::InputError {
+                parameter_name: "pattern".to_string(),
+                message: "CFA pattern must not be empty".to_string(),
+            });
+        }
+
+        if pattern.iter().any(|&color| color > 3) {
+            return Err(Error::InputError {
+                parameter_name: "pattern".to_string(),
+                message: "CFA colour indices must be in the 0..=3 range".to_string(),
+            });
+        }
+
+        Ok(Self { pattern })
+    }
+
+    /// Get the colour index of the filter at given position of the sensor.
+    #[must_use]
+    pub fn color_at(&self, row: usize, column: usize) -> usize {
+        let (height, width) = self.pattern.dim();
+
+        self.pattern[[row % height, column % width]]
+    }
+
+    /// Get a single repetition of the pattern.
+    #[must_use]
+    pub fn pattern(&self) -> &Array2<usize> {
+        &self.pattern
+    }
+
+    /// Get the number of distinct colours of the pattern, i.e. the highest colour index plus one.
+    #[must_use]
+    pub fn colors(&self) -> usize {
+        self.pattern.iter().max().map_or(0, |color| color + 1)
+    }
+}
+
+/// Metadata of the sensor a raw frame was captured with. Per-colour values are indexed by the
+/// colour indices of the [`CfaPattern`], i.e. in RGBE order.
+///
+/// Buffers of frames that carry their CFA data, see [`crate::raw::read_cfa_input`], hold raw
+/// values divided by the [full scale](SensorMetadata::full_scale) of the sensor, black level
+/// included. The black and white levels are applied per colour when merging, see
+/// [`SensorMetadata::normalize`], and the white balance once when demosaicing the merged mosaic.
+///
+/// Raw files read through the default path (e.g. [`crate::input::HDRInput::new`]) are developed
+/// by `imagepipe`, which already subtracts the black level, scales to the white level and white
+/// balances before the frames are merged. Their metadata is informational only: it doesn't enter
+/// the merge, and saturation is detected on the developed values.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SensorMetadata {
+    /// Camera make.
+    pub make: String,
+    /// Camera model.
+    pub model: String,
+    /// Colour filter array of the sensor, `None` for frames that were already demosaiced.
+    pub cfa: Option<CfaPattern>,
+    /// Raw value corresponding to no light, per colour.
+    pub black_levels: [f32; 4],
+    /// Raw value at which the sensor clips, per colour.
+    pub white_levels: [f32; 4],
+    /// White balance multipliers as shot, normalized so that green is 1. Applied when
+    /// demosaicing, never when merging.
+    pub white_balance: [f32; 4],
+    /// Matrix converting CIE XYZ to camera colours.
+    pub xyz_to_camera: [[f32; 3]; 4],
+}
+
+impl SensorMetadata {
+    /// Largest white level of the sensor, which raw values are divided by in the buffer of a frame.
+    #[must_use]
+    pub fn full_scale(&self) -> f32 {
+        let white = self.white_levels.iter().copied().fold(0., f32::max);
+
+        if white > 0. {
+            white
+        } else {
+            1.
+        }
+    }
+
+    /// Subtract the black level of given colour from a buffer value and scale it by the range
+    /// between the black and white level of that colour, so that values of all colours clip at 1.
+    #[must_use]
+    pub fn normalize(&self, color: usize, value: f32) -> f32 {
+        let full_scale = self.full_scale();
+        let black = self.black_levels[color] / full_scale;
+        let range = (self.white_levels[color] / full_scale - black).max(f32::EPSILON);
+
+        (value - black) / range
+    }
+
+    /// Matrix converting linear sRGB to white balanced camera colours, such that sRGB white maps
+    /// to a white balanced neutral. Rows beyond the number of colours of the sensor are unused.
+    #[must_use]
+    pub fn srgb_to_camera(&self) -> [[f32; 3]; 4] {
+        let mut srgb_to_camera = [[0.; 3]; 4];
+        for (row, xyz_to_camera) in srgb_to_camera.iter_mut().zip(&self.xyz_to_camera) {
+            for (column, value) in row.iter_mut().enumerate() {
+                *value = (0..3)
+                    .map(|index| xyz_to_camera[index] * SRGB_TO_XYZ[index][column])
+                    .sum();
+            }
+        }
+
+        // Normalize so that sRGB white maps to camera white.
+        for row in &mut srgb_to_camera {
+            let sum = row.iter().sum::<f32>();
+            if sum != 0. {
+                for value in row.iter_mut() {
+                    *value /= sum;
+                }
+            }
+        }
+
+        srgb_to_camera
+    }
+
+    /// Matrix converting white balanced camera colours to linear sRGB, such that a white balanced
+    /// neutral maps to sRGB white. Columns beyond the number of colours of the sensor are unused.
+    #[must_use]
+    pub fn camera_to_srgb(&self) -> [[f32; 4]; 3] {
+        pseudoinverse(&self.srgb_to_camera())
+    }
+}
+
+/// Least squares inverse `(AᵀA)⁻¹Aᵀ` of a 4x3 matrix. Rows of zeros, i.e. unused colours, don't
+/// contribute.
+fn pseudoinverse(matrix: &[[f32; 3]; 4]) -> [[f32; 4]; 3] {
+    let mut normal = [[0_f32; 3]; 3];
+    for (i, row) in normal.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = matrix.iter().map(|m| m[i] * m[j]).sum();
+        }
+    }
+
+    let determinant = normal[0][0] * (normal[1][1] * normal[2][2] - normal[1][2] * normal[2][1])
+        - normal[0][1] * (normal[1][0] * normal[2][2] - normal[1][2] * normal[2][0])
+        + normal[0][2] * (normal[1][0] * normal[2][1] - normal[1][1] * normal[2][0]);
+
+    let mut inverse = [[0_f32; 3]; 3];
+    if determinant != 0. {
+        for (i, row) in inverse.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                let (a, b) = ((j + 1) % 3, (j + 2) % 3);
+                let (c, d) = ((i + 1) % 3, (i + 2) % 3);
+                *value = (normal[a][c] * normal[b][d] - normal[a][d] * normal[b][c]) / determinant;
+            }
+        }
+    }
+
+    let mut result = [[0_f32; 4]; 3];
+    for (i, row) in result.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| inverse[i][k] * matrix[j][k]).sum();
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::HDRInput;
+    use crate::merge::{MergeStrategy, PoissonEstimator};
+    use ndarray::Array3;
+    use std::time::Duration;
+
+    #[test]
+    fn merge_applies_black_and_white_levels_per_colour() {
+        let sensor = SensorMetadata {
+            make: String::new(),
+            model: String::new(),
+            cfa: Some(CfaPattern::new(ndarray::arr2(&[[0, 1], [1, 2]])).expect("Pattern is valid")),
+            black_levels: [400., 256., 300., 0.],
+            white_levels: [4000., 4096., 3800., 0.],
+            white_balance: [2., 1., 1.5, 0.],
+            xyz_to_camera: [[0.; 3]; 4],
+        };
+        let radiance = [20., 10., 5.];
+        let colour_at =
+            |y: usize, x: usize| sensor.cfa.as_ref().map_or(0, |cfa| cfa.color_at(y, x));
+
+        let mut inputs = [0.01_f32, 0.04]
+            .into_iter()
+            .map(|exposure| {
+                let buffer = Array3::from_shape_fn((4, 4, 1), |(y, x, _)| {
+                    let colour = colour_at(y, x);
+                    let (black, white) = (sensor.black_levels[colour], sensor.white_levels[colour]);
+                    let raw = black + (radiance[colour] * exposure).min(1.) * (white - black);
+
+                    raw / sensor.full_scale()
+                });
+
+                HDRInput::with_buffer(buffer, Duration::from_secs_f32(exposure), 1.)
+                    .expect("Synthetic exposure should be valid")
+                    .with_sensor_metadata(sensor.clone())
+            })
+            .collect::<Vec<HDRInput>>();
+
+        let merged = PoissonEstimator::default()
+            .merge(&mut inputs)
+            .expect("Bracket should merge");
+
+        for ((y, x, _), &value) in merged.indexed_iter() {
+            let expected = radiance[colour_at(y, x)];
+
+            // The red photosites clip in the longer exposure and are taken from the shorter one
+            assert!(
+                (value - expected).abs() < expected * 1e-3,
+                "{value} != {expected}"
+            );
+        }
+    }
+}
@@ -0,0 +1,754 @@
+//! Merging of brackets that are too large to be held in memory, one strip of rows at a time.
+//!
+//! [`TiledMerge`] reads the inputs in strips of full rows and merges one strip at a time with any
+//! [`MergeStrategy`], so that only a strip of every input is held in memory at once. The height of
+//! the strips is derived from a memory budget. Merged strips are produced in order from top to
+//! bottom and can be written to disk right away with
+//! [`TiffWriter::save_strips`](crate::export::tiff::TiffWriter::save_strips) or
+//! `ExrWriter::save_strips`.
+//!
+//! RGB and grayscale TIFF files are decoded strip by strip (or row of tiles by row of tiles) when
+//! the `tiff` feature is enabled. Other files, including raw files, are decoded whole one at a
+//! time and their linear pixel values are spilled to a temporary file, which strips are read back
+//! from. Peak memory while opening those is that of decoding a single input.
+//!
+//! Strategies are run on every strip independently. This is exact for strategies that merge every
+//! pixel on its own, like [`crate::merge::PoissonEstimator`] and [`crate::merge::Debevec`].
+//! Neighbourhood operations, like the dilation of detected motion in [`crate::deghost`], see
+//! `overlap` rows of the adjacent strips, and match the result of merging the whole image as long
+//! as their radius doesn't exceed it. [`crate::merge::Robertson`] estimates the camera response
+//! from the pixels it is given, i.e. separately for every strip. Inputs must already be aligned.
+
+use crate::input::{ExposureOverrides, HDRInput};
+use crate::merge::MergeStrategy;
+use crate::Error;
+use ndarray::{s, Array3};
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of strip sized buffers a strategy is assumed to allocate while merging a strip besides
+/// the strips of the inputs, e.g. for weights, variances and the merged radiance.
+const WORKING_BUFFERS: usize = 8;
+
+/// Options of a strip by strip merge, see the [module documentation](self).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TiledMerge {
+    /// Approximate upper bound of the memory used for pixel data while merging, in bytes.
+    pub memory_budget: usize,
+    /// Number of rows of the adjacent strips merged along with every strip and then discarded, so
+    /// that neighbourhood operations of the strategy don't see the edges of the strips.
+    pub overlap: usize,
+    /// Directory inputs that can't be read strip by strip are spilled to. Defaults to the
+    /// temporary directory of the system.
+    pub temp_dir: Option<PathBuf>,
+}
+
+impl Default for TiledMerge {
+    fn default() -> Self {
+        Self {
+            memory_budget: 1 << 30,
+            overlap: 8,
+            temp_dir: None,
+        }
+    }
+}
+
+impl TiledMerge {
+    /// Open a bracket for a strip by strip merge, with per input overrides of the exposure
+    /// parameters, see [`HDRInput::with_overrides`]. Inputs are opened one after another.
+    ///
+    /// # Errors
+    /// - If fewer than two paths or not exactly one set of overrides per path are given
+    /// - If any of the inputs cannot be read, or its exposure or gain is neither overridden nor
+    ///   contained in the EXIF metadata
+    /// - If the inputs are of different dimensions
+    /// - If a spilled input cannot be written to the temporary directory
+    pub fn open<P: AsRef<Path>>(
+        &self,
+        paths: &[P],
+        overrides: &[ExposureOverrides],
+    ) -> Result<TiledInputs, Error> {
+        if paths.len() < 2 {
+            return Err(Error::InputError {
+                parameter_name: "paths".to_string(),
+                message: "At least two images must be provided".to_string(),
+            });
+        }
+
+        if overrides.len() != paths.len() {
+            return Err(Error::InputError {
+                parameter_name: "overrides".to_string(),
+                message: "Exactly one set of overrides must be given per path".to_string(),
+            });
+        }
+
+        let mut sources = Vec::with_capacity(paths.len());
+        let mut inputs = Vec::with_capacity(paths.len());
+
+        for (path, overrides) in paths.iter().zip(overrides) {
+            let (input, source) = self.open_input(path.as_ref(), overrides)?;
+
+            if sources
+                .first()
+                .is_some_and(|first: &StripSource| first.dim() != source.dim())
+            {
+                return Err(Error::InputError {
+                    parameter_name: "inputs".to_string(),
+                    message: "All images must have the same dimensions".to_string(),
+                });
+            }
+
+            inputs.push(input);
+            sources.push(source);
+        }
+
+        Ok(TiledInputs { inputs, sources })
+    }
+
+    /// Open a single input, returning it with an empty buffer along with the source of its pixel
+    /// values.
+    fn open_input(
+        &self,
+        path: &Path,
+        overrides: &ExposureOverrides,
+    ) -> Result<(HDRInput, StripSource), Error> {
+        #[cfg(feature = "tiff")]
+        if let Some(opened) = tiff_source::open(path, overrides)? {
+            return Ok(opened);
+        }
+
+        let mut input = HDRInput::with_overrides(path, overrides)?;
+        let buffer = std::mem::take(input.get_buffer_mut());
+        let temp_dir = self.temp_dir.clone().unwrap_or_else(std::env::temp_dir);
+
+        Ok((
+            input,
+            StripSource::Spilled(SpilledSource::new(&buffer, &temp_dir)?),
+        ))
+    }
+
+    /// Height of the strips a bracket is merged in, within the memory budget.
+    ///
+    /// # Errors
+    /// - If not even a single row fits into the memory budget
+    pub fn strip_height(&self, inputs: &TiledInputs) -> Result<usize, Error> {
+        let (height, width, channels) = inputs.dim();
+        let row_size = width * channels * size_of::<f32>();
+        let row_cost = row_size * (inputs.len() + WORKING_BUFFERS);
+        let cached = inputs
+            .sources
+            .iter()
+            .map(StripSource::cache_size)
+            .sum::<usize>();
+
+        let rows = self.memory_budget.saturating_sub(cached) / row_cost;
+        let strip_height = rows.saturating_sub(2 * self.overlap).min(height);
+
+        if strip_height == 0 {
+            return Err(Error::InputError {
+                parameter_name: "memory_budget".to_string(),
+                message: format!(
+                    "Memory budget of {} bytes is too small, at least {} bytes are needed",
+                    self.memory_budget,
+                    cached + row_cost * (1 + 2 * self.overlap)
+                ),
+            });
+        }
+
+        Ok(strip_height)
+    }
+
+    /// Merge an opened bracket strip by strip with the given strategy. Strips are read and merged
+    /// lazily as the returned iterator is advanced.
+    ///
+    /// # Errors
+    /// - If not even a single row fits into the memory budget
+    pub fn merge<'a>(
+        &self,
+        inputs: &'a mut TiledInputs,
+        strategy: &'a dyn MergeStrategy,
+    ) -> Result<MergedStrips<'a>, Error> {
+        Ok(MergedStrips {
+            strip_height: self.strip_height(inputs)?,
+            overlap: self.overlap,
+            next_row: 0,
+            inputs,
+            strategy,
+        })
+    }
+}
+
+/// A bracket opened for a strip by strip merge with [`TiledMerge::open`].
+pub struct TiledInputs {
+    inputs: Vec<HDRInput>,
+    sources: Vec<StripSource>,
+}
+
+impl TiledInputs {
+    /// Shape `(height, width, channels)` of the inputs.
+    #[must_use]
+    pub fn dim(&self) -> (usize, usize, usize) {
+        self.sources[0].dim()
+    }
+
+    /// The inputs with their exposure parameters and sensor metadata but empty buffers, e.g. to
+    /// describe the merge with [`crate::calibration::LuminanceCalibration::from_exposure`].
+    #[must_use]
+    pub fn as_slice(&self) -> &[HDRInput] {
+        &self.inputs
+    }
+
+    /// Returns the number of inputs.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.inputs.len()
+    }
+
+    /// Returns `true` if there are no inputs.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    /// Read the given rows of every input.
+    fn read_rows(&mut self, rows: Range<usize>) -> Result<Vec<HDRInput>, Error> {
+        self.inputs
+            .par_iter()
+            .zip(self.sources.par_iter_mut())
+            .map(|(input, source)| {
+                let mut strip = input.clone();
+                *strip.get_buffer_mut() = source.read_rows(rows.clone())?;
+
+                Ok(strip)
+            })
+            .collect()
+    }
+}
+
+/// Iterator over the merged strips of a bracket from top to bottom, see [`TiledMerge::merge`].
+/// Iteration ends after the first error.
+pub struct MergedStrips<'a> {
+    inputs: &'a mut TiledInputs,
+    strategy: &'a dyn MergeStrategy,
+    strip_height: usize,
+    overlap: usize,
+    next_row: usize,
+}
+
+impl MergedStrips<'_> {
+    /// Shape `(height, width, channels)` of the whole merged radiance.
+    #[must_use]
+    pub fn dim(&self) -> (usize, usize, usize) {
+        self.inputs.dim()
+    }
+
+    /// Number of rows of every strip, except for the last one which may be shorter.
+    #[must_use]
+    pub fn strip_height(&self) -> usize {
+        self.strip_height
+    }
+
+    /// Merge the strip covering the given rows.
+    fn merge_rows(&mut self, rows: Range<usize>) -> Result<Array3<f32>, Error> {
+        let (height, _, _) = self.dim();
+        let start = rows.start.saturating_sub(self.overlap);
+        let end = (rows.end + self.overlap).min(height);
+
+        let mut strips = self.inputs.read_rows(start..end)?;
+        let merged = self.strategy.merge(&mut strips)?;
+
+        Ok(merged.slice_move(s![rows.start - start..rows.end - start, .., ..]))
+    }
+}
+
+impl Iterator for MergedStrips<'_> {
+    type Item = Result<Array3<f32>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (height, _, _) = self.dim();
+
+        if self.next_row >= height {
+            return None;
+        }
+
+        let rows = self.next_row..(self.next_row + self.strip_height).min(height);
+        let merged = self.merge_rows(rows.clone());
+
+        self.next_row = if merged.is_ok() { rows.end } else { height };
+
+        Some(merged)
+    }
+}
+
+/// Source of the pixel values of an input, read strip by strip.
+enum StripSource {
+    Spilled(SpilledSource),
+    #[cfg(feature = "tiff")]
+    Tiff(Box<tiff_source::TiffSource>),
+}
+
+impl StripSource {
+    fn dim(&self) -> (usize, usize, usize) {
+        match self {
+            Self::Spilled(source) => source.dim,
+            #[cfg(feature = "tiff")]
+            Self::Tiff(source) => source.dim,
+        }
+    }
+
+    /// Memory held by the source between reads, in bytes.
+    fn cache_size(&self) -> usize {
+        match self {
+            Self::Spilled(_) => 0,
+            #[cfg(feature = "tiff")]
+            Self::Tiff(source) => source.cache_size(),
+        }
+    }
+
+    /// Read the linear pixel values of the given rows.
+    fn read_rows(&mut self, rows: Range<usize>) -> Result<Array3<f32>, Error> {
+        match self {
+            Self::Spilled(source) => source.read_rows(rows),
+            #[cfg(feature = "tiff")]
+            Self::Tiff(source) => source.read_rows(rows),
+        }
+    }
+}
+
+/// Linear pixel values of a decoded input, written to a temporary file that is deleted when the
+/// source is dropped.
+struct SpilledSource {
+    file: File,
+    path: PathBuf,
+    dim: (usize, usize, usize),
+}
+
+impl SpilledSource {
+    /// Spill a buffer to a new file in the given directory.
+    fn new(buffer: &Array3<f32>, directory: &Path) -> Result<Self, Error> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let (file, path) = loop {
+            let path = directory.join(format!(
+                "image-hdr-{}-{}.tmp",
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+
+            match File::options()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(file) => break (file, path),
+                Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => {}
+                Err(error) => return Err(error.into()),
+            }
+        };
+
+        // The file is deleted on drop from here on, also if writing it fails
+        let source = Self {
+            file,
+            path,
+            dim: buffer.dim(),
+        };
+
+        let mut writer = BufWriter::new(&source.file);
+        for value in buffer {
+            writer.write_all(&value.to_ne_bytes())?;
+        }
+        writer.flush()?;
+        drop(writer);
+
+        Ok(source)
+    }
+
+    fn read_rows(&mut self, rows: Range<usize>) -> Result<Array3<f32>, Error> {
+        let (_, width, channels) = self.dim;
+        let row_size = width * channels * size_of::<f32>();
+        let mut bytes = vec![0; rows.len() * row_size];
+
+        self.file
+            .seek(SeekFrom::Start((rows.start * row_size) as u64))?;
+        self.file.read_exact(&mut bytes)?;
+
+        let values = bytes
+            .chunks_exact(size_of::<f32>())
+            .map(|value| f32::from_ne_bytes([value[0], value[1], value[2], value[3]]))
+            .collect();
+
+        Array3::from_shape_vec((rows.len(), width, channels), values).map_err(|error| {
+            Error::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                error.to_string(),
+            ))
+        })
+    }
+}
+
+impl Drop for SpilledSource {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Strip by strip decoding of TIFF files.
+#[cfg(feature = "tiff")]
+mod tiff_source {
+    use super::StripSource;
+    use crate::exif::get_exif_data_from_path;
+    use crate::input::{ExposureOverrides, HDRInput};
+    use crate::transfer::TransferFunction;
+    use crate::Error;
+    use ndarray::{s, Array3};
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::ops::Range;
+    use std::path::Path;
+    use tiff::decoder::{Decoder, DecodingResult};
+    use tiff::tags::Tag;
+    use tiff::{ColorType, TiffError};
+
+    /// Decoded chunks of a TIFF file, read one row of chunks at a time.
+    pub(super) struct TiffSource {
+        decoder: Decoder<BufReader<File>>,
+        pub(super) dim: (usize, usize, usize),
+        /// Number of samples per pixel in the file, including alpha.
+        samples: usize,
+        /// Size of a strip or tile.
+        chunk_size: (usize, usize),
+        transfer_function: TransferFunction,
+        /// Index and linear pixel values of the last decoded row of chunks.
+        cached: Option<(usize, Array3<f32>)>,
+    }
+
+    /// Convert an error of the TIFF decoder.
+    fn tiff_error(error: TiffError) -> Error {
+        match error {
+            TiffError::IoError(error) => Error::IoError(error),
+            error => Error::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                error.to_string(),
+            )),
+        }
+    }
+
+    /// Open a TIFF file for strip by strip decoding. Returns `None` for files that aren't TIFF
+    /// files or use a pixel layout that is only supported when decoding the whole image, e.g.
+    /// palette or CMYK images.
+    pub(super) fn open(
+        path: &Path,
+        overrides: &ExposureOverrides,
+    ) -> Result<Option<(HDRInput, StripSource)>, Error> {
+        let is_tiff = path.extension().is_some_and(|extension| {
+            extension.eq_ignore_ascii_case("tif") || extension.eq_ignore_ascii_case("tiff")
+        });
+
+        if !is_tiff {
+            return Ok(None);
+        }
+
+        let mut decoder = Decoder::new(BufReader::new(File::open(path)?)).map_err(tiff_error)?;
+
+        let (channels, samples, bits) = match decoder.colortype().map_err(tiff_error)? {
+            ColorType::Gray(bits) => (1, 1, bits),
+            ColorType::GrayA(bits) => (1, 2, bits),
+            ColorType::RGB(bits) => (3, 3, bits),
+            ColorType::RGBA(bits) => (3, 4, bits),
+            _ => return Ok(None),
+        };
+        let photometric = decoder
+            .find_tag_unsigned::<u16>(Tag::PhotometricInterpretation)
+            .map_err(tiff_error)?;
+        let planar = decoder
+            .find_tag_unsigned::<u16>(Tag::PlanarConfiguration)
+            .map_err(tiff_error)?;
+
+        // White-is-zero, YCbCr and planar images are left to the full decoder
+        if !matches!(bits, 8 | 16 | 32 | 64)
+            || !matches!(photometric, Some(1 | 2))
+            || !matches!(planar, None | Some(1))
+        {
+            return Ok(None);
+        }
+
+        let (width, height) = decoder.dimensions().map_err(tiff_error)?;
+        let (chunk_width, chunk_height) = decoder.chunk_dimensions();
+        // The sample format is stored once per sample, so a list for colour images.
+        let float = matches!(
+            decoder.find_tag_unsigned_vec::<u16>(Tag::SampleFormat),
+            Ok(Some(formats)) if formats.first() == Some(&3)
+        );
+
+        let transfer_function = match &overrides.transfer_function {
+            Some(transfer_function) => transfer_function.clone(),
+            None => decoder
+                .find_tag(Tag::IccProfile)
+                .ok()
+                .flatten()
+                .and_then(|profile| profile.into_u8_vec().ok())
+                .and_then(|profile| TransferFunction::from_icc_profile(&profile).ok())
+                .unwrap_or(if float {
+                    TransferFunction::Linear
+                } else {
+                    TransferFunction::Srgb
+                }),
+        };
+
+        let overrides = overrides.resolve_required(get_exif_data_from_path(path))?;
+        let mut input = HDRInput::with_buffer(
+            Array3::default((0, 0, 0)),
+            overrides.exposure.unwrap_or_default(),
+            overrides.gain.unwrap_or_default(),
+        )?;
+        overrides.apply(&mut input)?;
+
+        let source = TiffSource {
+            decoder,
+            dim: (height as usize, width as usize, channels),
+            samples,
+            // Strips may be declared larger than the image
+            chunk_size: (
+                chunk_width.min(width) as usize,
+                chunk_height.min(height) as usize,
+            ),
+            transfer_function,
+            cached: None,
+        };
+
+        Ok(Some((input, StripSource::Tiff(Box::new(source)))))
+    }
+
+    /// Convert decoded samples to floats, normalizing integers to the `0..=1` range.
+    #[allow(clippy::cast_precision_loss)]
+    #[allow(clippy::cast_possible_truncation)]
+    fn normalize(samples: DecodingResult) -> Result<Vec<f32>, Error> {
+        Ok(match samples {
+            DecodingResult::U8(values) => values
+                .into_iter()
+                .map(|value| f32::from(value) / f32::from(u8::MAX))
+                .collect(),
+            DecodingResult::U16(values) => values
+                .into_iter()
+                .map(|value| f32::from(value) / f32::from(u16::MAX))
+                .collect(),
+            DecodingResult::U32(values) => values
+                .into_iter()
+                .map(|value| (f64::from(value) / f64::from(u32::MAX)) as f32)
+                .collect(),
+            DecodingResult::U64(values) => values
+                .into_iter()
+                .map(|value| (value as f64 / u64::MAX as f64) as f32)
+                .collect(),
+            DecodingResult::F16(values) => values.into_iter().map(f32::from).collect(),
+            DecodingResult::F32(values) => values,
+            DecodingResult::F64(values) => values.into_iter().map(|value| value as f32).collect(),
+            _ => {
+                return Err(Error::IoError(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Unsupported TIFF sample format",
+                )))
+            }
+        })
+    }
+
+    impl TiffSource {
+        /// Memory held between reads, in bytes: the linear pixel values of a row of chunks, and
+        /// its decoded samples while it is being converted.
+        pub(super) fn cache_size(&self) -> usize {
+            let (_, width, _) = self.dim;
+
+            2 * self.chunk_size.1 * width * self.samples * size_of::<f32>()
+        }
+
+        /// Decode the row of chunks with given index into linear pixel values.
+        #[allow(clippy::cast_possible_truncation)]
+        fn decode_chunk_row(&mut self, index: usize) -> Result<Array3<f32>, Error> {
+            let (height, width, channels) = self.dim;
+            let (chunk_width, chunk_height) = self.chunk_size;
+            let chunks_across = width.div_ceil(chunk_width);
+            let top = index * chunk_height;
+
+            let mut pixels =
+                Array3::<f32>::zeros((chunk_height.min(height - top), width, channels));
+
+            for column in 0..chunks_across {
+                let chunk = (index * chunks_across + column) as u32;
+                let (data_width, data_height) = self.decoder.chunk_data_dimensions(chunk);
+                let values = normalize(self.decoder.read_chunk(chunk).map_err(tiff_error)?)?;
+                let left = column * chunk_width;
+
+                for (offset, pixel) in values
+                    .chunks_exact(self.samples)
+                    .take(data_width as usize * data_height as usize)
+                    .enumerate()
+                {
+                    let (y, x) = (offset / data_width as usize, offset % data_width as usize);
+                    for (channel, value) in pixel.iter().take(channels).enumerate() {
+                        pixels[[y, left + x, channel]] = *value;
+                    }
+                }
+            }
+
+            self.transfer_function.linearize_buffer(&mut pixels)?;
+
+            Ok(pixels)
+        }
+
+        pub(super) fn read_rows(&mut self, rows: Range<usize>) -> Result<Array3<f32>, Error> {
+            let (_, width, channels) = self.dim;
+            let chunk_height = self.chunk_size.1;
+            let mut strip = Array3::<f32>::zeros((rows.len(), width, channels));
+
+            for index in rows.start / chunk_height..rows.end.div_ceil(chunk_height) {
+                let pixels = match self.cached.take() {
+                    Some((cached, pixels)) if cached == index => pixels,
+                    _ => self.decode_chunk_row(index)?,
+                };
+
+                let top = index * chunk_height;
+                let start = rows.start.max(top);
+                let end = rows.end.min(top + pixels.dim().0);
+
+                strip
+                    .slice_mut(s![start - rows.start..end - rows.start, .., ..])
+                    .assign(&pixels.slice(s![start - top..end - top, .., ..]));
+
+                self.cached = Some((index, pixels));
+            }
+
+            Ok(strip)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::tiff::TiffWriter;
+    use crate::input::HDRInputList;
+    use crate::merge::PoissonEstimator;
+    use image::{DynamicImage, ImageBuffer, Rgb};
+    use std::time::Duration;
+
+    const EXPOSURES: [f32; 3] = [0.002, 0.008, 0.032];
+
+    /// Write a bracket of a radiance ramp to a fresh directory: floating point TIFFs, which are
+    /// read strip by strip, and a JPEG, which is spilled.
+    #[allow(clippy::cast_precision_loss)]
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    fn write_bracket(name: &str) -> (PathBuf, Vec<PathBuf>) {
+        let directory =
+            std::env::temp_dir().join(format!("image-hdr-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&directory).expect("Directory should be created");
+
+        let paths = EXPOSURES
+            .iter()
+            .enumerate()
+            .map(|(index, &exposure)| {
+                let buffer = Array3::from_shape_fn((53, 37, 3), |(y, x, channel)| {
+                    let radiance = 2_f32.powf((x + y) as f32 / 9. - 1.) * (1. + channel as f32);
+
+                    (radiance * exposure).min(1.)
+                });
+
+                if index == 1 {
+                    let path = directory.join("middle.jpg");
+                    let image = ImageBuffer::<Rgb<u8>, Vec<u8>>::from_fn(37, 53, |x, y| {
+                        Rgb([0, 1, 2].map(|channel| {
+                            let value = buffer[[y as usize, x as usize, channel]];
+                            (value * 255.).round() as u8
+                        }))
+                    });
+                    DynamicImage::ImageRgb8(image)
+                        .save(&path)
+                        .expect("JPEG should be saved");
+                    path
+                } else {
+                    let path = directory.join(format!("{index}.tif"));
+                    TiffWriter
+                        .save(&buffer, &path)
+                        .expect("TIFF should be saved");
+                    path
+                }
+            })
+            .collect();
+
+        (directory, paths)
+    }
+
+    fn overrides() -> Vec<ExposureOverrides> {
+        EXPOSURES
+            .iter()
+            .map(|&exposure| ExposureOverrides {
+                exposure: Some(Duration::from_secs_f32(exposure)),
+                gain: Some(100.),
+                ..ExposureOverrides::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matches_merge_of_whole_images() {
+        let (directory, paths) = write_bracket("tiled-merge");
+        let mut tiled = TiledMerge {
+            overlap: 2,
+            temp_dir: Some(directory.clone()),
+            ..TiledMerge::default()
+        };
+
+        let strategy = PoissonEstimator::default();
+        let mut inputs = tiled
+            .open(&paths, &overrides())
+            .expect("Bracket should open");
+
+        // Leave room for strips of 8 rows next to the cached TIFF strips.
+        let cached = inputs
+            .sources
+            .iter()
+            .map(StripSource::cache_size)
+            .sum::<usize>();
+        tiled.memory_budget = cached + 37 * 3 * 4 * (3 + WORKING_BUFFERS) * (8 + 2 * 2);
+        let strips = tiled
+            .merge(&mut inputs, &strategy)
+            .expect("Budget should fit a strip");
+        assert_eq!(strips.strip_height(), 8);
+
+        let merged = strips
+            .collect::<Result<Vec<Array3<f32>>, Error>>()
+            .expect("Strips should merge");
+        let merged = ndarray::concatenate(
+            ndarray::Axis(0),
+            &merged.iter().map(Array3::view).collect::<Vec<_>>(),
+        )
+        .expect("Strips should have the same width");
+
+        let mut whole =
+            HDRInputList::with_overrides(&paths, &overrides()).expect("Bracket should be read");
+        let expected = strategy
+            .merge(whole.as_slice_mut())
+            .expect("Bracket should merge");
+
+        drop(inputs);
+        let leftovers = std::fs::read_dir(&directory)
+            .expect("Directory should be listed")
+            .count();
+        std::fs::remove_dir_all(&directory).expect("Directory should be removed");
+
+        assert_eq!(merged, expected);
+        assert_eq!(leftovers, paths.len());
+    }
+
+    #[test]
+    fn rejects_single_input() {
+        let result = TiledMerge::default().open(&[Path::new("single.tif")], &overrides()[..1]);
+
+        assert!(matches!(result, Err(Error::InputError { .. })));
+    }
+}
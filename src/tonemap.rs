@@ -0,0 +1,310 @@
+//! Tone mapping operators that compress the dynamic range of a merged radiance buffer into a
+//! display-referred image.
+//!
+//! Global operators ([`Reinhard`], [`Drago`], [`Aces`] and [`Hable`]) apply the same curve to
+//! every pixel. Local operators ([`Durand`], [`Fattal`] and [`Mantiuk`]) adapt to the
+//! neighbourhood of every pixel, which preserves local contrast in scenes with very large
+//! dynamic range, at a higher computational cost.
+
+use crate::export::validate_buffer;
+use crate::Error;
+use image::{DynamicImage, ImageBuffer, Luma, Rgb};
+use ndarray::{Array2, Array3, Axis, Zip};
+use rayon::prelude::*;
+
+pub mod aces;
+pub mod drago;
+pub mod durand;
+pub mod fattal;
+mod gradient;
+pub mod hable;
+pub mod mantiuk;
+pub mod reinhard;
+
+pub use aces::Aces;
+pub use drago::Drago;
+pub use durand::Durand;
+pub use fattal::Fattal;
+pub use hable::Hable;
+pub use mantiuk::Mantiuk;
+pub use reinhard::Reinhard;
+
+/// Rec. 709 luminance coefficients of linear RGB.
+const LUMINANCE_COEFFICIENTS: [f32; 3] = [0.2126, 0.7152, 0.0722];
+
+/// Offset added to luminance before taking its logarithm, to avoid the singularity at black.
+pub(crate) const LOG_OFFSET: f32 = 1e-6;
+
+/// An operator that maps scene-referred radiance to display-referred values.
+pub trait ToneMapOperator {
+    /// Tone map a radiance buffer of shape `(height, width, channels)` into linear display values
+    /// in the `0..=1` range, in the same layout as the radiance.
+    ///
+    /// # Errors
+    /// - If the buffer is empty or is neither RGB nor grayscale
+    /// - If the operator is configured with invalid parameters
+    fn tone_map(&self, radiance: &Array3<f32>) -> Result<Array3<f32>, Error>;
+
+    /// Tone map a radiance buffer into a 16-bit sRGB encoded image, see
+    /// [`ToneMapOperator::tone_map`].
+    ///
+    /// # Errors
+    /// - If the buffer is empty or is neither RGB nor grayscale
+    /// - If the operator is configured with invalid parameters
+    fn to_image(&self, radiance: &Array3<f32>) -> Result<DynamicImage, Error> {
+        Ok(encode_srgb(&self.tone_map(radiance)?))
+    }
+}
+
+/// Replace negative and NaN values with zero.
+fn sanitize(value: f32) -> f32 {
+    if value.is_nan() {
+        0.
+    } else {
+        value.max(0.)
+    }
+}
+
+/// Luminance of every pixel of a radiance buffer.
+pub(crate) fn luminance(radiance: &Array3<f32>) -> Array2<f32> {
+    let (height, width, channels) = radiance.dim();
+    let mut luminance = Array2::<f32>::zeros((height, width));
+
+    Zip::from(&mut luminance)
+        .and(radiance.lanes(Axis(2)))
+        .par_for_each(|luminance, pixel| {
+            *luminance = if channels == 1 {
+                sanitize(pixel[0])
+            } else {
+                pixel
+                    .iter()
+                    .zip(LUMINANCE_COEFFICIENTS)
+                    .map(|(value, coefficient)| sanitize(*value) * coefficient)
+                    .sum()
+            };
+        });
+
+    luminance
+}
+
+/// Geometric mean of the luminance, which is a robust estimate of the adaptation level of the
+/// scene.
+#[allow(clippy::cast_precision_loss)]
+pub(crate) fn log_average(luminance: &Array2<f32>) -> f32 {
+    let sum = luminance
+        .par_iter()
+        .map(|value| f64::from((value + LOG_OFFSET).ln()))
+        .sum::<f64>();
+
+    #[allow(clippy::cast_possible_truncation)]
+    let average = (sum / luminance.len() as f64).exp() as f32;
+
+    average.max(LOG_OFFSET)
+}
+
+/// Scale the colour of every pixel so that its luminance becomes the mapped luminance. The ratios
+/// between channels are raised to the power of `saturation` first, which preserves them for a
+/// saturation of 1 and desaturates colours for lower values.
+pub(crate) fn apply_luminance(
+    radiance: &Array3<f32>,
+    luminance: &Array2<f32>,
+    mapped: &Array2<f32>,
+    saturation: f32,
+) -> Array3<f32> {
+    let mut result = radiance.mapv(sanitize);
+
+    Zip::from(result.lanes_mut(Axis(2)))
+        .and(luminance)
+        .and(mapped)
+        .par_for_each(|mut pixel, &luminance, &mapped| {
+            pixel.mapv_inplace(|value| {
+                if luminance > 0. {
+                    ((value / luminance).powf(saturation) * mapped).clamp(0., 1.)
+                } else {
+                    0.
+                }
+            });
+        });
+
+    result
+}
+
+/// Scale a radiance buffer so that its log-average luminance becomes `key`.
+pub(crate) fn expose(radiance: &Array3<f32>, key: f32) -> Array3<f32> {
+    let scale = key / log_average(&luminance(radiance));
+
+    radiance.mapv(|value| sanitize(value) * scale)
+}
+
+/// Validate that a parameter is a finite positive number.
+pub(crate) fn validate_positive(parameter_name: &str, value: f32) -> Result<(), Error> {
+    if !value.is_finite() || value <= 0. {
+        return Err(Error::InputError {
+            parameter_name: parameter_name.to_string(),
+            message: "Must be a finite positive floating point number".to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Validate the radiance buffer passed to an operator.
+pub(crate) fn validate_radiance(radiance: &Array3<f32>) -> Result<(), Error> {
+    validate_buffer(radiance).map(|_| ())
+}
+
+/// Encode a linear value in the `0..=1` range with the sRGB transfer function.
+pub(crate) fn srgb_encode(value: f32) -> f32 {
+    let value = sanitize(value).min(1.);
+
+    if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1. / 2.4) - 0.055
+    }
+}
+
+/// Encode linear display values as a 16-bit sRGB image.
+fn encode_srgb(display: &Array3<f32>) -> DynamicImage {
+    quantize(&display.mapv(srgb_encode))
+}
+
+/// Quantize encoded values in the `0..=1` range into a 16-bit image.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_sign_loss)]
+pub(crate) fn quantize(display: &Array3<f32>) -> DynamicImage {
+    let (height, width, channels) = display.dim();
+    let quantize = |value: f32| (sanitize(value).min(1.) * f32::from(u16::MAX)).round() as u16;
+
+    if channels == 1 {
+        DynamicImage::ImageLuma16(ImageBuffer::<Luma<u16>, Vec<u16>>::from_fn(
+            width as u32,
+            height as u32,
+            |x, y| Luma([quantize(display[[y as usize, x as usize, 0]])]),
+        ))
+    } else {
+        DynamicImage::ImageRgb16(ImageBuffer::<Rgb<u16>, Vec<u16>>::from_fn(
+            width as u32,
+            height as u32,
+            |x, y| {
+                let (x, y) = (x as usize, y as usize);
+                Rgb([0, 1, 2].map(|channel| quantize(display[[y, x, channel]])))
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Colour ramp spanning 24 stops, brightening in row-major order.
+    #[allow(clippy::cast_precision_loss)]
+    fn ramp() -> Array3<f32> {
+        Array3::from_shape_fn((16, 32, 3), |(y, x, channel)| {
+            let stops = (y * 32 + x) as f32 / 512. * 24. - 8.;
+
+            2_f32.powf(stops) * [1., 0.8, 0.6][channel]
+        })
+    }
+
+    #[test]
+    fn global_operators_are_monotonic_and_in_range() {
+        let operators: [Box<dyn ToneMapOperator>; 4] = [
+            Box::new(Reinhard::default()),
+            Box::new(Drago::default()),
+            Box::new(Aces::default()),
+            Box::new(Hable::default()),
+        ];
+
+        for operator in operators {
+            let display = operator
+                .tone_map(&ramp())
+                .expect("Ramp should be tone mapped");
+
+            assert!(display
+                .iter()
+                .all(|value| value.is_finite() && (0. ..=1.).contains(value)));
+
+            let luminance = luminance(&display);
+            assert!(luminance
+                .iter()
+                .zip(luminance.iter().skip(1))
+                .all(|(darker, brighter)| brighter + 1e-6 >= *darker));
+        }
+    }
+
+    #[test]
+    fn reinhard_compresses_around_log_average() {
+        // Log-average luminance of 2
+        let radiance = Array3::from_shape_fn((2, 2, 1), |(y, _, _)| if y == 0 { 1. } else { 4. });
+
+        let display = Reinhard {
+            key: 0.18,
+            white_point: Some(1e6),
+        }
+        .tone_map(&radiance)
+        .expect("Radiance should be tone mapped");
+        assert!((display[[0, 0, 0]] - 0.09 / 1.09).abs() < 1e-5);
+        assert!((display[[1, 0, 0]] - 0.36 / 1.36).abs() < 1e-5);
+
+        let display = Reinhard::default()
+            .tone_map(&ramp())
+            .expect("Ramp should be tone mapped");
+        assert!((display[[15, 31, 0]] - 1.).abs() < 1e-5);
+        assert!((display[[8, 0, 2]] / display[[8, 0, 0]] - 0.6).abs() < 1e-4);
+    }
+
+    #[test]
+    fn rejects_invalid_parameters() {
+        let result = Reinhard {
+            key: -1.,
+            white_point: None,
+        }
+        .tone_map(&ramp());
+
+        assert!(matches!(result, Err(Error::InputError { .. })));
+        assert!(Aces::default().tone_map(&Array3::zeros((4, 4, 2))).is_err());
+    }
+
+    #[test]
+    fn local_operators_preserve_detail_across_large_steps() {
+        // A checkerboard texture of ±20% on either side of a 1000:1 step
+        let radiance = Array3::from_shape_fn((32, 64, 1), |(y, x, _)| {
+            let base = if x < 32 { 1. } else { 1000. };
+            let texture = if (x / 2 + y / 2) % 2 == 0 { 1.2 } else { 0.8 };
+
+            base * texture
+        });
+        // Contrast of the texture relative to the step, in log luminance
+        let detail = |display: &Array3<f32>| {
+            let texture = |x: usize| (display[[16, x, 0]] / display[[16, x + 2, 0]]).ln();
+            let step = (display[[16, 44, 0]] / display[[16, 12, 0]]).ln();
+
+            texture(12).min(texture(44)) / step
+        };
+        let scene_detail = 1.5_f32.ln() / 1000_f32.ln();
+
+        let operators: [Box<dyn ToneMapOperator>; 3] = [
+            Box::new(Durand::default()),
+            Box::new(Fattal::default()),
+            Box::new(Mantiuk::default()),
+        ];
+
+        for operator in operators {
+            let display = operator
+                .tone_map(&radiance)
+                .expect("Radiance should be tone mapped");
+
+            assert!(display
+                .iter()
+                .all(|value| value.is_finite() && (0. ..=1.).contains(value)));
+            assert!(detail(&display) > scene_detail);
+        }
+
+        let global = Reinhard::default()
+            .tone_map(&radiance)
+            .expect("Radiance should be tone mapped");
+        assert!(detail(&global) < scene_detail);
+    }
+}
@@ -0,0 +1,39 @@
+//! Filmic tone curve of the Academy Color Encoding System (ACES) reference rendering transform, as
+//! approximated by Krzysztof Narkowicz in [ACES Filmic Tone Mapping Curve](https://knarkowicz.wordpress.com/2016/01/06/aces-filmic-tone-mapping-curve/).
+//!
+//! The curve is applied to every channel independently, which desaturates bright colours
+//! similarly to film.
+
+use crate::tonemap::{expose, validate_positive, validate_radiance, ToneMapOperator};
+use crate::Error;
+use ndarray::Array3;
+
+/// ACES filmic operator.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aces {
+    /// Luminance the log-average luminance of the scene is mapped to before applying the curve.
+    pub key: f32,
+}
+
+impl Default for Aces {
+    fn default() -> Self {
+        Self { key: 0.18 }
+    }
+}
+
+/// Rational approximation of the ACES curve.
+fn curve(value: f32) -> f32 {
+    (value * (2.51 * value + 0.03) / (value * (2.43 * value + 0.59) + 0.14)).clamp(0., 1.)
+}
+
+impl ToneMapOperator for Aces {
+    fn tone_map(&self, radiance: &Array3<f32>) -> Result<Array3<f32>, Error> {
+        validate_radiance(radiance)?;
+        validate_positive("key", self.key)?;
+
+        let mut exposed = expose(radiance, self.key);
+        exposed.par_mapv_inplace(curve);
+
+        Ok(exposed)
+    }
+}
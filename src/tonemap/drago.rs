@@ -0,0 +1,70 @@
+//! Adaptive logarithmic mapping as described in [Adaptive Logarithmic Mapping For Displaying High Contrast Scenes](https://doi.org/10.1111/1467-8659.00689)
+//! by Drago et al.
+//!
+//! Luminance is compressed logarithmically, with the base of the logarithm varying between 2 for
+//! the darkest and 10 for the brightest pixels, which preserves detail in both.
+
+use crate::tonemap::{
+    apply_luminance, log_average, luminance, validate_positive, validate_radiance, ToneMapOperator,
+};
+use crate::Error;
+use ndarray::Array3;
+
+/// Drago's adaptive logarithmic operator.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Drago {
+    /// Bias in the `0..1` range that controls the contrast of the result. Lower values produce
+    /// brighter, lower contrast images. The paper recommends values between `0.7` and `0.9`.
+    pub bias: f32,
+    /// Maximum luminance of the display in cd/m², relative to a reference of 100 cd/m².
+    pub max_display_luminance: f32,
+    /// Exposure adjustment in stops, applied to the scene after normalising it by its
+    /// log-average luminance.
+    pub exposure: f32,
+}
+
+impl Default for Drago {
+    fn default() -> Self {
+        Self {
+            bias: 0.85,
+            max_display_luminance: 100.,
+            exposure: 0.,
+        }
+    }
+}
+
+impl ToneMapOperator for Drago {
+    fn tone_map(&self, radiance: &Array3<f32>) -> Result<Array3<f32>, Error> {
+        validate_radiance(radiance)?;
+        validate_positive("max_display_luminance", self.max_display_luminance)?;
+
+        if !(self.bias > 0. && self.bias < 1.) {
+            return Err(Error::InputError {
+                parameter_name: "bias".to_string(),
+                message: "Bias must be between 0 and 1".to_string(),
+            });
+        }
+
+        if !self.exposure.is_finite() {
+            return Err(Error::InputError {
+                parameter_name: "exposure".to_string(),
+                message: "Exposure must be a finite floating point number".to_string(),
+            });
+        }
+
+        let luminance = luminance(radiance);
+        let scale = 2_f32.powf(self.exposure) / log_average(&luminance);
+
+        let mut mapped = luminance.mapv(|value| value * scale);
+        let maximum = mapped.iter().copied().fold(f32::EPSILON, f32::max);
+
+        let exponent = self.bias.ln() / 0.5_f32.ln();
+        let normalization = self.max_display_luminance / 100. / (maximum + 1.).log10();
+
+        mapped.par_mapv_inplace(|value| {
+            normalization * (value + 1.).ln() / (2. + 8. * (value / maximum).powf(exponent)).ln()
+        });
+
+        Ok(apply_luminance(radiance, &luminance, &mapped, 1.))
+    }
+}
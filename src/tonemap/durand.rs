@@ -0,0 +1,201 @@
+//! Bilateral base/detail decomposition as described in [Fast Bilateral Filtering for the Display of High-Dynamic-Range Images](https://doi.org/10.1145/566654.566574)
+//! by Durand and Dorsey.
+//!
+//! Log luminance is split into a base layer, the edge-preserving bilateral filtered image, and a
+//! detail layer, the remainder. Only the contrast of the base layer is compressed, which keeps
+//! fine detail intact. The bilateral filter is evaluated with a bilateral grid as described in
+//! [A Fast Approximation of the Bilateral Filter using a Signal Processing Approach](https://doi.org/10.1007/s11263-007-0110-8)
+//! by Paris and Durand, whose cost doesn't depend on the size of the filter.
+
+use crate::tonemap::{
+    apply_luminance, luminance, validate_positive, validate_radiance, ToneMapOperator, LOG_OFFSET,
+};
+use crate::Error;
+use ndarray::{Array2, Array3, Axis, Zip};
+
+/// Cells of padding around the bilateral grid, so that the blur doesn't wrap or clip.
+const GRID_PADDING: usize = 2;
+
+/// Binomial approximation of a Gaussian with a standard deviation of one grid cell.
+const GRID_KERNEL: [f32; 5] = [1. / 16., 4. / 16., 6. / 16., 4. / 16., 1. / 16.];
+
+/// Durand and Dorsey's bilateral operator.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Durand {
+    /// Standard deviation of the spatial kernel of the bilateral filter in pixels. Defaults to 2%
+    /// of the larger dimension of the image.
+    pub spatial_sigma: Option<f32>,
+    /// Standard deviation of the range kernel of the bilateral filter, in decades of luminance.
+    pub range_sigma: f32,
+    /// Contrast between the brightest and darkest point of the base layer after compression.
+    pub contrast: f32,
+    /// Exponent applied to the colour ratios of every pixel, lower values desaturate the result.
+    pub saturation: f32,
+}
+
+impl Default for Durand {
+    fn default() -> Self {
+        Self {
+            spatial_sigma: None,
+            range_sigma: 0.4,
+            contrast: 5.,
+            saturation: 1.,
+        }
+    }
+}
+
+/// Linear interpolation weights of a continuous grid coordinate.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_sign_loss)]
+fn cell(position: f32) -> (usize, f32) {
+    let lower = position.floor();
+
+    (lower as usize, position - lower)
+}
+
+/// Edge-preserving bilateral filter of an image using a bilateral grid.
+#[allow(clippy::cast_precision_loss)]
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_sign_loss)]
+fn bilateral_filter(image: &Array2<f32>, spatial_sigma: f32, range_sigma: f32) -> Array2<f32> {
+    let (height, width) = image.dim();
+    let minimum = image.iter().copied().fold(f32::INFINITY, f32::min);
+    let maximum = image.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+
+    let position = |y: usize, x: usize, value: f32| {
+        (
+            y as f32 / spatial_sigma + GRID_PADDING as f32,
+            x as f32 / spatial_sigma + GRID_PADDING as f32,
+            (value - minimum) / range_sigma + GRID_PADDING as f32,
+        )
+    };
+
+    let grid_size = |extent: f32| extent.ceil() as usize + 2 * GRID_PADDING + 1;
+    let shape = (
+        grid_size((height - 1) as f32 / spatial_sigma),
+        grid_size((width - 1) as f32 / spatial_sigma),
+        grid_size((maximum - minimum) / range_sigma),
+    );
+
+    // Splat every pixel into its nearest cell, accumulating the value and the weight.
+    let mut values = Array3::<f32>::zeros(shape);
+    let mut weights = Array3::<f32>::zeros(shape);
+    for ((y, x), &value) in image.indexed_iter() {
+        let (grid_y, grid_x, grid_z) = position(y, x, value);
+        let index = (
+            grid_y.round() as usize,
+            grid_x.round() as usize,
+            grid_z.round() as usize,
+        );
+
+        values[index] += value;
+        weights[index] += 1.;
+    }
+
+    for grid in [&mut values, &mut weights] {
+        for axis in 0..3 {
+            blur_axis(grid, Axis(axis));
+        }
+    }
+
+    // Slice the grid with trilinear interpolation.
+    let mut result = Array2::<f32>::zeros((height, width));
+    Zip::indexed(&mut result)
+        .and(image)
+        .par_for_each(|(y, x), result, &value| {
+            let (grid_y, grid_x, grid_z) = position(y, x, value);
+            let (y0, fraction_y) = cell(grid_y);
+            let (x0, fraction_x) = cell(grid_x);
+            let (z0, fraction_z) = cell(grid_z);
+
+            let mut sum = 0.;
+            let mut weight = 0.;
+            for (dy, wy) in [(0, 1. - fraction_y), (1, fraction_y)] {
+                for (dx, wx) in [(0, 1. - fraction_x), (1, fraction_x)] {
+                    for (dz, wz) in [(0, 1. - fraction_z), (1, fraction_z)] {
+                        let index = (y0 + dy, x0 + dx, z0 + dz);
+                        sum += wy * wx * wz * values[index];
+                        weight += wy * wx * wz * weights[index];
+                    }
+                }
+            }
+
+            *result = if weight > f32::EPSILON {
+                sum / weight
+            } else {
+                value
+            };
+        });
+
+    result
+}
+
+/// Blur a grid along one axis with [`GRID_KERNEL`].
+fn blur_axis(grid: &mut Array3<f32>, axis: Axis) {
+    let radius = GRID_KERNEL.len() / 2;
+
+    for mut lane in grid.lanes_mut(axis) {
+        let source = lane.to_vec();
+
+        for (index, value) in lane.iter_mut().enumerate() {
+            *value = GRID_KERNEL
+                .iter()
+                .enumerate()
+                .filter_map(|(offset, weight)| {
+                    (index + offset)
+                        .checked_sub(radius)
+                        .and_then(|position| source.get(position))
+                        .map(|sample| sample * weight)
+                })
+                .sum();
+        }
+    }
+}
+
+impl ToneMapOperator for Durand {
+    #[allow(clippy::cast_precision_loss)]
+    fn tone_map(&self, radiance: &Array3<f32>) -> Result<Array3<f32>, Error> {
+        validate_radiance(radiance)?;
+        validate_positive("range_sigma", self.range_sigma)?;
+        validate_positive("saturation", self.saturation)?;
+        if let Some(spatial_sigma) = self.spatial_sigma {
+            validate_positive("spatial_sigma", spatial_sigma)?;
+        }
+        if !self.contrast.is_finite() || self.contrast <= 1. {
+            return Err(Error::InputError {
+                parameter_name: "contrast".to_string(),
+                message: "Contrast must be a finite number greater than 1".to_string(),
+            });
+        }
+
+        let (height, width, _) = radiance.dim();
+        let spatial_sigma = self
+            .spatial_sigma
+            .unwrap_or(height.max(width) as f32 * 0.02)
+            .max(1.);
+
+        let luminance = luminance(radiance);
+        let log_luminance = luminance.mapv(|value| (value + LOG_OFFSET).log10());
+        let base = bilateral_filter(&log_luminance, spatial_sigma, self.range_sigma);
+
+        let minimum = base.iter().copied().fold(f32::INFINITY, f32::min);
+        let maximum = base.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let compression = self.contrast.log10() / (maximum - minimum).max(f32::EPSILON);
+
+        let mut mapped = Array2::<f32>::zeros(luminance.dim());
+        Zip::from(&mut mapped)
+            .and(&log_luminance)
+            .and(&base)
+            .par_for_each(|mapped, &log_luminance, &base| {
+                let detail = log_luminance - base;
+                *mapped = 10_f32.powf((base - maximum) * compression + detail);
+            });
+
+        Ok(apply_luminance(
+            radiance,
+            &luminance,
+            &mapped,
+            self.saturation,
+        ))
+    }
+}
@@ -0,0 +1,156 @@
+//! Gradient domain compression as described in [Gradient Domain High Dynamic Range Compression](https://doi.org/10.1145/566654.566573)
+//! by Fattal et al.
+//!
+//! Gradients of the log luminance are attenuated, large gradients more than small ones, with an
+//! attenuation that is accumulated over a Gaussian pyramid so that edges of all scales are
+//! compressed. The tone mapped luminance is the solution of the Poisson equation whose right hand
+//! side is the divergence of the attenuated gradients.
+
+use crate::tonemap::gradient::{
+    downsample, gradient, gradient_adjoint, percentile, solve_poisson, upsample,
+};
+use crate::tonemap::{
+    apply_luminance, luminance, validate_positive, validate_radiance, ToneMapOperator, LOG_OFFSET,
+};
+use crate::Error;
+use ndarray::{Array2, Array3, Zip};
+use rayon::prelude::*;
+
+/// Levels of the pyramid whose smaller dimension falls below this size are not computed.
+const MIN_PYRAMID_SIZE: usize = 32;
+
+/// Maximum number of multigrid cycles of the Poisson solver.
+const MAX_CYCLES: usize = 30;
+
+/// Residual of the Poisson solver, relative to the right hand side, at which it stops.
+const TOLERANCE: f32 = 1e-3;
+
+/// Fraction of pixels whose tone mapped luminance is at most white.
+const WHITE_PERCENTILE: f32 = 0.995;
+
+/// Fattal's gradient domain operator.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fattal {
+    /// Gradient magnitude, relative to the average gradient magnitude of every pyramid level,
+    /// above which gradients are attenuated and below which they are magnified.
+    pub alpha: f32,
+    /// Exponent of the attenuation in the `0..=1` range. Lower values compress more strongly.
+    pub beta: f32,
+    /// Exponent applied to the colour ratios of every pixel, lower values desaturate the result.
+    pub saturation: f32,
+}
+
+impl Default for Fattal {
+    fn default() -> Self {
+        Self {
+            alpha: 0.1,
+            beta: 0.85,
+            saturation: 0.8,
+        }
+    }
+}
+
+impl Fattal {
+    /// Attenuation factors of the gradients of a single pyramid level.
+    #[allow(clippy::cast_precision_loss)]
+    #[allow(clippy::cast_possible_wrap)]
+    #[allow(clippy::cast_possible_truncation)]
+    fn level_attenuation(&self, level: &Array2<f32>, depth: usize) -> Array2<f32> {
+        let (height, width) = level.dim();
+        let spacing = 2_f32.powi(depth as i32 + 1);
+
+        let mut magnitudes = Array2::<f32>::zeros((height, width));
+        Zip::indexed(&mut magnitudes).par_for_each(|(y, x), magnitude| {
+            let horizontal = level[[y, (x + 1).min(width - 1)]] - level[[y, x.saturating_sub(1)]];
+            let vertical = level[[(y + 1).min(height - 1), x]] - level[[y.saturating_sub(1), x]];
+
+            *magnitude = horizontal.hypot(vertical) / spacing;
+        });
+
+        let average = magnitudes.par_iter().sum::<f32>() / magnitudes.len() as f32;
+        let alpha = self.alpha * average;
+
+        if alpha <= 0. {
+            return Array2::ones((height, width));
+        }
+
+        magnitudes.mapv(|magnitude| (magnitude.max(f32::EPSILON) / alpha).powf(self.beta - 1.))
+    }
+
+    /// Attenuation factors of the full resolution gradients, accumulated from coarse to fine.
+    fn attenuation(&self, log_luminance: &Array2<f32>) -> Array2<f32> {
+        let mut pyramid = vec![log_luminance.clone()];
+        while let Some(level) = pyramid.last() {
+            if level.nrows().min(level.ncols()) / 2 < MIN_PYRAMID_SIZE {
+                break;
+            }
+
+            let next = downsample(level);
+            pyramid.push(next);
+        }
+
+        pyramid
+            .iter()
+            .enumerate()
+            .rev()
+            .fold(None, |coarser: Option<Array2<f32>>, (depth, level)| {
+                let attenuation = self.level_attenuation(level, depth);
+
+                Some(match coarser {
+                    Some(coarser) => upsample(&coarser, level.dim()) * attenuation,
+                    None => attenuation,
+                })
+            })
+            .unwrap_or_else(|| Array2::ones(log_luminance.dim()))
+    }
+}
+
+impl ToneMapOperator for Fattal {
+    fn tone_map(&self, radiance: &Array3<f32>) -> Result<Array3<f32>, Error> {
+        validate_radiance(radiance)?;
+        validate_positive("alpha", self.alpha)?;
+        validate_positive("saturation", self.saturation)?;
+        if !(self.beta > 0. && self.beta <= 1.) {
+            return Err(Error::InputError {
+                parameter_name: "beta".to_string(),
+                message: "Beta must be greater than 0 and at most 1".to_string(),
+            });
+        }
+
+        let luminance = luminance(radiance);
+        let log_luminance = luminance.mapv(|value| (value + LOG_OFFSET).ln());
+        let attenuation = self.attenuation(&log_luminance);
+        let (height, width) = log_luminance.dim();
+
+        let (mut horizontal, mut vertical) = gradient(&log_luminance);
+        Zip::indexed(&mut horizontal)
+            .and(&mut vertical)
+            .par_for_each(|(y, x), horizontal, vertical| {
+                let centre = attenuation[[y, x]];
+                if x + 1 < width {
+                    *horizontal *= f32::midpoint(centre, attenuation[[y, x + 1]]);
+                }
+                if y + 1 < height {
+                    *vertical *= f32::midpoint(centre, attenuation[[y + 1, x]]);
+                }
+            });
+
+        let mut solution = log_luminance;
+        solve_poisson(
+            &mut solution,
+            &gradient_adjoint(&horizontal, &vertical),
+            MAX_CYCLES,
+            TOLERANCE,
+        );
+
+        let white = percentile(&solution, WHITE_PERCENTILE);
+        let mapped = solution.mapv(|value| (value - white).exp());
+
+        Ok(apply_luminance(
+            radiance,
+            &luminance,
+            &mapped,
+            self.saturation,
+        ))
+    }
+}
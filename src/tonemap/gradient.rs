@@ -0,0 +1,310 @@
+//! Building blocks shared by the gradient domain operators: image pyramids, finite differences
+//! and solvers for the resulting linear systems.
+//!
+//! Differences and the Laplacian use Neumann boundaries, i.e. there is no gradient across the
+//! border of the image. The Laplacian is the graph Laplacian of the pixel grid, which is the
+//! product of the adjoint of [`gradient`] with [`gradient`].
+
+use ndarray::{Array2, Zip};
+use rayon::prelude::*;
+
+/// Grids whose larger dimension is at most this size are solved directly by relaxation.
+const COARSEST_SIZE: usize = 4;
+
+/// Number of relaxation sweeps on the coarsest grid.
+const COARSEST_SWEEPS: usize = 64;
+
+/// Number of relaxation sweeps before and after the coarse grid correction.
+const SMOOTHING_SWEEPS: usize = 3;
+
+/// Damping of the Jacobi relaxation, optimal for the 5-point Laplacian.
+const JACOBI_DAMPING: f32 = 0.8;
+
+/// Downsample an image by a factor of two by averaging blocks of 2x2 pixels. Blocks at the border
+/// of images with odd dimensions average the pixels that exist.
+pub(super) fn downsample(image: &Array2<f32>) -> Array2<f32> {
+    let (height, width) = image.dim();
+
+    Array2::from_shape_fn((height.div_ceil(2), width.div_ceil(2)), |(y, x)| {
+        let rows = 2 * y..(2 * y + 2).min(height);
+        let columns = 2 * x..(2 * x + 2).min(width);
+        #[allow(clippy::cast_precision_loss)]
+        let count = (rows.len() * columns.len()) as f32;
+
+        rows.flat_map(|row| columns.clone().map(move |column| (row, column)))
+            .map(|index| image[index])
+            .sum::<f32>()
+            / count
+    })
+}
+
+/// Adjoint of [`downsample`], spreading every coarse pixel evenly over its block of fine pixels.
+pub(super) fn downsample_adjoint(coarse: &Array2<f32>, shape: (usize, usize)) -> Array2<f32> {
+    let (height, width) = shape;
+
+    Array2::from_shape_fn(shape, |(y, x)| {
+        let rows = (y / 2 * 2 + 2).min(height) - y / 2 * 2;
+        let columns = (x / 2 * 2 + 2).min(width) - x / 2 * 2;
+        #[allow(clippy::cast_precision_loss)]
+        let count = (rows * columns) as f32;
+
+        coarse[[y / 2, x / 2]] / count
+    })
+}
+
+/// Upsample an image to given shape with bilinear interpolation between pixel centres.
+#[allow(clippy::cast_precision_loss)]
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_sign_loss)]
+pub(super) fn upsample(image: &Array2<f32>, shape: (usize, usize)) -> Array2<f32> {
+    let (source_height, source_width) = image.dim();
+    let scale_y = source_height as f32 / shape.0 as f32;
+    let scale_x = source_width as f32 / shape.1 as f32;
+
+    let sample = |position: f32, size: usize| {
+        let position = position.clamp(0., (size - 1) as f32);
+        let lower = (position.floor() as usize).min(size.saturating_sub(2));
+        let upper = (lower + 1).min(size - 1);
+
+        (lower, upper, position - lower as f32)
+    };
+
+    let mut result = Array2::<f32>::zeros(shape);
+
+    Zip::indexed(&mut result).par_for_each(|(y, x), value| {
+        let (top, bottom, fraction_y) = sample((y as f32 + 0.5) * scale_y - 0.5, source_height);
+        let (left, right, fraction_x) = sample((x as f32 + 0.5) * scale_x - 0.5, source_width);
+
+        let upper = image[[top, left]] * (1. - fraction_x) + image[[top, right]] * fraction_x;
+        let lower = image[[bottom, left]] * (1. - fraction_x) + image[[bottom, right]] * fraction_x;
+
+        *value = upper * (1. - fraction_y) + lower * fraction_y;
+    });
+
+    result
+}
+
+/// Forward differences of an image in horizontal and vertical direction. Differences across the
+/// right and bottom border are zero.
+pub(super) fn gradient(image: &Array2<f32>) -> (Array2<f32>, Array2<f32>) {
+    let (height, width) = image.dim();
+    let mut horizontal = Array2::<f32>::zeros((height, width));
+    let mut vertical = Array2::<f32>::zeros((height, width));
+
+    Zip::indexed(&mut horizontal)
+        .and(&mut vertical)
+        .par_for_each(|(y, x), horizontal, vertical| {
+            if x + 1 < width {
+                *horizontal = image[[y, x + 1]] - image[[y, x]];
+            }
+            if y + 1 < height {
+                *vertical = image[[y + 1, x]] - image[[y, x]];
+            }
+        });
+
+    (horizontal, vertical)
+}
+
+/// Adjoint of [`gradient`], i.e. the negative divergence of a vector field.
+pub(super) fn gradient_adjoint(horizontal: &Array2<f32>, vertical: &Array2<f32>) -> Array2<f32> {
+    let (height, width) = horizontal.dim();
+    let mut result = Array2::<f32>::zeros((height, width));
+
+    Zip::indexed(&mut result).par_for_each(|(y, x), value| {
+        let mut sum = 0.;
+        if x + 1 < width {
+            sum -= horizontal[[y, x]];
+        }
+        if x > 0 {
+            sum += horizontal[[y, x - 1]];
+        }
+        if y + 1 < height {
+            sum -= vertical[[y, x]];
+        }
+        if y > 0 {
+            sum += vertical[[y - 1, x]];
+        }
+
+        *value = sum;
+    });
+
+    result
+}
+
+/// Number of neighbours of a pixel.
+fn degree((y, x): (usize, usize), (height, width): (usize, usize)) -> f32 {
+    f32::from(
+        u8::from(y > 0) + u8::from(y + 1 < height) + u8::from(x > 0) + u8::from(x + 1 < width),
+    )
+}
+
+/// Apply the graph Laplacian, i.e. the sum of the differences between a pixel and each of its
+/// neighbours.
+pub(super) fn laplacian(image: &Array2<f32>) -> Array2<f32> {
+    let (height, width) = image.dim();
+    let mut result = Array2::<f32>::zeros((height, width));
+
+    Zip::indexed(&mut result).par_for_each(|(y, x), value| {
+        let centre = image[[y, x]];
+        let mut sum = 0.;
+        if y > 0 {
+            sum += centre - image[[y - 1, x]];
+        }
+        if y + 1 < height {
+            sum += centre - image[[y + 1, x]];
+        }
+        if x > 0 {
+            sum += centre - image[[y, x - 1]];
+        }
+        if x + 1 < width {
+            sum += centre - image[[y, x + 1]];
+        }
+
+        *value = sum;
+    });
+
+    result
+}
+
+/// Dot product of two images, accumulated in double precision.
+pub(super) fn dot(first: &Array2<f32>, second: &Array2<f32>) -> f64 {
+    Zip::from(first)
+        .and(second)
+        .into_par_iter()
+        .map(|(first, second)| f64::from(*first) * f64::from(*second))
+        .sum()
+}
+
+/// Relax `laplacian(solution) = rhs` with damped Jacobi sweeps.
+fn smooth(solution: &mut Array2<f32>, rhs: &Array2<f32>, sweeps: usize) {
+    let shape = solution.dim();
+
+    for _ in 0..sweeps {
+        let residual = rhs - &laplacian(solution);
+
+        Zip::indexed(&mut *solution)
+            .and(&residual)
+            .par_for_each(|index, value, residual| {
+                let degree = degree(index, shape);
+                if degree > 0. {
+                    *value += JACOBI_DAMPING * residual / degree;
+                }
+            });
+    }
+}
+
+/// Sum blocks of 2x2 pixels, which restricts a residual to the next coarser grid.
+#[allow(clippy::cast_precision_loss)]
+fn restrict(residual: &Array2<f32>) -> Array2<f32> {
+    let (height, width) = residual.dim();
+
+    let mut coarse = downsample(residual);
+    Zip::indexed(&mut coarse).par_for_each(|(y, x), value| {
+        let rows = (2 * y + 2).min(height) - 2 * y;
+        let columns = (2 * x + 2).min(width) - 2 * x;
+        *value *= (rows * columns) as f32;
+    });
+
+    coarse
+}
+
+/// A single multigrid V-cycle for `laplacian(solution) = rhs`.
+fn v_cycle(solution: &mut Array2<f32>, rhs: &Array2<f32>) {
+    let shape = solution.dim();
+
+    if shape.0.max(shape.1) <= COARSEST_SIZE {
+        smooth(solution, rhs, COARSEST_SWEEPS);
+        return;
+    }
+
+    smooth(solution, rhs, SMOOTHING_SWEEPS);
+
+    let coarse_rhs = restrict(&(rhs - &laplacian(solution)));
+    let mut correction = Array2::<f32>::zeros(coarse_rhs.dim());
+    v_cycle(&mut correction, &coarse_rhs);
+    *solution += &upsample(&correction, shape);
+
+    smooth(solution, rhs, SMOOTHING_SWEEPS);
+}
+
+/// Solve the Poisson equation `laplacian(solution) = rhs` with Neumann boundaries using multigrid
+/// V-cycles, starting from the current value of `solution`. The mean of `rhs` is removed first so
+/// that the system has a solution, which is unique up to a constant.
+#[allow(clippy::cast_precision_loss)]
+pub(super) fn solve_poisson(
+    solution: &mut Array2<f32>,
+    rhs: &Array2<f32>,
+    max_cycles: usize,
+    tolerance: f32,
+) {
+    let mean = rhs.par_iter().map(|value| f64::from(*value)).sum::<f64>() / rhs.len() as f64;
+    #[allow(clippy::cast_possible_truncation)]
+    let rhs = rhs - mean as f32;
+    let target = f64::from(tolerance).powi(2) * dot(&rhs, &rhs);
+
+    for _ in 0..max_cycles {
+        v_cycle(solution, &rhs);
+
+        let residual = &rhs - &laplacian(solution);
+        if dot(&residual, &residual) <= target {
+            break;
+        }
+    }
+}
+
+/// Solve `operator(solution) = rhs` for a symmetric positive semi-definite operator with the
+/// conjugate gradient method, starting from the current value of `solution`.
+#[allow(clippy::cast_possible_truncation)]
+pub(super) fn conjugate_gradient<F>(
+    solution: &mut Array2<f32>,
+    rhs: &Array2<f32>,
+    operator: F,
+    iterations: usize,
+    tolerance: f32,
+) where
+    F: Fn(&Array2<f32>) -> Array2<f32>,
+{
+    let mut residual = rhs - &operator(solution);
+    let mut direction = residual.clone();
+    let mut residual_norm = dot(&residual, &residual);
+    let target = f64::from(tolerance).powi(2) * dot(rhs, rhs);
+
+    for _ in 0..iterations {
+        if residual_norm <= target {
+            break;
+        }
+
+        let projected = operator(&direction);
+        let curvature = dot(&direction, &projected);
+        if curvature <= 0. {
+            break;
+        }
+
+        let step = (residual_norm / curvature) as f32;
+        Zip::from(&mut *solution)
+            .and(&direction)
+            .par_for_each(|value, direction| *value += step * direction);
+        Zip::from(&mut residual)
+            .and(&projected)
+            .par_for_each(|value, projected| *value -= step * projected);
+
+        let next_norm = dot(&residual, &residual);
+        let beta = (next_norm / residual_norm) as f32;
+        Zip::from(&mut direction)
+            .and(&residual)
+            .par_for_each(|direction, residual| *direction = residual + beta * *direction);
+
+        residual_norm = next_norm;
+    }
+}
+
+/// Value below which given fraction of the values of an image lie.
+#[allow(clippy::cast_precision_loss)]
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_sign_loss)]
+pub(super) fn percentile(image: &Array2<f32>, fraction: f32) -> f32 {
+    let mut values = image.iter().copied().collect::<Vec<f32>>();
+    let index = ((values.len() - 1) as f32 * fraction).round() as usize;
+
+    *values.select_nth_unstable_by(index, f32::total_cmp).1
+}
@@ -0,0 +1,59 @@
+//! Filmic tone curve designed by John Hable for Uncharted 2, as described in [Filmic Tonemapping Operators](http://filmicworlds.com/blog/filmic-tonemapping-operators/).
+//!
+//! The curve has a linear section for mid-tones, a toe for the shadows and a shoulder for the
+//! highlights, and is applied to every channel independently.
+
+use crate::tonemap::{expose, validate_positive, validate_radiance, ToneMapOperator};
+use crate::Error;
+use ndarray::Array3;
+
+// Parameters of the curve, named and valued as in the original implementation.
+const SHOULDER_STRENGTH: f32 = 0.15;
+const LINEAR_STRENGTH: f32 = 0.5;
+const LINEAR_ANGLE: f32 = 0.1;
+const TOE_STRENGTH: f32 = 0.2;
+const TOE_NUMERATOR: f32 = 0.02;
+const TOE_DENOMINATOR: f32 = 0.3;
+
+/// Exposure bias applied before the curve in the original implementation.
+const EXPOSURE_BIAS: f32 = 2.;
+
+/// Hable's filmic operator.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Hable {
+    /// Luminance the log-average luminance of the scene is mapped to before applying the curve.
+    pub key: f32,
+    /// Exposed value that is mapped to pure white.
+    pub white_point: f32,
+}
+
+impl Default for Hable {
+    fn default() -> Self {
+        Self {
+            key: 0.18,
+            white_point: 11.2,
+        }
+    }
+}
+
+/// The unnormalized filmic curve.
+fn curve(value: f32) -> f32 {
+    (value * (SHOULDER_STRENGTH * value + LINEAR_ANGLE * LINEAR_STRENGTH)
+        + TOE_STRENGTH * TOE_NUMERATOR)
+        / (value * (SHOULDER_STRENGTH * value + LINEAR_STRENGTH) + TOE_STRENGTH * TOE_DENOMINATOR)
+        - TOE_NUMERATOR / TOE_DENOMINATOR
+}
+
+impl ToneMapOperator for Hable {
+    fn tone_map(&self, radiance: &Array3<f32>) -> Result<Array3<f32>, Error> {
+        validate_radiance(radiance)?;
+        validate_positive("key", self.key)?;
+        validate_positive("white_point", self.white_point)?;
+
+        let white = curve(self.white_point);
+        let mut exposed = expose(radiance, self.key);
+        exposed.par_mapv_inplace(|value| (curve(value * EXPOSURE_BIAS) / white).clamp(0., 1.));
+
+        Ok(exposed)
+    }
+}
@@ -0,0 +1,163 @@
+//! Contrast equalization as described in [A Perceptual Framework for Contrast Processing of High Dynamic Range Images](https://doi.org/10.1145/1166087.1166095)
+//! by Mantiuk et al.
+//!
+//! Contrast is measured as differences of log luminance between neighbouring pixels at every
+//! level of a Gaussian pyramid. The magnitudes of all contrasts are equalized by replacing them
+//! with their rank, which compresses the rare large contrasts of the light sources and magnifies
+//! the frequent small contrasts of textures. The image whose contrasts best match the equalized
+//! ones in the least squares sense is found with conjugate gradients.
+
+use crate::tonemap::gradient::{
+    conjugate_gradient, downsample, downsample_adjoint, gradient, gradient_adjoint, laplacian,
+    percentile,
+};
+use crate::tonemap::{
+    apply_luminance, luminance, validate_positive, validate_radiance, ToneMapOperator, LOG_OFFSET,
+};
+use crate::Error;
+use ndarray::{Array2, Array3, Zip};
+use rayon::prelude::*;
+
+/// Levels of the pyramid whose smaller dimension falls below this size are not computed.
+const MIN_PYRAMID_SIZE: usize = 8;
+
+/// Residual of the solver, relative to the right hand side, at which it stops.
+const TOLERANCE: f32 = 1e-3;
+
+/// Fraction of pixels whose tone mapped luminance is at most white.
+const WHITE_PERCENTILE: f32 = 0.995;
+
+/// Mantiuk's contrast equalization operator.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mantiuk {
+    /// Largest contrast between neighbouring pixels after equalization, in decades of luminance.
+    /// Higher values produce more detailed, lower values flatter results.
+    pub contrast: f32,
+    /// Exponent applied to the colour ratios of every pixel, lower values desaturate the result.
+    pub saturation: f32,
+    /// Maximum number of conjugate gradient iterations.
+    pub iterations: usize,
+}
+
+impl Default for Mantiuk {
+    fn default() -> Self {
+        Self {
+            contrast: 0.1,
+            saturation: 0.8,
+            iterations: 200,
+        }
+    }
+}
+
+/// Build a pyramid of an image, from finest to coarsest level.
+fn pyramid(image: &Array2<f32>, levels: usize) -> Vec<Array2<f32>> {
+    let mut pyramid = Vec::with_capacity(levels);
+    pyramid.push(image.clone());
+
+    while pyramid.len() < levels {
+        let next = downsample(&pyramid[pyramid.len() - 1]);
+        pyramid.push(next);
+    }
+
+    pyramid
+}
+
+/// Number of levels of the pyramid of an image with given shape.
+fn level_count((mut height, mut width): (usize, usize)) -> usize {
+    let mut levels = 1;
+
+    while height.min(width) / 2 >= MIN_PYRAMID_SIZE {
+        height = height.div_ceil(2);
+        width = width.div_ceil(2);
+        levels += 1;
+    }
+
+    levels
+}
+
+/// Sum per-level images of a pyramid after bringing them back to the finest level with the
+/// adjoint of the downsampling, i.e. the adjoint of [`pyramid`].
+fn collapse(levels: Vec<Array2<f32>>) -> Array2<f32> {
+    levels
+        .into_iter()
+        .rev()
+        .reduce(|coarser, level| downsample_adjoint(&coarser, level.dim()) + level)
+        .unwrap_or_else(|| panic!("Expected at least one pyramid level"))
+}
+
+/// Replace the gradient magnitudes of all levels with their rank among all magnitudes, scaled to
+/// the `0..=contrast` range, keeping their directions.
+#[allow(clippy::cast_precision_loss)]
+fn equalize(gradients: &mut [(Array2<f32>, Array2<f32>)], contrast: f32) {
+    let mut magnitudes = gradients
+        .iter()
+        .flat_map(|(horizontal, vertical)| {
+            horizontal
+                .iter()
+                .zip(vertical)
+                .map(|(horizontal, vertical)| horizontal.hypot(*vertical))
+        })
+        .collect::<Vec<f32>>();
+    magnitudes.par_sort_unstable_by(f32::total_cmp);
+
+    let count = magnitudes.len() as f32;
+    let rank =
+        |magnitude: f32| magnitudes.partition_point(|&value| value < magnitude) as f32 / count;
+
+    for (horizontal, vertical) in gradients {
+        Zip::from(horizontal)
+            .and(vertical)
+            .par_for_each(|horizontal, vertical| {
+                let magnitude = horizontal.hypot(*vertical);
+                if magnitude > 0. {
+                    let scale = contrast * rank(magnitude) / magnitude;
+                    *horizontal *= scale;
+                    *vertical *= scale;
+                }
+            });
+    }
+}
+
+impl ToneMapOperator for Mantiuk {
+    fn tone_map(&self, radiance: &Array3<f32>) -> Result<Array3<f32>, Error> {
+        validate_radiance(radiance)?;
+        validate_positive("contrast", self.contrast)?;
+        validate_positive("saturation", self.saturation)?;
+
+        let luminance = luminance(radiance);
+        let log_luminance = luminance.mapv(|value| (value + LOG_OFFSET).log10());
+        let levels = level_count(log_luminance.dim());
+
+        let mut gradients = pyramid(&log_luminance, levels)
+            .iter()
+            .map(gradient)
+            .collect::<Vec<(Array2<f32>, Array2<f32>)>>();
+        equalize(&mut gradients, self.contrast);
+
+        let rhs = collapse(
+            gradients
+                .iter()
+                .map(|(horizontal, vertical)| gradient_adjoint(horizontal, vertical))
+                .collect(),
+        );
+
+        let mut solution = log_luminance;
+        conjugate_gradient(
+            &mut solution,
+            &rhs,
+            |image| collapse(pyramid(image, levels).iter().map(laplacian).collect()),
+            self.iterations,
+            TOLERANCE,
+        );
+
+        let white = percentile(&solution, WHITE_PERCENTILE);
+        let mapped = solution.mapv(|value| 10_f32.powf(value - white));
+
+        Ok(apply_luminance(
+            radiance,
+            &luminance,
+            &mapped,
+            self.saturation,
+        ))
+    }
+}
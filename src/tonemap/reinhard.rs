@@ -0,0 +1,54 @@
+//! Photographic tone reproduction as described in [Photographic Tone Reproduction for Digital Images](https://doi.org/10.1145/566654.566575)
+//! by Reinhard et al.
+//!
+//! The scene is exposed so that its log-average luminance matches the key, and luminance is then
+//! compressed with `L * (1 + L / white²) / (1 + L)`, which maps the white point to 1.
+
+use crate::tonemap::{
+    apply_luminance, log_average, luminance, validate_positive, validate_radiance, ToneMapOperator,
+};
+use crate::Error;
+use ndarray::Array3;
+
+/// Reinhard's global photographic operator.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Reinhard {
+    /// Luminance the log-average luminance of the scene is mapped to before compression, e.g.
+    /// `0.18` for an average scene, higher for bright scenes and lower for dark ones.
+    pub key: f32,
+    /// Smallest exposed luminance that is mapped to pure white. Defaults to the largest luminance
+    /// of the scene, so that no pixel is clipped.
+    pub white_point: Option<f32>,
+}
+
+impl Default for Reinhard {
+    fn default() -> Self {
+        Self {
+            key: 0.18,
+            white_point: None,
+        }
+    }
+}
+
+impl ToneMapOperator for Reinhard {
+    fn tone_map(&self, radiance: &Array3<f32>) -> Result<Array3<f32>, Error> {
+        validate_radiance(radiance)?;
+        validate_positive("key", self.key)?;
+        if let Some(white_point) = self.white_point {
+            validate_positive("white_point", white_point)?;
+        }
+
+        let luminance = luminance(radiance);
+        let scale = self.key / log_average(&luminance);
+
+        let mut mapped = luminance.mapv(|value| value * scale);
+        let white_point = self
+            .white_point
+            .unwrap_or_else(|| mapped.iter().copied().fold(f32::EPSILON, f32::max));
+        let white_squared = white_point.powi(2);
+
+        mapped.par_mapv_inplace(|value| value * (1. + value / white_squared) / (1. + value));
+
+        Ok(apply_luminance(radiance, &luminance, &mapped, 1.))
+    }
+}
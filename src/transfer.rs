@@ -0,0 +1,363 @@
+//! Transfer functions (a.k.a. gamma curves) used to encode pixel values, and conversion of encoded
+//! pixel values back to linear light.
+
+use crate::input::HDRInput;
+use crate::response::ResponseCurve;
+use crate::Error;
+use image::DynamicImage;
+use ndarray::Array3;
+
+/// Number of levels an ICC tone reproduction curve is sampled at.
+const ICC_CURVE_LEVELS: usize = 4096;
+
+/// Transfer function that was used to encode the pixel values of an image.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransferFunction {
+    /// Pixel values are proportional to linear light.
+    Linear,
+    /// The piecewise sRGB transfer function (IEC 61966-2-1).
+    Srgb,
+    /// The Rec. 709 (BT.709) camera transfer function.
+    Rec709,
+    /// A pure power law with given exponent, e.g. `2.2`.
+    Gamma(f32),
+    /// An arbitrary per-channel curve, e.g. recovered from a bracket or read from an ICC profile.
+    Curve(ResponseCurve),
+}
+
+impl TransferFunction {
+    /// Guess the transfer function of a decoded image in absence of any colour metadata.
+    /// Floating point images are assumed to be linear, integer images are assumed to be sRGB.
+    #[must_use]
+    pub fn guess(image: &DynamicImage) -> Self {
+        match image {
+            DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => Self::Linear,
+            _ => Self::Srgb,
+        }
+    }
+
+    /// Read the transfer function from the tone reproduction curves of an ICC profile.
+    ///
+    /// # Errors
+    /// - If the profile is malformed
+    /// - If the profile doesn't contain tone reproduction curves, e.g. because it is a LUT based
+    ///   profile
+    pub fn from_icc_profile(profile: &[u8]) -> Result<Self, Error> {
+        let tags = icc::tags(profile)?;
+
+        let signatures: &[&[u8; 4]] = if tags.iter().any(|(signature, _)| signature == b"rTRC") {
+            &[b"rTRC", b"gTRC", b"bTRC"]
+        } else {
+            &[b"kTRC"]
+        };
+
+        let channels = signatures
+            .iter()
+            .map(|signature| {
+                let data = tags
+                    .iter()
+                    .find(|(tag, _)| tag == *signature)
+                    .map(|(_, data)| *data)
+                    .ok_or_else(|| icc::invalid("missing tone reproduction curve"))?;
+
+                icc::sample_curve(data, ICC_CURVE_LEVELS)
+            })
+            .collect::<Result<Vec<Vec<f32>>, Error>>()?;
+
+        Ok(Self::Curve(ResponseCurve::new(channels)?))
+    }
+
+    /// Convert a single encoded value in the `0..=1` range into linear light. Returns `None` for
+    /// [`TransferFunction::Curve`], which is channel dependent.
+    fn to_linear(&self, value: f32) -> Option<f32> {
+        let value = value.clamp(0., 1.);
+
+        match self {
+            Self::Linear => Some(value),
+            Self::Srgb => Some(if value <= 0.040_45 {
+                value / 12.92
+            } else {
+                ((value + 0.055) / 1.055).powf(2.4)
+            }),
+            Self::Rec709 => Some(if value < 0.081 {
+                value / 4.5
+            } else {
+                ((value + 0.099) / 1.099).powf(1. / 0.45)
+            }),
+            Self::Gamma(gamma) => Some(value.powf(*gamma)),
+            Self::Curve(_) => None,
+        }
+    }
+
+    /// Convert a buffer of encoded pixel values into linear light in place.
+    ///
+    /// # Errors
+    /// - If the gamma exponent is not a finite positive number
+    /// - If the curve doesn't match the channels of the buffer
+    pub fn linearize_buffer(&self, buffer: &mut Array3<f32>) -> Result<(), Error> {
+        match self {
+            Self::Linear => Ok(()),
+            Self::Curve(curve) => curve.linearize_buffer(buffer),
+            Self::Gamma(gamma) if !gamma.is_finite() || *gamma <= 0. => Err(Error::InputError {
+                parameter_name: "gamma".to_string(),
+                message: "Gamma must be a finite positive floating point number".to_string(),
+            }),
+            _ => {
+                buffer.par_mapv_inplace(|value| self.to_linear(value).unwrap_or(value));
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Convert the buffer of an input into linear light in place.
+    ///
+    /// # Errors
+    /// - If the gamma exponent is not a finite positive number
+    /// - If the curve doesn't match the channels of the input
+    pub fn linearize(&self, input: &mut HDRInput) -> Result<(), Error> {
+        self.linearize_buffer(input.get_buffer_mut())
+    }
+}
+
+/// Minimal reader for the parts of ICC profiles needed to recover tone reproduction curves.
+mod icc {
+    use crate::Error;
+
+    /// Size of the fixed ICC profile header.
+    const HEADER_SIZE: usize = 128;
+
+    pub(super) fn invalid(message: &str) -> Error {
+        Error::IoError(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Invalid ICC profile: {message}"),
+        ))
+    }
+
+    fn read_u16(data: &[u8], offset: usize) -> Result<u16, Error> {
+        data.get(offset..offset + 2)
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(u16::from_be_bytes)
+            .ok_or_else(|| invalid("unexpected end of data"))
+    }
+
+    fn read_u32(data: &[u8], offset: usize) -> Result<u32, Error> {
+        data.get(offset..offset + 4)
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(u32::from_be_bytes)
+            .ok_or_else(|| invalid("unexpected end of data"))
+    }
+
+    fn read_usize(data: &[u8], offset: usize) -> Result<usize, Error> {
+        usize::try_from(read_u32(data, offset)?).map_err(|_| invalid("offset out of range"))
+    }
+
+    /// Read a `s15Fixed16Number`.
+    #[allow(clippy::cast_precision_loss)]
+    fn read_fixed(data: &[u8], offset: usize) -> Result<f32, Error> {
+        Ok(read_u32(data, offset)?.cast_signed() as f32 / 65536.)
+    }
+
+    /// Signature and data of a tag.
+    pub(super) type Tag<'a> = ([u8; 4], &'a [u8]);
+
+    /// Get the signature and data of every tag in the profile.
+    pub(super) fn tags(profile: &[u8]) -> Result<Vec<Tag<'_>>, Error> {
+        let count = read_usize(profile, HEADER_SIZE)?;
+
+        (0..count)
+            .map(|tag| {
+                let entry = HEADER_SIZE + 4 + tag * 12;
+                let signature = profile
+                    .get(entry..entry + 4)
+                    .and_then(|bytes| bytes.try_into().ok())
+                    .ok_or_else(|| invalid("unexpected end of tag table"))?;
+                let offset = read_usize(profile, entry + 4)?;
+                let size = read_usize(profile, entry + 8)?;
+                let data = profile
+                    .get(offset..offset.saturating_add(size))
+                    .ok_or_else(|| invalid("tag data out of range"))?;
+
+                Ok((signature, data))
+            })
+            .collect()
+    }
+
+    /// Evaluate a parametric curve (`para` tag) of given function type at `x`. Parameter names
+    /// follow the ICC specification.
+    #[allow(clippy::many_single_char_names)]
+    fn parametric(function: u16, parameters: &[f32], x: f32) -> Result<f32, Error> {
+        let parameter = |index: usize| {
+            parameters
+                .get(index)
+                .copied()
+                .ok_or_else(|| invalid("missing parametric curve parameter"))
+        };
+        let g = parameter(0)?;
+
+        Ok(match function {
+            0 => x.powf(g),
+            1 => {
+                let (a, b) = (parameter(1)?, parameter(2)?);
+                if x >= -b / a {
+                    (a * x + b).powf(g)
+                } else {
+                    0.
+                }
+            }
+            2 => {
+                let (a, b, c) = (parameter(1)?, parameter(2)?, parameter(3)?);
+                if x >= -b / a {
+                    (a * x + b).powf(g) + c
+                } else {
+                    c
+                }
+            }
+            3 => {
+                let (a, b, c, d) = (parameter(1)?, parameter(2)?, parameter(3)?, parameter(4)?);
+                if x >= d {
+                    (a * x + b).powf(g)
+                } else {
+                    c * x
+                }
+            }
+            4 => {
+                let (a, b, c, d) = (parameter(1)?, parameter(2)?, parameter(3)?, parameter(4)?);
+                let (e, f) = (parameter(5)?, parameter(6)?);
+                if x >= d {
+                    (a * x + b).powf(g) + e
+                } else {
+                    c * x + f
+                }
+            }
+            _ => return Err(invalid("unknown parametric curve type")),
+        })
+    }
+
+    /// Sample a `curv` or `para` tag at evenly spaced levels.
+    #[allow(clippy::cast_precision_loss)]
+    pub(super) fn sample_curve(data: &[u8], levels: usize) -> Result<Vec<f32>, Error> {
+        let positions = (0..levels).map(|level| level as f32 / (levels - 1) as f32);
+
+        match data.get(0..4) {
+            Some(b"curv") => {
+                let count = read_usize(data, 8)?;
+                let table = (0..count)
+                    .map(|entry| read_u16(data, 12 + entry * 2))
+                    .collect::<Result<Vec<u16>, Error>>()?;
+
+                Ok(match table[..] {
+                    [] => positions.collect(),
+                    [gamma] => {
+                        let gamma = f32::from(gamma) / 256.;
+                        positions.map(|x| x.powf(gamma)).collect()
+                    }
+                    _ => positions
+                        .map(|x| {
+                            let position = x * (table.len() - 1) as f32;
+                            #[allow(clippy::cast_possible_truncation)]
+                            #[allow(clippy::cast_sign_loss)]
+                            let lower = (position.floor() as usize).min(table.len() - 2);
+                            let fraction = position - lower as f32;
+
+                            (f32::from(table[lower]) * (1. - fraction)
+                                + f32::from(table[lower + 1]) * fraction)
+                                / f32::from(u16::MAX)
+                        })
+                        .collect(),
+                })
+            }
+            Some(b"para") => {
+                let function = read_u16(data, 8)?;
+                let parameters = (0..7)
+                    .map_while(|index| read_fixed(data, 12 + index * 4).ok())
+                    .collect::<Vec<f32>>();
+
+                positions
+                    .map(|x| parametric(function, &parameters, x))
+                    .collect()
+            }
+            _ => Err(invalid("unsupported tone reproduction curve type")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ICC profile with a single grayscale tone reproduction curve of the given gamma.
+    fn gray_profile(gamma: f32) -> Vec<u8> {
+        #[allow(clippy::cast_possible_truncation)]
+        #[allow(clippy::cast_sign_loss)]
+        let gamma = (gamma * 256.).round() as u16;
+
+        let mut profile = vec![0; 128];
+        profile.extend(1_u32.to_be_bytes());
+        profile.extend(b"kTRC");
+        profile.extend(144_u32.to_be_bytes());
+        profile.extend(14_u32.to_be_bytes());
+        profile.extend(b"curv\0\0\0\0");
+        profile.extend(1_u32.to_be_bytes());
+        profile.extend(gamma.to_be_bytes());
+
+        profile
+    }
+
+    fn linearize(transfer_function: &TransferFunction, values: &[f32]) -> Vec<f32> {
+        let mut buffer = Array3::from_shape_vec((1, values.len(), 1), values.to_vec())
+            .expect("Values should form a row");
+        transfer_function
+            .linearize_buffer(&mut buffer)
+            .expect("Buffer should be linearized");
+
+        buffer.into_raw_vec_and_offset().0
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        for (actual, expected) in actual.iter().zip(expected) {
+            assert!((actual - expected).abs() < 1e-4, "{actual} != {expected}");
+        }
+    }
+
+    #[test]
+    fn linearizes_standard_transfer_functions() {
+        let values = [0., 0.02, 0.5, 1.];
+
+        assert_close(
+            &linearize(&TransferFunction::Srgb, &values),
+            &[0., 0.02 / 12.92, 0.214_041, 1.],
+        );
+        assert_close(
+            &linearize(&TransferFunction::Rec709, &values),
+            &[0., 0.02 / 4.5, 0.259_589, 1.],
+        );
+        assert_close(
+            &linearize(&TransferFunction::Gamma(2.2), &values),
+            &values.map(|value: f32| value.powf(2.2)),
+        );
+        assert_close(&linearize(&TransferFunction::Linear, &values), &values);
+
+        let mut buffer = Array3::zeros((1, 1, 1));
+        assert!(TransferFunction::Gamma(0.)
+            .linearize_buffer(&mut buffer)
+            .is_err());
+    }
+
+    #[test]
+    fn reads_curves_of_icc_profiles() {
+        let transfer_function =
+            TransferFunction::from_icc_profile(&gray_profile(2.2)).expect("Profile is valid");
+
+        // The curve is sampled, so values between levels are interpolated
+        let values = [0.1, 0.5, 0.9];
+        let expected = values.map(|value: f32| value.powf(2.199_219));
+        for (actual, expected) in linearize(&transfer_function, &values).iter().zip(expected) {
+            assert!((actual - expected).abs() < 1e-3, "{actual} != {expected}");
+        }
+
+        let profile = gray_profile(2.2);
+        assert!(TransferFunction::from_icc_profile(&profile[..140]).is_err());
+        assert!(TransferFunction::from_icc_profile(&profile[..130]).is_err());
+    }
+}